@@ -0,0 +1,115 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Machine-readable description of what this build of scrying supports,
+//! for tools that drive it
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    version: &'static str,
+    modes: BTreeMap<&'static str, ModeCapability>,
+    features: BTreeMap<&'static str, bool>,
+    input_formats: Vec<&'static str>,
+    report_formats: Vec<&'static str>,
+    log_formats: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModeCapability {
+    available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl ModeCapability {
+    fn available() -> Self {
+        Self {
+            available: true,
+            detail: None,
+        }
+    }
+}
+
+/// Work out the capabilities of this build. Compile-time features are
+/// taken from the cargo features, and the web mode checks whether a
+/// browser can be found
+pub fn capabilities() -> Capabilities {
+    let mut modes = BTreeMap::new();
+    // RDP uses TLS for the connection, which is always compiled in
+    modes.insert("rdp", ModeCapability::available());
+    modes.insert("vnc", ModeCapability::available());
+    modes.insert("winrm", ModeCapability::available());
+    modes.insert(
+        "web",
+        match headless_chrome::browser::default_executable() {
+            Ok(path) => ModeCapability {
+                available: true,
+                detail: Some(format!("Browser: {}", path.display())),
+            },
+            Err(e) => ModeCapability {
+                available: false,
+                detail: Some(format!("No browser found: {}", e)),
+            },
+        },
+    );
+
+    let mut features = BTreeMap::new();
+    features.insert("clipboard", cfg!(feature = "clipboard"));
+    features.insert("vnc-unix-socket", cfg!(unix));
+
+    #[allow(unused_mut)]
+    let mut input_formats = vec![
+        "targets",
+        "text",
+        "nmap-xml",
+        "nmap-grepable",
+        "nessus-xml",
+        "masscan-json",
+        "masscan-list",
+        "csv",
+    ];
+    #[cfg(feature = "clipboard")]
+    input_formats.push("clipboard");
+
+    Capabilities {
+        version: clap::crate_version!(),
+        modes,
+        features,
+        input_formats,
+        report_formats: vec!["html", "json", "csv", "pdf"],
+        log_formats: vec!["text", "json"],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capabilities_listed() {
+        let caps = capabilities();
+        assert!(caps.modes["rdp"].available);
+        assert!(caps.modes.contains_key("web"));
+        assert_eq!(caps.features["clipboard"], cfg!(feature = "clipboard"));
+        assert!(caps.report_formats.contains(&"html"));
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Defaults for the command-line options, read from a TOML file given
+//! with --config. Each key is the long name of an option, with `_` or `-`
+//! between the words, and its settings are added to the command line
+//! unless the option was given there already
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Short names of the options, so that e.g. `-o` on the command line
+/// overrides `output` in the file
+const SHORT_OPTIONS: [(char, &str); 7] = [
+    ('f', "file"),
+    ('t', "target"),
+    ('m', "mode"),
+    ('l', "log-file"),
+    ('o', "output"),
+    ('s', "silent"),
+    ('v', "verbose"),
+];
+
+/// Find the --config file in the command-line arguments. The first
+/// argument is the binary name
+pub fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).map(|a| a.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(|path| PathBuf::from(path.as_ref()));
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Read the config file and turn its settings into command-line
+/// arguments, leaving out those for options that are already in `args`
+pub fn config_args(
+    path: &Path,
+    args: &[OsString],
+) -> Result<Vec<OsString>, String> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        format!("Unable to read config file {}: {}", path.display(), e)
+    })?;
+    let config: Value = content.parse().map_err(|e| {
+        format!("Invalid config file {}: {}", path.display(), e)
+    })?;
+    settings_args(&config, args)
+        .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+fn settings_args(
+    config: &Value,
+    args: &[OsString],
+) -> Result<Vec<OsString>, String> {
+    let table = match config {
+        Value::Table(table) => table,
+        _ => return Err("expected a table of settings".to_string()),
+    };
+    let mut settings: Vec<OsString> = Vec::new();
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        if long == "config" {
+            return Err("config files can't include another".to_string());
+        }
+        if given(&long, args) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        match value {
+            Value::Boolean(true) => settings.push(flag.into()),
+            Value::Boolean(false) => {}
+            Value::Array(items) => {
+                for item in items {
+                    settings.push(flag.clone().into());
+                    settings.push(scalar(key, item)?.into());
+                }
+            }
+            value => {
+                settings.push(flag.into());
+                settings.push(scalar(key, value)?.into());
+            }
+        }
+    }
+    Ok(settings)
+}
+
+/// The text of a string or number value, as it would be typed on the
+/// command line
+fn scalar(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        value => Err(format!("{} can't be a {}", key, value.type_str())),
+    }
+}
+
+/// Whether the option with the long name `long` is in the command-line
+/// arguments
+fn given(long: &str, args: &[OsString]) -> bool {
+    let short = SHORT_OPTIONS
+        .iter()
+        .find(|(_, name)| *name == long)
+        .map(|(short, _)| *short);
+    args.iter().skip(1).map(|a| a.to_string_lossy()).any(|arg| {
+        match arg.strip_prefix("--") {
+            Some(name) => name.split('=').next() == Some(long),
+            None => {
+                arg.starts_with('-')
+                    && short.is_some()
+                    && arg.chars().nth(1) == short
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn find_config() {
+        let cli = args(&["scrying", "-t", "192.0.2.1", "--config", "a.toml"]);
+        assert_eq!(config_path(&cli), Some(PathBuf::from("a.toml")));
+        let cli = args(&["scrying", "--config=b.toml"]);
+        assert_eq!(config_path(&cli), Some(PathBuf::from("b.toml")));
+        assert_eq!(config_path(&args(&["scrying", "-t", "x"])), None);
+    }
+
+    #[test]
+    fn config_settings() {
+        let config: Value = r#"
+            # Team defaults
+            threads = 20
+            vnc_timeout = 30
+            insecure = true
+            silent = false
+            output = "scans"
+            web-proxy = "http://192.0.2.9:8080"
+            output_header = ["X-Team: red", "X-Scan: weekly"]
+        "#
+        .parse()
+        .unwrap();
+
+        let cli = args(&["scrying", "-t", "192.0.2.1", "-o", "here"]);
+        let settings: Vec<String> = settings_args(&config, &cli)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.into_string().unwrap())
+            .collect();
+        assert_eq!(
+            settings,
+            vec![
+                "--insecure",
+                "--output-header",
+                "X-Team: red",
+                "--output-header",
+                "X-Scan: weekly",
+                "--threads",
+                "20",
+                "--vnc-timeout",
+                "30",
+                "--web-proxy",
+                "http://192.0.2.9:8080",
+            ]
+        );
+
+        // Command-line options win over the file
+        let cli = args(&["scrying", "--threads=4", "--vnc-timeout", "5"]);
+        let settings = settings_args(&config, &cli).unwrap();
+        assert!(!settings.contains(&OsString::from("--threads")));
+        assert!(!settings.contains(&OsString::from("--vnc-timeout")));
+        assert!(settings.contains(&OsString::from("--output")));
+
+        let nested: Value = "config = \"other.toml\"".parse().unwrap();
+        assert!(settings_args(&nested, &cli).is_err());
+        let table: Value = "[web]\nthreads = 4".parse().unwrap();
+        assert!(settings_args(&table, &cli).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Credentials for RDP and VNC read from a --creds-file, so that they
+//! don't have to be given on the command line where they end up in the
+//! shell history and the process list. The file is TOML, with a global
+//! credential at the top level and overrides in a table for each target:
+//!
+//! ```toml
+//! username = "scanner"
+//! password = "global secret"
+//! domain = "CORP"
+//!
+//! ["192.0.2.10:3389"]
+//! username = "Administrator"
+//! password = "per-target secret"
+//!
+//! ["192.0.2.20"]
+//! password = "for every port on the host"
+//! ```
+
+use crate::parsing::Target;
+use crate::resolve;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use toml::Value;
+
+#[derive(Clone, Default, PartialEq)]
+pub struct Credential {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub domain: Option<String>,
+}
+
+/// The passwords are kept out of the debug output of the options
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credential")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<hidden>"))
+            .field("domain", &self.domain)
+            .finish()
+    }
+}
+
+impl Credential {
+    fn from_table(
+        table: &BTreeMap<String, Value>,
+        name: &str,
+    ) -> Result<Self, String> {
+        let mut credential = Credential::default();
+        for (key, value) in table {
+            let field = match key.as_str() {
+                "username" => &mut credential.username,
+                "password" => &mut credential.password,
+                "domain" => &mut credential.domain,
+                // Target tables are read separately
+                _ if name.is_empty() && matches!(value, Value::Table(_)) => {
+                    continue
+                }
+                _ => return Err(format!("unknown key {}{}", name, key)),
+            };
+            match value {
+                Value::String(s) => *field = Some(s.clone()),
+                _ => return Err(format!("{}{} must be a string", name, key)),
+            }
+        }
+        Ok(credential)
+    }
+
+    /// This credential with any fields it doesn't have taken from
+    /// `fallback`
+    fn or(&self, fallback: &Credential) -> Credential {
+        Credential {
+            username: self
+                .username
+                .clone()
+                .or_else(|| fallback.username.clone()),
+            password: self
+                .password
+                .clone()
+                .or_else(|| fallback.password.clone()),
+            domain: self.domain.clone().or_else(|| fallback.domain.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Credentials {
+    global: Credential,
+    /// Overrides keyed by target, e.g. 192.0.2.1:3389, or by host
+    targets: BTreeMap<String, Credential>,
+}
+
+impl Credentials {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        Self::parse(&content)
+            .map_err(|e| format!("Invalid credentials file {}: {}", path, e))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let config: Value = content.parse().map_err(|e| format!("{}", e))?;
+        let table = match config {
+            Value::Table(table) => table,
+            _ => return Err("expected a table of credentials".to_string()),
+        };
+        let global = Credential::from_table(&table, "")?;
+        let mut targets = BTreeMap::new();
+        for (target, value) in &table {
+            if let Value::Table(entry) = value {
+                let name = format!("{}.", target);
+                targets.insert(
+                    target.to_ascii_lowercase(),
+                    Credential::from_table(entry, &name)?,
+                );
+            }
+        }
+        Ok(Self { global, targets })
+    }
+
+    /// The credential for the target: its own entry, then the entry for
+    /// its host, with anything they leave out coming from the global one
+    pub fn for_target(&self, target: &Target) -> Credential {
+        let mut names = vec![target.to_string()];
+        if let Target::Address(addr) = target {
+            names.push(addr.to_string());
+            if let Some(host) = resolve::hostname(addr) {
+                names.push(host);
+            }
+            names.push(addr.ip().to_string());
+        }
+        names
+            .iter()
+            .find_map(|name| self.targets.get(&name.to_ascii_lowercase()))
+            .map_or_else(|| self.global.clone(), |c| c.or(&self.global))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn credentials_file() {
+        let credentials = Credentials::parse(
+            r#"
+            username = "scanner"
+            password = "hunter2"
+            domain = "CORP"
+
+            ["192.0.2.10:3389"]
+            username = "Administrator"
+            password = "rdp"
+
+            ["192.0.2.20"]
+            password = "vnc"
+            "#,
+        )
+        .unwrap();
+
+        let target = |t: &str| Target::Address(t.parse().unwrap());
+        assert_eq!(
+            credentials.for_target(&target("192.0.2.10:3389")),
+            Credential {
+                username: Some("Administrator".to_string()),
+                password: Some("rdp".to_string()),
+                domain: Some("CORP".to_string()),
+            }
+        );
+        let vnc = credentials.for_target(&target("192.0.2.20:5900"));
+        assert_eq!(vnc.password.as_deref(), Some("vnc"));
+        assert_eq!(vnc.username.as_deref(), Some("scanner"));
+        let other = credentials.for_target(&target("192.0.2.10:5900"));
+        assert_eq!(other.password.as_deref(), Some("hunter2"));
+
+        assert!(!format!("{:?}", credentials).contains("hunter2"));
+        assert!(Credentials::parse("user = \"x\"").is_err());
+        assert!(Credentials::parse("password = 1234").is_err());
+    }
+}
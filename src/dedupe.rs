@@ -0,0 +1,171 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Spot screenshots that have already been saved, e.g. the same login
+//! screen on lots of cloned machines, using a perceptual hash so that
+//! small differences such as a clock don't stop them from matching
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::sync::Mutex;
+
+/// Shared by all of the capture paths so that duplicates are found
+/// across target types
+static SEEN_IMAGES: ImageIndex = ImageIndex::new();
+
+/// The first capture of an image, which later duplicates point back to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Original {
+    pub target: String,
+    pub file: String,
+}
+
+#[derive(Debug)]
+struct ImageIndex {
+    seen: Mutex<Vec<(u64, Original)>>,
+}
+
+impl ImageIndex {
+    const fn new() -> Self {
+        Self {
+            seen: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn clear(&self) {
+        self.seen.lock().unwrap().clear();
+    }
+
+    /// Return the first image whose hash is within `threshold` bits of
+    /// `hash`, or record `target` and `file` as the original for this
+    /// hash if there isn't one
+    fn find_or_insert(
+        &self,
+        hash: u64,
+        threshold: u32,
+        target: &str,
+        file: &str,
+    ) -> Option<Original> {
+        let mut seen = self.seen.lock().unwrap();
+        if let Some((_, original)) = seen
+            .iter()
+            .find(|(h, _)| (h ^ hash).count_ones() <= threshold)
+        {
+            return Some(original.clone());
+        }
+        seen.push((
+            hash,
+            Original {
+                target: target.to_string(),
+                file: file.to_string(),
+            },
+        ));
+        None
+    }
+}
+
+/// Difference hash of an image: it is shrunk to 9x8 greyscale pixels and
+/// each bit records whether a pixel is brighter than its right-hand
+/// neighbour
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).grayscale();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y).0[0];
+            let right = small.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | u64::from(left > right);
+        }
+    }
+    hash
+}
+
+/// If `threshold` is set (i.e. --dedupe was given), check whether the
+/// image matches one that has already been captured. If it doesn't, it
+/// is recorded as captured from `target` and saved as `file`, which is
+/// relative to the output directory.
+pub fn find_duplicate(
+    image: &DynamicImage,
+    threshold: Option<u32>,
+    target: &str,
+    file: &str,
+) -> Option<Original> {
+    let threshold = threshold?;
+    let hash = dhash(image);
+    debug!("Image hash for {}: {:016x}", target, hash);
+    SEEN_IMAGES.find_or_insert(hash, threshold, target, file)
+}
+
+/// Forget the images from an earlier run in the same process
+pub fn reset() {
+    SEEN_IMAGES.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// 9x8 image with a horizontal gradient, brightest on the left
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(9, 8, |x, _| {
+            let v = 255 - x as u8 * 20;
+            Rgb([v, v, v])
+        })
+    }
+
+    #[test]
+    fn hash_differences() {
+        let image = gradient();
+        assert_eq!(dhash(&DynamicImage::ImageRgb8(image.clone())), u64::MAX);
+
+        let mut changed = image;
+        changed.put_pixel(1, 0, Rgb([255, 255, 255]));
+        let changed = dhash(&DynamicImage::ImageRgb8(changed));
+        assert_eq!((changed ^ u64::MAX).count_ones(), 1);
+    }
+
+    #[test]
+    fn near_duplicates() {
+        let index = ImageIndex::new();
+        let hash = 0xff00_ff00_ff00_ff00;
+        assert_eq!(index.find_or_insert(hash, 2, "a", "a.png"), None);
+        let original = Some(Original {
+            target: "a".to_string(),
+            file: "a.png".to_string(),
+        });
+        assert_eq!(index.find_or_insert(hash, 0, "b", "b.png"), original);
+        assert_eq!(
+            index.find_or_insert(hash ^ 0b11, 2, "c", "c.png"),
+            original
+        );
+
+        // Too many bits different, so this becomes a new original
+        assert_eq!(index.find_or_insert(hash ^ 0b111, 2, "d", "d.png"), None);
+        assert_eq!(
+            index
+                .find_or_insert(hash ^ 0b111, 0, "e", "e.png")
+                .unwrap()
+                .target,
+            "d"
+        );
+    }
+}
@@ -25,9 +25,24 @@ pub enum Error {
     #[error("IO error: {0}")]
     IoError(String),
 
+    /// The target actively refused the connection
+    #[error("IO error: {0}")]
+    ConnectionRefused(String),
+
+    /// The connection, or a read or write on it, timed out
+    #[error("IO error: {0}")]
+    ConnectionTimedOut(String),
+
     #[error("RDP error: {0}")]
     RdpError(String),
 
+    #[error("RDP server requires Network Level Authentication")]
+    RdpNlaRequired,
+
+    /// The server doesn't support TLS, which rdp-rs needs
+    #[error("RDP server only supports Standard RDP Security, without TLS")]
+    RdpStandardSecurityOnly,
+
     #[error("MPSC error: {0}")]
     MpscError(String),
 
@@ -37,8 +52,29 @@ pub enum Error {
     #[error("VNC error: {0}")]
     VncError(String),
 
+    #[error("PDF error: {0}")]
+    PdfError(String),
+
+    #[error("JSON error: {0}")]
+    JsonError(String),
+
+    #[error("WinRM error: {0}")]
+    WinRmError(String),
+
     #[error("Conversion error: {0}")]
     ConversionError(String),
+
+    #[error("Image is a single colour")]
+    BlankImage,
+
+    #[error("Target error: {0}")]
+    TargetError(String),
+
+    #[error("Output error: {0}")]
+    OutputError(String),
+
+    #[error("Capture still running after {0}s, abandoned")]
+    CaptureTimeout(u64),
 }
 
 impl From<failure::Error> for Error {
@@ -49,13 +85,31 @@ impl From<failure::Error> for Error {
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Self::IoError(e.to_string())
+        use std::io::ErrorKind::*;
+        // Refusals and timeouts are kept apart so that only timeouts are
+        // retried. Read timeouts are reported as WouldBlock on Unix
+        match e.kind() {
+            ConnectionRefused => Self::ConnectionRefused(e.to_string()),
+            TimedOut | WouldBlock => Self::ConnectionTimedOut(e.to_string()),
+            _ => Self::IoError(e.to_string()),
+        }
     }
 }
 
 impl From<rdp::model::error::Error> for Error {
     fn from(e: rdp::model::error::Error) -> Self {
-        Self::RdpError(format!("{:?}", e))
+        use std::io::ErrorKind::*;
+        match e {
+            rdp::model::error::Error::Io(e)
+                if matches!(
+                    e.kind(),
+                    ConnectionRefused | TimedOut | WouldBlock
+                ) =>
+            {
+                e.into()
+            }
+            e => Self::RdpError(format!("{:?}", e)),
+        }
     }
 }
 
@@ -77,9 +131,32 @@ impl From<askama::shared::Error> for Error {
     }
 }
 
+impl From<printpdf::Error> for Error {
+    fn from(e: printpdf::Error) -> Self {
+        Self::PdfError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::JsonError(e.to_string())
+    }
+}
+
 impl From<vnc::Error> for Error {
     fn from(e: vnc::Error) -> Self {
-        Self::VncError(e.to_string())
+        use std::io::ErrorKind::*;
+        match e {
+            vnc::Error::Io(e)
+                if matches!(
+                    e.kind(),
+                    ConnectionRefused | TimedOut | WouldBlock
+                ) =>
+            {
+                e.into()
+            }
+            e => Self::VncError(e.to_string()),
+        }
     }
 }
 
@@ -0,0 +1,160 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Lifecycle events written to stdout as JSON lines for --emit-events,
+//! so that other tools can follow a run as it happens
+
+use crate::logging;
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Start or stop writing events to stdout
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event<'a> {
+    /// A worker has started on the target
+    Dispatched,
+    /// A connection was made to the target
+    Connected,
+    /// The target was captured, with the image file if there is one
+    Captured { file: Option<&'a str> },
+    /// The capture failed
+    Failed { error: &'a str },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct EventRecord<'a> {
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+    event: &'static str,
+    mode: &'a str,
+    target: String,
+    correlation_id: Option<String>,
+    file: Option<&'a str>,
+    error: Option<&'a str>,
+}
+
+impl<'a> EventRecord<'a> {
+    fn new(
+        event: Event<'a>,
+        mode: &'a str,
+        target: &dyn Display,
+        correlation_id: Option<String>,
+    ) -> Self {
+        let (name, file, error) = match event {
+            Event::Dispatched => ("dispatched", None, None),
+            Event::Connected => ("connected", None, None),
+            Event::Captured { file } => ("captured", file, None),
+            Event::Failed { error } => ("failed", None, Some(error)),
+        };
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default(),
+            event: name,
+            mode,
+            target: target.to_string(),
+            correlation_id,
+            file,
+            error,
+        }
+    }
+}
+
+/// Write an event for the target if --emit-events was given, tagged with
+/// the current thread's correlation ID. `mode` is the capture type, e.g.
+/// "rdp"
+pub fn emit(event: Event<'_>, mode: &str, target: &dyn Display) {
+    if ENABLED.load(Ordering::Relaxed) {
+        write(EventRecord::new(
+            event,
+            mode,
+            target,
+            logging::correlation_id(),
+        ));
+    }
+}
+
+/// Write an event for a target captured by another thread, with that
+/// thread's correlation ID
+pub fn emit_for(
+    event: Event<'_>,
+    mode: &str,
+    target: &dyn Display,
+    correlation_id: &str,
+) {
+    if ENABLED.load(Ordering::Relaxed) {
+        let id = Some(correlation_id.to_string()).filter(|id| !id.is_empty());
+        write(EventRecord::new(event, mode, target, id));
+    }
+}
+
+fn write(record: EventRecord<'_>) {
+    if let Ok(line) = serde_json::to_string(&record) {
+        // Lock so that lines from different threads aren't interleaved.
+        // A consumer that has gone away can't be told about errors
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn event_records() {
+        let record = EventRecord::new(
+            Event::Captured {
+                file: Some("vnc/192.0.2.1-5900.png"),
+            },
+            "vnc",
+            &"192.0.2.1:5900",
+            Some("T00007".to_string()),
+        );
+        assert_eq!(record.event, "captured");
+        assert_eq!(record.mode, "vnc");
+        assert_eq!(record.target, "192.0.2.1:5900");
+        assert_eq!(record.correlation_id.as_deref(), Some("T00007"));
+        assert_eq!(record.file, Some("vnc/192.0.2.1-5900.png"));
+        assert_eq!(record.error, None);
+
+        let record = EventRecord::new(
+            Event::Failed {
+                error: "IO error: connection refused",
+            },
+            "rdp",
+            &"192.0.2.2:3389",
+            None,
+        );
+        assert_eq!(record.event, "failed");
+        assert_eq!(record.file, None);
+        assert_eq!(record.error, Some("IO error: connection refused"));
+    }
+}
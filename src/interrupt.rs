@@ -0,0 +1,113 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Ctrl-C handling. The first Ctrl-C stops new captures from being
+//! started and gives the ones in progress a short time to finish, so that
+//! the reports still get written. A second Ctrl-C exits straight away
+
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, Once};
+use std::time::Duration;
+
+/// How long captures that are in progress are given to finish after
+/// Ctrl-C is pressed
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+static INSTALL: Once = Once::new();
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+static STOPPING: Mutex<bool> = Mutex::new(false);
+static STOPPED: Condvar = Condvar::new();
+
+/// Handle Ctrl-C for the rest of the process. Only the first call has
+/// any effect
+pub fn install() {
+    INSTALL.call_once(|| {
+        if let Err(e) = ctrlc::set_handler(interrupted) {
+            warn!("Unable to handle Ctrl-C, it will exit at once: {}", e);
+        }
+    });
+}
+
+fn interrupted() {
+    if INTERRUPTS.fetch_add(1, Ordering::SeqCst) > 0 {
+        eprintln!("Interrupted again, exiting without writing the reports");
+        process::exit(130);
+    }
+    warn!(
+        "Interrupted, waiting up to {}s for the captures in progress to \
+        finish. Press Ctrl-C again to exit now",
+        GRACE_PERIOD.as_secs()
+    );
+    stop();
+}
+
+fn stop() {
+    *STOPPING.lock().unwrap() = true;
+    STOPPED.notify_all();
+}
+
+/// Forget any Ctrl-C from an earlier run in the same process
+pub fn reset() {
+    INTERRUPTS.store(0, Ordering::SeqCst);
+    *STOPPING.lock().unwrap() = false;
+}
+
+/// Whether Ctrl-C has been pressed, in which case no more captures
+/// should be started
+pub fn stopping() -> bool {
+    *STOPPING.lock().unwrap()
+}
+
+/// Sleep for `timeout`, returning early with true if Ctrl-C is pressed
+pub fn wait(timeout: Duration) -> bool {
+    let stopping = STOPPING.lock().unwrap();
+    let (stopping, _) = STOPPED
+        .wait_timeout_while(stopping, timeout, |stopping| !*stopping)
+        .unwrap();
+    *stopping
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn wait_until_stopped() {
+        assert!(!stopping());
+        assert!(!wait(Duration::from_millis(10)));
+
+        let start = Instant::now();
+        let waiter = thread::spawn(|| wait(Duration::from_secs(60)));
+        thread::sleep(Duration::from_millis(50));
+        stop();
+        assert!(waiter.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(stopping());
+        assert!(wait(Duration::from_secs(60)));
+
+        reset();
+        assert!(!stopping());
+        assert!(!wait(Duration::from_millis(10)));
+    }
+}
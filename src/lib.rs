@@ -0,0 +1,159 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Capture RDP, web, and VNC screenshots from other programs. Build the
+//! `Opts` with the settings to use, and the capture mode, then call
+//! `capture` for each target:
+//!
+//! ```no_run
+//! use scrying::{Mode, Opts, Target};
+//!
+//! let opts = Opts {
+//!     mode: Mode::Vnc,
+//!     vnc_frame_timeout: 30,
+//!     ..Default::default()
+//! };
+//! let target = Target::Address("192.0.2.1:5900".parse().unwrap());
+//! let capture = scrying::capture(&target, &opts).unwrap();
+//! std::fs::write("192.0.2.1-5900.png", capture.image).unwrap();
+//! ```
+//!
+//! `run` does everything the scrying binary does, saving the images and
+//! reports for all of the targets in `Opts` to the output directory.
+
+use log::info;
+
+mod argparse;
+mod capabilities;
+mod config;
+mod credentials;
+mod dedupe;
+mod digest;
+mod error;
+mod events;
+mod interrupt;
+mod logging;
+mod parsing;
+mod probe;
+mod progress;
+mod rdp;
+mod reporting;
+mod resolve;
+mod scan;
+mod sink;
+mod throttle;
+mod tls;
+mod util;
+mod vnc;
+mod watchdog;
+mod web;
+mod winrm;
+
+pub use argparse::{parse as parse_args, ImageFormat, Mode, Opts};
+pub use error::Error;
+pub use parsing::{AddrRange, Target};
+pub use scan::{run, Outcome};
+
+pub(crate) enum ThreadStatus {
+    Complete,
+}
+
+/// An image captured from a single target
+#[derive(Debug)]
+pub struct Capture {
+    pub mode: Mode,
+    pub target: String,
+    /// The image, encoded in `format`
+    pub image: Vec<u8>,
+    pub format: ImageFormat,
+    /// VNC desktop name or web page title
+    pub banner: Option<String>,
+}
+
+/// Capture an image of the target using `opts.mode`. Web targets can be
+/// captured with `Mode::Auto`, but RDP and VNC ones need the mode to be
+/// chosen as they are both addresses. Nothing is written to disk
+pub fn capture(target: &Target, opts: &Opts) -> Result<Capture, Error> {
+    let (image, banner) = match (opts.mode, target) {
+        (Mode::Rdp, _) => {
+            let (image, _) = rdp::grab(target, opts)?;
+            (encode(image, opts)?, None)
+        }
+        (Mode::Vnc, _) => {
+            let frame = vnc::grab(target, opts)?;
+            let desktop_name = Some(frame.desktop_name);
+            (
+                encode(frame.image, opts)?,
+                desktop_name.filter(|n| !n.is_empty()),
+            )
+        }
+        (Mode::Web, Target::Url(url)) | (Mode::Auto, Target::Url(url)) => {
+            let browser = web::launch_browser(opts)?;
+            let tab = browser.wait_for_initial_tab()?;
+            let page = web::grab(url, opts, &tab)?;
+            if opts.only_successful
+                && util::is_uniform(&image::load_from_memory(&page.image_data)?)
+            {
+                return Err(Error::BlankImage);
+            }
+            (page.image_data, page.title)
+        }
+        (mode, Target::Address(_)) => {
+            return Err(Error::TargetError(format!(
+                "Unable to capture {} in {:?} mode",
+                target, mode
+            )));
+        }
+    };
+    info!("Captured {}", target);
+    Ok(Capture {
+        mode: opts.mode,
+        target: target.to_string(),
+        image,
+        format: opts.image_format,
+        banner,
+    })
+}
+
+fn encode(image: image::DynamicImage, opts: &Opts) -> Result<Vec<u8>, Error> {
+    if opts.only_successful && util::is_uniform(&image) {
+        return Err(Error::BlankImage);
+    }
+    util::encode_image(&image, opts.image_format, opts.jpeg_quality)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture_needs_a_mode() {
+        let target = Target::Address("192.0.2.1:5900".parse().unwrap());
+        for mode in &[Mode::Auto, Mode::Web] {
+            let opts = Opts {
+                mode: *mode,
+                ..Default::default()
+            };
+            match capture(&target, &opts) {
+                Err(Error::TargetError(_)) => {}
+                other => panic!("{:?} mode gave {:?}", mode, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Logging helpers for tagging log lines with the correlation ID, mode,
+//! and target currently being processed by the thread, and for writing
+//! the log file as JSON lines.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use simplelog::{Config, SharedLogger};
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    static TARGET: RefCell<Option<TargetContext>> = const { RefCell::new(None) };
+}
+
+/// The target that the current thread is capturing
+#[derive(Clone, Debug, PartialEq)]
+struct TargetContext {
+    mode: &'static str,
+    target: String,
+}
+
+/// Generate a new correlation ID, unique within this run
+pub fn next_correlation_id() -> String {
+    format!("T{:05}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Set the correlation ID for log messages from the current thread
+pub fn set_correlation_id(id: Option<String>) {
+    CORRELATION_ID.with(|c| *c.borrow_mut() = id);
+}
+
+/// Get the correlation ID of the current thread, if one has been set
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.with(|c| c.borrow().clone())
+}
+
+/// Set the mode and target for log messages from the current thread
+pub fn set_target(mode: &'static str, target: &dyn Display) {
+    TARGET.with(|t| {
+        *t.borrow_mut() = Some(TargetContext {
+            mode,
+            target: target.to_string(),
+        })
+    });
+}
+
+fn target_context() -> Option<TargetContext> {
+    TARGET.with(|t| t.borrow().clone())
+}
+
+/// Prefix for log lines from the current thread, e.g.
+/// "[T00001 vnc 192.0.2.1:5900]"
+fn context_prefix() -> Option<String> {
+    match (correlation_id(), target_context()) {
+        (Some(id), Some(t)) => {
+            Some(format!("[{} {} {}]", id, t.mode, t.target))
+        }
+        (Some(id), None) => Some(format!("[{}]", id)),
+        (None, Some(t)) => Some(format!("[{} {}]", t.mode, t.target)),
+        (None, None) => None,
+    }
+}
+
+/// Wraps another logger to prefix each message with the correlation ID
+/// and target
+pub struct CorrelatedLogger {
+    inner: Box<dyn SharedLogger>,
+}
+
+impl CorrelatedLogger {
+    pub fn new(inner: Box<dyn SharedLogger>) -> Box<Self> {
+        Box::new(Self { inner })
+    }
+}
+
+impl Log for CorrelatedLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        match context_prefix() {
+            Some(prefix) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("{} {}", prefix, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+impl SharedLogger for CorrelatedLogger {
+    fn level(&self) -> LevelFilter {
+        self.inner.level()
+    }
+
+    fn config(&self) -> Option<&Config> {
+        self.inner.config()
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+    level: String,
+    target: &'a str,
+    correlation_id: Option<String>,
+    /// Capture mode and target being processed by the logging thread
+    mode: Option<&'static str>,
+    scan_target: Option<String>,
+    message: String,
+}
+
+/// Writes log messages to a file as JSON, one object per line
+pub struct JsonLogger {
+    level: LevelFilter,
+    file: Mutex<File>,
+}
+
+impl JsonLogger {
+    pub fn new(level: LevelFilter, file: File) -> Box<Self> {
+        Box::new(Self {
+            level,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let context = target_context();
+        let json_record = JsonRecord {
+            timestamp,
+            level: record.level().to_string(),
+            target: record.target(),
+            correlation_id: correlation_id(),
+            mode: context.as_ref().map(|c| c.mode),
+            scan_target: context.map(|c| c.target),
+            message: record.args().to_string(),
+        };
+        if let Ok(line) = serde_json::to_string(&json_record) {
+            let mut file = self.file.lock().unwrap();
+            // Nowhere to report logging failures, so ignore them
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+impl SharedLogger for JsonLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn correlation_id_is_per_thread() {
+        let id = next_correlation_id();
+        set_correlation_id(Some(id.clone()));
+        assert_eq!(correlation_id(), Some(id.clone()));
+
+        // Other threads do not see the ID
+        thread::spawn(|| assert_eq!(correlation_id(), None))
+            .join()
+            .unwrap();
+
+        assert_ne!(next_correlation_id(), id);
+        set_correlation_id(None);
+        assert_eq!(correlation_id(), None);
+    }
+
+    #[test]
+    fn target_context_prefix() {
+        thread::spawn(|| {
+            assert_eq!(context_prefix(), None);
+            set_correlation_id(Some("T00042".to_string()));
+            assert_eq!(context_prefix().unwrap(), "[T00042]");
+            set_correlation_id(None);
+
+            set_target("vnc", &"192.0.2.1:5900");
+            assert_eq!(context_prefix().unwrap(), "[vnc 192.0.2.1:5900]");
+
+            set_correlation_id(Some("T00042".to_string()));
+            assert_eq!(
+                context_prefix().unwrap(),
+                "[T00042 vnc 192.0.2.1:5900]"
+            );
+
+            set_correlation_id(None);
+            set_target("web", &"http://192.0.2.2/");
+            assert_eq!(context_prefix().unwrap(), "[web http://192.0.2.2/]");
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of targets from CSV files, e.g. exports from Shodan or Censys,
+//! with the columns to read given by --csv-columns
+
+use super::ScannedService;
+use crate::resolve;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Where to find a field in each row: a header name, matched ignoring
+/// case, or a column number starting from 1
+#[derive(Clone, Debug, PartialEq)]
+enum Column {
+    Name(String),
+    Number(usize),
+}
+
+impl Column {
+    fn parse(val: &str) -> Self {
+        match val.parse::<usize>() {
+            Ok(n) if n > 0 => Column::Number(n),
+            _ => Column::Name(val.to_ascii_lowercase()),
+        }
+    }
+
+    /// Index of the column in the rows, given the header if there is one
+    fn index(&self, header: Option<&[String]>) -> Result<usize, String> {
+        match self {
+            Column::Number(n) => Ok(n - 1),
+            Column::Name(name) => header
+                .and_then(|h| {
+                    h.iter().position(|c| c.trim().eq_ignore_ascii_case(name))
+                })
+                .ok_or_else(|| format!("No column named {:?}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Name(name) => write!(f, "{}", name),
+            Column::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Which columns of a CSV file hold each field, parsed from --csv-columns,
+/// e.g. host=ip_str,port=port,hostname=hostnames,service=product. The
+/// hostname and service columns are optional
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvColumns {
+    host: Column,
+    port: Column,
+    hostname: Option<Column>,
+    service: Option<Column>,
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        Self {
+            host: Column::Name("host".to_string()),
+            port: Column::Name("port".to_string()),
+            hostname: Some(Column::Name("hostname".to_string())),
+            service: Some(Column::Name("service".to_string())),
+        }
+    }
+}
+
+impl CsvColumns {
+    pub fn parse(val: &str) -> Result<Self, String> {
+        let mut fields = HashMap::new();
+        for mapping in val.split(',') {
+            let (field, column) = mapping.split_once('=').ok_or_else(|| {
+                format!("{:?} is not of the form field=column", mapping)
+            })?;
+            let field = field.trim();
+            if !["host", "port", "hostname", "service"].contains(&field) {
+                return Err(format!(
+                    "Unknown CSV field {:?}, expected host, port, hostname \
+                    or service",
+                    field
+                ));
+            }
+            let column = column.trim();
+            if column.is_empty() {
+                return Err(format!("No column given for {}", field));
+            }
+            fields.insert(field, Column::parse(column));
+        }
+        let mut required = |field| {
+            fields
+                .remove(field)
+                .ok_or_else(|| format!("A column must be given for {}", field))
+        };
+        Ok(Self {
+            host: required("host")?,
+            port: required("port")?,
+            hostname: fields.remove("hostname"),
+            service: fields.remove("service"),
+        })
+    }
+
+    fn columns(&self) -> impl Iterator<Item = &Column> {
+        vec![Some(&self.host), Some(&self.port)]
+            .into_iter()
+            .chain(vec![self.hostname.as_ref(), self.service.as_ref()])
+            .flatten()
+    }
+}
+
+/// A target read from a CSV row
+#[derive(Debug, PartialEq)]
+pub struct CsvTarget {
+    /// The service, with the hostname as its host if there is one
+    pub service: ScannedService,
+    /// The address given for the hostname, to resolve it to
+    pub address: Option<IpAddr>,
+}
+
+/// The targets read from a CSV file, and how many rows were skipped
+/// because they couldn't be read
+#[derive(Debug, Default, PartialEq)]
+pub struct CsvImport {
+    pub targets: Vec<CsvTarget>,
+    pub skipped: usize,
+}
+
+/// Read the targets from a CSV file. If any of the columns are given by
+/// name, the first row must be a header naming them. Otherwise a first
+/// row without a valid port is taken to be a header and skipped
+pub fn parse_csv(
+    content: &str,
+    columns: &CsvColumns,
+) -> Result<CsvImport, String> {
+    let mut rows = content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(split_record)
+        .peekable();
+
+    let by_name = columns.columns().any(|c| matches!(c, Column::Name(_)));
+    let header = if by_name {
+        match rows.next() {
+            Some(Some(header)) => Some(header),
+            Some(None) => return Err("Unreadable CSV header".to_string()),
+            None => return Ok(Default::default()),
+        }
+    } else {
+        None
+    };
+    let index = |column: &Column| column.index(header.as_deref());
+    let host_index = index(&columns.host)?;
+    let port_index = index(&columns.port)?;
+    let hostname_index = columns.hostname.as_ref().map(index).transpose()?;
+    let service_index = columns.service.as_ref().map(index).transpose()?;
+
+    if !by_name {
+        let is_header = match rows.peek() {
+            Some(Some(row)) => row
+                .get(port_index)
+                .is_some_and(|p| p.trim().parse::<u16>().is_err()),
+            _ => false,
+        };
+        if is_header {
+            rows.next();
+        }
+    }
+
+    let mut import = CsvImport::default();
+    for row in rows {
+        let fields = |row: &[String]| -> Option<CsvTarget> {
+            let host = row.get(host_index)?.trim();
+            let port = match row.get(port_index)?.trim().parse::<u16>() {
+                Ok(port) if port > 0 => port,
+                _ => return None,
+            };
+            let address = host.parse::<IpAddr>().ok();
+            if address.is_none() && !resolve::is_hostname(host) {
+                return None;
+            }
+            let hostname = hostname_index
+                .and_then(|i| row.get(i))
+                .and_then(|h| first_hostname(h));
+            let name = service_index
+                .and_then(|i| row.get(i))
+                .map(|s| service_name(s))
+                .unwrap_or_default();
+            let (host, address) = match (hostname, address) {
+                (Some(hostname), Some(ip)) => (hostname, Some(ip)),
+                (_, Some(IpAddr::V6(ip))) => (format!("[{}]", ip), None),
+                _ => (host.to_string(), None),
+            };
+            Some(CsvTarget {
+                service: ScannedService { host, port, name },
+                address,
+            })
+        };
+        match row.as_deref().and_then(fields) {
+            Some(target) => import.targets.push(target),
+            None => import.skipped += 1,
+        }
+    }
+    Ok(import)
+}
+
+/// Split a CSV line into its fields, handling quoted fields with ""
+/// for a literal quote. Returns None if a quote isn't closed, as fields
+/// spanning lines aren't supported
+fn split_record(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+/// The first valid hostname in a field that may list several, e.g.
+/// Shodan's semicolon separated hostnames
+fn first_hostname(field: &str) -> Option<String> {
+    field
+        .split(|c: char| c == ';' || c == ',' || c.is_whitespace())
+        .map(|h| h.trim().trim_end_matches('.').to_ascii_lowercase())
+        .find(|h| !h.is_empty() && resolve::is_hostname(h))
+}
+
+/// Convert a service name in a CSV file into the nmap equivalent
+fn service_name(name: &str) -> String {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "rdp" | "ms-wbt-server" | "remote desktop protocol" => "ms-wbt-server",
+        "http" | "http-alt" => "http",
+        "https" | "ssl/http" | "https-alt" => "https",
+        "vnc" | "rfb" => "vnc",
+        "wsman" | "winrm" => "wsman",
+        "wsmans" => "wsmans",
+        _ => "",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn target(host: &str, port: u16, name: &str) -> CsvTarget {
+        CsvTarget {
+            service: ScannedService {
+                host: host.to_string(),
+                port,
+                name: name.to_string(),
+            },
+            address: None,
+        }
+    }
+
+    #[test]
+    fn csv_columns() {
+        assert_eq!(
+            CsvColumns::parse(
+                "host=host,port=port,hostname=hostname,service=service"
+            ),
+            Ok(CsvColumns::default())
+        );
+        assert_eq!(
+            CsvColumns::parse("port=2, host=IP_STR"),
+            Ok(CsvColumns {
+                host: Column::Name("ip_str".to_string()),
+                port: Column::Number(2),
+                hostname: None,
+                service: None,
+            })
+        );
+        assert!(CsvColumns::parse("host=1").is_err());
+        assert!(CsvColumns::parse("host=1,port=2,banner=3").is_err());
+        assert!(CsvColumns::parse("host=1,port").is_err());
+        assert!(CsvColumns::parse("host=,port=2").is_err());
+    }
+
+    #[test]
+    fn csv_import() {
+        let content = "Host,Port,Hostname,Service\r\n\
+            192.0.2.1,3389,,rdp\r\n\
+            192.0.2.2,8443,\"intranet.example.com;www.example.com\",HTTPS\r\n\
+            2001:db8::3,5901,,\r\n\
+            192.0.2.4,not a port,,http\r\n\
+            \"192.0.2.5,80\r\n\
+            \r\n\
+            fileserver,445,,smb\r\n";
+        let import = parse_csv(content, &CsvColumns::default()).unwrap();
+        assert_eq!(
+            import.targets,
+            vec![
+                target("192.0.2.1", 3389, "ms-wbt-server"),
+                CsvTarget {
+                    address: Some("192.0.2.2".parse().unwrap()),
+                    ..target("intranet.example.com", 8443, "https")
+                },
+                target("[2001:db8::3]", 5901, ""),
+                target("fileserver", 445, ""),
+            ]
+        );
+        assert_eq!(import.skipped, 2);
+
+        // Columns given by number, with and without a header
+        let columns = CsvColumns::parse("host=2,port=1").unwrap();
+        for content in &["port,ip\n80,192.0.2.1\n", "80,192.0.2.1\n"] {
+            let import = parse_csv(content, &columns).unwrap();
+            assert_eq!(import.targets, vec![target("192.0.2.1", 80, "")]);
+            assert_eq!(import.skipped, 0);
+        }
+
+        let columns = CsvColumns::parse("host=ip_str,port=port").unwrap();
+        assert_eq!(
+            parse_csv("ip,port\n192.0.2.1,80\n", &columns),
+            Err("No column named \"ip_str\"".to_string())
+        );
+    }
+
+    #[test]
+    fn csv_records() {
+        assert_eq!(
+            split_record(r#"a,"b,c","say ""hi""",,"#),
+            Some(vec![
+                "a".to_string(),
+                "b,c".to_string(),
+                "say \"hi\"".to_string(),
+                String::new(),
+                String::new(),
+            ])
+        );
+        assert_eq!(split_record(r#"a,"b"#), None);
+    }
+}
@@ -0,0 +1,458 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Expansion of address and port ranges into individual targets
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Expand address and port ranges into individual host:port targets.
+/// Returns None if the input isn't a range, in which case it should be
+/// parsed as a normal target. Supported address formats are:
+///
+/// * CIDR ranges, e.g. 192.0.2.0/24 or 2001:db8::/120
+/// * IPv4 ranges, either varying only the last octet, e.g.
+///   192.0.2.1-50, or between two full addresses, e.g.
+///   192.0.2.200-192.0.3.10
+///
+/// Any of these, or a single host, may be followed by a port list such as
+/// `:5900-5905,5910`. IPv6 addresses must be in brackets to be given a
+/// port, e.g. `[2001:db8::1]:5900-5905`. Hosts without a port are given
+/// each of `default_ports`, if there are any.
+///
+/// Ranges containing more than `max` targets are rejected to avoid
+/// accidentally scanning huge networks.
+pub fn expand(
+    input: &str,
+    max: usize,
+    default_ports: &[u16],
+) -> Result<Option<Vec<String>>, String> {
+    let input = input.trim();
+    if input.contains("://") {
+        return Ok(None);
+    }
+
+    let (host, port_spec) = split_ports(input);
+    let ports = match port_spec {
+        Some(spec) => Some(parse_ports(spec)?),
+        None if !default_ports.is_empty() => Some(default_ports.to_vec()),
+        None => None,
+    };
+
+    let hosts = if let Some((addr, prefix)) = parse_cidr(host) {
+        Some(expand_cidr(addr, prefix, max)?)
+    } else if let Some((start, end)) = parse_dashed(host)? {
+        Some(expand_v4_range(start, end, max, host)?)
+    } else {
+        None
+    };
+
+    let ports = match ports {
+        Some(ports)
+            if hosts.is_some() || ports.len() > 1 || port_spec.is_none() =>
+        {
+            ports
+        }
+        // A single explicit port on a single host is a normal target
+        Some(_) => return Ok(None),
+        None => return Ok(hosts),
+    };
+    let hosts = hosts.unwrap_or_else(|| vec![host.to_string()]);
+
+    let size = hosts.len() as u128 * ports.len() as u128;
+    if size > max as u128 {
+        return Err(format!(
+            "{} contains {} targets, which is more than the limit of {} \
+            (see --max-expand)",
+            input, size, max
+        ));
+    }
+
+    let mut expanded = Vec::with_capacity(hosts.len() * ports.len());
+    for host in &hosts {
+        for port in &ports {
+            if host.parse::<Ipv6Addr>().is_ok() {
+                expanded.push(format!("[{}]:{}", host, port));
+            } else {
+                expanded.push(format!("{}:{}", host, port));
+            }
+        }
+    }
+    Ok(Some(expanded))
+}
+
+/// Split a target into its host and port list, e.g. "192.0.2.1:5900-5905"
+/// into "192.0.2.1" and "5900-5905". Unbracketed IPv6 addresses are
+/// returned whole as they can't be given a port
+fn split_ports(input: &str) -> (&str, Option<&str>) {
+    let (host, spec) = if let Some(rest) = input.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((host, "")) => return (host, None),
+            Some((host, spec)) => match spec.strip_prefix(':') {
+                Some(spec) => (host, spec),
+                None => return (input, None),
+            },
+            None => return (input, None),
+        }
+    } else {
+        match input.split_once(':') {
+            Some((host, spec)) if !spec.contains(':') => (host, spec),
+            _ => return (input, None),
+        }
+    };
+
+    if !spec.is_empty()
+        && spec
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '-' || c == ',')
+    {
+        (host, Some(spec))
+    } else {
+        (input, None)
+    }
+}
+
+/// Parse a comma-separated list of ports and port ranges, e.g.
+/// "80,443,8000-8010"
+pub fn parse_ports(spec: &str) -> Result<Vec<u16>, String> {
+    let mut ports = Vec::new();
+    for part in spec.split(',') {
+        let parse = |p: &str| match p.trim().parse::<u16>() {
+            Ok(port) if port > 0 => Ok(port),
+            _ => Err(format!("Invalid port {:?} in {}", p, spec)),
+        };
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if end < start {
+                    return Err(format!(
+                        "End of port range {} is before the start {} in {}",
+                        end, start, spec
+                    ));
+                }
+                ports.extend(start..=end);
+            }
+            None => ports.push(parse(part)?),
+        }
+    }
+    Ok(ports)
+}
+
+/// Split "address/prefix" into its parts, if it is in that form
+fn parse_cidr(input: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = input.split_once('/')?;
+    Some((addr.parse().ok()?, prefix.parse().ok()?))
+}
+
+/// Parse "start-end" where start is an IPv4 address and end is either a
+/// full address or the last octet. Returns None if the input isn't in
+/// that form, e.g. a hostname containing a hyphen
+fn parse_dashed(input: &str) -> Result<Option<(Ipv4Addr, Ipv4Addr)>, String> {
+    let (start, end) = match input.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let start: Ipv4Addr = match start.parse() {
+        Ok(start) => start,
+        Err(_) => return Ok(None),
+    };
+
+    let end = if let Ok(end) = end.parse::<Ipv4Addr>() {
+        end
+    } else if let Ok(last_octet) = end.parse::<u8>() {
+        let [a, b, c, _] = start.octets();
+        Ipv4Addr::new(a, b, c, last_octet)
+    } else {
+        return Err(format!("Invalid end of range in {}", input));
+    };
+
+    if end < start {
+        return Err(format!(
+            "End of range {} is before the start {} in {}",
+            end, start, input
+        ));
+    }
+    Ok(Some((start, end)))
+}
+
+fn expand_v4_range(
+    start: Ipv4Addr,
+    end: Ipv4Addr,
+    max: usize,
+    input: &str,
+) -> Result<Vec<String>, String> {
+    let (start, end) = (u32::from(start), u32::from(end));
+    check_size(u128::from(end - start) + 1, max, input)?;
+    Ok((start..=end)
+        .map(|a| Ipv4Addr::from(a).to_string())
+        .collect())
+}
+
+fn check_size(size: u128, max: usize, input: &str) -> Result<(), String> {
+    if size > max as u128 {
+        Err(format!(
+            "{} contains {} addresses, which is more than the limit of {} \
+            (see --max-expand)",
+            input, size, max
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn expand_cidr(
+    addr: IpAddr,
+    prefix: u8,
+    max: usize,
+) -> Result<Vec<String>, String> {
+    let input = format!("{}/{}", addr, prefix);
+    match AddrRange::from_cidr(addr, prefix)? {
+        AddrRange::V4(first, last) => {
+            check_size(u128::from(last - first) + 1, max, &input)?;
+            Ok((first..=last)
+                .map(|a| Ipv4Addr::from(a).to_string())
+                .collect())
+        }
+        AddrRange::V6(first, last) => {
+            // Saturates for the whole IPv6 address space
+            check_size((last - first).saturating_add(1), max, &input)?;
+            Ok((first..=last)
+                .map(|a| Ipv6Addr::from(a).to_string())
+                .collect())
+        }
+    }
+}
+
+/// An inclusive range of addresses, such as those given to --exclude.
+/// Unlike `expand`, membership is checked without listing every address
+/// so large ranges can be used
+#[derive(Clone, Debug, PartialEq)]
+pub enum AddrRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+impl AddrRange {
+    /// Parse a single address, a CIDR range, or an IPv4 range in any of
+    /// the forms accepted by `expand`. Ports aren't allowed, and IPv6
+    /// addresses may be bracketed
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        let host = input.trim_start_matches('[').trim_end_matches(']');
+        if let Some((addr, prefix)) = parse_cidr(host) {
+            return Self::from_cidr(addr, prefix);
+        }
+        if let Some((start, end)) = parse_dashed(host)? {
+            return Ok(AddrRange::V4(start.into(), end.into()));
+        }
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) => Ok(AddrRange::V4(addr.into(), addr.into())),
+            Ok(IpAddr::V6(addr)) => Ok(AddrRange::V6(addr.into(), addr.into())),
+            Err(_) => Err(format!(
+                "{} is not an IP address, CIDR range, or IPv4 range",
+                input
+            )),
+        }
+    }
+
+    fn from_cidr(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        match addr {
+            IpAddr::V4(addr) => {
+                if prefix > 32 {
+                    return Err(format!(
+                        "Invalid prefix length in {}/{}",
+                        addr, prefix
+                    ));
+                }
+                let host_bits = 32 - u32::from(prefix);
+                let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+                let first = u32::from(addr) & mask;
+                Ok(AddrRange::V4(first, first | !mask))
+            }
+            IpAddr::V6(addr) => {
+                if prefix > 128 {
+                    return Err(format!(
+                        "Invalid prefix length in {}/{}",
+                        addr, prefix
+                    ));
+                }
+                let host_bits = 128 - u32::from(prefix);
+                let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+                let first = u128::from(addr) & mask;
+                Ok(AddrRange::V6(first, first | !mask))
+            }
+        }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self, addr) {
+            (AddrRange::V4(first, last), IpAddr::V4(addr)) => {
+                (*first..=*last).contains(&u32::from(addr))
+            }
+            (AddrRange::V6(first, last), IpAddr::V6(addr)) => {
+                (*first..=*last).contains(&u128::from(addr))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expand_cidr_ranges() {
+        let expanded = expand("192.0.2.5/30", 16, &[]).unwrap().unwrap();
+        assert_eq!(
+            expanded,
+            vec!["192.0.2.4", "192.0.2.5", "192.0.2.6", "192.0.2.7"]
+        );
+
+        assert_eq!(expand("192.0.2.1/32", 16, &[]).unwrap().unwrap().len(), 1);
+        assert_eq!(
+            expand("192.0.2.0/24", 256, &[]).unwrap().unwrap().len(),
+            256
+        );
+
+        let expanded = expand("2001:db8::/127", 16, &[]).unwrap().unwrap();
+        assert_eq!(expanded, vec!["2001:db8::", "2001:db8::1"]);
+    }
+
+    #[test]
+    fn expand_limits() {
+        assert!(expand("10.0.0.0/8", 65536, &[]).is_err());
+        assert!(expand("2001:db8::/32", 65536, &[]).is_err());
+        assert!(expand("::/0", 65536, &[]).is_err());
+        assert!(expand("192.0.2.0/33", 65536, &[]).is_err());
+    }
+
+    #[test]
+    fn expand_dashed_ranges() {
+        assert_eq!(
+            expand("192.0.2.1-3", 16, &[]).unwrap().unwrap(),
+            vec!["192.0.2.1", "192.0.2.2", "192.0.2.3"]
+        );
+        assert_eq!(
+            expand("192.0.2.254-192.0.3.1", 16, &[]).unwrap().unwrap(),
+            vec!["192.0.2.254", "192.0.2.255", "192.0.3.0", "192.0.3.1"]
+        );
+        assert_eq!(expand("192.0.2.7-7", 16, &[]).unwrap().unwrap().len(), 1);
+
+        assert!(expand("192.0.2.50-10", 16, &[]).is_err());
+        assert!(expand("192.0.3.1-192.0.2.1", 16, &[]).is_err());
+        assert!(expand("192.0.2.1-300", 16, &[]).is_err());
+        assert!(expand("10.0.0.0-10.255.255.255", 65536, &[]).is_err());
+    }
+
+    #[test]
+    fn not_ranges() {
+        assert_eq!(expand("my-host.example.com", 16, &[]), Ok(None));
+        assert_eq!(expand("192.0.2.1", 16, &[]), Ok(None));
+        assert_eq!(expand("http://192.0.2.0/24", 16, &[]), Ok(None));
+        assert_eq!(expand("example.com/24", 16, &[]), Ok(None));
+    }
+
+    #[test]
+    fn expand_port_ranges() {
+        assert_eq!(
+            expand("192.0.2.1:5900-5902", 16, &[]).unwrap().unwrap(),
+            vec!["192.0.2.1:5900", "192.0.2.1:5901", "192.0.2.1:5902"]
+        );
+        assert_eq!(
+            expand("example.com:80,8000-8001", 16, &[])
+                .unwrap()
+                .unwrap(),
+            vec!["example.com:80", "example.com:8000", "example.com:8001"]
+        );
+        assert_eq!(
+            expand("[2001:db8::1]:5900-5901", 16, &[]).unwrap().unwrap(),
+            vec!["[2001:db8::1]:5900", "[2001:db8::1]:5901"]
+        );
+        assert_eq!(
+            expand("192.0.2.4/31:5900", 16, &[]).unwrap().unwrap(),
+            vec!["192.0.2.4:5900", "192.0.2.5:5900"]
+        );
+        assert_eq!(
+            expand("192.0.2.1-2:80,443", 16, &[]).unwrap().unwrap(),
+            vec![
+                "192.0.2.1:80",
+                "192.0.2.1:443",
+                "192.0.2.2:80",
+                "192.0.2.2:443"
+            ]
+        );
+        assert_eq!(expand("192.0.2.1:5900", 16, &[]), Ok(None));
+
+        assert!(expand("192.0.2.1:5905-5900", 16, &[]).is_err());
+        assert!(expand("192.0.2.1:0-5", 16, &[]).is_err());
+        assert!(expand("192.0.2.1:70000", 16, &[]).is_err());
+        assert!(expand("192.0.2.0/28:80,443", 16, &[]).is_err());
+    }
+
+    #[test]
+    fn default_ports() {
+        let ports = [5900, 5901];
+        assert_eq!(
+            expand("example.com", 16, &ports).unwrap().unwrap(),
+            vec!["example.com:5900", "example.com:5901"]
+        );
+        assert_eq!(
+            expand("2001:db8::1", 16, &ports).unwrap().unwrap(),
+            vec!["[2001:db8::1]:5900", "[2001:db8::1]:5901"]
+        );
+        assert_eq!(
+            expand("192.0.2.4/31", 16, &ports).unwrap().unwrap(),
+            vec![
+                "192.0.2.4:5900",
+                "192.0.2.4:5901",
+                "192.0.2.5:5900",
+                "192.0.2.5:5901"
+            ]
+        );
+        // Explicit ports and URLs are left alone
+        assert_eq!(expand("example.com:80", 16, &ports), Ok(None));
+        assert_eq!(expand("vnc://example.com", 16, &ports), Ok(None));
+    }
+
+    #[test]
+    fn address_ranges() {
+        let range = AddrRange::parse("192.0.2.0/30").unwrap();
+        assert!(range.contains("192.0.2.3".parse().unwrap()));
+        assert!(!range.contains("192.0.2.4".parse().unwrap()));
+
+        let range = AddrRange::parse("192.0.2.10-20").unwrap();
+        assert!(range.contains("192.0.2.10".parse().unwrap()));
+        assert!(range.contains("192.0.2.20".parse().unwrap()));
+        assert!(!range.contains("192.0.2.21".parse().unwrap()));
+
+        let range = AddrRange::parse("[2001:db8::1]").unwrap();
+        assert!(range.contains("2001:db8::1".parse().unwrap()));
+        assert!(!range.contains("2001:db8::2".parse().unwrap()));
+
+        // Large ranges aren't expanded, so aren't limited by --max-expand
+        let range = AddrRange::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains("10.255.0.1".parse().unwrap()));
+        assert!(!range.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(AddrRange::parse("::/0").is_ok());
+
+        assert!(AddrRange::parse("printer.local").is_err());
+        assert!(AddrRange::parse("192.0.2.1:9100").is_err());
+        assert!(AddrRange::parse("192.0.2.0/33").is_err());
+    }
+}
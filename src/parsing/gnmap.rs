@@ -0,0 +1,127 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of open ports from nmap's grepable (-oG) output
+
+use super::ScannedService;
+use std::net::IpAddr;
+
+/// Extract the open TCP ports from grepable nmap output. Each host has a
+/// line of the form
+/// "Host: 192.0.2.1 (name)\tPorts: 3389/open/tcp//ms-wbt-server///, ..."
+/// and lines without a Ports field, e.g. host status lines, are skipped
+pub fn parse_gnmap(content: &str) -> Result<Vec<ScannedService>, String> {
+    let mut services = Vec::new();
+    for line in content.lines() {
+        let line = line.trim_end();
+        if !line.starts_with("Host:") {
+            continue;
+        }
+        let mut host = None;
+        let mut ports = None;
+        for field in line.split('\t') {
+            if let Some(value) = field.strip_prefix("Host:") {
+                host = value.split_whitespace().next();
+            } else if let Some(value) = field.strip_prefix("Ports:") {
+                ports = Some(value);
+            }
+        }
+        let (host, ports) = match (host, ports) {
+            (Some(host), Some(ports)) => (host_string(host), ports),
+            (None, _) => return Err(format!("Invalid gnmap line: {}", line)),
+            (_, None) => continue,
+        };
+
+        for port in ports.split(',') {
+            // port/state/protocol/owner/service/rpc info/version/
+            let fields: Vec<&str> = port.trim().split('/').collect();
+            let (number, state, protocol, service) = match fields.as_slice() {
+                [number, state, protocol, _, service, ..] => {
+                    (*number, *state, *protocol, *service)
+                }
+                _ => return Err(format!("Invalid gnmap port: {}", port)),
+            };
+            if state != "open" || protocol != "tcp" {
+                continue;
+            }
+            let number = match number.parse::<u16>() {
+                Ok(number) if number > 0 => number,
+                _ => return Err(format!("Invalid gnmap port: {}", port)),
+            };
+            services.push(ScannedService {
+                host: host.clone(),
+                port: number,
+                name: service_name(service),
+            });
+        }
+    }
+    Ok(services)
+}
+
+/// Put IPv6 addresses in square brackets so that a port can be appended
+fn host_string(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(addr)) => format!("[{}]", addr),
+        _ => ip.to_string(),
+    }
+}
+
+/// Convert a grepable service name into the name used in XML output.
+/// Services detected through SSL are written as e.g. "ssl|http", and
+/// names that nmap is unsure of have a trailing "?"
+fn service_name(name: &str) -> String {
+    match name.trim_end_matches('?') {
+        "ssl|http" => "https".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn service(host: &str, port: u16, name: &str) -> ScannedService {
+        ScannedService {
+            host: host.to_string(),
+            port,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn gnmap_ports() {
+        let content = "# Nmap 7.80 scan initiated as: nmap -oG - 192.0.2.0/30
+Host: 192.0.2.1 (rdp.example.com)\tStatus: Up
+Host: 192.0.2.1 (rdp.example.com)\tPorts: 3389/open/tcp//ms-wbt-server///, 22/closed/tcp//ssh///, 161/open/udp//snmp///\tIgnored State: filtered (997)
+Host: 2001:db8::2 ()\tPorts: 8443/open/tcp//ssl|http//nginx/, 5901/open/tcp//vnc-1?///
+# Nmap done at Thu Jun 25 12:45:16 2020 -- 4 IP addresses (2 hosts up)
+";
+        assert_eq!(
+            parse_gnmap(content).unwrap(),
+            vec![
+                service("192.0.2.1", 3389, "ms-wbt-server"),
+                service("[2001:db8::2]", 8443, "https"),
+                service("[2001:db8::2]", 5901, "vnc-1"),
+            ]
+        );
+
+        assert!(parse_gnmap("Host: 192.0.2.1 ()\tPorts: 3389/open\n").is_err());
+        assert!(parse_gnmap("Host:\tPorts: 3389/open/tcp//rdp///\n").is_err());
+    }
+}
@@ -0,0 +1,229 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of open ports from masscan JSON (-oJ) and list (-oL) output
+
+use super::ScannedService;
+use serde_json::Value;
+use std::net::IpAddr;
+
+/// Extract the open TCP ports from masscan output, detecting whether it
+/// is in JSON or list format. Ports that masscan grabbed a banner from
+/// are given the corresponding service name
+pub fn parse_masscan(content: &str) -> Result<Vec<ScannedService>, String> {
+    let trimmed = content.trim_start();
+    let records = if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        parse_json(trimmed)?
+    } else {
+        parse_list(content)?
+    };
+
+    // Masscan reports each banner as a separate record, so merge them
+    // with the open port records
+    let mut services: Vec<ScannedService> = Vec::new();
+    for record in records {
+        match services
+            .iter_mut()
+            .find(|s| s.host == record.host && s.port == record.port)
+        {
+            Some(existing) if existing.name.is_empty() => {
+                existing.name = record.name;
+            }
+            Some(_) => {}
+            None => services.push(record),
+        }
+    }
+    Ok(services)
+}
+
+/// Parse masscan's JSON output. Older versions of masscan leave a trailing
+/// comma after the last record, which isn't valid JSON, so if the whole
+/// document does not parse then each line is parsed as a separate record
+fn parse_json(content: &str) -> Result<Vec<ScannedService>, String> {
+    let records: Vec<Value> = match serde_json::from_str(content) {
+        Ok(Value::Array(records)) => records,
+        _ => {
+            let mut records = Vec::new();
+            for line in content.lines() {
+                let line = line.trim().trim_end_matches(',');
+                if line.is_empty() || line == "[" || line == "]" {
+                    continue;
+                }
+                records.push(serde_json::from_str(line).map_err(|e| {
+                    format!("Invalid masscan JSON record {}: {}", line, e)
+                })?);
+            }
+            records
+        }
+    };
+
+    let mut services = Vec::new();
+    for record in records {
+        let host = match record.get("ip").and_then(Value::as_str) {
+            Some(ip) => host_string(ip),
+            None => continue,
+        };
+        let ports = match record.get("ports").and_then(Value::as_array) {
+            Some(ports) => ports,
+            None => continue,
+        };
+        for port in ports {
+            if port.get("proto").and_then(Value::as_str) != Some("tcp") {
+                continue;
+            }
+            let status = port.get("status").and_then(Value::as_str);
+            let service = port
+                .get("service")
+                .and_then(|s| s.get("name"))
+                .and_then(Value::as_str);
+            if status != Some("open") && service.is_none() {
+                continue;
+            }
+            let port = match port.get("port").and_then(Value::as_u64) {
+                Some(port) if port > 0 && port <= u64::from(u16::MAX) => {
+                    port as u16
+                }
+                _ => continue,
+            };
+            services.push(ScannedService {
+                host: host.clone(),
+                port,
+                name: service.map(service_name).unwrap_or_default(),
+            });
+        }
+    }
+    Ok(services)
+}
+
+/// Parse masscan's list output, which has lines of the form
+/// "open tcp 3389 192.0.2.1 1600000000" and
+/// "banner tcp 80 192.0.2.1 1600000000 http ..."
+fn parse_list(content: &str) -> Result<Vec<ScannedService>, String> {
+    let mut services = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (kind, proto, port, host) = match fields.as_slice() {
+            [kind, proto, port, host, ..] => (*kind, *proto, *port, *host),
+            _ => return Err(format!("Invalid masscan line: {}", line)),
+        };
+        if proto != "tcp" {
+            continue;
+        }
+        let port = match port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return Err(format!("Invalid port in masscan line: {}", line)),
+        };
+        let name = match kind {
+            "open" => String::new(),
+            "banner" => {
+                fields.get(5).map(|s| service_name(s)).unwrap_or_default()
+            }
+            _ => continue,
+        };
+        services.push(ScannedService {
+            host: host_string(host),
+            port,
+            name,
+        });
+    }
+    Ok(services)
+}
+
+/// Put IPv6 addresses in square brackets so that a port can be appended
+fn host_string(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(addr)) => format!("[{}]", addr),
+        _ => ip.to_string(),
+    }
+}
+
+/// Convert a masscan banner service name into the nmap equivalent
+fn service_name(name: &str) -> String {
+    match name {
+        "rdp" => "ms-wbt-server",
+        "http" => "http",
+        "vnc" => "vnc",
+        _ => "",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn service(host: &str, port: u16, name: &str) -> ScannedService {
+        ScannedService {
+            host: host.to_string(),
+            port,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn masscan_json() {
+        // Includes the trailing comma that older masscan versions write
+        let content = r#"[
+{   "ip": "192.0.2.1",   "timestamp": "1600000000", "ports": [ {"port": 3389, "proto": "tcp", "status": "open", "reason": "syn-ack", "ttl": 128} ] },
+{   "ip": "2001:db8::2",   "timestamp": "1600000000", "ports": [ {"port": 8080, "proto": "tcp", "status": "open", "reason": "syn-ack", "ttl": 64} ] },
+{   "ip": "2001:db8::2",   "timestamp": "1600000000", "ports": [ {"port": 8080, "proto": "tcp", "service": {"name": "http", "banner": "HTTP/1.0 200 OK"} } ] },
+{   "ip": "192.0.2.1",   "timestamp": "1600000000", "ports": [ {"port": 161, "proto": "udp", "status": "open", "reason": "", "ttl": 128} ] },
+]
+"#;
+        assert_eq!(
+            parse_masscan(content).unwrap(),
+            vec![
+                service("192.0.2.1", 3389, ""),
+                service("[2001:db8::2]", 8080, "http"),
+            ]
+        );
+
+        let content = r#"[{"ip": "192.0.2.3", "ports": [{"port": 5901, "proto": "tcp", "status": "open"}]}]"#;
+        assert_eq!(
+            parse_masscan(content).unwrap(),
+            vec![service("192.0.2.3", 5901, "")]
+        );
+
+        assert!(parse_masscan("[\n{\"ip\": \n]").is_err());
+    }
+
+    #[test]
+    fn masscan_list() {
+        let content = "#masscan
+open tcp 3389 192.0.2.1 1600000000
+open tcp 12345 192.0.2.1 1600000000
+open udp 161 192.0.2.1 1600000000
+banner tcp 12345 192.0.2.1 1600000000 http HTTP/1.1 200 OK
+# end
+";
+        assert_eq!(
+            parse_masscan(content).unwrap(),
+            vec![
+                service("192.0.2.1", 3389, ""),
+                service("192.0.2.1", 12345, "http"),
+            ]
+        );
+
+        assert!(parse_masscan("open tcp\n").is_err());
+    }
+}
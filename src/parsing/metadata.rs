@@ -0,0 +1,184 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Details about each service from the file that it was imported from,
+//! such as the product and OS that nmap identified, so that they can be
+//! shown in the report next to the capture
+
+use super::Target;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Details of a service by name, e.g. "product" and "os"
+pub type Metadata = BTreeMap<String, String>;
+
+/// Metadata for each target, keyed by the target as it appears in the
+/// report, given by the importers
+static METADATA: Mutex<BTreeMap<String, Metadata>> =
+    Mutex::new(BTreeMap::new());
+
+/// Record details about the service at `target`, keeping any that an
+/// earlier import gave
+pub fn add(target: &Target, metadata: Metadata) {
+    if metadata.is_empty() {
+        return;
+    }
+    let mut all = METADATA.lock().unwrap();
+    let existing = all.entry(target.to_string()).or_default();
+    for (key, value) in metadata {
+        existing.entry(key).or_insert(value);
+    }
+}
+
+/// Forget the details from earlier imports, ready for another run in the
+/// same process
+pub fn clear() {
+    METADATA.lock().unwrap().clear();
+}
+
+/// The details recorded for a target, if any
+pub fn lookup(target: &Target) -> Option<Metadata> {
+    METADATA.lock().unwrap().get(&target.to_string()).cloned()
+}
+
+/// Read the service product, version, and extra information from nmap
+/// XML output, along with the best OS match for each host. Falls back to
+/// the OS type given by service detection if OS detection wasn't run
+pub fn parse_nmap(
+    content: &str,
+) -> Result<Vec<(IpAddr, u16, Metadata)>, String> {
+    let doc = roxmltree::Document::parse(content).map_err(|e| e.to_string())?;
+    let mut services = Vec::new();
+    for host in doc.descendants().filter(|n| n.has_tag_name("host")) {
+        let addresses: Vec<IpAddr> = host
+            .children()
+            .filter(|n| n.has_tag_name("address"))
+            .filter(|n| n.attribute("addrtype") != Some("mac"))
+            .filter_map(|n| n.attribute("addr")?.parse().ok())
+            .collect();
+        // nmap lists the OS matches with the most accurate first
+        let os = host
+            .descendants()
+            .find(|n| n.has_tag_name("osmatch"))
+            .and_then(|n| n.attribute("name"));
+
+        for port in host.descendants().filter(|n| n.has_tag_name("port")) {
+            let number = match port.attribute("portid").map(str::parse) {
+                Some(Ok(number)) => number,
+                _ => continue,
+            };
+            let service = port.children().find(|n| n.has_tag_name("service"));
+            let attribute =
+                |name: &str| service.and_then(|s| s.attribute(name));
+
+            let mut metadata = Metadata::new();
+            let fields = [
+                ("product", attribute("product")),
+                ("version", attribute("version")),
+                ("extra_info", attribute("extrainfo")),
+                ("os", os.or_else(|| attribute("ostype"))),
+            ];
+            for (key, value) in fields.iter() {
+                if let Some(value) = value.filter(|v| !v.is_empty()) {
+                    metadata.insert(key.to_string(), value.to_string());
+                }
+            }
+            if metadata.is_empty() {
+                continue;
+            }
+            for ip in &addresses {
+                services.push((*ip, number, metadata.clone()));
+            }
+        }
+    }
+    Ok(services)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nmap_metadata() {
+        let xml = r#"<?xml version="1.0"?>
+<nmaprun scanner="nmap">
+<host><status state="up"/>
+<address addr="192.0.2.20" addrtype="ipv4"/>
+<address addr="00:0C:29:71:23:2B" addrtype="mac"/>
+<ports>
+<port protocol="tcp" portid="3389"><state state="open"/><service name="ms-wbt-server" product="Microsoft Terminal Services" ostype="Windows" method="probed" conf="10"/></port>
+<port protocol="tcp" portid="8080"><state state="open"/><service name="http-proxy" method="table" conf="3"/></port>
+</ports>
+<os><osmatch name="Microsoft Windows Server 2012 R2" accuracy="100"/><osmatch name="Microsoft Windows 8.1" accuracy="95"/></os>
+</host>
+<host><status state="up"/>
+<address addr="192.0.2.21" addrtype="ipv4"/>
+<ports>
+<port protocol="tcp" portid="5900"><state state="open"/><service name="vnc" product="RealVNC Enterprise" version="5.3 or later" extrainfo="protocol 3.8" ostype="Linux" method="probed" conf="10"/></port>
+</ports>
+</host>
+</nmaprun>"#;
+        let services = parse_nmap(xml).unwrap();
+        let first: IpAddr = "192.0.2.20".parse().unwrap();
+        let second: IpAddr = "192.0.2.21".parse().unwrap();
+        assert_eq!(services.len(), 3);
+
+        assert_eq!(services[0].0, first);
+        assert_eq!(services[0].1, 3389);
+        assert_eq!(
+            services[0].2.get("os").map(String::as_str),
+            Some("Microsoft Windows Server 2012 R2")
+        );
+        assert_eq!(
+            services[0].2.get("product").map(String::as_str),
+            Some("Microsoft Terminal Services")
+        );
+
+        // The OS match applies to every port on the host
+        assert_eq!(services[1].1, 8080);
+        assert_eq!(services[1].2.len(), 1);
+
+        assert_eq!(services[2].0, second);
+        assert_eq!(services[2].2.get("os").map(String::as_str), Some("Linux"));
+        assert_eq!(
+            services[2].2.get("extra_info").map(String::as_str),
+            Some("protocol 3.8")
+        );
+
+        let rdp = Target::Address("192.0.2.20:3389".parse().unwrap());
+        let web = Target::Url("http://192.0.2.20:8080/".parse().unwrap());
+        let named = Target::Url("http://legacy.example:8080/".parse().unwrap());
+        add(&rdp, services[0].2.clone());
+        add(&web, services[1].2.clone());
+        add(&named, services[2].2.clone());
+        assert_eq!(lookup(&rdp).unwrap().len(), 2);
+        assert_eq!(lookup(&web).unwrap().len(), 1);
+        let metadata = lookup(&named).unwrap();
+        assert_eq!(
+            metadata.get("version").map(String::as_str),
+            Some("5.3 or later")
+        );
+        // Earlier details are kept
+        add(&named, services[1].2.clone());
+        assert_eq!(lookup(&named).unwrap(), services[2].2);
+        let target = Target::Address("192.0.2.21:5901".parse().unwrap());
+        assert_eq!(lookup(&target), None);
+    }
+}
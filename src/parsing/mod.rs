@@ -18,18 +18,38 @@
 */
 
 use crate::argparse::{Mode, Opts};
+use crate::probe::{self, Protocol};
+use crate::resolve;
+use crate::util::{image_filename, percent_decode};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
 use nmap_xml_parser::host::Address;
 use nmap_xml_parser::host::Host;
 use nmap_xml_parser::port::Port;
 use nmap_xml_parser::{port::PortState, NmapResults};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt::Display;
 use std::fs::{self, File};
 use std::io::{self, prelude::*, BufReader};
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::thread;
 use url::Url;
 
+mod csv;
+mod expand;
+mod gnmap;
+mod masscan;
+mod metadata;
+mod nessus;
+
+pub use csv::CsvColumns;
+pub use expand::{parse_ports, AddrRange};
+pub use metadata::{clear as clear_metadata, Metadata};
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Target {
     Address(SocketAddr),
@@ -44,13 +64,157 @@ pub struct InputLists {
     pub rdp_targets: Vec<Target>,
     pub web_targets: Vec<Target>,
     pub vnc_targets: Vec<Target>,
+    pub winrm_targets: Vec<Target>,
+    /// Targets on non-standard ports in auto mode, which are sorted into
+    /// the other lists by `probe_unknown`
+    pub unknown_targets: Vec<Target>,
+    /// Web targets that came from a bare host, which is parsed as both
+    /// HTTP and HTTPS because its scheme isn't known
+    pub bare_web_targets: BTreeSet<String>,
 }
 
 impl InputLists {
     fn append(&mut self, list: &mut Self) {
+        self.bare_web_targets.append(&mut list.bare_web_targets);
         self.rdp_targets.append(&mut list.rdp_targets);
         self.web_targets.append(&mut list.web_targets);
         self.vnc_targets.append(&mut list.vnc_targets);
+        self.winrm_targets.append(&mut list.winrm_targets);
+        self.unknown_targets.append(&mut list.unknown_targets);
+    }
+
+    /// Remove targets whose address is in any of the excluded ranges,
+    /// returning how many were removed. URLs with a hostname are matched
+    /// by the address it resolves to, and are kept if it can't be
+    /// resolved.
+    fn exclude(&mut self, excluded: &[AddrRange]) -> usize {
+        if excluded.is_empty() {
+            return 0;
+        }
+        let mut resolved: BTreeMap<String, Option<IpAddr>> = BTreeMap::new();
+        let mut address = |t: &Target| -> Option<IpAddr> {
+            if let Some(ip) = t.ip() {
+                return Some(ip);
+            }
+            let url = match t {
+                Target::Url(url) => url,
+                Target::Address(_) => return None,
+            };
+            let host = url.host_str()?;
+            let port = url.port_or_known_default().unwrap_or(0);
+            *resolved.entry(host.to_string()).or_insert_with(|| {
+                match resolve::resolve(host, port) {
+                    Ok(addr) => Some(addr.ip()),
+                    Err(e) => {
+                        warn!(
+                            "Unable to resolve {} to exclude it: {}",
+                            host, e
+                        );
+                        None
+                    }
+                }
+            })
+        };
+        let mut keep = |t: &Target| match address(t) {
+            Some(ip) => !excluded.iter().any(|range| range.contains(ip)),
+            None => true,
+        };
+        let mut removed = 0;
+        for list in [
+            &mut self.rdp_targets,
+            &mut self.web_targets,
+            &mut self.vnc_targets,
+            &mut self.winrm_targets,
+            &mut self.unknown_targets,
+        ] {
+            let before = list.len();
+            list.retain(|t| keep(t));
+            removed += before - list.len();
+        }
+        removed
+    }
+
+    /// Add the web targets parsed from `input`, noting whether they came
+    /// from a bare host rather than a URL
+    fn add_web(&mut self, input: &str, mut targets: Vec<Target>) {
+        if !input.contains("://") {
+            self.bare_web_targets
+                .extend(targets.iter().map(|t| t.to_string()));
+        }
+        self.web_targets.append(&mut targets);
+    }
+
+    /// Whether the web target came from a bare host, so its scheme was
+    /// guessed
+    pub fn is_bare_web_target(&self, target: &Target) -> bool {
+        self.bare_web_targets.contains(&target.to_string())
+    }
+
+    /// Every target across all of the lists
+    fn iter(&self) -> impl Iterator<Item = &Target> {
+        self.rdp_targets
+            .iter()
+            .chain(&self.web_targets)
+            .chain(&self.vnc_targets)
+            .chain(&self.winrm_targets)
+            .chain(&self.unknown_targets)
+    }
+
+    /// Total number of targets across all of the lists
+    pub fn count(&self) -> usize {
+        self.rdp_targets.len()
+            + self.web_targets.len()
+            + self.vnc_targets.len()
+            + self.winrm_targets.len()
+            + self.unknown_targets.len()
+    }
+
+    /// Keep a random selection of `count` targets from across all of the
+    /// lists. The same seed always keeps the same targets
+    pub fn sample(&mut self, count: usize, seed: Option<u64>) {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut lists = [
+            &mut self.rdp_targets,
+            &mut self.web_targets,
+            &mut self.vnc_targets,
+            &mut self.winrm_targets,
+            &mut self.unknown_targets,
+        ];
+        let mut positions: Vec<(usize, usize)> = lists
+            .iter()
+            .enumerate()
+            .flat_map(|(l, list)| (0..list.len()).map(move |i| (l, i)))
+            .collect();
+        positions.shuffle(&mut rng);
+        positions.truncate(count);
+        let chosen: HashSet<(usize, usize)> = positions.into_iter().collect();
+        for (l, list) in lists.iter_mut().enumerate() {
+            let mut i = 0;
+            list.retain(|_| {
+                i += 1;
+                chosen.contains(&(l, i - 1))
+            });
+        }
+    }
+
+    /// Put each list in a random order, so that e.g. a range isn't
+    /// captured in sequence. The same seed always gives the same order
+    pub fn shuffle(&mut self, seed: Option<u64>) {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        for list in [
+            &mut self.rdp_targets,
+            &mut self.web_targets,
+            &mut self.vnc_targets,
+            &mut self.winrm_targets,
+        ] {
+            list.shuffle(&mut rng);
+        }
     }
 
     fn dedup(&mut self) {
@@ -60,6 +224,180 @@ impl InputLists {
         self.web_targets.dedup();
         self.vnc_targets.sort();
         self.vnc_targets.dedup();
+        self.winrm_targets.sort();
+        self.winrm_targets.dedup();
+        self.unknown_targets.sort();
+        self.unknown_targets.dedup();
+    }
+
+    /// Probe each of the unknown targets to detect which protocol it
+    /// speaks, and move it into the corresponding list. Targets that
+    /// can't be probed are tried as every target type
+    pub fn probe_unknown(&mut self, threads: usize) {
+        let unknown: Vec<Target> = self.unknown_targets.drain(..).collect();
+        if unknown.is_empty() {
+            return;
+        }
+        info!("Probing {} targets to detect their protocol", unknown.len());
+
+        let chunk_size = unknown.len().div_ceil(threads);
+        let results: Vec<(Target, Option<Protocol>)> = thread::scope(|s| {
+            let handles: Vec<_> = unknown
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    s.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|t| (t.clone(), probe_target(t)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        });
+
+        for (target, protocol) in results {
+            let input = target.to_string();
+            let as_web = Target::parse(&input, Mode::Web).unwrap_or_default();
+            match protocol {
+                Some(Protocol::Rdp) => self.rdp_targets.push(target),
+                Some(Protocol::Vnc) => self.vnc_targets.push(target),
+                Some(Protocol::Web) => self.add_web(&input, as_web),
+                None => {
+                    self.add_web(&input, as_web);
+                    self.rdp_targets.push(target.clone());
+                    self.vnc_targets.push(target);
+                }
+            }
+        }
+        self.dedup();
+    }
+
+    /// One line per target for --list-targets, giving the target type,
+    /// the target, and the image file relative to the output directory,
+    /// separated by tabs. Targets without an image file, and those whose
+    /// type will be decided by probing, have "-" as the file
+    pub fn listing(&self, opts: &Opts) -> Vec<String> {
+        let lists = [
+            ("rdp", &self.rdp_targets),
+            ("web", &self.web_targets),
+            ("vnc", &self.vnc_targets),
+            ("winrm", &self.winrm_targets),
+            ("probe", &self.unknown_targets),
+        ];
+        let mut lines = Vec::new();
+        for (mode, targets) in lists.iter() {
+            for target in targets.iter() {
+                let file = match *mode {
+                    "winrm" | "probe" => "-".to_string(),
+                    _ => format!(
+                        "{}/{}",
+                        mode,
+                        image_filename(opts, mode, target)
+                    ),
+                };
+                lines.push(format!("{}\t{}\t{}", mode, target, file));
+            }
+        }
+        lines
+    }
+
+    /// Image files that more than one target would be saved as, e.g.
+    /// because the --filename-template leaves out the path of web
+    /// targets. Each file is given with the targets that share it
+    pub fn filename_collisions(
+        &self,
+        opts: &Opts,
+    ) -> Vec<(String, Vec<String>)> {
+        let lists = [
+            ("rdp", &self.rdp_targets),
+            ("web", &self.web_targets),
+            ("vnc", &self.vnc_targets),
+        ];
+        let mut files: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (mode, targets) in lists.iter() {
+            for target in targets.iter() {
+                let file =
+                    format!("{}/{}", mode, image_filename(opts, mode, target));
+                files.entry(file).or_default().push(target.to_string());
+            }
+        }
+        files
+            .into_iter()
+            .filter(|(_, targets)| targets.len() > 1)
+            .collect()
+    }
+
+    /// Move web targets on the WinRM ports into the WinRM list. These
+    /// are HTTP services but there is nothing useful to screenshot, so
+    /// they are fingerprinted instead. Only the scheme that matches the
+    /// port is kept, i.e. http for 5985 and https for 5986.
+    fn split_winrm(&mut self) {
+        let mut web_targets = Vec::new();
+        for target in self.web_targets.drain(..) {
+            let winrm_scheme = match &target {
+                Target::Url(u) => match u.port() {
+                    Some(5985) => Some("http"),
+                    Some(5986) => Some("https"),
+                    _ => None,
+                },
+                Target::Address(_) => None,
+            };
+            match (winrm_scheme, &target) {
+                (Some(scheme), Target::Url(u)) => {
+                    if u.scheme() == scheme {
+                        debug!("{} identified as WinRM", target);
+                        self.winrm_targets.push(target);
+                    }
+                }
+                _ => web_targets.push(target),
+            }
+        }
+        self.web_targets = web_targets;
+        let web: HashSet<String> =
+            self.web_targets.iter().map(|t| t.to_string()).collect();
+        self.bare_web_targets.retain(|t| web.contains(t));
+    }
+}
+
+/// Probe a target, logging the result
+fn probe_target(target: &Target) -> Option<Protocol> {
+    let addr = match target {
+        Target::Address(addr) => addr,
+        Target::Url(_) => return None,
+    };
+    match probe::probe(addr) {
+        Ok(protocol) => {
+            info!("{} detected as {:?}", target, protocol);
+            Some(protocol)
+        }
+        Err(e) => {
+            warn!("Unable to probe {}, trying all target types: {}", target, e);
+            None
+        }
+    }
+}
+
+/// If the input is a host:port on a port that isn't recognised as any
+/// particular service, return its addresses so that they can be probed
+fn unknown_port_targets(input: &str) -> Option<Vec<Target>> {
+    if input.contains("://") {
+        return None;
+    }
+    let targets = Target::parse(input, Mode::Rdp).ok()?;
+    let unknown = targets.iter().all(|t| match t {
+        Target::Address(addr) => {
+            identify_service(addr.port(), "", &Mode::Auto).is_none()
+        }
+        Target::Url(_) => false,
+    });
+    if unknown && !targets.is_empty() {
+        Some(targets)
+    } else {
+        None
     }
 }
 
@@ -76,6 +414,36 @@ impl Ord for Target {
 }
 
 impl Target {
+    /// The IP address of the target, if it has one rather than a
+    /// hostname
+    fn ip(&self) -> Option<IpAddr> {
+        use url::Host;
+        match self {
+            Target::Address(addr) => Some(addr.ip()),
+            Target::Url(url) => match url.host()? {
+                Host::Ipv4(addr) => Some(IpAddr::V4(addr)),
+                Host::Ipv6(addr) => Some(IpAddr::V6(addr)),
+                Host::Domain(_) => None,
+            },
+        }
+    }
+
+    /// Details about the service from the file that it was imported
+    /// from, e.g. the product and OS that nmap identified
+    pub fn metadata(&self) -> Option<Metadata> {
+        metadata::lookup(self)
+    }
+
+    /// The path of a unix:/path/to/socket target
+    pub fn unix_socket(&self) -> Option<PathBuf> {
+        match self {
+            Target::Url(u) if u.scheme() == "unix" => {
+                percent_decode(u.path()).ok().map(PathBuf::from)
+            }
+            _ => None,
+        }
+    }
+
     fn parse(input: &str, mode: Mode) -> Result<Vec<Self>, &str> {
         use url::Host;
         // Parse a &str into a Target using the mode hint to guide output.
@@ -88,6 +456,43 @@ impl Target {
 
         //TODO basic auth
 
+        // IPv6 literals that start with a letter, such as fe80::1, would
+        // otherwise parse as a URL with a scheme of "fe80", so handle bare
+        // and bracketed addresses first
+        if let Some((addr, scope_id, port)) = parse_ipv6_literal(input) {
+            trace!("Parsed as IPv6 literal");
+            let sock_addr = |port| {
+                SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope_id))
+            };
+            return match mode {
+                Auto => unreachable!(),
+                Rdp => {
+                    Ok(vec![Target::Address(sock_addr(port.unwrap_or(3389)))])
+                }
+                Vnc => {
+                    Ok(vec![Target::Address(sock_addr(port.unwrap_or(5900)))])
+                }
+                // Chrome doesn't accept zone IDs in URLs
+                Web if scope_id != 0 => {
+                    Err("Zone IDs aren't supported for web targets")
+                }
+                Web => {
+                    let host = match port {
+                        Some(port) => format!("[{}]:{}", addr, port),
+                        None => format!("[{}]", addr),
+                    };
+                    ["https", "http"]
+                        .iter()
+                        .map(|scheme| {
+                            Url::parse(&format!("{}://{}", scheme, host))
+                                .map(Target::Url)
+                                .map_err(|_| "Unable to parse IPv6 URL")
+                        })
+                        .collect()
+                }
+            };
+        }
+
         // Try to match a URL format. Examples could be:
         // * http://example.com
         // * https://192.0.2.3
@@ -102,6 +507,9 @@ impl Target {
                     if mode != Web {
                         return Err("Non-web mode requested for web-type URL");
                     }
+                    if let Some(Host::Domain(d)) = u.host() {
+                        resolve::resolve_web_host(d);
+                    }
                     return Ok(vec![Target::Url(u)]);
                 }
                 "rdp" => {
@@ -149,7 +557,24 @@ impl Target {
                     };
                     return Ok(vec![Target::Address(address)]);
                 }
+                // A VNC server on a Unix domain socket, e.g.
+                // unix:/tmp/.X11-unix/vnc or unix:///run/vnc.sock
+                "unix" => {
+                    trace!("Parsed as Unix socket");
+                    if mode != Vnc {
+                        return Err("Non-VNC mode requested for Unix socket");
+                    }
+                    if u.path().is_empty() || !u.path().starts_with('/') {
+                        return Err("Unix socket path must be absolute");
+                    }
+                    return Ok(vec![Target::Url(u)]);
+                }
 
+                // A hostname and port, e.g. fileserver.corp:3389, looks
+                // like a URL with the hostname as its scheme
+                _ if is_host_port(input) => {
+                    trace!("Parsed as hostname and port");
+                }
                 _ => return Err("Invalid scheme"),
             }
         } else {
@@ -216,6 +641,11 @@ impl Target {
                     return Err("Unable to parse HTTP URL");
                 }
 
+                if let Some(Target::Url(u)) = targets.first() {
+                    if let Some(Host::Domain(d)) = u.host() {
+                        resolve::resolve_web_host(d);
+                    }
+                }
                 Ok(targets)
             }
             Vnc => {
@@ -247,7 +677,12 @@ impl Display for Target {
         fmt: &mut std::fmt::Formatter<'_>,
     ) -> Result<(), std::fmt::Error> {
         match self {
-            Target::Address(addr) => write!(fmt, "{}", addr),
+            // Addresses resolved with --hosts-file or --resolver keep
+            // their hostname
+            Target::Address(addr) => match resolve::hostname(addr) {
+                Some(host) => write!(fmt, "{}:{}", host, addr.port()),
+                None => write!(fmt, "{}", addr),
+            },
             Target::Url(url) => write!(fmt, "{}", url),
         }
     }
@@ -284,6 +719,21 @@ impl Display for InputLists {
             write!(fmt, "\n    {}", t)?;
         }
 
+        write!(fmt, "\nWinRM targets:")?;
+        if self.winrm_targets.is_empty() {
+            write!(fmt, " None")?;
+        }
+        for t in &self.winrm_targets {
+            write!(fmt, "\n    {}", t)?;
+        }
+
+        if !self.unknown_targets.is_empty() {
+            write!(fmt, "\nTargets to probe:")?;
+        }
+        for t in &self.unknown_targets {
+            write!(fmt, "\n    {}", t)?;
+        }
+
         Ok(())
     }
 }
@@ -312,19 +762,87 @@ fn domain_to_sockaddr(
     // Try to resolve the domain to an IP-port combination. The domain
     // in theory should not have a port alongside it, so this should
     // "just work", provided the domain resolves to a valid address.
-    let mut addrs = (domain, port).to_socket_addrs()?;
+    // --hosts-file and --resolver are used instead of the system
+    // resolver if they were given
+    resolve::resolve(domain, port)
+}
 
-    if let Some(sockaddr) = addrs.next() {
-        return Ok(sockaddr);
+/// Parse an IPv6 address that is either bare, e.g. 2001:db8::1, or in
+/// brackets with an optional port, e.g. [2001:db8::1]:5900. Link-local
+/// addresses may have a zone ID naming the interface, e.g. fe80::1%eth0,
+/// which is returned as the interface index, or 0 if there isn't one.
+/// The port is None if one wasn't given
+fn parse_ipv6_literal(input: &str) -> Option<(Ipv6Addr, u32, Option<u16>)> {
+    let (addr, port) = match input.strip_prefix('[') {
+        Some(rest) => {
+            let (addr, rest) = rest.split_once(']')?;
+            let port = match rest {
+                "" => None,
+                _ => Some(rest.strip_prefix(':')?.parse().ok()?),
+            };
+            (addr, port)
+        }
+        None => (input, None),
+    };
+    let (addr, scope_id) = match addr.split_once('%') {
+        Some((addr, zone)) => (addr, zone_index(zone)?),
+        None => (addr, 0),
+    };
+    Some((addr.parse().ok()?, scope_id, port))
+}
+
+/// Interface index for an IPv6 zone ID, which is either the index itself
+/// or, on Linux, the name of the interface
+fn zone_index(zone: &str) -> Option<u32> {
+    if let Ok(index) = zone.parse() {
+        return Some(index);
+    }
+    // Interface names can't contain slashes, so this stays within
+    // /sys/class/net
+    if zone.is_empty() || zone.contains('/') || zone.starts_with('.') {
+        return None;
     }
+    #[cfg(target_os = "linux")]
+    {
+        let path = Path::new("/sys/class/net").join(zone).join("ifindex");
+        match fs::read_to_string(path) {
+            Ok(index) => index.trim().parse().ok(),
+            Err(e) => {
+                warn!("Unknown interface {} in IPv6 zone ID: {}", zone, e);
+                None
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        warn!(
+            "Interface names in IPv6 zone IDs aren't supported on this \
+            platform, use the interface index instead of {}",
+            zone
+        );
+        None
+    }
+}
 
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "Unknown error resolving domain",
-    ))
+/// Whether the input is a hostname and port, e.g. fileserver.corp:3389
+fn is_host_port(input: &str) -> bool {
+    match input.rsplit_once(':') {
+        Some((host, port)) => {
+            resolve::is_hostname(host) && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
 }
 
 fn ip_port_to_sockaddr(input: &str) -> Result<SocketAddr, io::Error> {
+    // Hostnames go through the resolver so that --resolver, --hosts-file
+    // and hostnames from CSV files are used for them
+    if is_host_port(input) {
+        if let Some((host, port)) = input.rsplit_once(':') {
+            return resolve::resolve(host, port.parse().unwrap_or_default());
+        }
+    }
+
     let mut addrs = input.to_socket_addrs()?;
 
     if let Some(sockaddr) = addrs.next() {
@@ -337,191 +855,198 @@ fn ip_port_to_sockaddr(input: &str) -> Result<SocketAddr, io::Error> {
     ))
 }
 
-pub fn generate_target_lists(opts: &Opts) -> InputLists {
+/// Parse targets from lines of text, such as a targets file. `source` is
+/// used in log messages to identify where the lines came from.
+fn targets_from_lines<I>(lines: I, opts: &Opts, source: &str) -> InputLists
+where
+    I: Iterator<Item = io::Result<String>>,
+{
     use Mode::*;
+    let mode = opts.mode;
     let mut input_lists: InputLists = Default::default();
+    let mut parse_successful_count: usize = 0;
+    let mut parse_total_count: usize = 0;
+    let mut parse_unsuccessful_count: usize = 0;
+    // Probing needs a direct connection to the target
+    let probe_unknown = opts.protocol_probe
+        && opts.web_proxy.is_none()
+        && opts.rdp_proxy.is_none()
+        && opts.vnc_proxy.is_none();
+
+    for line in lines {
+        debug!("Reading target {:?}", line);
+        let t = match line {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("Error reading line {}", e);
+                parse_unsuccessful_count += 1;
+                continue;
+            }
+        };
 
-    // Process the optional command-line target argument
-    for t in &opts.targets {
-        let mut parse_successful = false;
-        match &opts.mode {
-            Auto => {
-                // Try parsing as both web and RDP, saving any that stick
-                if let Ok(mut targets) = Target::parse(&t, Rdp) {
-                    input_lists.rdp_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as RDP target", t);
-                }
-                if let Ok(mut targets) = Target::parse(&t, Web) {
-                    input_lists.web_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as Web target", t);
-                }
-                if let Ok(mut targets) = Target::parse(&t, Vnc) {
-                    input_lists.vnc_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as VNC target", t);
-                }
+        // Try to parse the line into a Target
+        parse_total_count += 1;
+
+        // Address and port ranges are expanded into individual targets,
+        // which are then parsed in the same way as any other target
+        let expanded = match expand::expand(&t, opts.max_expand, &opts.ports) {
+            Ok(Some(expanded)) => {
+                info!("{} expanded to {} targets", t, expanded.len());
+                expanded
             }
-            Web => {
-                if let Ok(mut targets) = Target::parse(&t, Web) {
-                    input_lists.web_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as Web target", t);
-                }
+            Ok(None) => vec![t],
+            Err(e) => {
+                warn!("Unable to expand {}: {}", t, e);
+                parse_unsuccessful_count += 1;
+                continue;
             }
-            Rdp => {
-                if let Ok(mut targets) = Target::parse(&t, Rdp) {
-                    input_lists.rdp_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as RDP target", t);
+        };
+
+        for t in expanded {
+            match mode {
+                Auto => {
+                    if probe_unknown {
+                        if let Some(mut targets) = unknown_port_targets(&t) {
+                            input_lists.unknown_targets.append(&mut targets);
+                            parse_successful_count += 1;
+                            info!("{} will be probed for its protocol", t);
+                            continue;
+                        }
+                    }
+                    // Try parsing as web, RDP, and VNC, saving any that
+                    // stick
+                    let mut success = false;
+                    if let Ok(mut targets) = Target::parse(&t, Rdp) {
+                        input_lists.rdp_targets.append(&mut targets);
+                        parse_successful_count += 1;
+                        success = true;
+                        info!("{} loaded as RDP target", t);
+                    }
+                    if let Ok(targets) = Target::parse(&t, Web) {
+                        input_lists.add_web(&t, targets);
+                        parse_successful_count += 1;
+                        success = true;
+                        info!("{} loaded as Web target", t);
+                    }
+                    if let Ok(mut targets) = Target::parse(&t, Vnc) {
+                        input_lists.vnc_targets.append(&mut targets);
+                        parse_successful_count += 1;
+                        success = true;
+                        info!("{} loaded as VNC target", t);
+                    }
+                    if !success {
+                        warn!("Unable to parse {}", t);
+                        parse_unsuccessful_count += 1;
+                    }
                 }
-            }
-            Vnc => {
-                if let Ok(mut targets) = Target::parse(&t, Vnc) {
-                    input_lists.vnc_targets.append(&mut targets);
-                    parse_successful = true;
-                    debug!("{} parsed as VNC target", t);
+                Web => {
+                    if let Ok(targets) = Target::parse(&t, Web) {
+                        input_lists.add_web(&t, targets);
+                        parse_successful_count += 1;
+                        info!("{} loaded as Web target", t);
+                    } else {
+                        warn!("{} is not a valid Web target", t);
+                        parse_unsuccessful_count += 1;
+                    }
+                }
+                Rdp => {
+                    if let Ok(mut targets) = Target::parse(&t, Rdp) {
+                        input_lists.rdp_targets.append(&mut targets);
+                        parse_successful_count += 1;
+                        info!("{} loaded as RDP target", t);
+                    } else {
+                        warn!("{} is not a valid RDP target", t);
+                        parse_unsuccessful_count += 1;
+                    }
+                }
+                Vnc => {
+                    if let Ok(mut targets) = Target::parse(&t, Vnc) {
+                        input_lists.vnc_targets.append(&mut targets);
+                        parse_successful_count += 1;
+                        info!("{} loaded as VNC target", t);
+                    } else {
+                        warn!("{} is not a valid VNC target", t);
+                        parse_unsuccessful_count += 1;
+                    }
                 }
             }
         }
-        if !parse_successful {
-            warn!("Unable to parse {}", t);
-        }
     }
 
-    // Process the optional input file
+    info!(
+        "Loaded {} targets from {} lines from {} with {} errors",
+        parse_successful_count,
+        parse_total_count,
+        source,
+        parse_unsuccessful_count,
+    );
+
+    input_lists
+}
+
+#[cfg(feature = "clipboard")]
+fn read_clipboard() -> Result<String, Box<dyn std::error::Error>> {
+    use clipboard::{ClipboardContext, ClipboardProvider};
+    // This fails on headless systems where there is no clipboard
+    let mut ctx: ClipboardContext = ClipboardProvider::new()?;
+    ctx.get_contents()
+}
+
+pub fn generate_target_lists(opts: &Opts) -> InputLists {
+    use Mode::*;
+    let mut input_lists: InputLists = Default::default();
+
+    // Process the optional command-line target argument
+    input_lists.append(&mut targets_from_lines(
+        opts.targets.iter().map(|t| Ok(t.clone())),
+        opts,
+        "the command line",
+    ));
+
+    // Process the optional input file, where "-" is standard input
     for file_name in &opts.files {
-        let mut parse_successful_count: usize = 0;
-        let mut parse_total_count: usize = 0;
-        let mut parse_unsuccessful_count: usize = 0;
-        // This is horribly deep nesting, but it has to try opening the
-        // provided file, iterate over a reader, parse each line into a
-        // string type and then behave slightly differently depending on
-        // the selected mode, failing gracefully at each stage if any
-        // errors occur.
-        //TODO make nesting less deep somehow
+        if file_name == "-" {
+            info!("Reading targets from standard input");
+            let stdin = io::stdin();
+            input_lists.append(&mut targets_from_lines(
+                stdin.lock().lines(),
+                opts,
+                "standard input",
+            ));
+            continue;
+        }
         match File::open(file_name) {
             Ok(file) => {
                 let reader = BufReader::new(file);
-                for line in reader.lines() {
-                    debug!("Reading target {:?}", line);
-                    match line {
-                        Ok(t) => {
-                            // Try to parse the line into a Target
-                            parse_total_count += 1;
-
-                            match &opts.mode {
-                                Auto => {
-                                    // Try parsing as web, RDP, and VNC,
-                                    // saving any that stick
-                                    let mut success = false;
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Rdp)
-                                    {
-                                        input_lists
-                                            .rdp_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as RDP target", t);
-                                    }
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Web)
-                                    {
-                                        input_lists
-                                            .web_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as Web target", t);
-                                    }
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Vnc)
-                                    {
-                                        input_lists
-                                            .vnc_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        success = true;
-                                        info!("{} loaded as VNC target", t);
-                                    }
-                                    if !success {
-                                        warn!("Unable to parse {}", t);
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Web => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Web)
-                                    {
-                                        input_lists
-                                            .web_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as Web target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid Web target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Rdp => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Rdp)
-                                    {
-                                        input_lists
-                                            .rdp_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as RDP target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid RDP target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                                Vnc => {
-                                    if let Ok(mut targets) =
-                                        Target::parse(&t, Vnc)
-                                    {
-                                        input_lists
-                                            .vnc_targets
-                                            .append(&mut targets);
-                                        parse_successful_count += 1;
-                                        info!("{} loaded as VNC target", t);
-                                    } else {
-                                        warn!(
-                                            "{} is not a valid RDP target",
-                                            t
-                                        );
-                                        parse_unsuccessful_count += 1;
-                                    }
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Error reading line {}", e);
-                            parse_unsuccessful_count += 1;
-                        }
-                    }
-                }
+                input_lists.append(&mut targets_from_lines(
+                    reader.lines(),
+                    opts,
+                    file_name,
+                ));
             }
             Err(e) => {
                 warn!("Error opening file: {:?}", e);
             }
         }
-        info!(
-            "Loaded {} targets from {} lines from {} with {} errors",
-            parse_successful_count,
-            parse_total_count,
-            file_name,
-            parse_unsuccessful_count,
-        );
+    }
+
+    // Read targets from the clipboard, parsed like a targets file
+    #[cfg(feature = "clipboard")]
+    {
+        if opts.from_clipboard {
+            match read_clipboard() {
+                Ok(contents) => {
+                    input_lists.append(&mut targets_from_lines(
+                        contents.lines().map(|l| Ok(l.to_string())),
+                        opts,
+                        "clipboard",
+                    ));
+                }
+                Err(e) => {
+                    warn!("Unable to read targets from clipboard: {}", e);
+                }
+            }
+        }
     }
 
     // Parse nmap file
@@ -539,6 +1064,22 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
                     }
                     Ok(results) => {
                         debug!("Successfully parsed file");
+                        let services: BTreeMap<_, _> =
+                            match metadata::parse_nmap(&content) {
+                                Ok(services) => services
+                                    .into_iter()
+                                    .map(|(ip, port, details)| {
+                                        ((ip, port), details)
+                                    })
+                                    .collect(),
+                                Err(e) => {
+                                    warn!(
+                                        "Error reading nmap service details: {}",
+                                        e
+                                    );
+                                    BTreeMap::new()
+                                }
+                            };
                         //TODO filter for host being UP
                         for (host, port) in results.iter_ports() {
                             // for each host check for some common open ports
@@ -546,9 +1087,17 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
 
                             // this has been broken out into a separate function
                             // for readability
-                            input_lists.append(&mut lists_from_nmap(
-                                host, port, &opts.mode,
-                            ));
+                            let mut lists =
+                                lists_from_nmap(host, port, &opts.mode);
+                            for target in lists.iter() {
+                                let details = target.ip().and_then(|ip| {
+                                    services.get(&(ip, port.port_number))
+                                });
+                                if let Some(details) = details {
+                                    metadata::add(target, details.clone());
+                                }
+                            }
+                            input_lists.append(&mut lists);
                         }
                     }
                 }
@@ -556,170 +1105,362 @@ pub fn generate_target_lists(opts: &Opts) -> InputLists {
         }
     }
 
-    input_lists.dedup();
-    input_lists
-}
+    // Parse grepable nmap files
+    for file in &opts.gnmaps {
+        info!("Loading grepable nmap file {}", file);
 
-fn lists_from_nmap(host: &Host, port: &Port, mode: &Mode) -> InputLists {
-    let mut list: InputLists = Default::default();
+        match fs::read_to_string(file) {
+            Err(e) => {
+                warn!("Error opening file: {}", e);
+            }
+            Ok(content) => match gnmap::parse_gnmap(&content) {
+                Err(e) => {
+                    warn!("Error parsing grepable nmap file: {}", e);
+                }
+                Ok(services) => {
+                    debug!("Successfully parsed file");
+                    for service in services {
+                        input_lists.append(&mut lists_from_service(
+                            &[service.host],
+                            service.port,
+                            &service.name,
+                            &opts.mode,
+                        ));
+                    }
+                }
+            },
+        }
+    }
 
-    //TODO service discovery for ports identified as
-    // "web", etc.
-    //TODO break this out into a function
-    //TODO code reuse
-    debug!("Parsing host {:?}", (host, port));
-    if port.status.state == PortState::Open {
-        debug!("open port");
-        // Found an open port, now add it to the
-        // input lists if it is appropriate
-        //TODO identify Web
-        match (port.port_number, port.service_info.name.as_str()) {
-            // RDP signatures
-            (3389, _) | (_, "ms-wbt-server") if mode.selected(Mode::Rdp) => {
-                debug!("Identified RDP");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
+    // Parse Nessus files
+    for file in &opts.nessus {
+        info!("Loading Nessus file {}", file);
 
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as RDP and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Rdp) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as RDP");
-                            list.rdp_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as RDP: {}", e);
-                        }
+        match fs::read_to_string(file) {
+            Err(e) => {
+                warn!("Error opening file: {}", e);
+            }
+            Ok(content) => match nessus::parse_nessus(&content) {
+                Err(e) => {
+                    warn!("Error parsing Nessus file: {}", e);
+                }
+                Ok(services) => {
+                    debug!("Successfully parsed file");
+                    for service in services {
+                        input_lists.append(&mut lists_from_service(
+                            &[service.host],
+                            service.port,
+                            &service.name,
+                            &opts.mode,
+                        ));
                     }
                 }
-            }
-            // HTTP(S) signatures
-            (80, _)
-            | (443, _)
-            | (631, _)
-            | (7443, _)
-            | (8080, _)
-            | (8443, _)
-            | (8000, _)
-            | (3000, _)
-            | (_, "http")
-            | (_, "http-mgt")
-            | (_, "https")
-            | (_, "http-alt")
-            | (_, "https-alt")
-                if mode.selected(Mode::Web) =>
-            {
-                debug!("Idenfified web");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
+            },
+        }
+    }
 
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as Web and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Web) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as Web");
-                            list.web_targets.append(&mut target);
-                        }
-                        Err(e) => {
-                            warn!("Error parsing target as Web: {}", e);
+    // Parse masscan files
+    for file in &opts.masscans {
+        info!("Loading masscan file {}", file);
+
+        match fs::read_to_string(file) {
+            Err(e) => {
+                warn!("Error opening file: {}", e);
+            }
+            Ok(content) => match masscan::parse_masscan(&content) {
+                Err(e) => {
+                    warn!("Error parsing masscan file: {}", e);
+                }
+                Ok(services) => {
+                    debug!("Successfully parsed file");
+                    // Masscan doesn't identify most services, so ports
+                    // that aren't recognised are parsed like a targets
+                    // file so that each capture mode can try them
+                    let mut unknown = Vec::new();
+                    for service in services {
+                        if identify_service(service.port, &service.name, &Auto)
+                            .is_some()
+                        {
+                            input_lists.append(&mut lists_from_service(
+                                &[service.host],
+                                service.port,
+                                &service.name,
+                                &opts.mode,
+                            ));
+                        } else {
+                            unknown.push(Ok(format!(
+                                "{}:{}",
+                                service.host, service.port
+                            )));
                         }
                     }
+                    input_lists.append(&mut targets_from_lines(
+                        unknown.into_iter(),
+                        opts,
+                        file,
+                    ));
                 }
-            }
-            // VNC signatures
-            (5900, _)
-            | (5901, _)
-            | (5902, _)
-            | (5903, _)
-            | (_, "vnc")
-            | (_, "vnc-1")
-            | (_, "vnc-2")
-            | (_, "vnc-3")
-                if mode.selected(Mode::Vnc) =>
-            {
-                debug!("Identified VNC");
-                let port = port.port_number;
-                // Iterate over the host's addresses. It may have multiple
-                // IPv6, IPv4, and MAC addresses and we want to add them
-                // all (well, maybe not the MAC addresses)
-                for address in host.addresses() {
-                    let target_string = match address {
-                        Address::IpAddr(IpAddr::V6(a)) => {
-                            trace!("address: {:?}", a);
-                            format!("[{}]:{}", a, port)
-                        }
-                        Address::IpAddr(IpAddr::V4(a)) => {
-                            trace!("legacy address: {:?}", a);
-                            format!("{}:{}", a, port)
-                        }
-                        Address::MacAddr(a) => {
-                            trace!("Ignoring MAC address {}", a);
-                            // Ignore the MAC address and move on
-                            continue;
-                        }
-                    };
+            },
+        }
+    }
+
+    // Parse CSV files
+    for file in &opts.csvs {
+        info!("Loading CSV file {}", file);
 
-                    // target_string now contains a string sockaddr
-                    // representation, so we parse it as RDP and see what
-                    // happens
-                    match Target::parse(&target_string, Mode::Vnc) {
-                        Ok(mut target) => {
-                            debug!("Successfully parsed as VNC");
-                            list.vnc_targets.append(&mut target);
+        match fs::read_to_string(file) {
+            Err(e) => {
+                warn!("Error opening file: {}", e);
+            }
+            Ok(content) => match csv::parse_csv(&content, &opts.csv_columns) {
+                Err(e) => {
+                    warn!("Error parsing CSV file: {}", e);
+                }
+                Ok(import) => {
+                    debug!("Successfully parsed file");
+                    if import.skipped > 0 {
+                        warn!(
+                            "Skipped {} malformed rows in {}",
+                            import.skipped, file
+                        );
+                    }
+                    // As with masscan, ports without a recognised
+                    // service are tried in each capture mode
+                    let mut unknown = Vec::new();
+                    for target in import.targets {
+                        let service = target.service;
+                        if let Some(ip) = target.address {
+                            resolve::add_host(&service.host, ip);
                         }
-                        Err(e) => {
-                            warn!("Error parsing target as VNC: {}", e);
+                        if identify_service(service.port, &service.name, &Auto)
+                            .is_some()
+                        {
+                            input_lists.append(&mut lists_from_service(
+                                &[service.host],
+                                service.port,
+                                &service.name,
+                                &opts.mode,
+                            ));
+                        } else {
+                            unknown.push(Ok(format!(
+                                "{}:{}",
+                                service.host, service.port
+                            )));
                         }
                     }
+                    input_lists.append(&mut targets_from_lines(
+                        unknown.into_iter(),
+                        opts,
+                        file,
+                    ));
                 }
+            },
+        }
+    }
+
+    // WinRM endpoints are only identified by port when the mode is
+    // being inferred, otherwise they are screenshotted as requested
+    if opts.mode == Auto {
+        input_lists.split_winrm();
+    }
+
+    input_lists.dedup();
+
+    let excluded = input_lists.exclude(&opts.exclude);
+    if excluded > 0 {
+        info!("Excluded {} targets", excluded);
+    }
+    input_lists
+}
+
+/// A TCP service found by a scanner such as Nessus or masscan, or read
+/// from grepable nmap output
+#[derive(Debug, PartialEq)]
+pub struct ScannedService {
+    /// The host's address, with IPv6 addresses in square brackets, or its
+    /// name if the scanner did not record an address
+    pub host: String,
+    pub port: u16,
+    /// The nmap equivalent of the scanner's service name, or an empty
+    /// string if it is unknown
+    pub name: String,
+}
+
+/// The type of service identified by a scanner, which determines which
+/// list a host:port ends up in
+#[derive(Debug, PartialEq)]
+enum Service {
+    Rdp,
+    Web,
+    Vnc,
+    WinRm { tls: bool },
+}
+
+/// Identify a service from its port number and nmap-style service name.
+/// Importers for other scanners should convert their service names into
+/// the nmap equivalents so that they are all treated the same way
+fn identify_service(port: u16, name: &str, mode: &Mode) -> Option<Service> {
+    match (port, name) {
+        // RDP signatures
+        (3389, _) | (_, "ms-wbt-server") if mode.selected(Mode::Rdp) => {
+            debug!("Identified RDP");
+            Some(Service::Rdp)
+        }
+        // WinRM signatures. These are HTTP but are only fingerprinted
+        // rather than screenshotted
+        (5985, _) | (5986, _) | (_, "wsman") | (_, "wsmans")
+            if *mode == Mode::Auto =>
+        {
+            debug!("Identified WinRM");
+            Some(Service::WinRm {
+                tls: port == 5986 || name == "wsmans",
+            })
+        }
+        // HTTP(S) signatures
+        (80, _)
+        | (443, _)
+        | (631, _)
+        | (7443, _)
+        | (8080, _)
+        | (8443, _)
+        | (8000, _)
+        | (3000, _)
+        | (_, "http")
+        | (_, "http-mgt")
+        | (_, "https")
+        | (_, "http-alt")
+        | (_, "https-alt")
+            if mode.selected(Mode::Web) =>
+        {
+            debug!("Idenfified web");
+            Some(Service::Web)
+        }
+        // VNC signatures, for displays :0 to :9
+        (5900..=5909, _)
+        | (_, "vnc")
+        | (_, "vnc-1")
+        | (_, "vnc-2")
+        | (_, "vnc-3")
+            if mode.selected(Mode::Vnc) =>
+        {
+            debug!("Identified VNC");
+            Some(Service::Vnc)
+        }
+        _ => None,
+    }
+}
+
+/// Add a service running on each of the given hosts to the relevant input
+/// list. IPv6 addresses in `hosts` must already be in square brackets
+fn lists_from_service(
+    hosts: &[String],
+    port: u16,
+    name: &str,
+    mode: &Mode,
+) -> InputLists {
+    let mut list: InputLists = Default::default();
+    let service = match identify_service(port, name, mode) {
+        Some(service) => service,
+        None => return list,
+    };
+
+    for host in hosts {
+        let target_string = match service {
+            Service::WinRm { tls: true } => {
+                format!("https://{}:{}", host, port)
+            }
+            Service::WinRm { tls: false } => {
+                format!("http://{}:{}", host, port)
+            }
+            _ => format!("{}:{}", host, port),
+        };
+
+        // target_string now contains a string sockaddr or URL
+        // representation, so we parse it in the relevant mode and see
+        // what happens
+        let (parse_mode, targets, name) = match service {
+            Service::Rdp => (Mode::Rdp, &mut list.rdp_targets, "RDP"),
+            Service::Web => (Mode::Web, &mut list.web_targets, "Web"),
+            Service::Vnc => (Mode::Vnc, &mut list.vnc_targets, "VNC"),
+            Service::WinRm { .. } => {
+                (Mode::Web, &mut list.winrm_targets, "WinRM")
+            }
+        };
+        match Target::parse(&target_string, parse_mode) {
+            Ok(target) if matches!(service, Service::Web) => {
+                debug!("Successfully parsed as {}", name);
+                list.add_web(&target_string, target);
+            }
+            Ok(mut target) => {
+                debug!("Successfully parsed as {}", name);
+                targets.append(&mut target);
+            }
+            Err(e) => {
+                warn!("Error parsing target as {}: {}", name, e);
             }
-            _ => {}
         }
     }
     list
 }
 
+fn lists_from_nmap(host: &Host, port: &Port, mode: &Mode) -> InputLists {
+    //TODO service discovery for ports identified as
+    // "web", etc.
+    debug!("Parsing host {:?}", (host, port));
+    if port.status.state != PortState::Open {
+        return Default::default();
+    }
+    debug!("open port");
+
+    // Iterate over the host's addresses. It may have multiple IPv6, IPv4,
+    // and MAC addresses and we want to add them all (well, maybe not
+    // the MAC addresses)
+    let mut hosts = Vec::new();
+    for address in host.addresses() {
+        match address {
+            Address::IpAddr(IpAddr::V6(a)) => {
+                trace!("address: {:?}", a);
+                hosts.push(format!("[{}]", a));
+            }
+            Address::IpAddr(IpAddr::V4(a)) => {
+                trace!("legacy address: {:?}", a);
+                hosts.push(a.to_string());
+            }
+            Address::MacAddr(a) => {
+                trace!("Ignoring MAC address {}", a);
+            }
+        }
+    }
+
+    // Found an open port, now add it to the input lists if it is
+    // appropriate
+    lists_from_service(
+        &hosts,
+        port.port_number,
+        port.service_info.name.as_str(),
+        mode,
+    )
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::argparse::ImageFormat;
+    use crate::util::FilenameTemplate;
+    use std::time::SystemTime;
+
+    /// The lists with every web target marked as coming from a bare host
+    fn bare_web(lists: InputLists) -> InputLists {
+        InputLists {
+            bare_web_targets: lists
+                .web_targets
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            ..lists
+        }
+    }
+
     #[test]
     fn parse_target_as_url() {
         use Mode::{Rdp, Vnc, Web};
@@ -783,6 +1524,29 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_unix_socket_target() {
+        let targets = Target::parse("unix:/tmp/vnc.sock", Mode::Vnc).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(
+            targets[0].unix_socket(),
+            Some(PathBuf::from("/tmp/vnc.sock"))
+        );
+
+        let targets =
+            Target::parse("unix:///run/my%20vnc.sock", Mode::Vnc).unwrap();
+        assert_eq!(
+            targets[0].unix_socket(),
+            Some(PathBuf::from("/run/my vnc.sock"))
+        );
+
+        assert!(Target::parse("unix:/tmp/vnc.sock", Mode::Web).is_err());
+        assert!(Target::parse("unix:/tmp/vnc.sock", Mode::Rdp).is_err());
+        assert!(Target::parse("unix:vnc.sock", Mode::Vnc).is_err());
+        let addr = Target::Address("192.0.2.1:5900".parse().unwrap());
+        assert_eq!(addr.unix_socket(), None);
+    }
+
     #[test]
     fn parse_target_as_url_with_domain() {
         use Mode::Rdp;
@@ -921,6 +1685,101 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_ipv6_literals() {
+        use Mode::{Rdp, Vnc, Web};
+        let addr = |s: &str| Target::Address(s.parse().unwrap());
+        let urls = |host: &str| {
+            vec![
+                Target::Url(Url::parse(&format!("https://{}", host)).unwrap()),
+                Target::Url(Url::parse(&format!("http://{}", host)).unwrap()),
+            ]
+        };
+
+        let test_cases: Vec<(&str, Mode, Vec<Target>)> = vec![
+            // Link-local
+            ("fe80::1", Rdp, vec![addr("[fe80::1]:3389")]),
+            ("fe80::1", Vnc, vec![addr("[fe80::1]:5900")]),
+            ("[fe80::1]:5901", Vnc, vec![addr("[fe80::1]:5901")]),
+            ("fe80::1", Web, urls("[fe80::1]")),
+            ("[fe80::1]:8080", Web, urls("[fe80::1]:8080")),
+            // Loopback
+            ("::1", Vnc, vec![addr("[::1]:5900")]),
+            ("[::1]", Rdp, vec![addr("[::1]:3389")]),
+            ("[::1]", Vnc, vec![addr("[::1]:5900")]),
+            ("[::1]:3390", Rdp, vec![addr("[::1]:3390")]),
+            ("[::1]", Web, urls("[::1]")),
+            // Full form is normalised
+            (
+                "2001:0db8:0000:0000:0000:0000:0000:0001",
+                Rdp,
+                vec![addr("[2001:db8::1]:3389")],
+            ),
+            (
+                "[2001:0db8:0000:0000:0000:0000:0000:0001]:5902",
+                Vnc,
+                vec![addr("[2001:db8::1]:5902")],
+            ),
+            (
+                "fd00:0000:0000:0000:0000:0000:0000:00ff",
+                Web,
+                urls("[fd00::ff]"),
+            ),
+        ];
+
+        for (input, mode, expected) in test_cases {
+            eprintln!("Test case: {} {:?}", input, mode);
+            assert_eq!(Target::parse(input, mode).unwrap(), expected);
+        }
+
+        // Zone IDs are kept for RDP and VNC
+        assert_eq!(
+            Target::parse("fe80::1%3", Vnc).unwrap(),
+            vec![addr("[fe80::1%3]:5900")]
+        );
+        assert_eq!(
+            Target::parse("[fe80::1%3]:3390", Rdp).unwrap(),
+            vec![addr("[fe80::1%3]:3390")]
+        );
+        assert!(Target::parse("fe80::1%3", Web).is_err());
+        assert!(parse_ipv6_literal("fe80::1%").is_none());
+        assert!(parse_ipv6_literal("fe80::1%../lo").is_none());
+        #[cfg(target_os = "linux")]
+        if Path::new("/sys/class/net/lo").exists() {
+            assert!(parse_ipv6_literal("fe80::1%lo").is_some_and(|a| a.1 > 0));
+        }
+
+        // A bracketed address must be followed by a valid port or nothing
+        assert!(parse_ipv6_literal("[fe80::1]5900").is_none());
+        assert!(parse_ipv6_literal("[fe80::1]:http").is_none());
+        assert!(parse_ipv6_literal("[192.0.2.1]:80").is_none());
+    }
+
+    #[test]
+    fn mode_default_ports() {
+        let lists = |mode| {
+            generate_target_lists(&Opts {
+                targets: vec!["192.0.2.5".into()],
+                mode,
+                ..Default::default()
+            })
+        };
+        let addr = |s: &str| Target::Address(s.parse().unwrap());
+        let web = vec![
+            Target::Url(Url::parse("http://192.0.2.5").unwrap()),
+            Target::Url(Url::parse("https://192.0.2.5").unwrap()),
+        ];
+
+        assert_eq!(lists(Mode::Rdp).rdp_targets, vec![addr("192.0.2.5:3389")]);
+        assert_eq!(lists(Mode::Vnc).vnc_targets, vec![addr("192.0.2.5:5900")]);
+        assert_eq!(lists(Mode::Web).web_targets, web);
+
+        let auto = lists(Mode::Auto);
+        assert_eq!(auto.rdp_targets, vec![addr("192.0.2.5:3389")]);
+        assert_eq!(auto.vnc_targets, vec![addr("192.0.2.5:5900")]);
+        assert_eq!(auto.web_targets, web);
+    }
+
     #[test]
     fn parse_invalid_addresses() {
         use Mode::{Rdp, Web};
@@ -951,7 +1810,10 @@ mod test {
         // because a lot of the code is the same. Just need valid web
         // and RDP addresses for the dedicated and auto modes, as well
         // a sample of invalid cases
-        let mut opts: Opts = Default::default();
+        let mut opts = Opts {
+            protocol_probe: false,
+            ..Default::default()
+        };
 
         let test_cases: Vec<(&str, InputLists, Mode)> = vec![
             ("rdp://192.0.2.1", Default::default(), Web),
@@ -968,6 +1830,9 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Rdp,
             ),
@@ -983,6 +1848,9 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
@@ -994,6 +1862,9 @@ mod test {
                         Url::parse("https://[2001:db8::6]:8080").unwrap(),
                     )],
                     vnc_targets: Vec::new(),
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Web,
             ),
@@ -1005,12 +1876,15 @@ mod test {
                         Url::parse("https://[2001:db8::6]").unwrap(),
                     )],
                     vnc_targets: Vec::new(),
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
             (
                 "2001:db8::6",
-                InputLists {
+                bare_web(InputLists {
                     rdp_targets: Vec::new(),
                     web_targets: vec![
                         Target::Url(
@@ -1021,7 +1895,10 @@ mod test {
                         ),
                     ],
                     vnc_targets: Vec::new(),
-                },
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
+                }),
                 Web,
             ),
             (
@@ -1036,12 +1913,15 @@ mod test {
                     )],
                     web_targets: Vec::new(),
                     vnc_targets: Vec::new(),
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Rdp,
             ),
             (
                 "[2001:db8::6]:3300",
-                InputLists {
+                bare_web(InputLists {
                     rdp_targets: vec![Target::Address(
                         "[2001:db8::6]:3300"
                             .to_socket_addrs()
@@ -1064,9 +1944,57 @@ mod test {
                             .next()
                             .unwrap(),
                     )],
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
+                }),
+                Auto,
+            ),
+            (
+                "192.0.2.1:5986",
+                InputLists {
+                    rdp_targets: vec![Target::Address(
+                        "192.0.2.1:5986"
+                            .to_socket_addrs()
+                            .unwrap()
+                            .next()
+                            .unwrap(),
+                    )],
+                    web_targets: Vec::new(),
+                    vnc_targets: vec![Target::Address(
+                        "192.0.2.1:5986"
+                            .to_socket_addrs()
+                            .unwrap()
+                            .next()
+                            .unwrap(),
+                    )],
+                    winrm_targets: vec![Target::Url(
+                        Url::parse("https://192.0.2.1:5986").unwrap(),
+                    )],
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
                 },
                 Auto,
             ),
+            (
+                "192.0.2.1:5985",
+                bare_web(InputLists {
+                    rdp_targets: Vec::new(),
+                    web_targets: vec![
+                        Target::Url(
+                            Url::parse("http://192.0.2.1:5985").unwrap(),
+                        ),
+                        Target::Url(
+                            Url::parse("https://192.0.2.1:5985").unwrap(),
+                        ),
+                    ],
+                    vnc_targets: Vec::new(),
+                    winrm_targets: Vec::new(),
+                    unknown_targets: Vec::new(),
+                    ..Default::default()
+                }),
+                Web,
+            ),
         ];
 
         for (input, input_lists, mode) in test_cases {
@@ -1080,12 +2008,48 @@ mod test {
         }
     }
 
+    #[test]
+    fn target_lists_from_lines() {
+        use Mode::Rdp;
+        let lines = vec![
+            Ok("rdp://192.0.2.1".to_string()),
+            Ok("not a target".to_string()),
+            Ok("192.0.2.2:3390".to_string()),
+            Ok("192.0.2.4/31".to_string()),
+            Ok("192.0.2.0/24".to_string()),
+        ];
+
+        let opts = Opts {
+            mode: Rdp,
+            max_expand: 16,
+            ..Default::default()
+        };
+        let parsed = targets_from_lines(lines.into_iter(), &opts, "test");
+        assert_eq!(
+            parsed.rdp_targets,
+            vec![
+                Target::Address(
+                    "192.0.2.1:3389".to_socket_addrs().unwrap().next().unwrap()
+                ),
+                Target::Address(
+                    "192.0.2.2:3390".to_socket_addrs().unwrap().next().unwrap()
+                ),
+                Target::Address(
+                    "192.0.2.4:3389".to_socket_addrs().unwrap().next().unwrap()
+                ),
+                Target::Address(
+                    "192.0.2.5:3389".to_socket_addrs().unwrap().next().unwrap()
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn load_from_nmap_xml() {
         // Load xml from a file and parse it
         let test_cases = vec![(
             "test/nmap.xml",
-            InputLists {
+            bare_web(InputLists {
                 rdp_targets: vec![
                     Target::Address(
                         "172.24.5.57:3389"
@@ -1115,7 +2079,10 @@ mod test {
                     ),
                 ],
                 vnc_targets: Vec::new(),
-            },
+                winrm_targets: Vec::new(),
+                unknown_targets: Vec::new(),
+                ..Default::default()
+            }),
         )];
         let mut opts: Opts = Default::default();
         for case in test_cases {
@@ -1128,6 +2095,381 @@ mod test {
         }
     }
 
+    #[test]
+    fn bare_web_targets() {
+        let opts = Opts {
+            targets: vec![
+                "192.0.2.1".into(),
+                "https://192.0.2.2".into(),
+                "http://192.0.2.2".into(),
+            ],
+            mode: Mode::Web,
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(parsed.web_targets.len(), 4);
+        let bare: Vec<&str> =
+            parsed.bare_web_targets.iter().map(|t| t.as_str()).collect();
+        assert_eq!(bare, vec!["http://192.0.2.1/", "https://192.0.2.1/"]);
+    }
+
+    #[test]
+    fn load_from_gnmap() {
+        let sockaddr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let opts = Opts {
+            gnmaps: vec!["test/nmap.gnmap".into()],
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(
+            parsed,
+            bare_web(InputLists {
+                rdp_targets: vec![sockaddr("192.0.2.1:3389")],
+                web_targets: vec![
+                    Target::Url(Url::parse("http://192.0.2.2/").unwrap()),
+                    Target::Url(Url::parse("https://192.0.2.2:80/").unwrap()),
+                ],
+                vnc_targets: vec![sockaddr("192.0.2.2:5901")],
+                winrm_targets: Vec::new(),
+                unknown_targets: Vec::new(),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn load_from_nessus_xml() {
+        let opts = Opts {
+            nessus: vec!["test/scan.nessus".into()],
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        eprintln!("Parsed: {:?}", parsed);
+
+        assert_eq!(
+            parsed,
+            bare_web(InputLists {
+                rdp_targets: vec![Target::Address(
+                    "192.0.2.10:3389"
+                        .to_socket_addrs()
+                        .unwrap()
+                        .next()
+                        .unwrap()
+                )],
+                web_targets: vec![
+                    Target::Url(
+                        Url::parse("http://[2001:db8::20]:8834/").unwrap()
+                    ),
+                    Target::Url(
+                        Url::parse("https://[2001:db8::20]:8834/").unwrap()
+                    ),
+                ],
+                vnc_targets: vec![Target::Address(
+                    "[2001:db8::20]:5901"
+                        .to_socket_addrs()
+                        .unwrap()
+                        .next()
+                        .unwrap()
+                )],
+                winrm_targets: vec![Target::Url(
+                    Url::parse("http://192.0.2.10:5985/").unwrap()
+                )],
+                unknown_targets: Vec::new(),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn load_from_csv() {
+        let opts = Opts {
+            csvs: vec!["test/exposure.csv".into()],
+            csv_columns: Default::default(),
+            protocol_probe: false,
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        let rdp: Vec<String> =
+            parsed.rdp_targets.iter().map(|t| t.to_string()).collect();
+        let web: Vec<String> =
+            parsed.web_targets.iter().map(|t| t.to_string()).collect();
+        let vnc: Vec<String> =
+            parsed.vnc_targets.iter().map(|t| t.to_string()).collect();
+        assert_eq!(rdp, vec!["198.51.100.23:8081", "rdp01.corp.example:3389"]);
+        assert_eq!(
+            web,
+            vec![
+                "http://198.51.100.23:8081/",
+                "http://intranet.corp.example:443/",
+                "https://198.51.100.23:8081/",
+                "https://intranet.corp.example/",
+            ]
+        );
+        assert_eq!(vnc, vec!["198.51.100.22:5900", "198.51.100.23:8081"]);
+        assert_eq!(
+            parsed.rdp_targets[1],
+            Target::Address("198.51.100.20:3389".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn load_from_masscan() {
+        let sockaddr = |s: &str| {
+            Target::Address(s.to_socket_addrs().unwrap().next().unwrap())
+        };
+        let mut opts = Opts {
+            masscans: vec!["test/masscan.txt".into()],
+            protocol_probe: false,
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(
+            parsed,
+            bare_web(InputLists {
+                rdp_targets: vec![
+                    sockaddr("192.0.2.1:12345"),
+                    sockaddr("192.0.2.1:3389"),
+                ],
+                web_targets: vec![
+                    Target::Url(Url::parse("http://192.0.2.1:12345/").unwrap()),
+                    Target::Url(
+                        Url::parse("https://192.0.2.1:12345/").unwrap()
+                    ),
+                ],
+                vnc_targets: vec![
+                    sockaddr("192.0.2.1:12345"),
+                    sockaddr("192.0.2.1:5905"),
+                ],
+                winrm_targets: Vec::new(),
+                unknown_targets: Vec::new(),
+                ..Default::default()
+            })
+        );
+
+        // Well-known ports for other modes are not captured in the forced
+        // mode
+        opts.mode = Mode::Vnc;
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(
+            parsed.vnc_targets,
+            vec![sockaddr("192.0.2.1:12345"), sockaddr("192.0.2.1:5905")]
+        );
+        assert!(parsed.rdp_targets.is_empty());
+    }
+
+    #[test]
+    fn unknown_ports_are_probed() {
+        let opts = Opts {
+            targets: vec![
+                "192.0.2.1:12345".into(),
+                "192.0.2.1:3389".into(),
+                "http://192.0.2.1:12346".into(),
+            ],
+            protocol_probe: true,
+            ..Default::default()
+        };
+        let parsed = generate_target_lists(&opts);
+        assert_eq!(
+            parsed.unknown_targets,
+            vec![Target::Address("192.0.2.1:12345".parse().unwrap())]
+        );
+        assert_eq!(
+            parsed.rdp_targets,
+            vec![Target::Address("192.0.2.1:3389".parse().unwrap())]
+        );
+        assert!(parsed.web_targets.contains(&Target::Url(
+            Url::parse("http://192.0.2.1:12346").unwrap()
+        )));
+    }
+
+    #[test]
+    fn excluded_targets() {
+        let opts = Opts {
+            targets: vec![
+                "192.0.2.0/29".into(),
+                "http://192.0.2.5:8080".into(),
+                "rdp://[2001:db8::1]".into(),
+            ],
+            mode: Mode::Rdp,
+            max_expand: 16,
+            exclude: vec![
+                AddrRange::parse("192.0.2.4-6").unwrap(),
+                AddrRange::parse("2001:db8::/64").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let lists = generate_target_lists(&opts);
+        let rdp: Vec<String> =
+            lists.rdp_targets.iter().map(|t| t.to_string()).collect();
+        assert_eq!(
+            rdp,
+            vec![
+                "192.0.2.0:3389",
+                "192.0.2.1:3389",
+                "192.0.2.2:3389",
+                "192.0.2.3:3389",
+                "192.0.2.7:3389",
+            ]
+        );
+
+        let opts = Opts {
+            targets: vec!["http://192.0.2.5:8080".into()],
+            mode: Mode::Web,
+            exclude: vec![AddrRange::parse("192.0.2.5").unwrap()],
+            ..Default::default()
+        };
+        assert!(generate_target_lists(&opts).web_targets.is_empty());
+
+        // Hostnames are excluded by the address they resolve to
+        resolve::add_host("fragile.example", "192.0.2.5".parse().unwrap());
+        let opts = Opts {
+            targets: vec![
+                "http://fragile.example:8080".into(),
+                "https://fragile.example/".into(),
+                "http://192.0.2.6/".into(),
+            ],
+            mode: Mode::Web,
+            exclude: vec![AddrRange::parse("192.0.2.5").unwrap()],
+            ..Default::default()
+        };
+        let web: Vec<String> = generate_target_lists(&opts)
+            .web_targets
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(web, vec!["http://192.0.2.6/"]);
+    }
+
+    #[test]
+    fn sample_targets() {
+        let opts = Opts {
+            targets: vec!["192.0.2.0/28".into()],
+            max_expand: 16,
+            ..Default::default()
+        };
+        let all = generate_target_lists(&opts);
+        // Auto mode gives RDP, HTTP, HTTPS, and VNC targets for each host
+        assert_eq!(all.count(), 64);
+
+        let mut sampled = generate_target_lists(&opts);
+        sampled.sample(10, Some(1234));
+        assert_eq!(sampled.count(), 10);
+        for (list, sampled_list) in [
+            (&all.rdp_targets, &sampled.rdp_targets),
+            (&all.web_targets, &sampled.web_targets),
+            (&all.vnc_targets, &sampled.vnc_targets),
+        ] {
+            assert!(sampled_list.iter().all(|t| list.contains(t)));
+        }
+
+        // The same seed chooses the same targets
+        let mut again = generate_target_lists(&opts);
+        again.sample(10, Some(1234));
+        assert_eq!(sampled, again);
+
+        // Sampling more than there are keeps them all
+        let mut everything = generate_target_lists(&opts);
+        everything.sample(100, None);
+        assert_eq!(everything, all);
+    }
+
+    #[test]
+    fn shuffle_targets() {
+        let opts = Opts {
+            targets: vec!["192.0.2.0/28".into()],
+            max_expand: 16,
+            mode: Mode::Rdp,
+            ..Default::default()
+        };
+        let all = generate_target_lists(&opts);
+        let mut shuffled = generate_target_lists(&opts);
+        shuffled.shuffle(Some(1234));
+        assert_ne!(shuffled.rdp_targets, all.rdp_targets);
+        let mut sorted = shuffled.rdp_targets.clone();
+        sorted.sort();
+        assert_eq!(sorted, all.rdp_targets);
+
+        // The same seed gives the same order
+        let mut again = generate_target_lists(&opts);
+        again.shuffle(Some(1234));
+        assert_eq!(shuffled, again);
+    }
+
+    #[test]
+    fn list_targets() {
+        let opts = Opts {
+            targets: vec![
+                "http://192.0.2.1:5985".into(),
+                "rdp://192.0.2.2".into(),
+                "vnc://[2001:db8::1]:5901".into(),
+                "http://192.0.2.3:8080/path".into(),
+                "192.0.2.4:12345".into(),
+            ],
+            protocol_probe: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            generate_target_lists(&opts).listing(&opts),
+            vec![
+                "rdp\t192.0.2.2:3389\trdp/192.0.2.2-3389.png",
+                "web\thttp://192.0.2.3:8080/path\t\
+                web/http_192.0.2.3_8080-path.png",
+                "vnc\t[2001:db8::1]:5901\tvnc/2001_db8__1-5901.png",
+                "winrm\thttp://192.0.2.1:5985/\t-",
+                "probe\t192.0.2.4:12345\t-",
+            ]
+        );
+        let opts = Opts {
+            image_format: ImageFormat::Jpeg,
+            ..opts
+        };
+        assert_eq!(
+            generate_target_lists(&opts).listing(&opts)[0],
+            "rdp\t192.0.2.2:3389\trdp/192.0.2.2-3389.jpg"
+        );
+    }
+
+    #[test]
+    fn filename_collisions() {
+        let opts = Opts {
+            targets: vec![
+                "http://192.0.2.1/login".into(),
+                "http://192.0.2.1/admin".into(),
+                "http://192.0.2.1:8080/".into(),
+                "192.0.2.2".into(),
+            ],
+            mode: Mode::Auto,
+            filename_template: Some(
+                FilenameTemplate::parse(
+                    "{mode}_{host}_{port}",
+                    SystemTime::UNIX_EPOCH,
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            generate_target_lists(&opts).filename_collisions(&opts),
+            vec![(
+                "web/web_192.0.2.1_80.png".to_string(),
+                vec![
+                    "http://192.0.2.1/admin".to_string(),
+                    "http://192.0.2.1/login".to_string(),
+                ]
+            )]
+        );
+
+        let opts = Opts {
+            filename_template: None,
+            ..opts
+        };
+        assert!(generate_target_lists(&opts)
+            .filename_collisions(&opts)
+            .is_empty());
+    }
+
     #[test]
     fn display_impl_for_target() {
         let test_cases = vec![
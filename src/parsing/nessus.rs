@@ -0,0 +1,150 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Import of services from Nessus (.nessus) XML reports
+
+use super::ScannedService;
+use std::net::IpAddr;
+
+/// Extract the TCP services listed in a Nessus report. Each port is
+/// typically reported by several plugins, so services are deduplicated
+/// per host
+pub fn parse_nessus(content: &str) -> Result<Vec<ScannedService>, String> {
+    let doc = roxmltree::Document::parse(content).map_err(|e| e.to_string())?;
+    if !doc.root_element().has_tag_name("NessusClientData_v2") {
+        return Err("Not a Nessus v2 report".to_string());
+    }
+
+    let mut services = Vec::new();
+    for report_host in
+        doc.descendants().filter(|n| n.has_tag_name("ReportHost"))
+    {
+        let host_ip = report_host
+            .descendants()
+            .find(|n| {
+                n.has_tag_name("tag") && n.attribute("name") == Some("host-ip")
+            })
+            .and_then(|n| n.text());
+        let host = match host_ip.or_else(|| report_host.attribute("name")) {
+            Some(host) => host.trim(),
+            None => continue,
+        };
+        let host = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(addr)) => format!("[{}]", addr),
+            _ => host.to_string(),
+        };
+
+        let mut host_services: Vec<ScannedService> = Vec::new();
+        for item in report_host
+            .children()
+            .filter(|n| n.has_tag_name("ReportItem"))
+        {
+            if item.attribute("protocol") != Some("tcp") {
+                continue;
+            }
+            // Port 0 is used for findings about the host as a whole
+            let port = match item.attribute("port").map(str::parse::<u16>) {
+                Some(Ok(port)) if port > 0 => port,
+                _ => continue,
+            };
+            let plugin_output = item
+                .children()
+                .find(|n| n.has_tag_name("plugin_output"))
+                .and_then(|n| n.text())
+                .unwrap_or_default();
+            let name = service_name(
+                item.attribute("svc_name").unwrap_or_default(),
+                plugin_output,
+            );
+
+            match host_services.iter_mut().find(|s| s.port == port) {
+                // Prefer a recognised name over whatever was seen first
+                Some(existing) if existing.name.is_empty() => {
+                    existing.name = name;
+                }
+                Some(_) => {}
+                None => host_services.push(ScannedService {
+                    host: host.clone(),
+                    port,
+                    name,
+                }),
+            }
+        }
+        services.append(&mut host_services);
+    }
+    Ok(services)
+}
+
+/// Convert a Nessus service name into the nmap equivalent, falling back
+/// to the plugin output for services that Nessus did not name, e.g.
+/// "A web server is running on this port through TLS." Returns an empty
+/// string if the service is not recognised
+fn service_name(svc_name: &str, plugin_output: &str) -> String {
+    let name = match svc_name {
+        "msrdp" | "rdp" => "ms-wbt-server",
+        "www" | "http" => "http",
+        "https" => "https",
+        "vnc" => "vnc",
+        "wsman" => "wsman",
+        _ => {
+            let output = plugin_output.to_lowercase();
+            if output.contains("remote desktop")
+                || output.contains("terminal services")
+            {
+                "ms-wbt-server"
+            } else if output.contains("vnc server") {
+                "vnc"
+            } else if output.contains("web server") {
+                if output.contains("through tls")
+                    || output.contains("through ssl")
+                {
+                    "https"
+                } else {
+                    "http"
+                }
+            } else {
+                ""
+            }
+        }
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn service_names() {
+        assert_eq!(service_name("msrdp", ""), "ms-wbt-server");
+        assert_eq!(service_name("www", ""), "http");
+        assert_eq!(
+            service_name(
+                "unknown",
+                "A web server is running on this port through TLS."
+            ),
+            "https"
+        );
+        assert_eq!(
+            service_name("general", "A VNC server is running on this port."),
+            "vnc"
+        );
+        assert_eq!(service_name("ssh", "An SSH server is running"), "");
+    }
+}
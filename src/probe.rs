@@ -0,0 +1,270 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Protocol detection for targets on non-standard ports
+
+use crate::throttle;
+use crate::util;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to wait for the server to speak first, as VNC servers do
+const BANNER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// X.224 Connection Request containing an RDP Negotiation Request for
+/// TLS or CredSSP security
+const X224_CONNECTION_REQUEST: [u8; 19] = [
+    0x03, 0x00, 0x00, 0x13, // TPKT header
+    0x0e, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, // X.224 Connection Request
+    0x01, 0x00, 0x08, 0x00, 0x03, 0x00, 0x00, 0x00, // RDP_NEG_REQ
+];
+
+/// X.224 Connection Request offering only TLS security, which servers
+/// that enforce Network Level Authentication refuse
+const X224_TLS_REQUEST: [u8; 19] = [
+    0x03, 0x00, 0x00, 0x13, // TPKT header
+    0x0e, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, // X.224 Connection Request
+    0x01, 0x00, 0x08, 0x00, 0x01, 0x00, 0x00, 0x00, // RDP_NEG_REQ
+];
+
+/// RDP_NEG_FAILURE code for servers that only support Standard RDP
+/// Security
+const SSL_NOT_ALLOWED_BY_SERVER: u32 = 0x02;
+
+/// RDP_NEG_FAILURE code for servers that only accept CredSSP
+const HYBRID_REQUIRED_BY_SERVER: u32 = 0x05;
+
+/// Why an RDP server refused the TLS security that captures use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RdpRefusal {
+    /// The server only accepts CredSSP, i.e. it enforces NLA
+    NlaRequired,
+    /// The server only supports Standard RDP Security, without TLS
+    StandardSecurityOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Rdp,
+    Vnc,
+    Web,
+}
+
+/// Connect to the target and work out which protocol it speaks from the
+/// first bytes exchanged. VNC servers send an "RFB " banner as soon as
+/// the connection is made. Otherwise an X.224 Connection Request is sent,
+/// which RDP servers answer with a Connection Confirm. Anything else is
+/// assumed to be a web server
+pub fn probe(addr: &SocketAddr) -> io::Result<Protocol> {
+    throttle::wait_for_rate();
+    let mut stream = TcpStream::connect_timeout(addr, PROBE_TIMEOUT)?;
+    stream.set_read_timeout(Some(BANNER_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+
+    let mut buf = [0; 12];
+    match stream.read(&mut buf) {
+        Ok(n) => {
+            trace!("Banner from {}: {:?}", addr, &buf[..n]);
+            return Ok(classify_banner(&buf[..n]));
+        }
+        Err(e)
+            if e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut => {}
+        Err(e) => return Err(e),
+    }
+
+    // Nothing was received, so the server is waiting for the client
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.write_all(&X224_CONNECTION_REQUEST)?;
+    // A web server may just close the connection or time out, which
+    // still means it isn't RDP
+    let n = stream.read(&mut buf).unwrap_or(0);
+    trace!("Response from {}: {:?}", addr, &buf[..n]);
+    Ok(classify_response(&buf[..n]))
+}
+
+/// Ask the RDP server for TLS security without NLA, as the capture does,
+/// and check whether it refuses because it requires NLA or doesn't
+/// support TLS at all
+pub fn rdp_refusal(
+    addr: &SocketAddr,
+    proxy: Option<&str>,
+) -> io::Result<Option<RdpRefusal>> {
+    throttle::wait_for_rate();
+    let mut stream = util::connect(addr, proxy, Some(PROBE_TIMEOUT))?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    stream.write_all(&X224_TLS_REQUEST)?;
+
+    let mut buf = [0; 19];
+    let n = stream.read(&mut buf)?;
+    trace!("Negotiation response from {}: {:?}", addr, &buf[..n]);
+    Ok(classify_refusal(&buf[..n]))
+}
+
+/// Check for an X.224 Connection Confirm carrying an RDP_NEG_FAILURE
+/// that says why the server refused TLS. Servers older than Windows
+/// Server 2003 don't understand the negotiation request, and confirm the
+/// connection without a response to it as they only have Standard RDP
+/// Security
+fn classify_refusal(response: &[u8]) -> Option<RdpRefusal> {
+    match response {
+        [0x03, 0x00, _, _, _, code, _, _, _, _, _, 0x03, _, _, _, rest @ ..]
+            if code & 0xf0 == 0xd0 && rest.len() >= 4 =>
+        {
+            let failure =
+                u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            match failure {
+                HYBRID_REQUIRED_BY_SERVER => Some(RdpRefusal::NlaRequired),
+                SSL_NOT_ALLOWED_BY_SERVER => {
+                    Some(RdpRefusal::StandardSecurityOnly)
+                }
+                _ => None,
+            }
+        }
+        [0x03, 0x00, 0x00, 0x0b, 0x06, code, _, _, _, _, _]
+            if code & 0xf0 == 0xd0 =>
+        {
+            Some(RdpRefusal::StandardSecurityOnly)
+        }
+        _ => None,
+    }
+}
+
+fn classify_banner(banner: &[u8]) -> Protocol {
+    if banner.starts_with(b"RFB ") {
+        Protocol::Vnc
+    } else {
+        Protocol::Web
+    }
+}
+
+/// Check for a TPKT header followed by an X.224 Connection Confirm
+fn classify_response(response: &[u8]) -> Protocol {
+    match response {
+        [0x03, 0x00, _, _, _, code, ..] if code & 0xf0 == 0xd0 => Protocol::Rdp,
+        _ => Protocol::Web,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn classify_first_bytes() {
+        assert_eq!(classify_banner(b"RFB 003.008\n"), Protocol::Vnc);
+        assert_eq!(classify_banner(b"SSH-2.0-Open"), Protocol::Web);
+        assert_eq!(classify_banner(b""), Protocol::Web);
+
+        let confirm = [
+            0x03, 0x00, 0x00, 0x13, 0x0e, 0xd0, 0x00, 0x00, 0x12, 0x34, 0x00,
+            0x02, 0x1f, 0x08, 0x00, 0x02, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(classify_response(&confirm), Protocol::Rdp);
+        assert_eq!(classify_response(b"HTTP/1.1 400 Bad"), Protocol::Web);
+        assert_eq!(classify_response(&[]), Protocol::Web);
+    }
+
+    #[test]
+    fn nla_negotiation_failure() {
+        let failure = |code: u8| {
+            [
+                0x03, 0x00, 0x00, 0x13, 0x0e, 0xd0, 0x00, 0x00, 0x12, 0x34,
+                0x00, 0x03, 0x00, 0x08, 0x00, code, 0x00, 0x00, 0x00,
+            ]
+        };
+        assert_eq!(
+            classify_refusal(&failure(0x05)),
+            Some(RdpRefusal::NlaRequired)
+        );
+        assert_eq!(
+            classify_refusal(&failure(0x02)),
+            Some(RdpRefusal::StandardSecurityOnly)
+        );
+        // SSL_REQUIRED_BY_SERVER
+        assert_eq!(classify_refusal(&failure(0x01)), None);
+        // A Connection Confirm with RDP_NEG_RSP choosing TLS
+        let confirm = [
+            0x03, 0x00, 0x00, 0x13, 0x0e, 0xd0, 0x00, 0x00, 0x12, 0x34, 0x00,
+            0x02, 0x1f, 0x08, 0x00, 0x01, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(classify_refusal(&confirm), None);
+        // A Connection Confirm from a server that predates negotiation
+        let legacy = [
+            0x03, 0x00, 0x00, 0x0b, 0x06, 0xd0, 0x00, 0x00, 0x12, 0x34, 0x00,
+        ];
+        assert_eq!(
+            classify_refusal(&legacy),
+            Some(RdpRefusal::StandardSecurityOnly)
+        );
+        assert_eq!(classify_refusal(&failure(0x05)[..16]), None);
+        assert_eq!(classify_refusal(&[]), None);
+
+        let rdp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let rdp_addr = rdp.local_addr().unwrap();
+        let rdp_handle = thread::spawn(move || {
+            let (mut stream, _) = rdp.accept().unwrap();
+            let mut request = [0; 19];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request, X224_TLS_REQUEST);
+            stream.write_all(&failure(0x05)).unwrap();
+        });
+        assert_eq!(
+            rdp_refusal(&rdp_addr, None).unwrap(),
+            Some(RdpRefusal::NlaRequired)
+        );
+        rdp_handle.join().unwrap();
+    }
+
+    #[test]
+    fn probe_servers() {
+        // A VNC server speaks first
+        let vnc = TcpListener::bind("127.0.0.1:0").unwrap();
+        let vnc_addr = vnc.local_addr().unwrap();
+        let vnc_handle = thread::spawn(move || {
+            let (mut stream, _) = vnc.accept().unwrap();
+            stream.write_all(b"RFB 003.008\n").unwrap();
+        });
+        assert_eq!(probe(&vnc_addr).unwrap(), Protocol::Vnc);
+        vnc_handle.join().unwrap();
+
+        // An RDP server waits for the Connection Request
+        let rdp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let rdp_addr = rdp.local_addr().unwrap();
+        let rdp_handle = thread::spawn(move || {
+            let (mut stream, _) = rdp.accept().unwrap();
+            let mut request = [0; 19];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request, X224_CONNECTION_REQUEST);
+            stream
+                .write_all(&[0x03, 0x00, 0x00, 0x0b, 0x06, 0xd0, 0, 0, 0, 0, 0])
+                .unwrap();
+        });
+        assert_eq!(probe(&rdp_addr).unwrap(), Protocol::Rdp);
+        rdp_handle.join().unwrap();
+    }
+}
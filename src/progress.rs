@@ -0,0 +1,118 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Progress bar on the terminal showing how many targets are done and
+//! roughly how long the rest will take
+
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+
+pub struct Progress {
+    total: usize,
+    done: AtomicUsize,
+    start: Instant,
+    enabled: bool,
+}
+
+impl Progress {
+    /// A progress bar for `total` targets, which is only drawn if
+    /// `enabled` and stderr is a terminal
+    pub fn new(total: usize, enabled: bool) -> Self {
+        Self {
+            total,
+            done: AtomicUsize::new(0),
+            start: Instant::now(),
+            enabled: enabled && io::stderr().is_terminal(),
+        }
+    }
+
+    /// Record that a target has finished, successfully or not
+    pub fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.enabled {
+            let line = render(done, self.total, self.start.elapsed());
+            // The cursor is left at the start of the line so that log
+            // messages overwrite the bar rather than being appended to
+            // it. It is drawn again at the next tick
+            let mut stderr = io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K{}\r", line);
+            let _ = stderr.flush();
+        }
+    }
+
+    /// Clear the bar once all of the targets are done
+    pub fn finish(&self) {
+        if self.enabled {
+            let mut stderr = io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K");
+            let _ = stderr.flush();
+        }
+    }
+}
+
+/// e.g. "[=======>          ] 120/300 (40%) ETA 2m05s"
+fn render(done: usize, total: usize, elapsed: Duration) -> String {
+    let total = total.max(done).max(1);
+    let filled = done * BAR_WIDTH / total;
+    let mut bar = "=".repeat(filled);
+    if filled < BAR_WIDTH {
+        bar.push('>');
+    }
+    let remaining = total - done;
+    let eta = elapsed.mul_f64(remaining as f64 / done.max(1) as f64);
+    format!(
+        "[{:<width$}] {}/{} ({}%) ETA {}",
+        bar,
+        done,
+        total,
+        done * 100 / total,
+        format_duration(eta),
+        width = BAR_WIDTH,
+    )
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn progress_line() {
+        assert_eq!(
+            render(10, 40, Duration::from_secs(30)),
+            "[=======>                      ] 10/40 (25%) ETA 1m30s"
+        );
+        assert_eq!(
+            render(40, 40, Duration::from_secs(120)),
+            "[==============================] 40/40 (100%) ETA 0s"
+        );
+        assert_eq!(format_duration(Duration::from_secs(7384)), "2h03m");
+    }
+}
@@ -18,10 +18,17 @@
 */
 
 use crate::argparse::Opts;
+use crate::credentials::Credential;
+use crate::dedupe;
 use crate::error::Error;
+use crate::events::{self, Event};
+use crate::logging;
 use crate::parsing::Target;
-use crate::reporting::{AsReportMessage, ReportMessage};
-use crate::util::target_to_filename;
+use crate::probe::{self, RdpRefusal};
+use crate::reporting::{AsReportMessage, FailureOutput, ReportMessage};
+use crate::sink;
+use crate::throttle;
+use crate::util;
 use crate::ThreadStatus;
 use image::{DynamicImage, ImageBuffer, Rgba};
 #[allow(unused)]
@@ -29,24 +36,38 @@ use log::{debug, error, info, trace, warn};
 use rdp::core::client::Connector;
 use rdp::core::client::RdpClient;
 use rdp::core::event::RdpEvent;
-use socks::Socks5Stream;
-use std::io::Read;
-use std::io::Write;
-use std::net::TcpStream;
+use rdp::model::error::{Error as RdpError, RdpErrorKind, RdpResult};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, mpsc::Receiver, mpsc::Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 //TODO maybe make this configurable
 const IMAGE_WIDTH: u16 = 1280;
 const IMAGE_HEIGHT: u16 = 1024;
 
+/// How long to wait before reconnecting after the server drops an RDP
+/// connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 pub struct RdpOutput {
     target: String,
     file: String,
+    duplicate_of: Option<String>,
+    attempts: usize,
+    duration: Duration,
+    correlation_id: String,
+}
+
+impl RdpOutput {
+    /// Number of connections made before the image was captured
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
 }
 
 impl AsReportMessage for RdpOutput {
@@ -59,6 +80,21 @@ impl AsReportMessage for RdpOutput {
     fn file(&self) -> &str {
         &self.file
     }
+    fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+    fn set_target(&mut self, target: String) {
+        self.target = target;
+    }
+    fn duplicate_of(&self) -> Option<&str> {
+        self.duplicate_of.as_deref()
+    }
+    fn set_duplicate_of(&mut self, target: String) {
+        self.duplicate_of = Some(target);
+    }
 }
 
 struct BitmapChunk {
@@ -164,7 +200,7 @@ impl Image {
 
     fn initialise_buffer(&mut self, chunk: &BitmapChunk) -> Result<(), ()> {
         use ImageMode::*;
-        println!("BITS PER PIXEL: {}", chunk.bpp);
+        debug!("Bits per pixel: {}", chunk.bpp);
         //TODO get these values properly
         // IMAGE_WIDTH and IMAGE_HEIGHT are u16
         let width = IMAGE_WIDTH as u32;
@@ -172,7 +208,7 @@ impl Image {
 
         let pixel_size = 4; //chunk.data.len() as u32
                             // / ((chunk.right - chunk.left) * (chunk.bottom - chunk.top));
-        println!("PIXEL SIZE {}", pixel_size);
+        debug!("Pixel size: {}", pixel_size);
 
         // Have to do a let binding here and then transfer to the self.*
         // variables pending https://github.com/rust-lang/rfcs/pull/2909
@@ -210,84 +246,102 @@ impl Image {
     }
 }
 
-/// Wrapper enum to hold TCP and Socks5 streams. This enum implements
-/// Read and Write transitively
-enum SocketType {
-    Socks5(Socks5Stream),
-    Tcp(TcpStream),
+/// Whether the error looks like the server dropping the connection, in
+/// which case a fresh connection may succeed
+fn should_reconnect(e: &RdpError) -> bool {
+    use std::io::ErrorKind::*;
+    match e {
+        RdpError::Io(e) => matches!(
+            e.kind(),
+            ConnectionReset | ConnectionAborted | UnexpectedEof | BrokenPipe
+        ),
+        RdpError::RdpError(e) => matches!(e.kind(), RdpErrorKind::Disconnect),
+        _ => false,
+    }
 }
 
-impl Read for SocketType {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        use SocketType::*;
-        match self {
-            Socks5(s) => s.read(buf),
-            Tcp(s) => s.read(buf),
-        }
-    }
+/// Whether the connection timed out, rather than being refused or
+/// dropped, in which case it is worth trying again after a delay
+fn timed_out(e: &RdpError) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e, RdpError::Io(e) if matches!(e.kind(), TimedOut | WouldBlock))
 }
 
-impl Write for SocketType {
-    fn write(
-        &mut self,
-        buf: &[u8],
-    ) -> std::result::Result<usize, std::io::Error> {
-        use SocketType::*;
-        match self {
-            Socks5(s) => s.write(buf),
-            Tcp(s) => s.write(buf),
+/// Work out why the connection failed. Servers that require NLA, or that
+/// only support Standard RDP Security, refuse the negotiation, which
+/// otherwise looks like any other protocol error, so those that could be
+/// reached are asked again to check
+fn failure_reason(addr: &SocketAddr, opts: &Opts, e: RdpError) -> Error {
+    use std::io::ErrorKind::*;
+    if let RdpError::Io(io) = &e {
+        if matches!(io.kind(), ConnectionRefused | TimedOut | WouldBlock) {
+            return e.into();
         }
     }
-    fn flush(&mut self) -> Result<(), std::io::Error> {
-        use SocketType::*;
-        match self {
-            Socks5(s) => s.flush(),
-            Tcp(s) => s.flush(),
+    match probe::rdp_refusal(addr, opts.rdp_proxy.as_deref()) {
+        Ok(Some(RdpRefusal::NlaRequired)) => {
+            info!("Server requires Network Level Authentication");
+            Error::RdpNlaRequired
+        }
+        Ok(Some(RdpRefusal::StandardSecurityOnly)) => {
+            info!("Server only supports Standard RDP Security");
+            Error::RdpStandardSecurityOnly
+        }
+        Ok(None) => e.into(),
+        Err(probe_error) => {
+            debug!("Unable to check for NLA: {}", probe_error);
+            e.into()
         }
     }
 }
 
-fn capture_worker(
-    target: &Target,
+/// Make a single connection to the RDP server and collect bitmaps until
+/// the timeout is reached. An error is returned if the connection fails
+/// or is dropped before any image data is received.
+///
+/// Without a username and password the session is left at the login
+/// screen. With them NLA is allowed, so servers that require it can be
+/// captured too, and the screen is captured after logging in.
+fn capture_attempt(
+    addr: &SocketAddr,
     opts: &Opts,
-    report_tx: &mpsc::Sender<ReportMessage>,
-) -> Result<(), Error> {
-    info!("Connecting to {:?}", target);
-    let addr = match target {
-        Target::Address(sock_addr) => sock_addr,
-        Target::Url(_) => {
-            return Err(Error::RdpError(format!(
-                "Invalid RDP target: {}",
-                target
-            )));
-        }
+    credential: &Credential,
+) -> RdpResult<Image> {
+    throttle::wait_for_rate();
+    let stream = util::connect(addr, opts.rdp_proxy.as_deref(), None)
+        .map_err(RdpError::Io)?;
+
+    let logon = match (&credential.username, &credential.password) {
+        (Some(username), Some(password)) => Some((username, password)),
+        _ => None,
     };
-
-    // If the proxy configuration is selected then create a Socks5
-    // connection, otherwise create a regular TCP stream. The wrapper
-    // enum is used to get around type errors and the limitation that
-    // trait objects can only have one main trait (i.e. "dyn Read +
-    // Write") is not possible.
-    let stream = if let Some(proxy) = &opts.rdp_proxy {
-        debug!("Connecting to Socks proxy");
-        SocketType::Socks5(Socks5Stream::connect(proxy, *addr)?)
-    } else {
-        SocketType::Tcp(TcpStream::connect(&addr)?)
-    };
-
     let mut connector = Connector::new()
         .screen(IMAGE_WIDTH, IMAGE_HEIGHT)
-        .use_nla(false)
+        .use_nla(logon.is_some())
         .check_certificate(false)
-        .blank_creds(true)
-        .credentials("".to_string(), "".to_string(), "".to_string());
+        .blank_creds(logon.is_none());
+    connector = match logon {
+        Some((username, password)) => connector.credentials(
+            credential.domain.clone().unwrap_or_default(),
+            username.clone(),
+            password.clone(),
+        ),
+        None => connector.credentials(
+            "".to_string(),
+            "".to_string(),
+            "".to_string(),
+        ),
+    };
     let client = connector.connect(stream)?;
+    events::emit(Event::Connected, "rdp", addr);
 
     let mut rdp_image: Image = Default::default();
     {
         // Spawn a thread to listen for bitmap events
-        let (bmp_sender, bmp_receiver): (Sender<BitmapChunk>, Receiver<_>) =
-            mpsc::channel();
+        let (bmp_sender, bmp_receiver): (
+            Sender<RdpResult<BitmapChunk>>,
+            Receiver<_>,
+        ) = mpsc::channel();
         let _bmp_thread_handle = thread::spawn(move || {
             bmp_thread(client, bmp_sender);
         });
@@ -299,39 +353,144 @@ fn capture_worker(
                     warn!("Timeout reached");
                     break;
                 }
-                Ok(chunk) => {
+                Ok(Ok(chunk)) => {
                     if rdp_image.add_chunk(&chunk).is_err() {
                         warn!("Attempted to add invalid chunk");
                         //break;
                     }
                 }
+                Ok(Err(e)) => {
+                    if rdp_image.image.is_none() {
+                        return Err(e);
+                    }
+                    // Keep whatever has been received so far
+                    warn!("RDP connection closed: {:?}", e);
+                    break;
+                }
             }
         }
     }
+
+    Ok(rdp_image)
+}
+
+/// Connect to the target and capture its screen, reconnecting if the
+/// server drops the connection. Returns the image and the number of
+/// connections made
+pub(crate) fn grab(
+    target: &Target,
+    opts: &Opts,
+) -> Result<(DynamicImage, usize), Error> {
+    info!("Connecting to {:?}", target);
+    let addr = match target {
+        Target::Address(sock_addr) => sock_addr,
+        Target::Url(_) => {
+            return Err(Error::RdpError(format!(
+                "Invalid RDP target: {}",
+                target
+            )));
+        }
+    };
+
+    // Some servers drop the first connection, e.g. due to licensing or
+    // session limits, but accept a fresh one after a short pause. Hosts
+    // that time out may be rate limiting, so they are given longer
+    let credential = opts
+        .credentials
+        .as_ref()
+        .map(|c| c.for_target(target))
+        .unwrap_or_default();
+    let mut attempts: usize = 0;
+    let mut retries: usize = 0;
+    let rdp_image = loop {
+        attempts += 1;
+        match capture_attempt(addr, opts, &credential) {
+            Ok(image) => break image,
+            Err(e) if retries < opts.retries && timed_out(&e) => {
+                retries += 1;
+                let delay = throttle::backoff_delay(retries);
+                warn!(
+                    "RDP connection to {} timed out, retrying in {}s \
+                    (retry {} of {})",
+                    target,
+                    delay.as_secs(),
+                    retries,
+                    opts.retries
+                );
+                thread::sleep(delay);
+            }
+            Err(e)
+                if attempts <= opts.rdp_reconnects && should_reconnect(&e) =>
+            {
+                warn!(
+                    "RDP connection to {} dropped ({:?}), reconnecting \
+                    (attempt {} of {})",
+                    target,
+                    e,
+                    attempts + 1,
+                    opts.rdp_reconnects + 1
+                );
+                thread::sleep(RECONNECT_DELAY);
+            }
+            Err(e) => return Err(failure_reason(addr, opts, e)),
+        }
+    };
+
     match rdp_image.image {
         Some(di) => {
             info!("Successfully received image");
-            let filename = format!("{}.png", target_to_filename(&target));
-            let relative_filepath = Path::new("rdp").join(&filename);
-            let filepath = Path::new(&opts.output_dir).join(&relative_filepath);
-            info!("Saving image as {}", filepath.display());
-            di.extract().save(&filepath)?;
-            let rdp_message = RdpOutput {
-                target: target.to_string(),
-                file: relative_filepath.display().to_string(),
-            }
-            .as_report_message();
-            report_tx.send(rdp_message)?;
+            Ok((di.extract(), attempts))
         }
-        _ => unimplemented!(),
+        None => Err(Error::RdpError(format!(
+            "No image data received from {}",
+            target
+        ))),
     }
+}
+
+fn capture_worker(
+    target: &Target,
+    opts: &Opts,
+    report_tx: &mpsc::Sender<ReportMessage>,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let (image, attempts) = grab(target, opts)?;
+
+    let filename = util::image_filename(opts, "rdp", target);
+    let relative_filepath = Path::new("rdp").join(&filename);
+    if opts.only_successful && util::is_uniform(&image) {
+        return Err(Error::BlankImage);
+    }
+    let mut file = sink::location(opts, &relative_filepath);
+    let duplicate =
+        dedupe::find_duplicate(&image, opts.dedupe, &target.to_string(), &file);
+    if let Some(original) = &duplicate {
+        info!("Image is a duplicate of {}, not saving", original.target);
+        file = original.file.clone();
+    } else {
+        info!("Saving image as {}", file);
+        util::save_image(&image, &relative_filepath, opts)?;
+        if opts.enhance {
+            util::save_enhanced(&image, &relative_filepath, opts)?;
+        }
+    }
+    let rdp_message = RdpOutput {
+        target: target.to_string(),
+        file,
+        duplicate_of: duplicate.map(|d| d.target),
+        attempts,
+        duration: start.elapsed(),
+        correlation_id: logging::correlation_id().unwrap_or_default(),
+    }
+    .as_report_message();
+    report_tx.send(rdp_message)?;
 
     Ok(())
 }
 
 fn bmp_thread<T: Read + Write>(
     mut client: RdpClient<T>,
-    sender: Sender<BitmapChunk>,
+    sender: Sender<RdpResult<BitmapChunk>>,
 ) {
     let break_cond = AtomicBool::new(false);
     while !break_cond.load(Ordering::Relaxed) {
@@ -370,7 +529,7 @@ fn bmp_thread<T: Read + Write>(
                     chunk.data.len(),
                 );
 
-                if sender.send(chunk).is_err() {
+                if sender.send(Ok(chunk)).is_err() {
                     // Recevier disconnected, most likely because the timeout
                     // was reached
                     info!("Bitmap channel disconnected");
@@ -383,6 +542,9 @@ fn bmp_thread<T: Read + Write>(
             Ok(_) => (),
             Err(e) => {
                 error!("{:?}", e);
+                // Pass the error on so that the capture can decide
+                // whether to reconnect
+                let _ = sender.send(Err(e));
                 break;
             }
         }
@@ -395,9 +557,45 @@ pub fn capture(
     tx: mpsc::Sender<ThreadStatus>,
     report_tx: &mpsc::Sender<ReportMessage>,
 ) {
+    let start = Instant::now();
     if let Err(e) = capture_worker(target, opts, report_tx) {
         warn!("error: {}", e);
+        if opts.only_successful {
+            util::remove_image(opts, "rdp", target);
+        }
+        let failure = FailureOutput::new(target, "rdp", &e, start.elapsed());
+        // Sending fails if --target-timeout has already given up on
+        // this capture and nothing is listening any more
+        let _ = report_tx.send(failure.as_report_message());
     }
 
-    tx.send(ThreadStatus::Complete).unwrap();
+    let _ = tx.send(ThreadStatus::Complete);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn reconnect_on_dropped_connection() {
+        let reset = RdpError::Io(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "reset",
+        ));
+        let refused = RdpError::Io(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "refused",
+        ));
+
+        assert!(should_reconnect(&reset));
+        assert!(!should_reconnect(&refused));
+        assert!(!should_reconnect(&RdpError::SslHandshakeError));
+
+        let timeout =
+            RdpError::Io(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+        assert!(timed_out(&timeout));
+        assert!(!timed_out(&refused));
+        assert!(!timed_out(&reset));
+    }
 }
@@ -0,0 +1,215 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Group captured targets that look like the same software or appliance.
+//!
+//! The heuristics are deliberately conservative: targets are only
+//! grouped when a fingerprint matches exactly, and fingerprints that are
+//! likely to be shared by unrelated hosts are not used at all.
+//!
+//! * Web: the normalised page hash (see `web::page_hash`). Blank pages
+//!   are not hashed so don't form a group
+//! * VNC: the desktop name. Default desktop names usually include the
+//!   hostname, so in practice only appliances that name their desktop
+//!   after the product are grouped
+//! * WinRM: the full response summary (status, Server header and
+//!   authentication schemes), but only when an HTTP response was read,
+//!   and separately the subject of the certificate of HTTPS listeners
+//!
+//! RDP captures aren't grouped because there is no banner to go on: the
+//! desktop is only seen as an image, and a server's own certificate
+//! isn't available from the RDP client.
+
+use crate::reporting::AsReportMessage;
+use crate::vnc::VncOutput;
+use crate::web::WebOutput;
+use crate::winrm::WinRmOutput;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+/// Targets that share a fingerprint
+#[derive(Debug)]
+pub struct Cluster {
+    pub description: String,
+    pub targets: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Fingerprint {
+    PageHash(String),
+    VncDesktop(String),
+    WinRmResponse(String),
+    TlsSubject(String),
+}
+
+impl Fingerprint {
+    fn describe(&self) -> String {
+        match self {
+            Self::PageHash(hash) => format!("Identical web page ({})", hash),
+            Self::VncDesktop(name) => {
+                format!("Identical VNC desktop name ({})", name)
+            }
+            Self::WinRmResponse(summary) => {
+                format!("Identical WinRM response ({})", summary)
+            }
+            Self::TlsSubject(subject) => {
+                format!("Identical certificate subject ({})", subject)
+            }
+        }
+    }
+}
+
+/// Group the outputs by fingerprint, only keeping groups with more than
+/// one member. Largest groups come first
+pub fn find_clusters(
+    web_outputs: &[WebOutput],
+    vnc_outputs: &[VncOutput],
+    winrm_outputs: &[WinRmOutput],
+) -> Vec<Cluster> {
+    let mut fingerprints: Vec<(Fingerprint, &str)> = Vec::new();
+    for out in web_outputs {
+        if let Some(hash) = out.body_hash() {
+            let fingerprint = Fingerprint::PageHash(hash.to_string());
+            fingerprints.push((fingerprint, out.target()));
+        }
+    }
+    for out in vnc_outputs {
+        if let Some(name) = out.banner() {
+            let fingerprint = Fingerprint::VncDesktop(name.to_string());
+            fingerprints.push((fingerprint, out.target()));
+        }
+    }
+    for out in winrm_outputs {
+        // Only fingerprint actual responses, not "unreachable" or other
+        // probe results
+        if out.status().starts_with("HTTP ") {
+            let fingerprint =
+                Fingerprint::WinRmResponse(out.status().to_string());
+            fingerprints.push((fingerprint, out.target()));
+        }
+        if let Some(subject) = out.banner() {
+            let fingerprint = Fingerprint::TlsSubject(subject.to_string());
+            fingerprints.push((fingerprint, out.target()));
+        }
+    }
+    group(fingerprints)
+}
+
+/// Group targets by fingerprint
+fn group(fingerprints: Vec<(Fingerprint, &str)>) -> Vec<Cluster> {
+    let mut groups: BTreeMap<Fingerprint, Vec<String>> = BTreeMap::new();
+    for (fingerprint, target) in fingerprints {
+        groups
+            .entry(fingerprint)
+            .or_default()
+            .push(target.to_string());
+    }
+
+    let mut clusters: Vec<Cluster> = groups
+        .into_iter()
+        .filter(|(_, targets)| targets.len() > 1)
+        .map(|(fingerprint, targets)| Cluster {
+            description: fingerprint.describe(),
+            targets,
+        })
+        .collect();
+    // The sort is stable so clusters of the same size stay in
+    // fingerprint order
+    clusters.sort_by_key(|c| Reverse(c.targets.len()));
+    clusters
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn cluster_winrm_responses() {
+        let outputs: Vec<WinRmOutput> = vec![
+            (
+                "http://192.0.2.1:5985",
+                "HTTP 401, Server: Microsoft-HTTPAPI/2.0",
+            ),
+            (
+                "http://192.0.2.2:5985",
+                "HTTP 401, Server: Microsoft-HTTPAPI/2.0",
+            ),
+            (
+                "http://192.0.2.3:5985",
+                "HTTP 401, Server: Microsoft-HTTPAPI/2.0",
+            ),
+            ("http://192.0.2.4:5985", "HTTP 404, Server: nginx"),
+        ]
+        .into_iter()
+        .map(|(t, s)| {
+            WinRmOutput::new(t.to_string(), s.to_string(), Duration::default())
+        })
+        .collect();
+
+        let clusters = find_clusters(&[], &[], &outputs);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].targets.len(), 3);
+        assert!(clusters[0].description.contains("Microsoft-HTTPAPI"));
+    }
+
+    #[test]
+    fn cluster_banners() {
+        let fingerprints = vec![
+            (
+                Fingerprint::VncDesktop("iDRAC".to_string()),
+                "192.0.2.1:5901",
+            ),
+            (
+                Fingerprint::VncDesktop("iDRAC".to_string()),
+                "192.0.2.2:5901",
+            ),
+            (
+                Fingerprint::VncDesktop(
+                    "root's X desktop (db01:1)".to_string(),
+                ),
+                "192.0.2.3:5901",
+            ),
+            (
+                Fingerprint::TlsSubject("O=Example, CN=appliance".to_string()),
+                "https://192.0.2.4:5986/",
+            ),
+            (
+                Fingerprint::TlsSubject("O=Example, CN=appliance".to_string()),
+                "https://192.0.2.5:5986/",
+            ),
+            (
+                Fingerprint::TlsSubject("O=Example, CN=appliance".to_string()),
+                "https://192.0.2.6:5986/",
+            ),
+        ];
+        let clusters = group(fingerprints);
+        let summary: Vec<(&str, usize)> = clusters
+            .iter()
+            .map(|c| (c.description.as_str(), c.targets.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("Identical certificate subject (O=Example, CN=appliance)", 3),
+                ("Identical VNC desktop name (iDRAC)", 2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Spreadsheet-friendly report with one row per target, built from the
+//! same entries as the JSON report
+
+use super::json::JsonEntry;
+use super::write_atomically;
+use crate::error::Error;
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::Path;
+
+const HEADER: [&str; 7] = [
+    "target",
+    "mode",
+    "file",
+    "success",
+    "error",
+    "duration_ms",
+    "banner",
+];
+
+/// Quote a field if it contains a delimiter, quote or line break, as
+/// described in RFC 4180. Values that a spreadsheet would treat as a
+/// formula, such as a banner starting with `=`, are prefixed with `'` so
+/// that opening the report can't run anything
+fn field(value: &str) -> Cow<'_, str> {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        Cow::Owned(format!("'{}", value))
+    } else {
+        Cow::Borrowed(value)
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        value
+    }
+}
+
+fn row(entry: &JsonEntry) -> String {
+    let duration_ms = entry
+        .duration
+        .map(|d| format!("{:.0}", d * 1000.0))
+        .unwrap_or_default();
+    let fields = [
+        field(&entry.target),
+        field(entry.mode),
+        field(entry.file.as_deref().unwrap_or_default()),
+        Cow::Borrowed(if entry.success { "true" } else { "false" }),
+        field(entry.error.as_deref().unwrap_or_default()),
+        Cow::Owned(duration_ms),
+        field(entry.banner.as_deref().unwrap_or_default()),
+    ];
+    fields.join(",")
+}
+
+/// Render the entries as CSV with a header line. Lines end in CRLF so
+/// that spreadsheet programs are happy with them
+pub fn csv_report(entries: &[JsonEntry]) -> String {
+    let mut report = HEADER.join(",");
+    report.push_str("\r\n");
+    for entry in entries {
+        report.push_str(&row(entry));
+        report.push_str("\r\n");
+    }
+    report
+}
+
+pub fn write_csv(path: &Path, entries: &[JsonEntry]) -> Result<(), Error> {
+    write_atomically(path, |file| {
+        file.write_all(csv_report(entries).as_bytes())?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_quoting() {
+        let entries = vec![
+            JsonEntry {
+                target: "http://192.0.2.1/a,b".to_string(),
+                mode: "web",
+                file: Some("web/http_192.0.2.1_a,b.png".to_string()),
+                timestamp: Some(1593002716.284),
+                duration: Some(1.25),
+                success: true,
+                error: None,
+                failure_kind: None,
+                duplicate_of: None,
+                banner: Some("Say \"hello\"".to_string()),
+                nla_required: false,
+                standard_security_only: false,
+                metadata: None,
+                clusters: Vec::new(),
+                tls: None,
+            },
+            JsonEntry {
+                target: "192.0.2.2:5900".to_string(),
+                mode: "vnc",
+                file: None,
+                timestamp: None,
+                duration: None,
+                success: false,
+                error: None,
+                failure_kind: None,
+                duplicate_of: None,
+                banner: None,
+                nla_required: false,
+                standard_security_only: false,
+                metadata: None,
+                clusters: Vec::new(),
+                tls: None,
+            },
+        ];
+        assert_eq!(
+            csv_report(&entries),
+            "target,mode,file,success,error,duration_ms,banner\r\n\
+            \"http://192.0.2.1/a,b\",web,\"web/http_192.0.2.1_a,b.png\",\
+            true,,1250,\"Say \"\"hello\"\"\"\r\n\
+            192.0.2.2:5900,vnc,,false,,,\r\n"
+        );
+    }
+
+    #[test]
+    fn formulas_escaped() {
+        assert_eq!(field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(field("+1"), "'+1");
+        assert_eq!(field("-1"), "'-1");
+        assert_eq!(field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(field("\tcmd"), "'\tcmd");
+        assert_eq!(field("\rcmd"), "\"'\rcmd\"");
+        assert_eq!(field("a=b"), "a=b");
+        assert_eq!(field(""), "");
+    }
+}
@@ -0,0 +1,486 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Machine-readable report with one entry per target
+
+use super::{write_atomically, AsReportMessage, FailureOutput, ReportTemplate};
+use crate::error::Error;
+use crate::parsing::Metadata;
+use crate::tls::TlsDetails;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Serialize)]
+pub struct JsonEntry {
+    pub(super) target: String,
+    pub(super) mode: &'static str,
+    pub(super) file: Option<String>,
+    /// Seconds since the Unix epoch at which the capture was received
+    pub(super) timestamp: Option<f64>,
+    /// How long the capture took in seconds, if it was attempted
+    pub(super) duration: Option<f64>,
+    pub(super) success: bool,
+    /// Why the capture failed, if it was attempted
+    pub(super) error: Option<String>,
+    /// Whether the failed connection was refused or timed out
+    pub(super) failure_kind: Option<&'static str>,
+    /// The target that this image duplicates, if --dedupe is enabled
+    pub(super) duplicate_of: Option<String>,
+    /// Text identifying the server, e.g. the VNC desktop name or the web
+    /// page title
+    pub(super) banner: Option<String>,
+    /// Whether an RDP server refused the capture because it requires
+    /// Network Level Authentication
+    pub(super) nla_required: bool,
+    /// Whether an RDP server refused the capture because it only supports
+    /// Standard RDP Security
+    pub(super) standard_security_only: bool,
+    /// Details about the service from the file it was imported from
+    pub(super) metadata: Option<Metadata>,
+    /// Descriptions of the groups of similar hosts that the target is in
+    pub(super) clusters: Vec<String>,
+    /// The server's certificate and handshake fingerprints, for VeNCrypt
+    /// and WinRM over HTTPS
+    pub(super) tls: Option<TlsDetails>,
+}
+
+fn success<T: AsReportMessage>(
+    out: &T,
+    mode: &'static str,
+    received: &HashMap<String, f64>,
+) -> JsonEntry {
+    JsonEntry {
+        target: out.target().to_string(),
+        mode,
+        file: Some(out.file().to_string()).filter(|f| !f.is_empty()),
+        timestamp: received.get(out.correlation_id()).copied(),
+        duration: Some(out.duration().as_secs_f64()),
+        success: true,
+        error: None,
+        failure_kind: None,
+        duplicate_of: out.duplicate_of().map(str::to_string),
+        banner: out.banner().map(str::to_string),
+        nla_required: false,
+        standard_security_only: false,
+        metadata: None,
+        clusters: Vec::new(),
+        tls: out.tls().cloned(),
+    }
+}
+
+/// The host:port part of a URL, used to match web targets whose scheme
+/// was changed by probing
+fn without_scheme(target: &str) -> &str {
+    target.split_once("://").map_or(target, |(_, rest)| rest)
+}
+
+/// An entry for each capture, followed by an unsuccessful entry for each
+/// target that didn't produce one, including the error if the capture
+/// failed rather than not being attempted
+pub fn json_entries(
+    report: &ReportTemplate,
+    winrm_targets: &[String],
+    received: &HashMap<String, f64>,
+) -> Vec<JsonEntry> {
+    let mut entries = Vec::new();
+    entries.extend(
+        report
+            .rdp_outputs
+            .iter()
+            .map(|out| success(out, "rdp", received)),
+    );
+    entries.extend(
+        report
+            .web_outputs
+            .iter()
+            .map(|out| success(out, "web", received)),
+    );
+    entries.extend(
+        report
+            .vnc_outputs
+            .iter()
+            .map(|out| success(out, "vnc", received)),
+    );
+    entries.extend(
+        report
+            .winrm_outputs
+            .iter()
+            .map(|out| success(out, "winrm", received)),
+    );
+
+    let captured: HashSet<(&str, &str)> = entries
+        .iter()
+        .map(|e| (e.mode, e.target.as_str()))
+        .collect();
+    let probed: HashSet<&str> = report
+        .web_outputs
+        .iter()
+        .filter(|out| out.scheme_probed())
+        .map(|out| without_scheme(out.target()))
+        .collect();
+    // Web failures are matched ignoring the scheme for the same reason
+    let failed: HashMap<(&str, &str), &FailureOutput> = report
+        .failures
+        .iter()
+        .map(|out| {
+            let target = if out.mode() == "web" {
+                without_scheme(out.target())
+            } else {
+                out.target()
+            };
+            ((out.mode(), target), out)
+        })
+        .collect();
+
+    let mut failures = Vec::new();
+    let target_lists: [(&'static str, &[String]); 4] = [
+        ("rdp", &report.rdp_targets),
+        ("web", &report.web_targets),
+        ("vnc", &report.vnc_targets),
+        ("winrm", winrm_targets),
+    ];
+    for (mode, targets) in target_lists.iter() {
+        for target in targets.iter() {
+            let done = captured.contains(&(*mode, target.as_str()))
+                || (*mode == "web" && probed.contains(without_scheme(target)));
+            if done {
+                continue;
+            }
+            let key = if *mode == "web" {
+                (*mode, without_scheme(target))
+            } else {
+                (*mode, target.as_str())
+            };
+            let failure = failed.get(&key);
+            failures.push(JsonEntry {
+                target: target.clone(),
+                mode,
+                file: None,
+                timestamp: failure
+                    .and_then(|f| received.get(f.correlation_id()))
+                    .copied(),
+                duration: failure.map(|f| f.duration().as_secs_f64()),
+                success: false,
+                error: failure.map(|f| f.reason().to_string()),
+                failure_kind: failure.map(|f| f.kind().as_str()),
+                duplicate_of: None,
+                banner: None,
+                nla_required: failure.is_some_and(|f| f.nla_required()),
+                standard_security_only: failure
+                    .is_some_and(|f| f.standard_security_only()),
+                metadata: None,
+                clusters: Vec::new(),
+                tls: None,
+            });
+        }
+    }
+    entries.extend(failures);
+    for entry in entries.iter_mut() {
+        entry.metadata = report.metadata.get(&entry.target).cloned();
+        entry.clusters = report
+            .clusters
+            .iter()
+            .filter(|c| c.targets.contains(&entry.target))
+            .map(|c| c.description.clone())
+            .collect();
+    }
+    entries
+}
+
+/// The mode names used in the report, so that entries read back from an
+/// earlier report can use the same static strings
+const MODES: [&str; 4] = ["rdp", "web", "vnc", "winrm"];
+
+/// A successful entry from an earlier run's report. Anything that didn't
+/// capture isn't kept, as the target is being tried again
+fn previous_entry(value: &Value) -> Option<JsonEntry> {
+    if value.get("success").and_then(Value::as_bool) != Some(true) {
+        return None;
+    }
+    let string = |key: &str| value.get(key)?.as_str().map(str::to_string);
+    let mode = value.get("mode")?.as_str()?;
+    let metadata: Option<Metadata> =
+        value.get("metadata").and_then(Value::as_object).map(|m| {
+            m.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        });
+    let clusters = value
+        .get("clusters")
+        .and_then(Value::as_array)
+        .map(|c| {
+            c.iter()
+                .filter_map(|d| d.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(JsonEntry {
+        target: string("target")?,
+        mode: MODES.iter().copied().find(|m| *m == mode)?,
+        file: string("file"),
+        timestamp: value.get("timestamp").and_then(Value::as_f64),
+        duration: value.get("duration").and_then(Value::as_f64),
+        success: true,
+        error: None,
+        failure_kind: None,
+        duplicate_of: string("duplicate_of"),
+        banner: string("banner"),
+        nla_required: false,
+        standard_security_only: false,
+        metadata,
+        clusters,
+        tls: value.get("tls").map(|tls| {
+            let string = |key: &str| tls.get(key)?.as_str().map(str::to_string);
+            TlsDetails {
+                subject: string("subject"),
+                sha256: string("sha256"),
+                ja3: string("ja3"),
+                ja3s: string("ja3s"),
+            }
+        }),
+    })
+}
+
+/// For --resume, put back the captures from the report that an earlier
+/// run left at `path`. The targets they were for were skipped by this
+/// run, so would otherwise be listed as not attempted
+pub fn merge_previous(
+    entries: &mut [JsonEntry],
+    path: &Path,
+) -> Result<(), Error> {
+    let content = fs::read_to_string(path)?;
+    let previous: Vec<JsonEntry> = match serde_json::from_str(&content)? {
+        Value::Array(values) => {
+            values.iter().filter_map(previous_entry).collect()
+        }
+        _ => {
+            return Err(Error::OutputError(format!(
+                "{} doesn't contain a list of entries",
+                path.display()
+            )))
+        }
+    };
+    let mut previous: HashMap<(&'static str, String), JsonEntry> = previous
+        .into_iter()
+        .map(|e| ((e.mode, e.target.clone()), e))
+        .collect();
+    for entry in entries.iter_mut() {
+        // Only targets that weren't attempted, anything that failed or
+        // was captured again is more up to date
+        if entry.success || entry.error.is_some() {
+            continue;
+        }
+        if let Some(old) = previous.remove(&(entry.mode, entry.target.clone()))
+        {
+            *entry = old;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_json(path: &Path, entries: &[JsonEntry]) -> Result<(), Error> {
+    write_atomically(path, |file| {
+        serde_json::to_writer_pretty(file, entries)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::Target;
+    use crate::reporting::clusters::Cluster;
+    use crate::winrm::WinRmOutput;
+    use std::time::Duration;
+
+    #[test]
+    fn failed_targets_included() {
+        let report = ReportTemplate {
+            rdp_targets: vec![
+                "192.0.2.1:3389".to_string(),
+                "192.0.2.4:3389".to_string(),
+            ],
+            web_targets: Vec::new(),
+            vnc_targets: Vec::new(),
+            rdp_outputs: Vec::new(),
+            web_outputs: Vec::new(),
+            vnc_outputs: Vec::new(),
+            winrm_outputs: vec![WinRmOutput::new(
+                "http://192.0.2.2:5985/".to_string(),
+                "HTTP 401".to_string(),
+                Duration::from_millis(100),
+            )],
+            failures: vec![
+                FailureOutput::new(
+                    &Target::Address("192.0.2.1:3389".parse().unwrap()),
+                    "rdp",
+                    &Error::RdpError("Connection refused".to_string()),
+                    Duration::from_secs(2),
+                ),
+                FailureOutput::new(
+                    &Target::Address("192.0.2.4:3389".parse().unwrap()),
+                    "rdp",
+                    &Error::RdpNlaRequired,
+                    Duration::from_secs(1),
+                ),
+            ],
+            clusters: vec![Cluster {
+                description: "Identical WinRM response (HTTP 401)".to_string(),
+                targets: vec![
+                    "http://192.0.2.2:5985/".to_string(),
+                    "http://192.0.2.9:5985/".to_string(),
+                ],
+            }],
+            metadata: vec![(
+                "192.0.2.4:3389".to_string(),
+                vec![("os".to_string(), "Windows".to_string())]
+                    .into_iter()
+                    .collect(),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let winrm_targets = vec![
+            "http://192.0.2.2:5985/".to_string(),
+            "http://192.0.2.3:5985/".to_string(),
+        ];
+        let entries = json_entries(&report, &winrm_targets, &HashMap::new());
+
+        let summary: Vec<(&str, &str, bool, Option<&str>)> = entries
+            .iter()
+            .map(|e| (e.mode, e.target.as_str(), e.success, e.error.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("winrm", "http://192.0.2.2:5985/", true, None),
+                (
+                    "rdp",
+                    "192.0.2.1:3389",
+                    false,
+                    Some("RDP error: Connection refused")
+                ),
+                (
+                    "rdp",
+                    "192.0.2.4:3389",
+                    false,
+                    Some("RDP server requires Network Level Authentication")
+                ),
+                ("winrm", "http://192.0.2.3:5985/", false, None),
+            ]
+        );
+        assert_eq!(entries[1].failure_kind, Some("other"));
+        assert_eq!(entries[3].failure_kind, None);
+        assert!(!entries[1].nla_required);
+        assert!(entries[2].nla_required);
+        assert!(!entries[2].standard_security_only);
+        assert_eq!(entries[1].metadata, None);
+        assert_eq!(
+            entries[2].metadata.as_ref().and_then(|m| m.get("os")),
+            Some(&"Windows".to_string())
+        );
+        assert_eq!(
+            entries[0].clusters,
+            vec!["Identical WinRM response (HTTP 401)".to_string()]
+        );
+        assert!(entries[1].clusters.is_empty());
+        assert_eq!(entries[1].duration, Some(2.0));
+        assert_eq!(entries[3].duration, None);
+    }
+
+    #[test]
+    fn previous_entries_merged() {
+        let path = std::env::temp_dir().join(format!(
+            "scrying-previous-report-{}.json",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            r#"[
+  {"target": "192.0.2.1:5900", "mode": "vnc", "file": "vnc/192.0.2.1-5900.png",
+   "timestamp": 1593002714.5, "duration": 2.5, "success": true, "error": null,
+   "failure_kind": null, "duplicate_of": null, "banner": "iDRAC",
+   "nla_required": false, "standard_security_only": false,
+   "metadata": {"os": "Linux"}, "clusters": [],
+   "tls": {"subject": "CN=idrac", "sha256": "0a1b", "ja3": null,
+           "ja3s": "f4febc55ea12b31ae17cfb7e614afda8"}},
+  {"target": "192.0.2.2:5900", "mode": "vnc", "file": "vnc/192.0.2.2-5900.png",
+   "success": true},
+  {"target": "192.0.2.3:5900", "mode": "vnc", "file": null, "success": false,
+   "error": "IO error: connection timed out"}
+]"#,
+        )
+        .unwrap();
+
+        let not_attempted = |target: &str| JsonEntry {
+            target: target.to_string(),
+            mode: "vnc",
+            file: None,
+            timestamp: None,
+            duration: None,
+            success: false,
+            error: None,
+            failure_kind: None,
+            duplicate_of: None,
+            banner: None,
+            nla_required: false,
+            standard_security_only: false,
+            metadata: None,
+            clusters: Vec::new(),
+            tls: None,
+        };
+        let mut entries = vec![
+            not_attempted("192.0.2.1:5900"),
+            // Failed in this run
+            JsonEntry {
+                error: Some("Connection refused".to_string()),
+                ..not_attempted("192.0.2.2:5900")
+            },
+            not_attempted("192.0.2.3:5900"),
+        ];
+        merge_previous(&mut entries, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(entries[0].success);
+        assert_eq!(entries[0].file.as_deref(), Some("vnc/192.0.2.1-5900.png"));
+        assert_eq!(entries[0].duration, Some(2.5));
+        assert_eq!(entries[0].banner.as_deref(), Some("iDRAC"));
+        assert_eq!(
+            entries[0].metadata.as_ref().and_then(|m| m.get("os")),
+            Some(&"Linux".to_string())
+        );
+        assert_eq!(
+            entries[0].tls,
+            Some(TlsDetails {
+                subject: Some("CN=idrac".to_string()),
+                sha256: Some("0a1b".to_string()),
+                ja3: None,
+                ja3s: Some("f4febc55ea12b31ae17cfb7e614afda8".to_string()),
+            })
+        );
+        assert!(!entries[1].success);
+        assert!(!entries[2].success);
+        assert_eq!(entries[2].error, None);
+
+        assert!(merge_previous(&mut entries, &path).is_err());
+    }
+}
@@ -0,0 +1,811 @@
+use crate::argparse::{Opts, ReportFormat};
+use crate::error::Error;
+use crate::events::{self, Event};
+use crate::logging;
+use crate::parsing::{InputLists, Metadata, Target};
+use crate::rdp::RdpOutput;
+use crate::tls::TlsDetails;
+use crate::vnc::VncOutput;
+use crate::web::WebOutput;
+use crate::winrm::WinRmOutput;
+use askama::Template;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use clusters::Cluster;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use pdf::PdfEntry;
+
+mod clusters;
+mod csv;
+mod json;
+mod pdf;
+
+#[derive(Template)]
+#[template(path = "report.html")]
+struct ReportTemplate {
+    rdp_targets: Vec<String>,
+    web_targets: Vec<String>,
+    vnc_targets: Vec<String>,
+    rdp_outputs: Vec<RdpOutput>,
+    web_outputs: Vec<WebOutput>,
+    vnc_outputs: Vec<VncOutput>,
+    winrm_outputs: Vec<WinRmOutput>,
+    failures: Vec<FailureOutput>,
+    clusters: Vec<Cluster>,
+    /// Details from the import files, keyed by target
+    metadata: HashMap<String, Metadata>,
+}
+
+impl ReportTemplate {
+    /// The imported details about a target, one per line
+    fn details(&self, target: &str) -> Vec<String> {
+        let metadata = match self.metadata.get(target) {
+            Some(metadata) => metadata,
+            None => return Vec::new(),
+        };
+        metadata
+            .iter()
+            .map(|(key, value)| {
+                let label = match key.as_str() {
+                    "os" => "OS",
+                    "product" => "Product",
+                    "version" => "Version",
+                    "extra_info" => "Info",
+                    other => other,
+                };
+                format!("{}: {}", label, value)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum ReportMessage {
+    RdpOutput(RdpOutput),
+    WebOutput(WebOutput),
+    VncOutput(VncOutput),
+    WinRmOutput(WinRmOutput),
+    Failure(FailureOutput),
+    GenerateReport,
+}
+
+pub trait AsReportMessage {
+    /// Convert the object into an instance of the ReportMessage enum
+    fn as_report_message(self) -> ReportMessage;
+
+    /// Return the target, e.g. http://[2001:db8::2]:8080
+    fn target(&self) -> &str;
+
+    /// Return the filename relative to the "output" directory
+    fn file(&self) -> &str;
+
+    /// Replace the target, e.g. with a redacted version
+    fn set_target(&mut self, target: String);
+
+    /// Return the correlation ID used in the logs for this target
+    fn correlation_id(&self) -> &str;
+
+    /// Return how long the capture took
+    fn duration(&self) -> Duration;
+
+    /// Return the target that captured the same image first, if this
+    /// image wasn't saved because of --dedupe
+    fn duplicate_of(&self) -> Option<&str> {
+        None
+    }
+
+    /// Replace the target recorded by `duplicate_of`, e.g. with a
+    /// redacted version
+    fn set_duplicate_of(&mut self, _target: String) {}
+
+    /// Return text that the server gave to identify itself, e.g. the VNC
+    /// desktop name or the web page title
+    fn banner(&self) -> Option<&str> {
+        None
+    }
+
+    /// Remove the banner, e.g. because it may name the host
+    fn clear_banner(&mut self) {}
+
+    /// Return the server's certificate and handshake fingerprints, for
+    /// the TLS connections that scrying makes itself
+    fn tls(&self) -> Option<&TlsDetails> {
+        None
+    }
+
+    /// Return how long the capture took, formatted for the report
+    fn duration_secs(&self) -> String {
+        format!("{:.1}s", self.duration().as_secs_f64())
+    }
+}
+
+/// How a capture failed, so that hosts that refused the connection can
+/// be told apart from those that may be filtered or rate limited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureKind {
+    /// The host actively refused the connection
+    Refused,
+    /// The connection or the capture timed out
+    Timeout,
+    Other,
+}
+
+impl FailureKind {
+    pub fn of(error: &Error) -> Self {
+        match error {
+            Error::ConnectionRefused(_) => Self::Refused,
+            Error::ConnectionTimedOut(_) | Error::CaptureTimeout(_) => {
+                Self::Timeout
+            }
+            _ => Self::Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Refused => "refused",
+            Self::Timeout => "timeout",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A target that could not be captured, and the reason why
+#[derive(Debug)]
+pub struct FailureOutput {
+    target: String,
+    mode: &'static str,
+    reason: String,
+    kind: FailureKind,
+    /// Whether an RDP server refused the capture because it requires
+    /// Network Level Authentication
+    nla_required: bool,
+    /// Whether an RDP server refused the capture because it doesn't
+    /// support TLS
+    standard_security_only: bool,
+    duration: Duration,
+    correlation_id: String,
+}
+
+impl FailureOutput {
+    /// Create a failure for the current thread's correlation ID. `mode`
+    /// is the capture type, e.g. "rdp"
+    pub fn new(
+        target: &Target,
+        mode: &'static str,
+        error: &Error,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            target: target.to_string(),
+            mode,
+            reason: error.to_string(),
+            kind: FailureKind::of(error),
+            nla_required: matches!(error, Error::RdpNlaRequired),
+            standard_security_only: matches!(
+                error,
+                Error::RdpStandardSecurityOnly
+            ),
+            duration,
+            correlation_id: logging::correlation_id().unwrap_or_default(),
+        }
+    }
+
+    /// The capture type, e.g. "rdp" or "web"
+    pub fn mode(&self) -> &'static str {
+        self.mode
+    }
+
+    /// Why the capture failed
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Whether the connection was refused, timed out, or failed in some
+    /// other way
+    pub fn kind(&self) -> FailureKind {
+        self.kind
+    }
+
+    /// Whether the server was reachable but requires NLA
+    pub fn nla_required(&self) -> bool {
+        self.nla_required
+    }
+
+    /// Whether the server was reachable but only supports Standard RDP
+    /// Security
+    pub fn standard_security_only(&self) -> bool {
+        self.standard_security_only
+    }
+}
+
+impl AsReportMessage for FailureOutput {
+    fn as_report_message(self) -> ReportMessage {
+        ReportMessage::Failure(self)
+    }
+    fn target(&self) -> &str {
+        &self.target
+    }
+    /// Nothing was captured, so there is no file
+    fn file(&self) -> &str {
+        ""
+    }
+    fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+    fn set_target(&mut self, target: String) {
+        self.target = target;
+    }
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+/// Write the captured or failed event for a report message, for
+/// --emit-events
+fn emit_event(msg: &ReportMessage) {
+    fn captured<T: AsReportMessage>(out: &T, mode: &str) {
+        let file = Some(out.file()).filter(|f| !f.is_empty());
+        let event = Event::Captured { file };
+        events::emit_for(event, mode, &out.target(), out.correlation_id());
+    }
+    match msg {
+        ReportMessage::GenerateReport => {}
+        ReportMessage::RdpOutput(out) => captured(out, "rdp"),
+        ReportMessage::WebOutput(out) => captured(out, "web"),
+        ReportMessage::VncOutput(out) => captured(out, "vnc"),
+        ReportMessage::WinRmOutput(out) => captured(out, "winrm"),
+        ReportMessage::Failure(out) => events::emit_for(
+            Event::Failed {
+                error: out.reason(),
+            },
+            out.mode(),
+            &out.target(),
+            out.correlation_id(),
+        ),
+    }
+}
+
+/// Replaces the host part of targets with consistent pseudonyms, e.g.
+/// host-1, host-2, so that reports can be shared without disclosing
+/// internal hostnames and addresses
+#[derive(Debug, Default)]
+struct Redactor {
+    /// Map of original host to pseudonym
+    pseudonyms: HashMap<String, String>,
+    /// Original hosts in the order that pseudonyms were assigned
+    hosts: Vec<String>,
+}
+
+impl Redactor {
+    fn pseudonym(&mut self, host: &str) -> String {
+        if let Some(p) = self.pseudonyms.get(host) {
+            return p.clone();
+        }
+        self.hosts.push(host.to_string());
+        let p = format!("host-{}", self.hosts.len());
+        self.pseudonyms.insert(host.to_string(), p.clone());
+        p
+    }
+
+    /// Redact the host from a target string, keeping the scheme, port,
+    /// and path intact
+    fn redact(&mut self, target: &str) -> String {
+        if let Ok(addr) = target.parse::<SocketAddr>() {
+            let host = addr.ip().to_string();
+            return format!("{}:{}", self.pseudonym(&host), addr.port());
+        }
+
+        // Targets given by hostname are shown as host:port
+        if let Some((host, port)) = target.rsplit_once(':') {
+            if !host.is_empty()
+                && !host.contains(&[':', '/'][..])
+                && port.parse::<u16>().is_ok()
+            {
+                return format!("{}:{}", self.pseudonym(host), port);
+            }
+        }
+
+        if let Ok(mut url) = Url::parse(target) {
+            // IPv6 hosts are bracketed in URLs but not in socket
+            // addresses, so strip them to keep the pseudonyms consistent
+            if let Some(host) = url
+                .host_str()
+                .map(|h| h.trim_start_matches('[').trim_end_matches(']'))
+                .map(str::to_string)
+            {
+                let p = self.pseudonym(&host);
+                if url.set_host(Some(&p)).is_ok() {
+                    return url.to_string();
+                }
+            }
+        }
+
+        // Unrecognised format, so redact the whole thing to be safe
+        self.pseudonym(target)
+    }
+
+    fn redact_outputs<T: AsReportMessage>(&mut self, outputs: &mut [T]) {
+        for out in outputs {
+            let target = self.redact(out.target());
+            out.set_target(target);
+            if let Some(original) = out.duplicate_of().map(|o| self.redact(o)) {
+                out.set_duplicate_of(original);
+            }
+            // Banners are free text that often name the host, so there's
+            // no reliable way of redacting just the host from them
+            out.clear_banner();
+        }
+    }
+
+    /// Write the pseudonym to host mapping, one per line
+    fn write_mapping(&self, path: &Path) -> Result<(), Error> {
+        let mut mapping = String::new();
+        for host in &self.hosts {
+            mapping.push_str(&format!("{} {}\n", self.pseudonyms[host], host));
+        }
+        write_atomically(path, |file| {
+            file.write_all(mapping.as_bytes())?;
+            Ok(())
+        })
+    }
+}
+
+/// Collect the captures into PDF entries with their metadata as captions
+fn pdf_entries(report: &ReportTemplate) -> Vec<PdfEntry> {
+    let mut entries = Vec::new();
+    for out in &report.rdp_outputs {
+        let mut details = vec![
+            format!("ID: {}", out.correlation_id()),
+            format!("Capture time: {}", out.duration_secs()),
+        ];
+        if let Some(original) = out.duplicate_of() {
+            details.push(format!("Duplicate of {}", original));
+        }
+        if out.attempts() > 1 {
+            details.push(format!("Connection attempts: {}", out.attempts()));
+        }
+        entries.push(PdfEntry {
+            heading: format!("RDP: {}", out.target()),
+            file: Some(out.file().to_string()),
+            details,
+        });
+    }
+    for out in &report.web_outputs {
+        let mut details = vec![
+            format!("ID: {}", out.correlation_id()),
+            format!("Capture time: {}", out.duration_secs()),
+        ];
+        if let Some(original) = out.duplicate_of() {
+            details.push(format!("Duplicate of {}", original));
+        }
+        if let Some(title) = out.banner() {
+            details.push(format!("Page title: {}", title));
+        }
+        if let Some(size) = out.scroll_size() {
+            details.push(format!("Page size: {}", size));
+        }
+        if let Some(hash) = out.body_hash() {
+            details.push(format!("Page hash: {}", hash));
+        }
+        if out.scheme_probed() {
+            details.push("Scheme detected by probing".to_string());
+        }
+        entries.push(PdfEntry {
+            heading: format!("Web: {}", out.target()),
+            file: Some(out.file().to_string()),
+            details,
+        });
+    }
+    for out in &report.vnc_outputs {
+        let mut details = vec![
+            format!("ID: {}", out.correlation_id()),
+            format!("Capture time: {}", out.duration_secs()),
+        ];
+        if let Some(original) = out.duplicate_of() {
+            details.push(format!("Duplicate of {}", original));
+        }
+        if let Some(name) = out.banner() {
+            details.push(format!("Desktop name: {}", name));
+        }
+        if out.attempts() > 1 {
+            details.push(format!("Attempts: {}", out.attempts()));
+        }
+        if out.supplied_password() {
+            details.push("Supplied password accepted".to_string());
+        }
+        if let Some(entry) = out.password_entry() {
+            details.push(format!("Password list entry: {}", entry));
+        }
+        if let Some(coverage) = out.partial_coverage() {
+            details.push(format!("Partial capture: {} received", coverage));
+        }
+        entries.push(PdfEntry {
+            heading: format!("VNC: {}", out.target()),
+            file: Some(out.file().to_string()),
+            details,
+        });
+    }
+    for out in &report.winrm_outputs {
+        entries.push(PdfEntry {
+            heading: format!("WinRM: {}", out.target()),
+            file: None,
+            details: vec![
+                out.status().to_string(),
+                format!("ID: {}", out.correlation_id()),
+            ],
+        });
+    }
+    for out in &report.failures {
+        entries.push(PdfEntry {
+            heading: format!("Failed {}: {}", out.mode(), out.target()),
+            file: None,
+            details: vec![
+                out.reason().to_string(),
+                format!("ID: {}", out.correlation_id()),
+                format!("Time taken: {}", out.duration_secs()),
+            ],
+        });
+    }
+    entries
+}
+
+/// One line with the number of successful and failed captures for each
+/// mode that had any targets, e.g. "rdp: 30 ok / 5 failed"
+fn summary_line(
+    succeeded: &[(&'static str, usize)],
+    failed: &HashMap<&'static str, usize>,
+) -> String {
+    let counts: Vec<String> = succeeded
+        .iter()
+        .map(|(mode, ok)| (mode, ok, failed.get(mode).copied().unwrap_or(0)))
+        .filter(|(_, ok, failed)| **ok > 0 || *failed > 0)
+        .map(|(mode, ok, failed)| {
+            format!("{}: {} ok / {} failed", mode, ok, failed)
+        })
+        .collect();
+    if counts.is_empty() {
+        "Nothing was captured".to_string()
+    } else {
+        counts.join(", ")
+    }
+}
+
+/// Write a report file by writing to a temporary file next to it and then
+/// renaming it into place, so that anything reading the report, e.g.
+/// between --watch passes, sees either the old one or the new one in full
+pub(crate) fn write_atomically<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+{
+    let mut temp_name = OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path).map_err(Error::from).and_then(|f| {
+        let mut file = BufWriter::new(f);
+        write(&mut file)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    });
+    if result.is_err() && temp_path.exists() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Collect the messages from the capture threads and write the reports
+/// once `GenerateReport` is received. This thread is the only one that
+/// writes report files, so the workers never write to them directly and
+/// nothing can be interleaved.
+///
+/// Messages are handled in the order they arrive on the channel: those
+/// from one worker stay in the order that it sent them, but those from
+/// different workers are in whatever order they finished. Entries in the
+/// reports follow that order within each type of capture. Any messages
+/// sent after `GenerateReport` are not included. Returns the number of
+/// targets that couldn't be captured.
+pub fn reporting_thread(
+    rx: mpsc::Receiver<ReportMessage>,
+    opts: Arc<Opts>,
+    targets: Arc<InputLists>,
+) -> Result<usize, Error> {
+    // Vecs to collect the output messages in
+    let mut rdp_outputs: Vec<RdpOutput> = Vec::new();
+    let mut web_outputs: Vec<WebOutput> = Vec::new();
+    let mut vnc_outputs: Vec<VncOutput> = Vec::new();
+    let mut winrm_outputs: Vec<WinRmOutput> = Vec::new();
+    let mut failures: Vec<FailureOutput> = Vec::new();
+    // When each output was received, keyed by correlation ID
+    let mut received: HashMap<String, f64> = HashMap::new();
+    // Failures for the summary, counted before --only-successful drops
+    // them
+    let mut failed: HashMap<&'static str, usize> = HashMap::new();
+
+    // Main loop listening on the channel
+    while let Ok(msg) = rx.recv() {
+        use ReportMessage::*;
+        debug!("Received message: {:?}", msg);
+        let correlation_id = match &msg {
+            GenerateReport => None,
+            RdpOutput(out) => Some(out.correlation_id()),
+            WebOutput(out) => Some(out.correlation_id()),
+            VncOutput(out) => Some(out.correlation_id()),
+            WinRmOutput(out) => Some(out.correlation_id()),
+            Failure(out) => Some(out.correlation_id()),
+        };
+        emit_event(&msg);
+        if let Some(id) = correlation_id {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default();
+            received.insert(id.to_string(), now);
+        }
+        match msg {
+            GenerateReport => break,
+            RdpOutput(out) => rdp_outputs.push(out),
+            WebOutput(out) => web_outputs.push(out),
+            VncOutput(out) => vnc_outputs.push(out),
+            WinRmOutput(out) => winrm_outputs.push(out),
+            Failure(out) => {
+                *failed.entry(out.mode()).or_insert(0) += 1;
+                // Reviewers only want to see the captures that worked
+                if !opts.only_successful {
+                    failures.push(out);
+                }
+            }
+        }
+    }
+    let summary = summary_line(
+        &[
+            ("rdp", rdp_outputs.len()),
+            ("web", web_outputs.len()),
+            ("vnc", vnc_outputs.len()),
+            ("winrm", winrm_outputs.len()),
+        ],
+        &failed,
+    );
+
+    info!("Generating report");
+
+    debug!("RDP outputs: {:?}", rdp_outputs);
+    debug!("Web outputs: {:?}", web_outputs);
+
+    let report_file = Path::new(&opts.output_dir).join("report.html");
+
+    let mut rdp_targets: Vec<String> =
+        targets.rdp_targets.iter().map(|t| t.to_string()).collect();
+    let mut web_targets: Vec<String> =
+        targets.web_targets.iter().map(|t| t.to_string()).collect();
+    let mut vnc_targets: Vec<String> =
+        targets.vnc_targets.iter().map(|t| t.to_string()).collect();
+    let mut winrm_targets: Vec<String> = targets
+        .winrm_targets
+        .iter()
+        .map(|t| t.to_string())
+        .collect();
+    let mut metadata: HashMap<String, Metadata> = targets
+        .rdp_targets
+        .iter()
+        .chain(targets.web_targets.iter())
+        .chain(targets.vnc_targets.iter())
+        .chain(targets.winrm_targets.iter())
+        .filter_map(|t| Some((t.to_string(), t.metadata()?)))
+        .collect();
+
+    if let Some(map_file) = &opts.redact {
+        info!("Redacting targets in report");
+        let mut redactor: Redactor = Default::default();
+        for t in rdp_targets
+            .iter_mut()
+            .chain(web_targets.iter_mut())
+            .chain(vnc_targets.iter_mut())
+            .chain(winrm_targets.iter_mut())
+        {
+            *t = redactor.redact(t);
+        }
+        redactor.redact_outputs(&mut rdp_outputs);
+        redactor.redact_outputs(&mut web_outputs);
+        redactor.redact_outputs(&mut vnc_outputs);
+        redactor.redact_outputs(&mut winrm_outputs);
+        redactor.redact_outputs(&mut failures);
+        metadata = metadata
+            .into_iter()
+            .map(|(target, details)| (redactor.redact(&target), details))
+            .collect();
+
+        let map_file = Path::new(map_file);
+        redactor.write_mapping(map_file)?;
+        info!("Redaction mapping saved to {:?}", map_file);
+    }
+
+    let clusters =
+        clusters::find_clusters(&web_outputs, &vnc_outputs, &winrm_outputs);
+
+    let report_template = ReportTemplate {
+        rdp_targets,
+        web_targets,
+        vnc_targets,
+        rdp_outputs,
+        web_outputs,
+        vnc_outputs,
+        winrm_outputs,
+        failures,
+        clusters,
+        metadata,
+    };
+    if opts.report_formats.contains(&ReportFormat::Html) {
+        let report = report_template.render()?;
+        debug!("Report: {:?}", report);
+        write_atomically(&report_file, |file| {
+            file.write_all(report.as_bytes())?;
+            Ok(())
+        })?;
+        info!("Report saved to {:?}", report_file);
+    }
+
+    let wants_entries = opts.report_formats.contains(&ReportFormat::Json)
+        || opts.report_formats.contains(&ReportFormat::Csv);
+    if wants_entries {
+        let mut entries =
+            json::json_entries(&report_template, &winrm_targets, &received);
+        let previous = Path::new(&opts.output_dir).join("report.json");
+        if opts.resume && previous.exists() {
+            if let Err(e) = json::merge_previous(&mut entries, &previous) {
+                warn!("Unable to read the earlier JSON report: {}", e);
+            }
+        }
+        // As in the HTML report, reviewers only want the captures that
+        // worked
+        if opts.only_successful {
+            entries.retain(|e| e.success);
+        }
+        if opts.report_formats.contains(&ReportFormat::Json) {
+            let json_file = Path::new(&opts.output_dir).join("report.json");
+            json::write_json(&json_file, &entries)?;
+            info!("JSON report saved to {:?}", json_file);
+        }
+        if opts.report_formats.contains(&ReportFormat::Csv) {
+            let csv_file = Path::new(&opts.output_dir).join("report.csv");
+            csv::write_csv(&csv_file, &entries)?;
+            info!("CSV report saved to {:?}", csv_file);
+        }
+    }
+
+    if let Some(pdf_file) = &opts.pdf {
+        let entries = pdf_entries(&report_template);
+        let pdf_file = Path::new(pdf_file);
+        pdf::write_pdf(pdf_file, Path::new(&opts.output_dir), &entries)?;
+        info!("PDF report saved to {:?}", pdf_file);
+    }
+
+    // Keep stdout to just the events for --emit-events
+    if opts.silent {
+        info!("{}", summary);
+    } else if opts.emit_events {
+        eprintln!("{}", summary);
+    } else {
+        println!("{}", summary);
+    }
+
+    Ok(failed.values().sum())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn failure_kinds() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        let would_block = io::Error::from(io::ErrorKind::WouldBlock);
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+
+        assert_eq!(FailureKind::of(&refused.into()), FailureKind::Refused);
+        assert_eq!(FailureKind::of(&timed_out.into()), FailureKind::Timeout);
+        assert_eq!(FailureKind::of(&would_block.into()), FailureKind::Timeout);
+        assert_eq!(FailureKind::of(&denied.into()), FailureKind::Other);
+        assert_eq!(
+            FailureKind::of(&Error::CaptureTimeout(30)),
+            FailureKind::Timeout
+        );
+        assert_eq!(FailureKind::of(&Error::RdpNlaRequired), FailureKind::Other);
+
+        // Refusals reported by the protocol libraries are classified too
+        let rdp_refused = rdp::model::error::Error::Io(io::Error::from(
+            io::ErrorKind::ConnectionRefused,
+        ));
+        assert_eq!(FailureKind::of(&rdp_refused.into()), FailureKind::Refused);
+        let vnc_timed_out =
+            vnc::Error::Io(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(
+            FailureKind::of(&vnc_timed_out.into()),
+            FailureKind::Timeout
+        );
+    }
+
+    #[test]
+    fn summary_counts() {
+        let mut failed = HashMap::new();
+        failed.insert("web", 14);
+        failed.insert("vnc", 2);
+        assert_eq!(
+            summary_line(
+                &[("rdp", 30), ("web", 120), ("vnc", 0), ("winrm", 0)],
+                &failed
+            ),
+            "rdp: 30 ok / 0 failed, web: 120 ok / 14 failed, \
+            vnc: 0 ok / 2 failed"
+        );
+        assert_eq!(
+            summary_line(&[("rdp", 0)], &HashMap::new()),
+            "Nothing was captured"
+        );
+    }
+
+    #[test]
+    fn atomic_writes() {
+        let base = std::env::temp_dir()
+            .join(format!("scrying-report-test-{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+        let path = base.join("report.json");
+        let temp_path = base.join(".report.json.tmp");
+
+        write_atomically(&path, |f| Ok(f.write_all(b"first")?)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        assert!(!temp_path.exists());
+
+        // A failed write leaves the previous report in place
+        let result = write_atomically(&path, |f| {
+            f.write_all(b"partial")?;
+            Err(Error::BlankImage)
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        assert!(!temp_path.exists());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn redact_targets() {
+        let mut redactor: Redactor = Default::default();
+
+        assert_eq!(redactor.redact("192.0.2.1:3389"), "host-1:3389");
+        assert_eq!(
+            redactor.redact("https://example.com:8443/login"),
+            "https://host-2:8443/login"
+        );
+        assert_eq!(redactor.redact("http://192.0.2.1/"), "http://host-1/");
+        assert_eq!(redactor.redact("[2001:db8::1]:5900"), "host-3:5900");
+        assert_eq!(redactor.redact("http://[2001:db8::1]/"), "http://host-3/");
+        assert_eq!(redactor.redact("fileserver:3389"), "host-4:3389");
+
+        assert_eq!(
+            redactor.hosts,
+            vec!["192.0.2.1", "example.com", "2001:db8::1", "fileserver"]
+        );
+    }
+}
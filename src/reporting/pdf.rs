@@ -0,0 +1,182 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::write_atomically;
+use crate::error::Error;
+use image::GenericImageView;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use printpdf::{
+    BuiltinFont, Image, IndirectFontRef, Mm, PdfDocument, PdfDocumentReference,
+    PdfLayerReference,
+};
+use std::path::Path;
+
+// A4 landscape
+const PAGE_WIDTH: f64 = 297.0;
+const PAGE_HEIGHT: f64 = 210.0;
+const MARGIN: f64 = 10.0;
+const LINE_HEIGHT: f64 = 6.0;
+
+/// Space reserved above each image for the caption
+const CAPTION_HEIGHT: f64 = 30.0;
+
+/// Resolution printpdf assumes when no DPI is given for an image
+const IMAGE_DPI: f64 = 300.0;
+
+/// Images wider than this are scaled down before being embedded to keep
+/// the memory use and file size under control for large runs
+const MAX_IMAGE_WIDTH: u32 = 1920;
+
+/// One entry in the PDF report
+#[derive(Debug)]
+pub struct PdfEntry {
+    pub heading: String,
+    /// Image file relative to the output directory, if there is one
+    pub file: Option<String>,
+    pub details: Vec<String>,
+}
+
+struct Fonts {
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+}
+
+fn new_page(doc: &PdfDocumentReference) -> PdfLayerReference {
+    let (page, layer) =
+        doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+    doc.get_page(page).get_layer(layer)
+}
+
+/// Write the caption at the top of the page
+fn write_caption(layer: &PdfLayerReference, fonts: &Fonts, entry: &PdfEntry) {
+    let mut y = PAGE_HEIGHT - MARGIN - LINE_HEIGHT;
+    layer.use_text(
+        entry.heading.as_str(),
+        14.0,
+        Mm(MARGIN),
+        Mm(y),
+        &fonts.bold,
+    );
+    for line in &entry.details {
+        y -= LINE_HEIGHT;
+        layer.use_text(line.as_str(), 10.0, Mm(MARGIN), Mm(y), &fonts.regular);
+    }
+}
+
+/// Add the image below the caption, scaled to fit the rest of the page
+fn add_image(
+    layer: PdfLayerReference,
+    output_dir: &Path,
+    file: &str,
+) -> Result<(), Error> {
+    let mut img = image::open(output_dir.join(file))?;
+    if img.width() > MAX_IMAGE_WIDTH {
+        img = img.thumbnail(MAX_IMAGE_WIDTH, MAX_IMAGE_WIDTH);
+    }
+    // Drop any alpha channel, which PDF images don't support directly
+    let img = image::DynamicImage::ImageRgb8(img.to_rgb());
+
+    let (width, height) = img.dimensions();
+    let width_mm = f64::from(width) * 25.4 / IMAGE_DPI;
+    let height_mm = f64::from(height) * 25.4 / IMAGE_DPI;
+    let available_width = PAGE_WIDTH - 2.0 * MARGIN;
+    let available_height = PAGE_HEIGHT - 2.0 * MARGIN - CAPTION_HEIGHT;
+    let scale = (available_width / width_mm).min(available_height / height_mm);
+
+    Image::from_dynamic_image(&img).add_to_layer(
+        layer,
+        Some(Mm(MARGIN)),
+        Some(Mm(MARGIN)),
+        None,
+        Some(scale),
+        Some(scale),
+        None,
+    );
+
+    Ok(())
+}
+
+/// Write a PDF with a title page followed by one page per captured
+/// image. Entries without an image, e.g. WinRM fingerprints, are listed
+/// on the pages after the title. The whole document is built in memory,
+/// including every image, and then saved. Each image file is only
+/// decoded while its page is being added.
+pub fn write_pdf(
+    path: &Path,
+    output_dir: &Path,
+    entries: &[PdfEntry],
+) -> Result<(), Error> {
+    let (doc, page, layer) = PdfDocument::new(
+        "Report for NCC Group Scrying",
+        Mm(PAGE_WIDTH),
+        Mm(PAGE_HEIGHT),
+        "Layer 1",
+    );
+    let fonts = Fonts {
+        regular: doc.add_builtin_font(BuiltinFont::Helvetica)?,
+        bold: doc.add_builtin_font(BuiltinFont::HelveticaBold)?,
+    };
+
+    // Title page, followed by the text-only entries
+    let mut layer = doc.get_page(page).get_layer(layer);
+    let mut y = PAGE_HEIGHT - MARGIN - 2.0 * LINE_HEIGHT;
+    layer.use_text(
+        "Report for NCC Group Scrying",
+        24.0,
+        Mm(MARGIN),
+        Mm(y),
+        &fonts.bold,
+    );
+    y -= 2.0 * LINE_HEIGHT;
+    for entry in entries.iter().filter(|e| e.file.is_none()) {
+        let line = format!("{} - {}", entry.heading, entry.details.join(", "));
+        if y < MARGIN {
+            layer = new_page(&doc);
+            y = PAGE_HEIGHT - MARGIN - LINE_HEIGHT;
+        }
+        layer.use_text(line, 10.0, Mm(MARGIN), Mm(y), &fonts.regular);
+        y -= LINE_HEIGHT;
+    }
+
+    for entry in entries {
+        let file = match &entry.file {
+            Some(f) => f,
+            None => continue,
+        };
+        debug!("Adding {} to PDF", file);
+        let layer = new_page(&doc);
+        write_caption(&layer, &fonts, entry);
+        if let Err(e) = add_image(layer.clone(), output_dir, file) {
+            warn!("Unable to add {} to PDF: {}", file, e);
+            layer.use_text(
+                format!("Image unavailable: {}", e),
+                10.0,
+                Mm(MARGIN),
+                Mm(PAGE_HEIGHT / 2.0),
+                &fonts.regular,
+            );
+        }
+    }
+
+    write_atomically(path, |file| {
+        doc.save(file)?;
+        Ok(())
+    })
+}
@@ -0,0 +1,497 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Hostname resolution for targets. With --hosts-file or --resolver,
+//! hostnames are looked up in the hosts file and then with the given DNS
+//! server instead of the system resolver, which may not see internal DNS
+//! on a pivoted scan. Addresses found this way remember their hostname so
+//! that it can be shown in reports and used in image filenames
+
+use crate::util;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+static RESOLVER: RwLock<Option<Arc<Resolver>>> = RwLock::new(None);
+
+/// Hostnames of the addresses found by the resolver
+static HOSTNAMES: Mutex<BTreeMap<SocketAddr, String>> =
+    Mutex::new(BTreeMap::new());
+
+/// Hostnames of the addresses found by the system resolver. Unlike
+/// HOSTNAMES these don't replace the address in filenames and reports,
+/// and are only used to check TLS certificates
+static SYSTEM_HOSTNAMES: Mutex<BTreeMap<SocketAddr, String>> =
+    Mutex::new(BTreeMap::new());
+
+/// Addresses found by the resolver for each hostname, so that Chrome can
+/// be told about them
+static ADDRESSES: Mutex<BTreeMap<String, IpAddr>> = Mutex::new(BTreeMap::new());
+
+/// Addresses given for hostnames by target importers, e.g. the hostname
+/// column of a CSV file, which are used ahead of any resolver
+static KNOWN: Mutex<BTreeMap<String, IpAddr>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+pub struct Resolver {
+    hosts: HashMap<String, IpAddr>,
+    server: Option<SocketAddr>,
+    /// SOCKS5 proxy for connecting to the DNS server
+    proxy: Option<String>,
+}
+
+impl Resolver {
+    /// Resolver using the hosts file, if there is one, and then the DNS
+    /// server, if there is one. Queries are made over TCP so that they
+    /// can go through the proxy
+    pub fn new(
+        hosts_file: Option<&str>,
+        server: Option<SocketAddr>,
+        proxy: Option<&str>,
+    ) -> Result<Self, String> {
+        let hosts = match hosts_file {
+            Some(file) => {
+                let text = fs::read_to_string(file).map_err(|e| {
+                    format!("Unable to read hosts file {}: {}", file, e)
+                })?;
+                parse_hosts(&text).map_err(|e| {
+                    format!("Invalid hosts file {}: {}", file, e)
+                })?
+            }
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            hosts,
+            server,
+            proxy: proxy.map(str::to_string),
+        })
+    }
+
+    /// Look the host up in the hosts file and then with the DNS server.
+    /// None means that neither was given, so the system resolver should
+    /// be used
+    fn lookup(&self, host: &str) -> io::Result<Option<IpAddr>> {
+        if let Some(ip) = self.hosts.get(&host.to_ascii_lowercase()) {
+            debug!("Found {} in the hosts file: {}", host, ip);
+            return Ok(Some(*ip));
+        }
+        let server = match self.server {
+            Some(server) => server,
+            None => return Ok(None),
+        };
+        for qtype in &[TYPE_A, TYPE_AAAA] {
+            let addrs = query(&server, self.proxy.as_deref(), host, *qtype)?;
+            if let Some(ip) = addrs.first() {
+                debug!("{} resolved to {} by {}", host, ip, server);
+                return Ok(Some(*ip));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no addresses according to {}", host, server),
+        ))
+    }
+}
+
+/// Use the resolver for the rest of the run
+pub fn configure(resolver: Resolver) {
+    *RESOLVER.write().unwrap() = Some(Arc::new(resolver));
+}
+
+/// Go back to the system resolver and forget the addresses found so far,
+/// ready for another run in the same process
+pub fn reset() {
+    *RESOLVER.write().unwrap() = None;
+    HOSTNAMES.lock().unwrap().clear();
+    SYSTEM_HOSTNAMES.lock().unwrap().clear();
+    ADDRESSES.lock().unwrap().clear();
+    KNOWN.lock().unwrap().clear();
+}
+
+/// Resolve the hostname to the given address rather than looking it up,
+/// e.g. because a target list gave both
+pub fn add_host(host: &str, ip: IpAddr) {
+    KNOWN.lock().unwrap().insert(host.to_ascii_lowercase(), ip);
+}
+
+/// Look the host up in the addresses given by importers and then with
+/// the configured resolver. None means that the system resolver should
+/// be used
+fn lookup(host: &str) -> io::Result<Option<IpAddr>> {
+    if let Some(ip) = KNOWN.lock().unwrap().get(&host.to_ascii_lowercase()) {
+        return Ok(Some(*ip));
+    }
+    // The lock isn't held during the lookup, which may be a DNS query
+    let resolver = RESOLVER.read().unwrap().clone();
+    match resolver {
+        Some(resolver) => resolver.lookup(host),
+        None => Ok(None),
+    }
+}
+
+/// Resolve a hostname to an address with the configured resolver,
+/// falling back to the system one
+pub fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    if let Ok(ip) = host.parse() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+    if let Some(ip) = lookup(host)? {
+        let addr = SocketAddr::new(ip, port);
+        HOSTNAMES
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_insert_with(|| host.to_string());
+        ADDRESSES.lock().unwrap().insert(host.to_string(), ip);
+        return Ok(addr);
+    }
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("Unknown error resolving domain"))?;
+    SYSTEM_HOSTNAMES
+        .lock()
+        .unwrap()
+        .entry(addr)
+        .or_insert_with(|| host.to_string());
+    Ok(addr)
+}
+
+/// Look up a web target's host in the addresses given by importers and
+/// with the configured resolver, so that Chrome can be given its address.
+/// The system resolver is left to Chrome
+pub fn resolve_web_host(host: &str) {
+    match lookup(host) {
+        Ok(Some(ip)) => {
+            ADDRESSES.lock().unwrap().insert(host.to_string(), ip);
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Unable to resolve {}: {}", host, e),
+    }
+}
+
+/// The address that a hostname has already been resolved to, by any
+/// resolver, or been given by an importer. No lookups are made
+pub fn cached_address(host: &str) -> Option<IpAddr> {
+    if let Some(ip) = KNOWN.lock().unwrap().get(&host.to_ascii_lowercase()) {
+        return Some(*ip);
+    }
+    if let Some(ip) = ADDRESSES.lock().unwrap().get(host) {
+        return Some(*ip);
+    }
+    SYSTEM_HOSTNAMES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(host))
+        .map(|(addr, _)| addr.ip())
+}
+
+/// The hostname that an address was resolved from, if it was resolved
+/// with --hosts-file or --resolver or given by an importer
+pub fn hostname(addr: &SocketAddr) -> Option<String> {
+    HOSTNAMES.lock().unwrap().get(addr).cloned()
+}
+
+/// The name that a TLS server at the address should have a certificate
+/// for. This is the hostname that the address came from with any
+/// resolver, or the address itself
+pub fn server_name(addr: &SocketAddr) -> String {
+    hostname(addr)
+        .or_else(|| SYSTEM_HOSTNAMES.lock().unwrap().get(addr).cloned())
+        .unwrap_or_else(|| addr.ip().to_string())
+}
+
+/// Value for Chrome's --host-resolver-rules mapping the web hosts found
+/// by the resolver to their addresses
+pub fn host_resolver_rules() -> Option<String> {
+    let addresses = ADDRESSES.lock().unwrap();
+    let rules: Vec<String> = addresses
+        .iter()
+        .map(|(host, ip)| match ip {
+            IpAddr::V4(ip) => format!("MAP {} {}", host, ip),
+            IpAddr::V6(ip) => format!("MAP {} [{}]", host, ip),
+        })
+        .collect();
+    Some(rules.join(", ")).filter(|r| !r.is_empty())
+}
+
+/// Parse a hosts file in the /etc/hosts format, i.e. an address followed
+/// by its names on each line. As with /etc/hosts the first address given
+/// for a name is used
+fn parse_hosts(text: &str) -> Result<HashMap<String, IpAddr>, String> {
+    let mut hosts = HashMap::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next() {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| {
+            format!("line {}: {:?} is not an IP address", num + 1, ip)
+        })?;
+        for name in fields {
+            if !is_hostname(name) {
+                return Err(format!(
+                    "line {}: {:?} is not a valid hostname",
+                    num + 1,
+                    name
+                ));
+            }
+            hosts.entry(name.to_ascii_lowercase()).or_insert(ip);
+        }
+    }
+    Ok(hosts)
+}
+
+/// Whether the name is made of the characters allowed in DNS names.
+/// Hostnames end up in filenames, so nothing else is accepted
+pub fn is_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._".contains(c))
+}
+
+/// Ask the DNS server for the host's records of type `qtype`, over TCP
+fn query(
+    server: &SocketAddr,
+    proxy: Option<&str>,
+    host: &str,
+    qtype: u16,
+) -> io::Result<Vec<IpAddr>> {
+    let id: u16 = rand::random();
+    let request = build_query(id, host, qtype)?;
+    let mut stream = util::connect(server, proxy, Some(DNS_TIMEOUT))?;
+    stream.set_read_timeout(Some(DNS_TIMEOUT))?;
+    stream.set_write_timeout(Some(DNS_TIMEOUT))?;
+    // Messages over TCP are prefixed with their length
+    stream.write_all(&(request.len() as u16).to_be_bytes())?;
+    stream.write_all(&request)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut response)?;
+    parse_response(&response, id, qtype)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn build_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(host.len() + 18);
+    message.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid hostname {}", host),
+            ));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Addresses of type `qtype` from the answers in a DNS response. A name
+/// that doesn't exist gives no addresses rather than an error
+fn parse_response(
+    message: &[u8],
+    id: u16,
+    qtype: u16,
+) -> io::Result<Vec<IpAddr>> {
+    let truncated = || invalid("Truncated DNS response".to_string());
+    let u16_at = |pos: usize| {
+        message
+            .get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    if u16_at(0)? != id {
+        return Err(invalid("DNS response ID doesn't match".to_string()));
+    }
+    let flags = u16_at(2)?;
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN
+        3 => return Ok(Vec::new()),
+        rcode => {
+            return Err(invalid(format!("DNS server returned error {}", rcode)))
+        }
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(message, pos)?;
+        let rtype = u16_at(pos)?;
+        let class = u16_at(pos + 2)?;
+        let len = usize::from(u16_at(pos + 8)?);
+        let data = message
+            .get(pos + 10..pos + 10 + len)
+            .ok_or_else(truncated)?;
+        pos += 10 + len;
+        // CNAMEs are skipped, as servers include the records that they
+        // point to in the answer
+        if rtype != qtype || class != CLASS_IN {
+            continue;
+        }
+        match data.len() {
+            4 => addrs.push(IpAddr::V4(Ipv4Addr::new(
+                data[0], data[1], data[2], data[3],
+            ))),
+            16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => return Err(invalid("Invalid address record".to_string())),
+        }
+    }
+    Ok(addrs)
+}
+
+/// Position after the name starting at `pos`, which may end with a
+/// pointer to a name elsewhere in the message
+fn skip_name(message: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *message
+            .get(pos)
+            .ok_or_else(|| invalid("Truncated DNS name".to_string()))?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + usize::from(len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn hosts_file() {
+        let hosts = parse_hosts(
+            "# Internal hosts\n\
+            10.0.0.5\tfileserver fileserver.corp.example  # the NAS\n\
+            \n\
+            2001:db8::7 Intranet\n\
+            10.0.0.6 fileserver\n",
+        )
+        .unwrap();
+        assert_eq!(hosts["fileserver"], "10.0.0.5".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            hosts["fileserver.corp.example"],
+            "10.0.0.5".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(hosts["intranet"], "2001:db8::7".parse::<IpAddr>().unwrap());
+        assert!(parse_hosts("fileserver 10.0.0.5\n").is_err());
+        assert!(parse_hosts("10.0.0.5 a/b\n").is_err());
+
+        let resolver = Resolver {
+            hosts,
+            ..Default::default()
+        };
+        assert_eq!(
+            resolver.lookup("INTRANET").unwrap(),
+            Some("2001:db8::7".parse().unwrap())
+        );
+        assert_eq!(resolver.lookup("example.com").unwrap(), None);
+    }
+
+    #[test]
+    fn server_names() {
+        let addr = resolve("localhost", 5900).unwrap();
+        assert_eq!(server_name(&addr), "localhost");
+        assert_eq!(hostname(&addr), None);
+        let addr = "192.0.2.1:5900".parse().unwrap();
+        assert_eq!(server_name(&addr), "192.0.2.1");
+    }
+
+    /// Answer one DNS query over TCP with an A record for `answer`,
+    /// reached through a compressed pointer to the question name
+    fn dns_server(answer: [u8; 4]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut len = [0; 2];
+            s.read_exact(&mut len).unwrap();
+            let mut query = vec![0; usize::from(u16::from_be_bytes(len))];
+            s.read_exact(&mut query).unwrap();
+
+            let mut response = query.clone();
+            // Response, recursion available, one answer
+            response[2..4].copy_from_slice(&[0x81, 0x80]);
+            response[6..8].copy_from_slice(&[0, 1]);
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend_from_slice(&answer);
+            s.write_all(&(response.len() as u16).to_be_bytes()).unwrap();
+            s.write_all(&response).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn dns_query() {
+        let server = dns_server([10, 1, 2, 3]);
+        let addrs = query(&server, None, "rdp.corp.example", TYPE_A).unwrap();
+        assert_eq!(addrs, vec!["10.1.2.3".parse::<IpAddr>().unwrap()]);
+
+        let request = build_query(7, "a.example.", TYPE_A).unwrap();
+        assert_eq!(&request[12..], b"\x01a\x07example\x00\x00\x01\x00\x01");
+        assert!(build_query(7, "a..example", TYPE_A).is_err());
+
+        // NXDOMAIN
+        let mut response = request;
+        response[2..4].copy_from_slice(&[0x81, 0x83]);
+        assert!(parse_response(&response, 7, TYPE_A).unwrap().is_empty());
+        response[2..4].copy_from_slice(&[0x81, 0x82]);
+        assert!(parse_response(&response, 7, TYPE_A).is_err());
+        assert!(parse_response(&response, 8, TYPE_A).is_err());
+        assert!(parse_response(&response[..20], 7, TYPE_A).is_err());
+    }
+}
@@ -0,0 +1,944 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Capturing all of the targets given on the command line, as done by
+//! the scrying binary
+
+use crate::argparse::Opts;
+use crate::error::Error;
+use crate::events::{self, Event};
+use crate::logging::{self, CorrelatedLogger, JsonLogger};
+use crate::parsing::{self, generate_target_lists, InputLists, Target};
+use crate::progress::Progress;
+use crate::reporting::{self, AsReportMessage, FailureOutput, ReportMessage};
+use crate::resolve::{self, Resolver};
+use crate::sink::{self, HttpSink};
+use crate::throttle::{self, HostLimiter};
+use crate::util::FilenameTemplate;
+use crate::{
+    capabilities, dedupe, interrupt, rdp, util, vnc, watchdog, web, winrm,
+    ThreadStatus,
+};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use simplelog::{
+    CombinedLogger, ConfigBuilder, LevelFilter, SharedLogger, TermLogger,
+    TerminalMode, WriteLogger,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::create_dir_all;
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How a scan finished, which the scrying binary turns into its exit code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every target was captured, or there was nothing to capture
+    Success,
+    /// Some of the targets couldn't be captured, or the scan was stopped
+    /// with Ctrl-C before they were all tried
+    Failures,
+    /// The scan couldn't be started, e.g. because of a bad setting
+    Fatal,
+}
+
+impl Outcome {
+    /// Exit code for the outcome. Failed targets only give a non-zero
+    /// code with `--fail-on-error`
+    pub fn exit_code(self, fail_on_error: bool) -> i32 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::Failures if fail_on_error => 2,
+            Outcome::Failures => 0,
+            Outcome::Fatal => 1,
+        }
+    }
+}
+
+/// Capture the targets given in `opts`, saving the images and reports
+/// to the output directory. Problems that stop the scan from starting
+/// are logged, or printed if logging isn't set up yet, and give
+/// `Outcome::Fatal`
+pub fn run(opts: Opts) -> Outcome {
+    let opts = Arc::new(opts);
+    reset_state();
+
+    if opts.capabilities {
+        match serde_json::to_string_pretty(&capabilities::capabilities()) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Unable to serialise capabilities: {}", e);
+                return Outcome::Fatal;
+            }
+        }
+        return Outcome::Success;
+    }
+
+    // Keep stdout to just the listing for --list-targets, or the events
+    // for --emit-events
+    if !opts.list_targets && !opts.emit_events {
+        println!("Starting NCC Group Scrying...");
+    }
+    events::set_enabled(opts.emit_events);
+
+    // Configure logging
+    let mut log_dests: Vec<Box<dyn SharedLogger>> = Vec::new();
+
+    if let Some(log_file) = &opts.log_file {
+        // Enable logging to a file at INFO level by default
+        // Increasing global log verbosity increases log file verbosity
+        // accordingly. Combinations such as --silent -vv make sense
+        // when using a log file as the file will get TRACE messages
+        // while the terminal only gets WARN and higher.
+        let level_filter = match opts.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        let file = match File::create(log_file) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Unable to create log file {}: {}", log_file, e);
+                return Outcome::Fatal;
+            }
+        };
+        if opts.log_json {
+            log_dests.push(JsonLogger::new(level_filter, file));
+        } else {
+            log_dests.push(CorrelatedLogger::new(WriteLogger::new(
+                level_filter,
+                ConfigBuilder::new().build(),
+                file,
+            )));
+        }
+    }
+
+    let level_filter = if !opts.silent && !opts.list_targets {
+        match opts.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    } else {
+        LevelFilter::Warn
+    };
+
+    // Events are written to stdout, so keep the logs out of the way
+    let terminal_mode = if opts.emit_events {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
+    log_dests.push(CorrelatedLogger::new(TermLogger::new(
+        level_filter,
+        ConfigBuilder::new().build(),
+        terminal_mode,
+    )));
+
+    // Only one logger can be installed in a process, so a later run
+    // keeps logging wherever the first one did
+    if let Err(e) = CombinedLogger::init(log_dests) {
+        warn!("Logging has already been set up, not changing it: {}", e);
+    }
+
+    debug!("Got opts:\n{:?}", opts);
+
+    throttle::set_rate(opts.rate);
+    throttle::set_jitter(opts.jitter);
+    interrupt::install();
+
+    // Hostnames are resolved as the targets are parsed
+    if opts.resolver.is_some() || opts.hosts_file.is_some() {
+        match Resolver::new(
+            opts.hosts_file.as_deref(),
+            opts.resolver,
+            opts.resolver_proxy.as_deref(),
+        ) {
+            Ok(resolver) => resolve::configure(resolver),
+            Err(e) => {
+                error!("{}", e);
+                return Outcome::Fatal;
+            }
+        }
+    }
+
+    // Load in the target lists, parsed from arguments, files, and nmap
+    let mut targets = generate_target_lists(&opts);
+    if let Some(max) = opts.max_targets {
+        let count = targets.count();
+        if count > max && opts.sample {
+            info!("Sampling {} of {} targets", max, count);
+            targets.sample(max, opts.seed);
+        } else if count > max {
+            error!(
+                "There are {} targets, more than --max-targets {}. Use \
+                --sample to capture a random selection of them",
+                count, max
+            );
+            return Outcome::Fatal;
+        }
+    }
+    if opts.list_targets {
+        for line in targets.listing(&opts) {
+            println!("{}", line);
+        }
+        return Outcome::Success;
+    }
+    if opts.test_import {
+        println!("{}", targets);
+        info!("--test-import was supplied, exiting");
+        return Outcome::Success;
+    }
+    targets.probe_unknown(opts.threads);
+    for (file, clashing) in targets.filename_collisions(&opts) {
+        warn!(
+            "{} is the image file for each of {}, so only one of their \
+            images will be kept. Include {{target}} in the filename \
+            template to tell them apart",
+            file,
+            clashing.join(", ")
+        );
+    }
+    if opts.shuffle {
+        targets.shuffle(opts.seed);
+    }
+    let targets = Arc::new(targets);
+    if !opts.emit_events {
+        println!("{}", targets);
+    }
+
+    if let Some(url) = &opts.output_url {
+        info!("Uploading images to {}", url.as_str());
+        match HttpSink::new(
+            url.clone(),
+            opts.output_method,
+            opts.output_headers.clone(),
+        ) {
+            Ok(http) => sink::configure(Box::new(http)),
+            Err(e) => {
+                error!("Unable to upload to {}: {}", url.as_str(), e);
+                return Outcome::Fatal;
+            }
+        }
+    }
+
+    // Create output directories if they do not exist. Reports are still
+    // written to the output directory when images are uploaded
+    let output_base = Path::new(&opts.output_dir);
+    let local = !sink::is_remote();
+    let output_dirs = [
+        ("rdp", targets.rdp_targets.is_empty()),
+        ("web", targets.web_targets.is_empty()),
+        ("vnc", targets.vnc_targets.is_empty()),
+    ];
+    for (mode, empty) in &output_dirs {
+        let dir = output_base.join(mode);
+        if local && !empty && !dir.is_dir() {
+            if let Err(e) = create_dir_all(&dir) {
+                error!("Error creating directory {}: {}", dir.display(), e);
+                return Outcome::Fatal;
+            }
+        }
+    }
+
+    let failed = match opts.watch {
+        Some(interval) => watch(&opts, &targets, Duration::from_secs(interval)),
+        None => {
+            let failed = capture_pass(opts.clone(), targets);
+            // Ctrl-C finishes a --watch, but leaves a single pass with
+            // targets that were never tried
+            if interrupt::stopping() {
+                failed.max(1)
+            } else {
+                failed
+            }
+        }
+    };
+
+    if opts.latest_symlink {
+        if let Err(e) = util::update_latest_link(output_base) {
+            warn!("Failed to update latest link: {}", e);
+        }
+    }
+
+    if failed > 0 {
+        Outcome::Failures
+    } else {
+        Outcome::Success
+    }
+}
+
+/// Clear the state that the modules keep for the whole process, so that
+/// nothing carries over from an earlier run, e.g. when embedded in
+/// another program
+fn reset_state() {
+    interrupt::reset();
+    resolve::reset();
+    sink::reset();
+    parsing::clear_metadata();
+    dedupe::reset();
+}
+
+/// Capture every target once, then write the reports. Returns the number
+/// of targets that couldn't be captured
+fn capture_pass(opts: Arc<Opts>, targets: Arc<InputLists>) -> usize {
+    // Start report collating thread
+    let (report_tx, report_rx): (
+        mpsc::Sender<ReportMessage>,
+        mpsc::Receiver<_>,
+    ) = mpsc::channel();
+    let opts_clone = opts.clone();
+    let targets_clone = targets.clone();
+    let reporting_handle = thread::spawn(move || {
+        debug!("Starting report thread");
+        reporting::reporting_thread(report_rx, opts_clone, targets_clone)
+    });
+
+    // Shared between all of the workers so that the per-host and total
+    // limits apply across the different target types. Each type has its
+    // own pool of workers, so unless --threads-per-mode is given the
+    // total number of captures is also limited here
+    let total_limit = if opts.threads_per_mode {
+        None
+    } else {
+        Some(opts.threads)
+    };
+    let limiter = Arc::new(HostLimiter::new(opts.max_per_host, total_limit));
+    let total = targets.rdp_targets.len()
+        + targets.web_targets.len()
+        + targets.vnc_targets.len()
+        + targets.winrm_targets.len();
+    let progress = Arc::new(Progress::new(total, !opts.silent));
+
+    // Spawn threads to iterate over the targets
+    let rdp_handle = if !targets.rdp_targets.is_empty() {
+        let targets_clone = targets.clone();
+        let opts_clone = opts.clone();
+        let report_tx_clone = report_tx.clone();
+        let limiter_clone = limiter.clone();
+        let progress_clone = progress.clone();
+        Some(thread::spawn(move || {
+            debug!("Starting RDP worker threads");
+            let result = rdp_worker(
+                targets_clone,
+                opts_clone,
+                report_tx_clone,
+                limiter_clone,
+                progress_clone,
+            );
+            worker_finished("RDP", result)
+        }))
+    } else {
+        None
+    };
+
+    let web_handle = if !targets.web_targets.is_empty() {
+        let targets_clone = targets.clone();
+        let opts_clone = opts.clone();
+        let report_tx_clone = report_tx.clone();
+        let limiter_clone = limiter.clone();
+        let progress_clone = progress.clone();
+        Some(thread::spawn(move || {
+            debug!("Starting Web worker threads");
+            let result = web_worker(
+                targets_clone,
+                opts_clone,
+                report_tx_clone,
+                limiter_clone,
+                progress_clone,
+            );
+            worker_finished("Web", result)
+        }))
+    } else {
+        None
+    };
+
+    let vnc_handle = if !targets.vnc_targets.is_empty() {
+        let targets_clone = targets.clone();
+        let opts_clone = opts.clone();
+        let report_tx_clone = report_tx.clone();
+        let limiter_clone = limiter.clone();
+        let progress_clone = progress.clone();
+        Some(thread::spawn(move || {
+            debug!("Starting VNC worker threads");
+            let result = vnc_worker(
+                targets_clone,
+                opts_clone,
+                report_tx_clone,
+                limiter_clone,
+                progress_clone,
+            );
+            worker_finished("VNC", result)
+        }))
+    } else {
+        None
+    };
+
+    let winrm_handle = if !targets.winrm_targets.is_empty() {
+        let targets_clone = targets.clone();
+        let opts_clone = opts.clone();
+        let report_tx_clone = report_tx.clone();
+        let limiter_clone = limiter.clone();
+        let progress_clone = progress.clone();
+        Some(thread::spawn(move || {
+            debug!("Starting WinRM worker thread");
+            winrm_worker(
+                targets_clone,
+                opts_clone,
+                report_tx_clone,
+                limiter_clone,
+                progress_clone,
+            );
+            true
+        }))
+    } else {
+        None
+    };
+
+    // wait for the workers to complete. A worker that stopped early left
+    // some of its targets untried, so the pass counts as having failures
+    let mut failed_workers = 0;
+    for h in [rdp_handle, web_handle, vnc_handle, winrm_handle]
+        .iter_mut()
+        .filter_map(Option::take)
+    {
+        if !h.join().unwrap_or(false) {
+            failed_workers += 1;
+        }
+    }
+    progress.finish();
+    // Only fails if the reporting thread has already stopped, which the
+    // join below shows
+    let _ = report_tx.send(ReportMessage::GenerateReport);
+    match reporting_handle.join() {
+        Ok(Ok(failed)) => failed + failed_workers,
+        Ok(Err(e)) => {
+            error!("Failed to write the reports: {}", e);
+            failed_workers.max(1)
+        }
+        Err(_) => {
+            error!("The reporting thread stopped unexpectedly");
+            failed_workers.max(1)
+        }
+    }
+}
+
+/// Log why a pool of workers stopped early. Returns whether it finished
+fn worker_finished(
+    mode: &str,
+    result: Result<(), Box<dyn std::error::Error>>,
+) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{} captures stopped early: {}", mode, e);
+            false
+        }
+    }
+}
+
+/// With --watch, capture the targets every `interval` until Ctrl-C is
+/// pressed. Each pass names its images with the time it started. Returns
+/// the number of failed captures across all of the passes
+fn watch(opts: &Opts, targets: &Arc<InputLists>, interval: Duration) -> usize {
+    let mut pass = 1;
+    let mut failed = 0;
+    loop {
+        let start = Instant::now();
+        info!("Starting capture pass {}", pass);
+        let pass_opts = pass_opts(opts, SystemTime::now());
+        failed += capture_pass(Arc::new(pass_opts), targets.clone());
+
+        let wait = interval.checked_sub(start.elapsed()).unwrap_or_default();
+        if wait == Duration::from_secs(0) {
+            warn!(
+                "Capture pass {} took longer than the {}s --watch interval",
+                pass,
+                interval.as_secs()
+            );
+        }
+        if interrupt::stopping() || interrupt::wait(wait) {
+            break;
+        }
+        pass += 1;
+    }
+    info!("Stopped watching after {} capture passes", pass);
+    failed
+}
+
+/// Options for a --watch pass starting at `start`, with the time in the
+/// image names so that each pass keeps its own images
+fn pass_opts(opts: &Opts, start: SystemTime) -> Opts {
+    let template = match &opts.filename_template {
+        Some(template) => template.timestamped(start),
+        None => FilenameTemplate::parse("{target}-{timestamp}", start)
+            .expect("Default --watch filename template is invalid"),
+    };
+    Opts {
+        filename_template: Some(template),
+        ..opts.clone()
+    }
+}
+
+/// After Ctrl-C, wait for up to the grace period for the `running`
+/// captures to finish. Returns the number that are still running
+fn wait_for_running(
+    status_rx: &mpsc::Receiver<ThreadStatus>,
+    mut running: usize,
+    progress: &Progress,
+) -> usize {
+    let deadline = Instant::now() + interrupt::GRACE_PERIOD;
+    while running > 0 {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        match status_rx.recv_timeout(wait) {
+            Ok(ThreadStatus::Complete) => {
+                running -= 1;
+                progress.tick();
+            }
+            Err(_) => break,
+        }
+    }
+    running
+}
+
+/// With --resume, skip targets whose image was saved by an earlier run
+fn already_captured(opts: &Opts, mode: &str, target: &Target) -> bool {
+    let exists = opts.resume && util::image_exists(opts, mode, target);
+    if exists {
+        debug!("Skipping {}, image already exists", target);
+    }
+    exists
+}
+
+fn rdp_worker(
+    targets: Arc<InputLists>,
+    opts: Arc<Opts>,
+    report_tx: mpsc::Sender<ReportMessage>,
+    limiter: Arc<HostLimiter>,
+    progress: Arc<Progress>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use mpsc::{Receiver, Sender};
+    let max_workers = opts.threads;
+    let mut num_workers: usize = 0;
+    let mut targets_iter = targets.rdp_targets.iter().filter(|t| {
+        // Skipped targets count as done for the progress bar
+        let skip = already_captured(&opts, "rdp", t);
+        if skip {
+            progress.tick();
+        }
+        !skip
+    });
+    let mut workers: Vec<_> = Vec::new();
+    let (thread_status_tx, thread_status_rx): (
+        Sender<ThreadStatus>,
+        Receiver<ThreadStatus>,
+    ) = mpsc::channel();
+    loop {
+        // check for status messages
+        // Turn off clippy's single_match warning here because match
+        // matches the intuition for how try_recv is processed better
+        // than an if let.
+        #[allow(clippy::single_match)]
+        match thread_status_rx.try_recv() {
+            Ok(ThreadStatus::Complete) => {
+                info!("Thread complete, yay");
+                num_workers -= 1;
+                progress.tick();
+            }
+            Err(_) => {}
+        }
+        if interrupt::stopping() {
+            info!("Not starting any more captures");
+            break;
+        }
+        if num_workers < max_workers {
+            if let Some(target) = targets_iter.next() {
+                let target = target.clone();
+                let id = logging::next_correlation_id();
+                info!("Adding worker for {:?} as {}", target, id);
+                let opts_clone = opts.clone();
+                let tx = thread_status_tx.clone();
+                let report_tx_clone = report_tx.clone();
+                let limiter_clone = limiter.clone();
+                // Only the initial batch of workers is staggered, later
+                // ones are spread out by waiting for a free worker
+                let delay = if workers.len() < max_workers {
+                    throttle::stagger_delay(opts.stagger)
+                } else {
+                    Duration::from_millis(0)
+                };
+                let handle = thread::spawn(move || {
+                    logging::set_correlation_id(Some(id));
+                    logging::set_target("rdp", &target);
+                    events::emit(Event::Dispatched, "rdp", &target);
+                    thread::sleep(delay);
+                    let permit = limiter_clone.acquire(&target);
+                    let timeout =
+                        opts_clone.target_timeout.map(Duration::from_secs);
+                    let capture_target = target.clone();
+                    watchdog::run(
+                        timeout,
+                        "rdp",
+                        &target,
+                        permit,
+                        tx,
+                        &report_tx_clone,
+                        move |tx, report_tx| {
+                            rdp::capture(
+                                &capture_target,
+                                &opts_clone,
+                                tx,
+                                report_tx,
+                            )
+                        },
+                    )
+                });
+
+                workers.push(handle);
+                num_workers += 1;
+            } else {
+                break;
+            }
+        }
+    }
+    debug!("At the join part");
+    if interrupt::stopping() {
+        let running =
+            wait_for_running(&thread_status_rx, num_workers, &progress);
+        if running > 0 {
+            warn!("Leaving {} RDP captures that are still running", running);
+            return Ok(());
+        }
+    }
+    let mut panicked = 0;
+    for w in workers {
+        debug!("Joining {:?}", w);
+        if w.join().is_err() {
+            panicked += 1;
+        }
+    }
+    // The last batch of workers finished after the loop stopped checking
+    for _ in thread_status_rx.try_iter() {
+        progress.tick();
+    }
+
+    if panicked > 0 {
+        return Err(format!("{} RDP captures panicked", panicked).into());
+    }
+    Ok(())
+}
+
+fn web_worker(
+    targets: Arc<InputLists>,
+    opts: Arc<Opts>,
+    report_tx: mpsc::Sender<ReportMessage>,
+    limiter: Arc<HostLimiter>,
+    progress: Arc<Progress>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let browser = web::launch_browser(&opts)
+        .map_err(|e| format!("Unable to start Chrome: {}", e))?;
+    let tab = browser
+        .wait_for_initial_tab()
+        .map_err(|e| format!("Unable to open a Chrome tab: {}", e))?;
+
+    // Probing needs a direct connection to the target
+    let scheme_probe = opts.scheme_probe && opts.web_proxy.is_none();
+    if opts.scheme_probe && !scheme_probe {
+        info!("Not probing web schemes because a proxy is in use");
+    }
+    // Bare targets are parsed as both HTTP and HTTPS, so once probed
+    // they resolve to the same URL
+    let mut captured: HashSet<String> = HashSet::new();
+    // On the default ports they are tried as HTTPS first, and only as
+    // HTTP if that fails
+    let fallbacks = if opts.scheme_fallback {
+        web::scheme_fallbacks(&targets)
+    } else {
+        HashMap::new()
+    };
+    let fallback_targets: HashSet<String> =
+        fallbacks.values().map(|t| t.to_string()).collect();
+
+    for target in &targets.web_targets {
+        if interrupt::stopping() {
+            break;
+        }
+        if fallback_targets.contains(&target.to_string()) {
+            debug!("{} will be tried if HTTPS fails", target);
+            progress.tick();
+            continue;
+        }
+        logging::set_correlation_id(Some(logging::next_correlation_id()));
+        logging::set_target("web", target);
+        // URLs given with a scheme are captured as they are
+        let (target, scheme_probed) =
+            if scheme_probe && targets.is_bare_web_target(target) {
+                web::choose_scheme(target)
+            } else {
+                (target.clone(), false)
+            };
+        logging::set_target("web", &target);
+        events::emit(Event::Dispatched, "web", &target);
+        if scheme_probed && !captured.insert(target.to_string()) {
+            debug!("Already captured {}", target);
+            progress.tick();
+            continue;
+        }
+        let fallback = fallbacks.get(&target.to_string());
+        if already_captured(&opts, "web", &target)
+            || fallback.is_some_and(|t| already_captured(&opts, "web", t))
+        {
+            progress.tick();
+            continue;
+        }
+        let _permit = limiter.acquire(&target);
+        let start = Instant::now();
+        // Trying the other scheme is reported in the same way as finding
+        // it by probing, so that both targets are accounted for
+        let scheme_probed = scheme_probed || fallback.is_some();
+        let mut result =
+            web::capture(&target, scheme_probed, &opts, &tab, &report_tx);
+        let target = match (&result, fallback) {
+            (Err(e), Some(http))
+                if !matches!(
+                    e,
+                    Error::IoError(_)
+                        | Error::ConnectionRefused(_)
+                        | Error::ConnectionTimedOut(_)
+                ) =>
+            {
+                info!("Unable to capture {}, trying {}: {}", target, http, e);
+                if opts.only_successful {
+                    util::remove_image(&opts, "web", &target);
+                }
+                logging::set_target("web", http);
+                result = web::capture(http, true, &opts, &tab, &report_tx);
+                http.clone()
+            }
+            _ => target,
+        };
+        if let Err(e) = result {
+            if opts.only_successful {
+                util::remove_image(&opts, "web", &target);
+            }
+            let failure =
+                FailureOutput::new(&target, "web", &e, start.elapsed());
+            report_tx.send(failure.as_report_message())?;
+            match e {
+                Error::ConnectionRefused(e) | Error::ConnectionTimedOut(e) => {
+                    warn!("Failed to capture {}: {}", target, e);
+                }
+                // Writing the output would fail for every other target too
+                Error::IoError(e) => {
+                    error!("IO error: {}", e);
+                    break;
+                }
+                Error::ChromeError(e) => {
+                    warn!("Failed to capture image: {}", e);
+                }
+                Error::BlankImage => {
+                    info!("Not saving {}, the page is blank", target);
+                }
+                // Decoding the screenshot to check or hash it can fail
+                e => warn!("Failed to process image: {}", e),
+            }
+        }
+        progress.tick();
+    }
+    Ok(())
+}
+
+fn vnc_worker(
+    targets: Arc<InputLists>,
+    opts: Arc<Opts>,
+    report_tx: mpsc::Sender<ReportMessage>,
+    limiter: Arc<HostLimiter>,
+    progress: Arc<Progress>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use mpsc::{Receiver, Sender};
+    let max_workers = opts.threads;
+    let mut num_workers: usize = 0;
+    let mut targets_iter = targets.vnc_targets.iter().filter(|t| {
+        // Skipped targets count as done for the progress bar
+        let skip = already_captured(&opts, "vnc", t);
+        if skip {
+            progress.tick();
+        }
+        !skip
+    });
+    let mut workers: Vec<_> = Vec::new();
+    let (thread_status_tx, thread_status_rx): (
+        Sender<ThreadStatus>,
+        Receiver<ThreadStatus>,
+    ) = mpsc::channel();
+    loop {
+        // check for status messages
+        // Turn off clippy's single_match warning here because match
+        // matches the intuition for how try_recv is processed better
+        // than an if let.
+        #[allow(clippy::single_match)]
+        match thread_status_rx.try_recv() {
+            Ok(ThreadStatus::Complete) => {
+                info!("Thread complete, yay");
+                num_workers -= 1;
+                progress.tick();
+            }
+            Err(_) => {}
+        }
+        if interrupt::stopping() {
+            info!("Not starting any more captures");
+            break;
+        }
+        if num_workers < max_workers {
+            if let Some(target) = targets_iter.next() {
+                let target = target.clone();
+                let id = logging::next_correlation_id();
+                info!("Adding VNC worker for {:?} as {}", target, id);
+                let opts_clone = opts.clone();
+                let tx = thread_status_tx.clone();
+                let report_tx_clone = report_tx.clone();
+                let limiter_clone = limiter.clone();
+                // Only the initial batch of workers is staggered, later
+                // ones are spread out by waiting for a free worker
+                let delay = if workers.len() < max_workers {
+                    throttle::stagger_delay(opts.stagger)
+                } else {
+                    Duration::from_millis(0)
+                };
+                let handle = thread::spawn(move || {
+                    logging::set_correlation_id(Some(id));
+                    logging::set_target("vnc", &target);
+                    events::emit(Event::Dispatched, "vnc", &target);
+                    thread::sleep(delay);
+                    let permit = limiter_clone.acquire(&target);
+                    let timeout =
+                        opts_clone.target_timeout.map(Duration::from_secs);
+                    let capture_target = target.clone();
+                    watchdog::run(
+                        timeout,
+                        "vnc",
+                        &target,
+                        permit,
+                        tx,
+                        &report_tx_clone,
+                        move |tx, report_tx| {
+                            vnc::capture(
+                                &capture_target,
+                                &opts_clone,
+                                tx,
+                                report_tx,
+                            )
+                        },
+                    )
+                });
+
+                workers.push(handle);
+                num_workers += 1;
+            } else {
+                break;
+            }
+        }
+    }
+    debug!("At the join part");
+    if interrupt::stopping() {
+        let running =
+            wait_for_running(&thread_status_rx, num_workers, &progress);
+        if running > 0 {
+            warn!("Leaving {} VNC captures that are still running", running);
+            return Ok(());
+        }
+    }
+    let mut panicked = 0;
+    for w in workers {
+        debug!("Joining {:?}", w);
+        if w.join().is_err() {
+            panicked += 1;
+        }
+    }
+    // The last batch of workers finished after the loop stopped checking
+    for _ in thread_status_rx.try_iter() {
+        progress.tick();
+    }
+
+    if panicked > 0 {
+        return Err(format!("{} VNC captures panicked", panicked).into());
+    }
+    Ok(())
+}
+
+fn winrm_worker(
+    targets: Arc<InputLists>,
+    opts: Arc<Opts>,
+    report_tx: mpsc::Sender<ReportMessage>,
+    limiter: Arc<HostLimiter>,
+    progress: Arc<Progress>,
+) {
+    // Each worker takes the next target until they have all been probed
+    let next = AtomicUsize::new(0);
+    let probe_targets = || loop {
+        if interrupt::stopping() {
+            break;
+        }
+        let target = match targets
+            .winrm_targets
+            .get(next.fetch_add(1, Ordering::SeqCst))
+        {
+            Some(target) => target,
+            None => break,
+        };
+        logging::set_correlation_id(Some(logging::next_correlation_id()));
+        logging::set_target("winrm", target);
+        events::emit(Event::Dispatched, "winrm", target);
+        let _permit = limiter.acquire(target);
+        let start = Instant::now();
+        if let Err(e) = winrm::capture(target, &opts, &report_tx) {
+            warn!("Failed to probe {}: {}", target, e);
+            let failure =
+                FailureOutput::new(target, "winrm", &e, start.elapsed());
+            if report_tx.send(failure.as_report_message()).is_err() {
+                break;
+            }
+        }
+        progress.tick();
+    };
+    let workers = opts.threads.clamp(1, targets.winrm_targets.len().max(1));
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(probe_targets);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(Outcome::Success.exit_code(true), 0);
+        assert_eq!(Outcome::Failures.exit_code(false), 0);
+        assert_eq!(Outcome::Failures.exit_code(true), 2);
+        assert_eq!(Outcome::Fatal.exit_code(false), 1);
+    }
+}
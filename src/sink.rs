@@ -0,0 +1,464 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Where captured images are written. They go in the output directory
+//! unless --output-url is given, in which case they are uploaded over
+//! HTTP instead and the report links to them there
+
+use crate::argparse::{Opts, OutputMethod};
+use crate::error::Error;
+use crate::resolve;
+use crate::throttle;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use native_tls::TlsConnector;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times to try uploading a file when the failure may be
+/// temporary, e.g. a dropped connection or a 503 response
+const UPLOAD_ATTEMPTS: usize = 3;
+
+/// Upper limit on how much of the response to read, only the status line
+/// is needed
+const MAX_RESPONSE_SIZE: u64 = 16 * 1024;
+
+static SINK: RwLock<Option<Arc<dyn Sink>>> = RwLock::new(None);
+
+/// Files written into the output directory during this run, so that
+/// those from earlier runs are never removed
+static WRITTEN: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Somewhere that captured files can be written to
+pub trait Sink: Send + Sync {
+    /// Where the file at `path`, relative to the output, can be found
+    /// once it has been written. This is what the report links to
+    fn location(&self, path: &Path) -> String;
+
+    /// Write the file at `path`, relative to the output
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), Error>;
+}
+
+/// Files saved under the output directory, the default
+pub struct LocalSink {
+    output_dir: PathBuf,
+}
+
+impl LocalSink {
+    pub fn new(output_dir: &str) -> Self {
+        Self {
+            output_dir: PathBuf::from(output_dir),
+        }
+    }
+}
+
+impl Sink for LocalSink {
+    fn location(&self, path: &Path) -> String {
+        path.display().to_string()
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let path = self.output_dir.join(path);
+        fs::write(&path, data)?;
+        WRITTEN.lock().unwrap().insert(path);
+        Ok(())
+    }
+}
+
+/// Files uploaded to --output-url. Each one is sent to the URL with its
+/// path appended, e.g. https://bucket.example/scan/vnc/192.0.2.1-5900.png
+pub struct HttpSink {
+    url: Url,
+    method: OutputMethod,
+    /// Extra headers from --output-header, e.g. for authentication
+    headers: Vec<String>,
+    /// Used for every upload to an https:// URL
+    tls: Option<TlsConnector>,
+}
+
+impl HttpSink {
+    pub fn new(
+        url: Url,
+        method: OutputMethod,
+        headers: Vec<String>,
+    ) -> Result<Self, Error> {
+        let tls = if url.scheme() == "https" {
+            let connector = TlsConnector::new()
+                .map_err(|e| Error::OutputError(format!("TLS error: {}", e)))?;
+            Some(connector)
+        } else {
+            None
+        };
+        Ok(Self {
+            url,
+            method,
+            headers,
+            tls,
+        })
+    }
+
+    /// The URL path that the file is uploaded to
+    fn request_path(&self, path: &Path) -> String {
+        format!(
+            "{}/{}",
+            self.url.path().trim_end_matches('/'),
+            encode_path(path)
+        )
+    }
+
+    /// The request for uploading the file, up to the start of the body
+    fn request_head(&self, path: &Path, len: usize) -> String {
+        let method = match self.method {
+            OutputMethod::Put => "PUT",
+            OutputMethod::Post => "POST",
+        };
+        let host = self.url.host_str().unwrap_or_default();
+        let host = match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\n\
+            Content-Length: {}\r\nX-Scrying-Path: {}\r\n",
+            method,
+            self.request_path(path),
+            host,
+            content_type(path),
+            len,
+            encode_path(path),
+        );
+        for header in &self.headers {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head
+    }
+
+    fn send<S: Read + Write>(
+        &self,
+        mut stream: S,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<(), Failed> {
+        stream.write_all(self.request_head(path, data.len()).as_bytes())?;
+        stream.write_all(data)?;
+        stream.flush()?;
+
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE_SIZE).read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match response_status(status) {
+            Some(200..=299) => Ok(()),
+            code => {
+                let error = Error::OutputError(format!(
+                    "{} was rejected by {}: {:?}",
+                    path.display(),
+                    self.url.as_str(),
+                    status
+                ));
+                // Overloaded or restarting servers are worth trying
+                // again, but any other refusal will just happen again
+                match code {
+                    Some(429) | Some(500..=599) => {
+                        Err(Failed::Transient(error))
+                    }
+                    _ => Err(Failed::Permanent(error)),
+                }
+            }
+        }
+    }
+
+    /// Make one attempt at uploading the file
+    fn upload(&self, path: &Path, data: &[u8]) -> Result<(), Failed> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let addr = resolve::resolve(
+            host.trim_start_matches('[').trim_end_matches(']'),
+            port,
+        )?;
+        let stream = TcpStream::connect_timeout(&addr, UPLOAD_TIMEOUT)?;
+        stream.set_read_timeout(Some(UPLOAD_TIMEOUT))?;
+        stream.set_write_timeout(Some(UPLOAD_TIMEOUT))?;
+
+        match &self.tls {
+            Some(connector) => {
+                let domain = host.trim_start_matches('[').trim_end_matches(']');
+                // Most handshake failures are certificates that aren't
+                // trusted, which won't change by trying again
+                let tls = connector.connect(domain, stream).map_err(|e| {
+                    Failed::Permanent(Error::OutputError(format!(
+                        "TLS handshake with {} failed: {}",
+                        host, e
+                    )))
+                })?;
+                self.send(tls, path, data)
+            }
+            None => self.send(stream, path, data),
+        }
+    }
+}
+
+/// Why an upload failed, and whether it is worth trying again
+#[derive(Debug)]
+enum Failed {
+    /// Connection problems and server errors, which may go away
+    Transient(Error),
+    /// Anything else, such as the upload being forbidden
+    Permanent(Error),
+}
+
+impl From<std::io::Error> for Failed {
+    fn from(e: std::io::Error) -> Self {
+        Self::Transient(e.into())
+    }
+}
+
+impl Sink for HttpSink {
+    fn location(&self, path: &Path) -> String {
+        format!(
+            "{}/{}",
+            self.url.as_str().trim_end_matches('/'),
+            encode_path(path)
+        )
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            match self.upload(path, data) {
+                Ok(()) => return Ok(()),
+                Err(Failed::Transient(e)) if attempt < UPLOAD_ATTEMPTS => {
+                    let delay = throttle::backoff_delay(attempt);
+                    warn!(
+                        "Upload of {} failed, retrying in {:?}: {}",
+                        path.display(),
+                        delay,
+                        e
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(Failed::Transient(e)) | Err(Failed::Permanent(e)) => {
+                    return Err(e)
+                }
+            }
+        }
+    }
+}
+
+/// Use the sink for the rest of the run
+pub fn configure(sink: Box<dyn Sink>) {
+    *SINK.write().unwrap() = Some(Arc::from(sink));
+}
+
+/// Go back to writing into the output directory, ready for another run
+/// in the same process
+pub fn reset() {
+    *SINK.write().unwrap() = None;
+    WRITTEN.lock().unwrap().clear();
+}
+
+/// Whether the file at `path`, including the output directory, was
+/// written into the output directory during this run
+pub fn written_this_run(path: &Path) -> bool {
+    WRITTEN.lock().unwrap().contains(path)
+}
+
+fn configured() -> Option<Arc<dyn Sink>> {
+    SINK.read().unwrap().clone()
+}
+
+/// Whether captures are being written somewhere other than the output
+/// directory
+pub fn is_remote() -> bool {
+    configured().is_some()
+}
+
+/// Where the file at `path`, relative to the output, will be written
+pub fn location(opts: &Opts, path: &Path) -> String {
+    match configured() {
+        Some(sink) => sink.location(path),
+        None => LocalSink::new(&opts.output_dir).location(path),
+    }
+}
+
+/// Write the file at `path`, relative to the output, with the configured
+/// sink or into the output directory if there isn't one
+pub fn write(opts: &Opts, path: &Path, data: &[u8]) -> Result<(), Error> {
+    match configured() {
+        Some(sink) => sink.write(path, data),
+        None => LocalSink::new(&opts.output_dir).write(path, data),
+    }
+}
+
+/// Join the parts of a relative path with /, percent-encoding anything
+/// that might not be allowed in a URL
+fn encode_path(path: &Path) -> String {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            let mut encoded = String::new();
+            for b in part.to_string_lossy().bytes() {
+                match b {
+                    b'A'..=b'Z'
+                    | b'a'..=b'z'
+                    | b'0'..=b'9'
+                    | b'-'
+                    | b'.'
+                    | b'_'
+                    | b'~' => encoded.push(b as char),
+                    _ => encoded.push_str(&format!("%{:02X}", b)),
+                }
+            }
+            parts.push(encoded);
+        }
+    }
+    parts.join("/")
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => "image/png",
+        Some("jpg") => "image/jpeg",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The status code from an HTTP status line, e.g. 200 from HTTP/1.1 200 OK
+fn response_status(line: &str) -> Option<u16> {
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => {
+            code.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A stream that replies with `response` to whatever is written
+    struct MockServer {
+        request: Vec<u8>,
+        response: Cursor<Vec<u8>>,
+    }
+
+    impl Read for &mut MockServer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for &mut MockServer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.request.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn mock(response: &str) -> MockServer {
+        MockServer {
+            request: Vec::new(),
+            response: Cursor::new(response.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn http_uploads() {
+        let sink = HttpSink::new(
+            Url::parse("https://bucket.example:8443/scan/").unwrap(),
+            OutputMethod::Put,
+            vec!["Authorization: Bearer abc".to_string()],
+        )
+        .unwrap();
+        let path = Path::new("web").join("http_example.com 1.png");
+        assert_eq!(
+            sink.location(&path),
+            "https://bucket.example:8443/scan/web/http_example.com%201.png"
+        );
+
+        let mut server = mock("HTTP/1.1 201 Created\r\n\r\n");
+        sink.send(&mut server, &path, b"image").unwrap();
+        let request = String::from_utf8(server.request).unwrap();
+        assert!(request.starts_with(
+            "PUT /scan/web/http_example.com%201.png HTTP/1.1\r\n\
+            Host: bucket.example:8443\r\nContent-Type: image/png\r\n\
+            Content-Length: 5\r\n"
+        ));
+        assert!(request.contains("\r\nAuthorization: Bearer abc\r\n"));
+        assert!(request.ends_with("\r\n\r\nimage"));
+
+        let mut server = mock("HTTP/1.1 403 Forbidden\r\n\r\n");
+        assert!(matches!(
+            sink.send(&mut server, &path, b"image"),
+            Err(Failed::Permanent(_))
+        ));
+        let mut server = mock("HTTP/1.1 503 Service Unavailable\r\n\r\n");
+        assert!(matches!(
+            sink.send(&mut server, &path, b"image"),
+            Err(Failed::Transient(_))
+        ));
+        let mut server = mock("");
+        assert!(sink.send(&mut server, &path, b"image").is_err());
+    }
+
+    #[test]
+    fn webhook_uploads() {
+        let sink = HttpSink::new(
+            Url::parse("http://192.0.2.1/hook").unwrap(),
+            OutputMethod::Post,
+            Vec::new(),
+        )
+        .unwrap();
+        let path = Path::new("vnc").join("192.0.2.5-5900.raw.json");
+        let head = sink.request_head(&path, 2);
+        assert!(head.starts_with(
+            "POST /hook/vnc/192.0.2.5-5900.raw.json HTTP/1.1\r\n\
+            Host: 192.0.2.1\r\nContent-Type: application/json\r\n"
+        ));
+        assert!(
+            head.contains("\r\nX-Scrying-Path: vnc/192.0.2.5-5900.raw.json")
+        );
+    }
+
+    #[test]
+    fn status_lines() {
+        assert_eq!(response_status("HTTP/1.1 204 No Content"), Some(204));
+        assert_eq!(response_status("HTTP/1.0 500"), Some(500));
+        assert_eq!(response_status("SSH-2.0-OpenSSH_8.4"), None);
+        assert_eq!(response_status(""), None);
+    }
+}
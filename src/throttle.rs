@@ -0,0 +1,400 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::parsing::Target;
+use crate::resolve;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Shared by all of the capture paths so that --rate applies to the total
+/// number of new connections
+static RATE_LIMITER: RateLimiter = RateLimiter::new();
+
+/// First and longest delays between retries of connections that time out
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Limits the number of simultaneous connections to each host, and
+/// optionally in total, across all of the workers. Hosts are identified
+/// by their resolved address so that e.g. https://example.com and
+/// rdp://example.com share a limit.
+#[derive(Debug, Default)]
+pub struct HostLimiter {
+    limit: Option<usize>,
+    total_limit: Option<usize>,
+    active: Mutex<ActiveConnections>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ActiveConnections {
+    hosts: HashMap<String, usize>,
+    total: usize,
+}
+
+/// Permit to connect to a host. The slot is released when this is dropped
+pub struct HostPermit {
+    limiter: Arc<HostLimiter>,
+    host: Option<String>,
+    counted: bool,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        if self.host.is_none() && !self.counted {
+            return;
+        }
+        let mut active = self.limiter.active.lock().unwrap();
+        if let Some(host) = &self.host {
+            if let Some(count) = active.hosts.get_mut(host) {
+                *count -= 1;
+                if *count == 0 {
+                    active.hosts.remove(host);
+                }
+            }
+        }
+        if self.counted {
+            active.total -= 1;
+        }
+        self.limiter.released.notify_all();
+    }
+}
+
+impl HostLimiter {
+    /// Create a limiter allowing `limit` simultaneous connections to each
+    /// host and `total_limit` simultaneous connections overall. Either
+    /// may be None for no limit
+    pub fn new(limit: Option<usize>, total_limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            total_limit,
+            ..Default::default()
+        }
+    }
+
+    /// Block until a connection to the target's host is allowed
+    pub fn acquire(self: &Arc<Self>, target: &Target) -> HostPermit {
+        if self.limit.is_none() && self.total_limit.is_none() {
+            return HostPermit {
+                limiter: Arc::clone(self),
+                host: None,
+                counted: false,
+            };
+        }
+
+        let host = self.limit.map(|_| host_key(target));
+        let mut active = self.active.lock().unwrap();
+        loop {
+            let host_full = match (&host, self.limit) {
+                (Some(host), Some(limit)) => {
+                    active.hosts.get(host).copied().unwrap_or(0) >= limit
+                }
+                _ => false,
+            };
+            let total_full = match self.total_limit {
+                Some(limit) => active.total >= limit,
+                None => false,
+            };
+            if !host_full && !total_full {
+                break;
+            }
+            if host_full {
+                debug!("Waiting for a free connection slot for {:?}", host);
+            } else {
+                debug!("Waiting for a free connection slot");
+            }
+            active = self.released.wait(active).unwrap();
+        }
+        if let Some(host) = &host {
+            *active.hosts.entry(host.clone()).or_insert(0) += 1;
+        }
+        active.total += 1;
+
+        HostPermit {
+            limiter: Arc::clone(self),
+            host,
+            counted: true,
+        }
+    }
+}
+
+/// Token bucket limiting how often new connections are opened. The bucket
+/// holds a single token so that connections are evenly spaced rather than
+/// sent in bursts, which is gentler on fragile devices. With --jitter, a
+/// random delay is added to each interval so that the spacing doesn't
+/// form a recognisable pattern
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Time between connections in microseconds, or 0 for no limit
+    interval_us: AtomicU64,
+    /// Most extra time between connections in milliseconds
+    jitter_ms: AtomicU64,
+    /// When the next token becomes available
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    const fn new() -> Self {
+        Self {
+            interval_us: AtomicU64::new(0),
+            jitter_ms: AtomicU64::new(0),
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Allow `per_second` connections per second, or any number if 0
+    fn set_rate(&self, per_second: u32) {
+        let interval = if per_second == 0 {
+            0
+        } else {
+            1_000_000 / u64::from(per_second)
+        };
+        self.interval_us.store(interval, Ordering::SeqCst);
+    }
+
+    /// Add up to `jitter_ms` milliseconds between connections
+    fn set_jitter(&self, jitter_ms: u64) {
+        self.jitter_ms.store(jitter_ms, Ordering::SeqCst);
+    }
+
+    /// Block until the next token is available
+    fn wait(&self) {
+        let interval = self.interval_us.load(Ordering::SeqCst);
+        let jitter = self.jitter_ms.load(Ordering::SeqCst);
+        if interval == 0 && jitter == 0 {
+            return;
+        }
+        let interval = Duration::from_micros(interval) + stagger_delay(jitter);
+
+        let delay = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let slot = match *next {
+                Some(n) if n > now => n,
+                _ => now,
+            };
+            *next = Some(slot + interval);
+            slot - now
+        };
+        if delay > Duration::from_millis(0) {
+            trace!("Waiting {:?} before connecting", delay);
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Set the rate limit shared by all of the capture paths
+pub fn set_rate(per_second: u32) {
+    RATE_LIMITER.set_rate(per_second);
+}
+
+/// Set the most random delay to add between connections
+pub fn set_jitter(jitter_ms: u64) {
+    RATE_LIMITER.set_jitter(jitter_ms);
+}
+
+/// Block until the rate limit allows a new connection. This should be
+/// called before every connection attempt, including retries
+pub fn wait_for_rate() {
+    RATE_LIMITER.wait();
+}
+
+/// Delay before the `retry`th retry of a connection that timed out,
+/// starting at one second and doubling each time up to a minute
+pub fn backoff_delay(retry: usize) -> Duration {
+    let exponent = retry.saturating_sub(1).min(6) as u32;
+    (BACKOFF_BASE * 2u32.pow(exponent)).min(BACKOFF_MAX)
+}
+
+/// Random delay of up to `stagger_ms` milliseconds, used to spread out
+/// the start times of the initial workers so that they don't all connect
+/// at the same moment, and for --jitter
+pub fn stagger_delay(stagger_ms: u64) -> Duration {
+    if stagger_ms == 0 {
+        return Duration::from_millis(0);
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0..stagger_ms))
+}
+
+/// Identify the host of a target by its IP address. A URL's hostname is
+/// only looked up in the addresses that targets have already been
+/// resolved to, so that taking a permit never waits for DNS, and the
+/// hostname itself is used if it hasn't been resolved
+fn host_key(target: &Target) -> String {
+    match target {
+        Target::Address(addr) => addr.ip().to_string(),
+        Target::Url(u) => {
+            let host = u
+                .host_str()
+                .unwrap_or_default()
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return ip.to_string();
+            }
+            match resolve::cached_address(host) {
+                Some(ip) => ip.to_string(),
+                None => host.to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use url::Url;
+
+    #[test]
+    fn host_keys() {
+        let rdp = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let web =
+            Target::Url(Url::parse("https://192.0.2.1:8443/path").unwrap());
+        let web6 = Target::Url(Url::parse("http://[2001:db8::1]/").unwrap());
+
+        assert_eq!(host_key(&rdp), "192.0.2.1");
+        assert_eq!(host_key(&web), "192.0.2.1");
+        assert_eq!(host_key(&web6), "2001:db8::1");
+
+        // Hostnames share a key with the address they resolved to
+        resolve::add_host("limited.example", "192.0.2.7".parse().unwrap());
+        let named =
+            Target::Url(Url::parse("https://limited.example/").unwrap());
+        let unresolved =
+            Target::Url(Url::parse("https://unresolved.invalid/").unwrap());
+        assert_eq!(host_key(&named), "192.0.2.7");
+        assert_eq!(host_key(&unresolved), "unresolved.invalid");
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let delays: Vec<u64> =
+            (1..=8).map(|r| backoff_delay(r).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff_delay(usize::MAX), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn stagger_within_window() {
+        assert_eq!(stagger_delay(0), Duration::from_millis(0));
+        for _ in 0..100 {
+            assert!(stagger_delay(50) < Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn limit_per_host() {
+        let limiter = Arc::new(HostLimiter::new(Some(1), None));
+        let target = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let other = Target::Address("192.0.2.2:3389".parse().unwrap());
+
+        let permit = limiter.acquire(&target);
+        // A different host is not affected
+        drop(limiter.acquire(&other));
+
+        let (tx, rx) = mpsc::channel();
+        let limiter_clone = limiter.clone();
+        let target_clone = target.clone();
+        let handle = thread::spawn(move || {
+            let _permit = limiter_clone.acquire(&target_clone);
+            tx.send(()).unwrap();
+        });
+
+        // The second connection to the same host must wait
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(permit);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn limit_in_total() {
+        let limiter = Arc::new(HostLimiter::new(None, Some(2)));
+        let first = Target::Address("192.0.2.1:3389".parse().unwrap());
+        let second = Target::Address("192.0.2.2:5900".parse().unwrap());
+        let third = Target::Address("192.0.2.3:3389".parse().unwrap());
+
+        let permit = limiter.acquire(&first);
+        let _second_permit = limiter.acquire(&second);
+
+        let (tx, rx) = mpsc::channel();
+        let limiter_clone = limiter.clone();
+        let handle = thread::spawn(move || {
+            let _permit = limiter_clone.acquire(&third);
+            tx.send(()).unwrap();
+        });
+
+        // A third connection must wait even though it is to another host
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(permit);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn rate_limit() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        limiter.set_rate(20);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.wait();
+        }
+        // The first token is available immediately
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn jitter() {
+        let limiter = RateLimiter::new();
+        limiter.set_jitter(40);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.wait();
+        }
+        // Four gaps of less than 40ms each
+        assert!(start.elapsed() < Duration::from_millis(160 + 100));
+        assert!(limiter.next.lock().unwrap().is_some());
+
+        limiter.set_rate(20);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.wait();
+        }
+        // The jitter is on top of the rate limit, and the first token is
+        // the one left over from before
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,565 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Details of the TLS connections that scrying makes itself, for
+//! VeNCrypt and the WinRM probes: the server's certificate and the JA3
+//! and JA3S fingerprints of the handshake. native-tls doesn't give the
+//! handshake messages, so the stream is wrapped in a `Recorder` before
+//! it is given to native-tls, and the hellos are read from what it
+//! recorded.
+
+use crate::digest;
+use native_tls::TlsStream;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+/// Most that is recorded of each direction of a connection. The hellos
+/// are the first handshake messages, so this leaves plenty of room
+const MAX_RECORDED: usize = 32 * 1024;
+
+const CONTENT_HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+
+/// The server's certificate and fingerprints of the handshake
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TlsDetails {
+    /// Subject of the server's certificate, e.g. "O=Example, CN=host"
+    pub subject: Option<String>,
+    /// SHA-256 of the server's DER encoded certificate
+    pub sha256: Option<String>,
+    /// JA3 fingerprint of the ClientHello that scrying sent
+    pub ja3: Option<String>,
+    /// JA3S fingerprint of the server's ServerHello
+    pub ja3s: Option<String>,
+}
+
+impl TlsDetails {
+    /// The fingerprints that were found, for the HTML report
+    pub fn fingerprints(&self) -> String {
+        let labelled = [
+            ("certificate SHA-256", &self.sha256),
+            ("JA3", &self.ja3),
+            ("JA3S", &self.ja3s),
+        ];
+        labelled
+            .iter()
+            .filter_map(|(label, value)| {
+                value.as_ref().map(|v| format!("{} {}", label, v))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Stream that keeps a copy of the data sent and received until the
+/// details are taken from it
+pub struct Recorder<S> {
+    stream: S,
+    sent: Vec<u8>,
+    received: Vec<u8>,
+    recording: bool,
+}
+
+impl<S> Recorder<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            sent: Vec::new(),
+            received: Vec::new(),
+            recording: true,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+fn record(log: &mut Vec<u8>, data: &[u8]) {
+    let room = MAX_RECORDED.saturating_sub(log.len());
+    log.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+impl<S: Read> Read for Recorder<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        if self.recording {
+            record(&mut self.received, &buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Recorder<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        if self.recording {
+            record(&mut self.sent, &buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Stop recording the connection, now that the handshake is complete,
+/// and work out the details of the handshake and the certificate
+pub fn details<S: Read + Write>(
+    stream: &mut TlsStream<Recorder<S>>,
+) -> TlsDetails {
+    let certificate = match stream.peer_certificate() {
+        Ok(Some(certificate)) => certificate.to_der().ok(),
+        _ => None,
+    };
+    let recorder = stream.get_mut();
+    recorder.recording = false;
+    let sent = std::mem::take(&mut recorder.sent);
+    let received = std::mem::take(&mut recorder.received);
+    TlsDetails {
+        subject: certificate.as_deref().and_then(certificate_subject),
+        sha256: certificate.as_deref().map(digest::sha256_hex),
+        ja3: handshake_message(&sent, CLIENT_HELLO)
+            .and_then(|hello| ja3_string(&hello))
+            .map(|ja3| digest::md5_hex(ja3.as_bytes())),
+        ja3s: handshake_message(&received, SERVER_HELLO)
+            .and_then(|hello| ja3s_string(&hello))
+            .map(|ja3s| digest::md5_hex(ja3s.as_bytes())),
+    }
+}
+
+/// Reads the big endian fields of a handshake message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// A vector preceded by its length in `len_bytes` bytes
+    fn vector(&mut self, len_bytes: usize) -> Option<&'a [u8]> {
+        let len = self
+            .take(len_bytes)?
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | usize::from(b));
+        self.take(len)
+    }
+
+    /// The type and contents of each extension, which are optional at
+    /// the end of both hellos
+    fn extensions(&mut self) -> Option<Vec<(u16, &'a [u8])>> {
+        let mut extensions = Vec::new();
+        if self.0.is_empty() {
+            return Some(extensions);
+        }
+        let mut reader = Reader(self.vector(2)?);
+        while !reader.0.is_empty() {
+            extensions.push((reader.u16()?, reader.vector(2)?));
+        }
+        Some(extensions)
+    }
+}
+
+/// The body of the first handshake message in the recorded TLS records,
+/// if it has the given type. Messages can be split across records, so
+/// the records' contents are joined first
+fn handshake_message(records: &[u8], msg_type: u8) -> Option<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut rest = records;
+    while let [CONTENT_HANDSHAKE, _, _, high, low, body @ ..] = rest {
+        let len = usize::from(u16::from_be_bytes([*high, *low]));
+        let len = len.min(body.len());
+        messages.extend_from_slice(&body[..len]);
+        rest = &body[len..];
+    }
+    let mut reader = Reader(&messages);
+    if reader.take(1)? != [msg_type] {
+        return None;
+    }
+    reader.vector(3).map(<[u8]>::to_vec)
+}
+
+/// GREASE values are random reserved values that clients send so that
+/// servers don't choke on unknown ones, and are left out of fingerprints
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Values separated by dashes, as they are in JA3 fingerprints
+fn dashed<I, T>(values: I) -> String
+where
+    I: IntoIterator<Item = T>,
+    T: ToString,
+{
+    values
+        .into_iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn u16_list(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .filter(|v| !is_grease(*v))
+        .collect()
+}
+
+/// The fields of a ClientHello that are hashed for its JA3 fingerprint:
+/// the version, cipher suites, extensions, supported groups and point
+/// formats
+fn ja3_string(hello: &[u8]) -> Option<String> {
+    let mut reader = Reader(hello);
+    let version = reader.u16()?;
+    reader.take(32)?;
+    reader.vector(1)?;
+    let ciphers = u16_list(reader.vector(2)?);
+    reader.vector(1)?;
+    let extensions = reader.extensions()?;
+
+    let mut groups = Vec::new();
+    let mut formats: &[u8] = &[];
+    for (ext_type, data) in &extensions {
+        match *ext_type {
+            EXT_SUPPORTED_GROUPS => groups = u16_list(Reader(data).vector(2)?),
+            EXT_EC_POINT_FORMATS => formats = Reader(data).vector(1)?,
+            _ => {}
+        }
+    }
+    let types = extensions
+        .iter()
+        .map(|(ext_type, _)| *ext_type)
+        .filter(|t| !is_grease(*t));
+    Some(format!(
+        "{},{},{},{},{}",
+        version,
+        dashed(ciphers),
+        dashed(types),
+        dashed(groups),
+        dashed(formats.iter())
+    ))
+}
+
+/// The fields of a ServerHello that are hashed for its JA3S fingerprint:
+/// the version, cipher suite and extensions
+fn ja3s_string(hello: &[u8]) -> Option<String> {
+    let mut reader = Reader(hello);
+    let version = reader.u16()?;
+    reader.take(32)?;
+    reader.vector(1)?;
+    let cipher = reader.u16()?;
+    reader.take(1)?;
+    let extensions = reader.extensions()?;
+    let types = extensions.iter().map(|(ext_type, _)| *ext_type);
+    Some(format!("{},{},{}", version, cipher, dashed(types)))
+}
+
+/// Read a DER tag and length, returning the tag, the contents, and the
+/// rest of the input
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first & 0x80 == 0 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let (len, after) = rest.split_at(octets);
+        rest = after;
+        len.iter().fold(0, |acc, &b| (acc << 8) | b as usize)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// The common name, organisation, and organisational unit from the
+/// subject of a DER encoded certificate, e.g. "CN=host, O=Example"
+fn certificate_subject(der: &[u8]) -> Option<String> {
+    const SEQUENCE: u8 = 0x30;
+    const SET: u8 = 0x31;
+    // 2.5.4.x, the X.520 attribute types
+    const ATTRIBUTES: [(&[u8], &str); 3] = [
+        (&[0x55, 0x04, 0x03], "CN"),
+        (&[0x55, 0x04, 0x0a], "O"),
+        (&[0x55, 0x04, 0x0b], "OU"),
+    ];
+
+    let (_, certificate, _) =
+        der_element(der).filter(|(tag, _, _)| *tag == SEQUENCE)?;
+    let (_, mut tbs, _) =
+        der_element(certificate).filter(|(tag, _, _)| *tag == SEQUENCE)?;
+    // The version is optional, then the serial number, signature
+    // algorithm, issuer, and validity come before the subject
+    let (tag, _, rest) = der_element(tbs)?;
+    tbs = if tag == 0xa0 {
+        der_element(rest)?.2
+    } else {
+        rest
+    };
+    for _ in 0..3 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, mut names, _) =
+        der_element(tbs).filter(|(tag, _, _)| *tag == SEQUENCE)?;
+
+    let mut parts = Vec::new();
+    while !names.is_empty() {
+        let (tag, mut set, rest) = der_element(names)?;
+        names = rest;
+        if tag != SET {
+            return None;
+        }
+        while !set.is_empty() {
+            let (_, attribute, rest) = der_element(set)?;
+            set = rest;
+            let (_, oid, value) = der_element(attribute)?;
+            let (_, value, _) = der_element(value)?;
+            if let Some((_, label)) =
+                ATTRIBUTES.iter().find(|(known, _)| *known == oid)
+            {
+                let value = String::from_utf8_lossy(value);
+                parts.push(format!("{}={}", label, value));
+            }
+        }
+    }
+    Some(parts.join(", ")).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Wrap a handshake message in TLS records of at most `split` bytes
+    fn records(msg_type: u8, body: &[u8], split: usize) -> Vec<u8> {
+        let mut message = vec![msg_type];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(body);
+        message
+            .chunks(split)
+            .flat_map(|chunk| {
+                let mut record = vec![CONTENT_HANDSHAKE, 0x03, 0x03];
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect()
+    }
+
+    fn vector(len_bytes: usize, data: &[u8]) -> Vec<u8> {
+        let len = (data.len() as u32).to_be_bytes();
+        [&len[4 - len_bytes..], data].concat()
+    }
+
+    fn extensions(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let data: Vec<u8> = extensions
+            .iter()
+            .flat_map(|(ext_type, data)| {
+                [&ext_type.to_be_bytes()[..], &vector(2, data)].concat()
+            })
+            .collect();
+        vector(2, &data)
+    }
+
+    #[test]
+    fn client_hello_fingerprint() {
+        let hello = [
+            &[0x03, 0x03][..],
+            &[0x5c; 32],
+            &vector(1, &[]),
+            &vector(2, &[0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2b]),
+            &vector(1, &[0x00]),
+            &extensions(&[
+                (0x1a1a, vec![]),
+                (0x0000, vector(2, &[0, 0, 1, b'x'])),
+                (
+                    EXT_SUPPORTED_GROUPS,
+                    vector(2, &[0x2a, 0x2a, 0x00, 0x1d, 0x00, 0x17]),
+                ),
+                (EXT_EC_POINT_FORMATS, vector(1, &[0x00])),
+                (0x0010, vector(2, &vector(1, b"h2"))),
+            ]),
+        ]
+        .concat();
+        // Split across records, as large ClientHellos are
+        let sent = records(CLIENT_HELLO, &hello, 50);
+        let message = handshake_message(&sent, CLIENT_HELLO).unwrap();
+        assert_eq!(message, hello);
+        assert_eq!(
+            ja3_string(&message).as_deref(),
+            Some("771,4865-49195,0-10-11-16,29-23,0")
+        );
+        assert_eq!(handshake_message(&sent, SERVER_HELLO), None);
+
+        // Extensions are optional
+        let bare = [&hello[..35], &vector(2, &[0x00, 0x2f]), &[1, 0]].concat();
+        assert_eq!(ja3_string(&bare).as_deref(), Some("771,47,,,"));
+    }
+
+    #[test]
+    fn server_hello_fingerprint() {
+        let hello = [
+            &[0x03, 0x03][..],
+            &[0x11; 32],
+            &vector(1, &[0x22; 32]),
+            &[0x13, 0x01, 0x00],
+            &extensions(&[(0x002b, vec![0x03, 0x04]), (0x0033, vec![0; 36])]),
+        ]
+        .concat();
+        // A TLS 1.3 server follows the ServerHello with ChangeCipherSpec
+        // and encrypted records, which are ignored
+        let received = [
+            records(SERVER_HELLO, &hello, 1024),
+            vec![20, 0x03, 0x03, 0x00, 0x01, 0x01],
+            vec![23, 0x03, 0x03, 0x00, 0x02, 0xaa, 0xbb],
+        ]
+        .concat();
+        let message = handshake_message(&received, SERVER_HELLO).unwrap();
+        assert_eq!(ja3s_string(&message).as_deref(), Some("771,4865,43-51"));
+
+        // An alert instead of a ServerHello
+        assert_eq!(
+            handshake_message(&[21, 0x03, 0x03, 0x00, 0x02, 2, 40], 2),
+            None
+        );
+        assert_eq!(ja3s_string(&message[..40]), None);
+    }
+
+    #[test]
+    fn fingerprint_summary() {
+        let details = TlsDetails {
+            subject: Some("CN=host".to_string()),
+            sha256: Some("0a1b".to_string()),
+            ja3: None,
+            ja3s: Some("f4fe".to_string()),
+        };
+        assert_eq!(
+            details.fingerprints(),
+            "certificate SHA-256 0a1b, JA3S f4fe"
+        );
+        assert_eq!(TlsDetails::default().fingerprints(), "");
+    }
+
+    #[test]
+    fn grease_values() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+
+    #[test]
+    fn recorder_limit() {
+        let mut recorder = Recorder::new(Cursor::new(vec![7; 10]));
+        recorder.write_all(&[1, 2, 3]).unwrap();
+        let mut buf = [0; 4];
+        recorder.read_exact(&mut buf).unwrap();
+        // The cursor is shared, so the read follows the write
+        assert_eq!(recorder.sent, vec![1, 2, 3]);
+        assert_eq!(recorder.received, vec![7; 4]);
+
+        recorder.recording = false;
+        recorder.write_all(&[4]).unwrap();
+        assert_eq!(recorder.sent, vec![1, 2, 3]);
+
+        let mut recorder = Recorder::new(Cursor::new(Vec::new()));
+        recorder.write_all(&vec![0; MAX_RECORDED + 10]).unwrap();
+        assert_eq!(recorder.sent.len(), MAX_RECORDED);
+    }
+
+    /// DER encode an element, using the long form length when needed
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        if contents.len() < 0x80 {
+            out.push(contents.len() as u8);
+        } else {
+            out.extend(&[0x82, (contents.len() >> 8) as u8]);
+            out.push(contents.len() as u8);
+        }
+        out.extend(contents);
+        out
+    }
+
+    fn name(attributes: &[(&[u8], &str)]) -> Vec<u8> {
+        let sets: Vec<u8> = attributes
+            .iter()
+            .flat_map(|(oid, value)| {
+                let attribute =
+                    [der(0x06, oid), der(0x0c, value.as_bytes())].concat();
+                der(0x31, &der(0x30, &attribute))
+            })
+            .collect();
+        der(0x30, &sets)
+    }
+
+    #[test]
+    fn certificate_subjects() {
+        let common_name: &[u8] = &[0x55, 0x04, 0x03];
+        let organisation: &[u8] = &[0x55, 0x04, 0x0a];
+        let country: &[u8] = &[0x55, 0x04, 0x06];
+        let subject = name(&[
+            (country, "GB"),
+            (organisation, "Example"),
+            (common_name, "WIN-3J2K1.corp.example"),
+        ]);
+        let tbs = |version: bool| {
+            let mut tbs = Vec::new();
+            if version {
+                tbs.extend(der(0xa0, &der(0x02, &[2])));
+            }
+            tbs.extend(der(0x02, &[0x12, 0x34]));
+            tbs.extend(der(0x30, &der(0x06, &[0x2a, 0x86, 0x48])));
+            tbs.extend(name(&[(common_name, "Example CA")]));
+            tbs.extend(der(0x30, &[0x17, 0x00, 0x17, 0x00]));
+            tbs.extend(&subject);
+            // The public key, which is long enough for a long form length
+            tbs.extend(der(0x30, &[0; 300]));
+            der(0x30, &tbs)
+        };
+        for version in [true, false].iter() {
+            let certificate =
+                der(0x30, &[tbs(*version), der(0x03, &[0])].concat());
+            assert_eq!(
+                certificate_subject(&certificate).as_deref(),
+                Some("O=Example, CN=WIN-3J2K1.corp.example")
+            );
+        }
+
+        assert_eq!(certificate_subject(&[]), None);
+        assert_eq!(certificate_subject(&[0x30, 0x05, 0x30]), None);
+    }
+}
@@ -17,27 +17,144 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::argparse::Opts;
+use crate::argparse::{Opts, VncAuthMethod};
+use crate::dedupe;
 use crate::error::Error;
+use crate::events::{self, Event};
+use crate::logging;
 use crate::parsing::Target;
-use crate::reporting::{AsReportMessage, ReportMessage};
-use crate::util::target_to_filename;
+use crate::reporting::{AsReportMessage, FailureOutput, ReportMessage};
+use crate::resolve;
+use crate::sink;
+use crate::throttle;
+use crate::tls::TlsDetails;
+use crate::util;
 use crate::ThreadStatus;
-use image::{DynamicImage, ImageBuffer, Rgb};
+use image::{DynamicImage, Rgb, RgbImage};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::net::TcpStream;
-use std::path::Path;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 use vnc::client::{AuthChoice, AuthMethod, Client};
 use vnc::Colour;
 use vnc::{PixelFormat, Rect};
 
+mod relay;
+#[cfg(test)]
+mod test_server;
+mod unix;
+mod vencrypt;
+
+/// Base delay between attempts when the server disconnects before the
+/// frame is complete. This is multiplied by the attempt number
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Most characters of clipboard text to keep for --vnc-clipboard
+const CLIPBOARD_LIMIT: usize = 1000;
+
+/// 32 bit true colour, requested from servers unless --vnc-native-format
+/// is given. Many servers default to 16 bit colour, which loses detail
+const PREFERRED_FORMAT: PixelFormat = PixelFormat {
+    bits_per_pixel: 32,
+    depth: 24,
+    big_endian: false,
+    true_colour: true,
+    red_max: 255,
+    green_max: 255,
+    blue_max: 255,
+    red_shift: 16,
+    green_shift: 8,
+    blue_shift: 0,
+};
+
+/// Encodings offered to servers, in order of preference. The vnc crate
+/// can't decode anything else, e.g. Hextile or Tight
+const ENCODINGS: &[vnc::Encoding] = &[
+    vnc::Encoding::Zrle,
+    vnc::Encoding::CopyRect,
+    vnc::Encoding::Raw,
+    vnc::Encoding::Cursor,
+    vnc::Encoding::DesktopSize,
+];
+
+/// How the VNC session was authenticated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VncAuth {
+    NoPassword,
+    /// The password given with --vnc-password
+    Password,
+    /// The line number in the password list of the accepted password
+    PasswordList(usize),
+}
+
 #[derive(Debug)]
 pub struct VncOutput {
     target: String,
     file: String,
+    duplicate_of: Option<String>,
+    /// Name the server gave for the desktop, which often includes the
+    /// hostname or OS
+    desktop_name: String,
+    auth: VncAuth,
+    coverage: Option<f64>,
+    attempts: usize,
+    duration: Duration,
+    correlation_id: String,
+    /// Clipboard text sent by the server, for --vnc-clipboard
+    clipboard: Option<String>,
+    /// The server's certificate and handshake, if it uses VeNCrypt
+    tls: Option<TlsDetails>,
+}
+
+impl VncOutput {
+    /// The line number in the password list of the password that was
+    /// accepted, or None if the password list wasn't used. The password
+    /// itself is deliberately not recorded in the report
+    pub fn password_entry(&self) -> Option<usize> {
+        match self.auth {
+            VncAuth::PasswordList(entry) => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// Whether the password given with --vnc-password or --creds-file
+    /// was used
+    pub fn supplied_password(&self) -> bool {
+        self.auth == VncAuth::Password
+    }
+
+    /// Number of connections made to capture the image
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    /// Whether the server let us in without a password
+    pub fn no_password(&self) -> bool {
+        self.auth == VncAuth::NoPassword
+    }
+
+    /// If only part of the framebuffer was received, the percentage of
+    /// it that was
+    pub fn partial_coverage(&self) -> Option<String> {
+        self.coverage.map(|c| format!("{:.1}%", c))
+    }
+
+    /// Clipboard text that the server sent during the capture, if
+    /// --vnc-clipboard was given
+    pub fn clipboard(&self) -> Option<&str> {
+        self.clipboard.as_deref()
+    }
 }
 
 impl AsReportMessage for VncOutput {
@@ -50,20 +167,158 @@ impl AsReportMessage for VncOutput {
     fn file(&self) -> &str {
         &self.file
     }
+    fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+    fn set_target(&mut self, target: String) {
+        self.target = target;
+    }
+    fn duplicate_of(&self) -> Option<&str> {
+        self.duplicate_of.as_deref()
+    }
+    fn set_duplicate_of(&mut self, target: String) {
+        self.duplicate_of = Some(target);
+    }
+    fn banner(&self) -> Option<&str> {
+        Some(self.desktop_name.as_str()).filter(|n| !n.is_empty())
+    }
+    fn clear_banner(&mut self) {
+        self.desktop_name.clear();
+        // The clipboard and certificate are just as likely to name the
+        // host
+        self.clipboard = None;
+        if let Some(tls) = &mut self.tls {
+            tls.subject = None;
+        }
+    }
+    fn tls(&self) -> Option<&TlsDetails> {
+        self.tls.as_ref()
+    }
 }
 
 //TODO code reuse with RDP?
 struct Image {
-    image: DynamicImage,
+    image: RgbImage,
     format: PixelFormat,
-    colour_map: Option<ColourMap>,
-    _width: u16,
-    _height: u16,
+    /// Colour map for servers that aren't using true colour, populated
+    /// by SetColourMap events
+    palette: Option<Vec<(u8, u8, u8)>>,
+    width: u16,
+    height: u16,
+    /// Which pixels have been received from the server
+    covered: Vec<bool>,
+    covered_count: usize,
+    /// Pointer shape from the Cursor pseudo-encoding
+    cursor: Option<Cursor>,
+    /// The framebuffer in the negotiated pixel format for --save-raw
+    raw: Option<Vec<u8>>,
+    /// The last clipboard text sent by the server
+    clipboard: Option<String>,
+    /// Where the image is in the server's framebuffer for --vnc-region.
+    /// Otherwise the image is the whole framebuffer
+    region: Option<Rect>,
+}
+
+/// A framebuffer as received from the server, before it was converted to
+/// RGB, and the description of its pixels to write alongside it
+pub(crate) struct RawFrame {
+    data: Vec<u8>,
+    metadata: RawMetadata,
 }
 
-enum ColourFormat {
-    U8((u8, u8, u8)),
-    U16((u16, u16, u16)),
+/// The sidecar JSON for --save-raw
+#[derive(Debug, PartialEq, Serialize)]
+struct RawMetadata {
+    width: u16,
+    height: u16,
+    pixel_format: RawPixelFormat,
+    /// The colour map for 8 bit servers, scaled to 8 bits per channel
+    palette: Option<Vec<(u8, u8, u8)>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct RawPixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_colour: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl From<&PixelFormat> for RawPixelFormat {
+    fn from(format: &PixelFormat) -> Self {
+        Self {
+            bits_per_pixel: format.bits_per_pixel,
+            depth: format.depth,
+            big_endian: format.big_endian,
+            true_colour: format.true_colour,
+            red_max: format.red_max,
+            green_max: format.green_max,
+            blue_max: format.blue_max,
+            red_shift: format.red_shift,
+            green_shift: format.green_shift,
+            blue_shift: format.blue_shift,
+        }
+    }
+}
+
+impl RawFrame {
+    /// Write the framebuffer and its description next to the image at
+    /// `path`, relative to the output
+    fn save(&self, path: &Path, opts: &Opts) -> Result<(), Error> {
+        let raw_path = util::raw_path(path);
+        info!(
+            "Saving raw framebuffer as {}",
+            sink::location(opts, &raw_path)
+        );
+        sink::write(opts, &raw_path, &self.data)?;
+        let metadata = serde_json::to_string_pretty(&self.metadata)?;
+        sink::write(opts, &util::raw_metadata_path(path), metadata.as_bytes())
+    }
+}
+
+/// A pointer shape sent by the server, decoded to RGB. Pixels that are
+/// masked out are None
+#[derive(Debug)]
+struct Cursor {
+    width: u16,
+    hotspot: (u16, u16),
+    pixels: Vec<Option<Rgb<u8>>>,
+}
+
+/// How polling for a frame ended
+#[derive(Debug, PartialEq)]
+enum FrameStatus {
+    Complete,
+    Disconnected,
+    TimedOut,
+}
+
+/// How many frames to receive for --vnc-frames, and how long to wait
+/// for them after the first, before keeping the most complete
+#[derive(Clone, Copy, Debug)]
+struct FrameLimit {
+    frames: usize,
+    wait: Duration,
+}
+
+/// Number of pixels that aren't the most common colour, i.e. roughly how
+/// much has been drawn over the background
+fn frame_score(image: &RgbImage) -> usize {
+    let mut counts: HashMap<Rgb<u8>, usize> = HashMap::new();
+    for p in image.pixels() {
+        *counts.entry(*p).or_insert(0) += 1;
+    }
+    let background = counts.values().copied().max().unwrap_or_default();
+    image.pixels().count() - background
 }
 
 impl Image {
@@ -72,39 +327,73 @@ impl Image {
         width: u16,
         height: u16,
     ) -> Result<Self, Error> {
-        let image = match (format.depth, format.true_colour) {
-            (15, true) | (16, true) | (24, true) => {
-                DynamicImage::ImageRgb8(ImageBuffer::<Rgb<u8>, Vec<u8>>::new(
-                    width.into(),
-                    height.into(),
-                ))
-            }
-            (8, false) => DynamicImage::ImageRgb16(ImageBuffer::<
-                Rgb<u16>,
-                Vec<u16>,
-            >::new(
-                width.into(),
-                height.into(),
-            )),
+        match (format.depth, format.true_colour) {
+            (15, true) | (16, true) | (24, true) | (32, true) | (8, false) => {}
             (d, t) => {
                 return Err(Error::VncError(format!(
                     "Invalid colour depth: {}, true colour: {}",
                     d, t
                 )))
             }
-        };
+        }
 
         Ok(Self {
-            image,
+            image: RgbImage::new(width.into(), height.into()),
             format,
-            colour_map: None,
-            _width: width,
-            _height: height,
+            palette: None,
+            width,
+            height,
+            covered: vec![false; usize::from(width) * usize::from(height)],
+            covered_count: 0,
+            cursor: None,
+            raw: None,
+            clipboard: None,
+            region: None,
+        })
+    }
+
+    /// Only keep the given part of the framebuffer, for --vnc-region
+    fn with_region(mut self, region: Rect) -> Self {
+        self.region = Some(region);
+        self.resize(region.width, region.height);
+        self
+    }
+
+    /// The part of the framebuffer to request updates for
+    fn update_rect(&self) -> Rect {
+        self.region.unwrap_or(Rect {
+            left: 0,
+            top: 0,
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    /// Also keep the framebuffer in the negotiated pixel format, for
+    /// --save-raw
+    fn keep_raw(mut self) -> Result<Self, Error> {
+        let len = usize::from(self.width)
+            * usize::from(self.height)
+            * bytes_per_pixel(&self.format)?;
+        self.raw = Some(vec![0; len]);
+        Ok(self)
+    }
+
+    /// Take the raw framebuffer, if it was kept
+    fn take_raw(&mut self) -> Option<RawFrame> {
+        let data = self.raw.take()?;
+        Some(RawFrame {
+            data,
+            metadata: RawMetadata {
+                width: self.width,
+                height: self.height,
+                pixel_format: RawPixelFormat::from(&self.format),
+                palette: self.palette.clone(),
+            },
         })
     }
 
     fn put_pixels(&mut self, rect: Rect, pixels: &[u8]) -> Result<(), Error> {
-        use ColourFormat::*;
         trace!("pixels: {:?}", pixels);
         trace!("rect: {:?}", rect);
 
@@ -123,23 +412,98 @@ impl Image {
         // Borrow the pixel format from self before mutably borrowing
         // the image
         let format = &self.format;
-        let colour_map = &self.colour_map;
+        let palette = &self.palette;
 
         // Rect { left: 1216, top: 704, width: 64, height: 16 }
-        let bytes_per_pixel = match format.bits_per_pixel {
-            8 => 1,
-            16 => 2,
-            32 => 4,
-            _ => {
-                return Err(Error::VncError(format!(
-                    "Invalid bits per pixel: {}",
-                    format.bits_per_pixel
-                )))
+        let bytes_per_pixel = bytes_per_pixel(format)?;
+
+        // The server chooses the rect, so make sure that it fits in the
+        // framebuffer before drawing it. The sums are done as u32 as they
+        // could overflow a u16. Servers may send more than the region
+        // that was requested, which is cropped below instead
+        if self.region.is_none()
+            && (u32::from(rect.left) + u32::from(rect.width)
+                > u32::from(self.width)
+                || u32::from(rect.top) + u32::from(rect.height)
+                    > u32::from(self.height))
+        {
+            return Err(Error::VncError(format!(
+                "{}x{} rect at ({}, {}) is outside the {}x{} framebuffer",
+                rect.width,
+                rect.height,
+                rect.left,
+                rect.top,
+                self.width,
+                self.height
+            )));
+        }
+
+        // ZRLE sends 32 bit pixels as 3 byte CPIXELs when the colour
+        // fits in 3 bytes. Put the padding byte back so that the rest of
+        // the pixels are in the negotiated format
+        let pixel_count = usize::from(rect.width) * usize::from(rect.height);
+        let pixels: Cow<[u8]> = match cpixel_padding(format) {
+            Some(pad) if pixels.len() == pixel_count * 3 => {
+                trace!("Expanding 3 byte CPIXELs");
+                Cow::Owned(expand_cpixels(pixels, pad))
             }
+            _ => Cow::Borrowed(pixels),
+        };
+
+        // Make sure the server sent as many bytes as the rect implies,
+        // otherwise the slicing below would panic
+        let expected_len = pixel_count * bytes_per_pixel;
+        if pixels.len() != expected_len {
+            return Err(Error::VncError(format!(
+                "Pixel data length mismatch for {}x{} rect at ({}, {}): \
+                expected {} bytes, got {}",
+                rect.width,
+                rect.height,
+                rect.left,
+                rect.top,
+                expected_len,
+                pixels.len()
+            )));
+        }
+
+        // The part of the rect that is in the image, in image coordinates
+        let (origin_x, origin_y) = self
+            .region
+            .map_or((0, 0), |r| (usize::from(r.left), usize::from(r.top)));
+        let clip = |start: u16, len: u16, origin: usize, size: u16| {
+            let start = usize::from(start);
+            let end =
+                (start + usize::from(len)).min(origin + usize::from(size));
+            (start.max(origin) - origin)..end.saturating_sub(origin)
+        };
+        let xs = clip(rect.left, rect.width, origin_x, self.width);
+        let ys = clip(rect.top, rect.height, origin_y, self.height);
+        if xs.is_empty() || ys.is_empty() {
+            trace!("Rect is outside the region");
+            return Ok(());
+        }
+        // Offset of an image pixel in the rect's pixel data
+        let src_offset = |x: usize, y: usize| {
+            ((y + origin_y - usize::from(rect.top)) * usize::from(rect.width)
+                + (x + origin_x - usize::from(rect.left)))
+                * bytes_per_pixel
         };
-        let mut idx = 0_usize;
-        for y in rect.top..(rect.top + rect.height) {
-            for x in rect.left..(rect.left + rect.width) {
+
+        // Rows of the rect are contiguous in the raw framebuffer
+        let row_len = xs.len() * bytes_per_pixel;
+        if let Some(raw) = &mut self.raw {
+            for y in ys.clone() {
+                let src = src_offset(xs.start, y);
+                let start =
+                    (y * usize::from(self.width) + xs.start) * bytes_per_pixel;
+                raw[start..(start + row_len)]
+                    .copy_from_slice(&pixels[src..(src + row_len)]);
+            }
+        }
+
+        for y in ys {
+            for x in xs.clone() {
+                let idx = src_offset(x, y);
                 trace!(
                     "Position: {},{}: {:?}",
                     x,
@@ -147,44 +511,146 @@ impl Image {
                     &pixels[idx..(idx + bytes_per_pixel)]
                 );
 
-                match &mut self.image {
-                    DynamicImage::ImageRgb8(img) => {
-                        if let U8((r, g, b)) = Image::pixel_to_rgb(
-                            format,
-                            colour_map,
-                            &pixels[idx..(idx + bytes_per_pixel)],
-                        )? {
-                            img.put_pixel(x.into(), y.into(), Rgb([r, g, b]))
-                        } else {
-                            return Err(Error::VncError(
-                                "Colour format mismatch: expected 8-bit colours".to_string(),
-                            ));
-                        }
-                    }
-                    DynamicImage::ImageRgb16(img) => {
-                        if let U16((r, g, b)) = Image::pixel_to_rgb(
-                            format,
-                            colour_map,
-                            &pixels[idx..(idx + bytes_per_pixel)],
-                        )? {
-                            img.put_pixel(x.into(), y.into(), Rgb([r, g, b]))
-                        } else {
-                            return Err(Error::VncError(
-                                "Colour format mismatch: expected 16-bit colours".to_string(),
-                            ));
-                        }
-                    }
+                let (r, g, b) = Image::pixel_to_rgb(
+                    format,
+                    palette,
+                    &pixels[idx..(idx + bytes_per_pixel)],
+                )?;
+                self.image.put_pixel(x as u32, y as u32, Rgb([r, g, b]));
+
+                // Borrow the fields directly as format and palette are
+                // still borrowed from self
+                let pos = y * usize::from(self.width) + x;
+                if !self.covered[pos] {
+                    self.covered[pos] = true;
+                    self.covered_count += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy a rectangle of pixels from elsewhere in the framebuffer. The
+    /// source is copied out before writing so that overlapping regions,
+    /// e.g. when scrolling, aren't corrupted part way through. With
+    /// --vnc-region only the part of the destination inside the region is
+    /// written, and pixels copied from outside it are left black and
+    /// counted as not received, as nothing outside the region is kept
+    fn copy_pixels(&mut self, src: Rect, dst: Rect) -> Result<(), Error> {
+        // The framebuffer size is only known without a region
+        if self.region.is_none() {
+            for rect in &[src, dst] {
+                if u32::from(rect.left) + u32::from(src.width)
+                    > u32::from(self.width)
+                    || u32::from(rect.top) + u32::from(src.height)
+                        > u32::from(self.height)
+                {
+                    return Err(Error::VncError(format!(
+                        "CopyRect {}x{} at ({}, {}) is outside the \
+                        framebuffer",
+                        src.width, src.height, rect.left, rect.top
+                    )));
+                }
+            }
+        }
+
+        let (origin_x, origin_y) = self
+            .region
+            .map_or((0, 0), |r| (usize::from(r.left), usize::from(r.top)));
+        let (width, height) =
+            (usize::from(self.width), usize::from(self.height));
+        // Index in the image of a position in the framebuffer, if it is
+        // inside the image
+        let index = |left: u16, top: u16, x: usize, y: usize| {
+            let x = (usize::from(left) + x).checked_sub(origin_x)?;
+            let y = (usize::from(top) + y).checked_sub(origin_y)?;
+            Some(y * width + x).filter(|_| x < width && y < height)
+        };
+        let bytes_per_pixel = match self.raw {
+            Some(_) => bytes_per_pixel(&self.format)?,
+            None => 0,
+        };
 
-                    _ => unimplemented!(),
+        // Each destination in the image, with the source pixel and whether
+        // it was received if the source is in the image too. The raw
+        // source pixels are copied out in the same order
+        let mut copies = Vec::new();
+        let mut raw_pixels = Vec::new();
+        for y in 0..usize::from(src.height) {
+            for x in 0..usize::from(src.width) {
+                let to = match index(dst.left, dst.top, x, y) {
+                    Some(to) => to,
+                    None => continue,
+                };
+                let from = index(src.left, src.top, x, y);
+                let source = from.map(|from| {
+                    let (x, y) = ((from % width) as u32, (from / width) as u32);
+                    (*self.image.get_pixel(x, y), self.covered[from])
+                });
+                if let (Some(raw), Some(from)) = (&self.raw, from) {
+                    let start = from * bytes_per_pixel;
+                    raw_pixels.extend_from_slice(
+                        &raw[start..(start + bytes_per_pixel)],
+                    );
                 }
+                copies.push((to, source));
+            }
+        }
 
-                idx += bytes_per_pixel;
+        let mut raw_pixels = raw_pixels.chunks(bytes_per_pixel.max(1));
+        for (to, source) in copies {
+            let (pixel, covered) = source.unwrap_or((Rgb([0, 0, 0]), false));
+            let (x, y) = ((to % width) as u32, (to / width) as u32);
+            self.image.put_pixel(x, y, pixel);
+            if let Some(raw) = &mut self.raw {
+                let start = to * bytes_per_pixel;
+                let end = start + bytes_per_pixel;
+                match source.and_then(|_| raw_pixels.next()) {
+                    Some(pixel) => raw[start..end].copy_from_slice(pixel),
+                    None => raw[start..end].fill(0),
+                }
+            }
+            if covered != self.covered[to] {
+                self.covered[to] = covered;
+                if covered {
+                    self.covered_count += 1;
+                } else {
+                    self.covered_count -= 1;
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Reallocate the framebuffer after the server changes the desktop
+    /// size. Anything already received is discarded, as a full update is
+    /// requested for the new size. With --vnc-region the image stays the
+    /// size of the region
+    fn resize(&mut self, width: u16, height: u16) {
+        let (width, height) =
+            self.region.map_or((width, height), |r| (r.width, r.height));
+        self.image = RgbImage::new(width.into(), height.into());
+        self.width = width;
+        self.height = height;
+        self.covered = vec![false; usize::from(width) * usize::from(height)];
+        self.covered_count = 0;
+        if let (Some(raw), Ok(bytes_per_pixel)) =
+            (&mut self.raw, bytes_per_pixel(&self.format))
+        {
+            *raw = vec![0; self.covered.len() * bytes_per_pixel];
+        }
+    }
+
+    /// Percentage of the framebuffer that has been received
+    fn coverage(&self) -> f64 {
+        if self.covered.is_empty() {
+            return 100.0;
+        }
+        self.covered_count as f64 * 100.0 / self.covered.len() as f64
+    }
+
     /// Convert two bytes of RGB16 into their corresponding r,g,b
     /// components according to the given pixel format
     ///
@@ -267,16 +733,13 @@ impl Image {
     ///   green_shift: 0,
     ///   blue_shift: 0
     /// }
-    /// This one results in Unsupported event: SetColourMap which we
-    /// need to handle somehow
-
-    //TODO unit test
+    /// This one sends a SetColourMap event, and each pixel is an index
+    /// into the colour map
     fn pixel_to_rgb(
         format: &PixelFormat,
-        colour_map: &Option<ColourMap>,
+        palette: &Option<Vec<(u8, u8, u8)>>,
         bytes: &[u8],
-    ) -> Result<ColourFormat, Error> {
-        use ColourFormat::*;
+    ) -> Result<(u8, u8, u8), Error> {
         //TODO code reuse
         match (format.bits_per_pixel, format.depth) {
             (16, 16) | (16, 15) => {
@@ -300,177 +763,994 @@ impl Image {
                 let g = g << (8 - green_mask.count_ones()); // 2
                 let r = r << (8 - red_mask.count_ones()); // 3
 
-                Ok(U8((r.try_into()?, g.try_into()?, b.try_into()?)))
+                Ok((r.try_into()?, g.try_into()?, b.try_into()?))
             }
-            (32, 24) => {
+            (32, 24) | (32, 32) => {
                 let bytes: [u8; 4] = bytes.try_into()?;
                 let px = if format.big_endian {
                     u32::from_be_bytes(bytes)
                 } else {
                     u32::from_le_bytes(bytes)
                 };
-                let blue_mask = format.blue_max as u32; // 5 bits
-                let green_mask = format.green_max as u32; // 6 bits
-                let red_mask = format.red_max as u32; // 5 bits
+                // Only the bits covered by each channel's shift and max
+                // are colour data. The remaining byte is padding for
+                // depth 24 or alpha for depth 32, and is masked out here
+                // wherever the shifts put it.
+                let b = channel_to_u8(px >> format.blue_shift, format.blue_max);
+                let g =
+                    channel_to_u8(px >> format.green_shift, format.green_max);
+                let r = channel_to_u8(px >> format.red_shift, format.red_max);
 
-                let b = (px >> format.blue_shift) & blue_mask; // 0x1f
-                let g = (px >> format.green_shift) & green_mask; // 0x3f
-                let r = (px >> format.red_shift) & red_mask; // 0x1f
+                Ok((r, g, b))
+            }
+            (8, 8) if !format.true_colour => match palette {
+                // The palette always has 256 entries so any index is
+                // valid
+                Some(palette) => Ok(palette[usize::from(bytes[0])]),
+                None => Err(Error::VncError(
+                    "No colour map supplied for 8-bit mode!".to_string(),
+                )),
+            },
+            (bpp, depth) => Err(Error::VncError(format!(
+                "Unsupported colour depth: {} bits per pixel, depth {}",
+                bpp, depth
+            ))),
+        }
+    }
+
+    /// Save the pointer shape. The pixels are in the current pixel format,
+    /// and the mask has a bit for each pixel, set if it is part of the
+    /// pointer, with each row padded to a whole byte
+    fn set_cursor(
+        &mut self,
+        (width, height): (u16, u16),
+        hotspot: (u16, u16),
+        pixels: &[u8],
+        mask_bits: &[u8],
+    ) -> Result<(), Error> {
+        if width == 0 || height == 0 {
+            // The server has hidden the pointer
+            self.cursor = None;
+            return Ok(());
+        }
+        let bytes_per_pixel = bytes_per_pixel(&self.format)?;
+        let row_bytes = usize::from(width).div_ceil(8);
+        let count = usize::from(width) * usize::from(height);
+        if pixels.len() != count * bytes_per_pixel
+            || mask_bits.len() != row_bytes * usize::from(height)
+        {
+            return Err(Error::VncError(format!(
+                "Cursor data length mismatch for {}x{} cursor: {} pixel \
+                bytes and {} mask bytes",
+                width,
+                height,
+                pixels.len(),
+                mask_bits.len()
+            )));
+        }
 
-                // Values do not need left shifting because they are
-                // already 8-bits long
+        let mut decoded = Vec::with_capacity(count);
+        for (i, pixel) in pixels.chunks_exact(bytes_per_pixel).enumerate() {
+            let (x, y) = (i % usize::from(width), i / usize::from(width));
+            let mask = mask_bits[y * row_bytes + x / 8];
+            decoded.push(if mask & (0x80 >> (x % 8)) != 0 {
+                let (r, g, b) =
+                    Image::pixel_to_rgb(&self.format, &self.palette, pixel)?;
+                Some(Rgb([r, g, b]))
+            } else {
+                None
+            });
+        }
+        self.cursor = Some(Cursor {
+            width,
+            hotspot,
+            pixels: decoded,
+        });
+        Ok(())
+    }
 
-                Ok(U8((r.try_into()?, g.try_into()?, b.try_into()?)))
-            }
-            (8, 8) => {
-                let px = bytes[0];
-                if let Some(colour_map) = colour_map {
-                    let colour = &colour_map.colours[px as usize];
-                    let r = colour.red;
-                    let g = colour.green;
-                    let b = colour.blue;
-
-                    Ok(U16((
-                        r.try_into().unwrap(),
-                        g.try_into().unwrap(),
-                        b.try_into().unwrap(),
-                    )))
-                } else {
-                    Err(Error::VncError(
-                        "No colour map supplied for 8-bit mode!".to_string(),
-                    ))
-                }
+    /// Draw the pointer onto the framebuffer, if the server has sent one.
+    /// RFB doesn't tell the client where the pointer is, so its hotspot
+    /// is put in the middle of the screen. This still shows its shape,
+    /// e.g. a busy cursor
+    fn draw_cursor(&mut self) {
+        let cursor = match &self.cursor {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        let left = i64::from(self.width / 2) - i64::from(cursor.hotspot.0);
+        let top = i64::from(self.height / 2) - i64::from(cursor.hotspot.1);
+        for (i, pixel) in cursor.pixels.iter().enumerate() {
+            let pixel = match pixel {
+                Some(pixel) => pixel,
+                None => continue,
+            };
+            let x = left + (i % usize::from(cursor.width)) as i64;
+            let y = top + (i / usize::from(cursor.width)) as i64;
+            if (0..i64::from(self.width)).contains(&x)
+                && (0..i64::from(self.height)).contains(&y)
+            {
+                self.image.put_pixel(x as u32, y as u32, *pixel);
             }
-            d => panic!("Unsupported colour depth {:?}", d),
         }
     }
 
+    /// Update the palette with colours starting at `first_colour`. The
+    /// server may send the whole map at once or only some entries, and
+    /// any entries that haven't been set are black
     fn set_colour_map(
         &mut self,
         first_colour: u16,
         colours: Vec<Colour>,
     ) -> Result<(), Error> {
-        if colours.len() != 256 {
+        let first = usize::from(first_colour);
+        if first + colours.len() > 256 {
             return Err(Error::VncError(format!(
-                "Invalid number of colours in map: {}",
-                colours.len()
+                "Colour map entries {} to {} are out of range",
+                first,
+                first + colours.len()
             )));
         }
-        self.colour_map = Some(ColourMap {
-            first_colour,
-            colours,
-        });
+        let palette = self.palette.get_or_insert_with(|| vec![(0, 0, 0); 256]);
+        for (entry, colour) in palette[first..].iter_mut().zip(colours) {
+            // RFB colour map entries are 16 bits per channel
+            *entry = (
+                (colour.red >> 8) as u8,
+                (colour.green >> 8) as u8,
+                (colour.blue >> 8) as u8,
+            );
+        }
 
         Ok(())
     }
 }
 
-struct ColourMap {
-    #[allow(unused)]
-    first_colour: u16,
-    colours: Vec<Colour>,
+/// Number of bytes in each pixel of the given format
+fn bytes_per_pixel(format: &PixelFormat) -> Result<usize, Error> {
+    match format.bits_per_pixel {
+        8 => Ok(1),
+        16 => Ok(2),
+        32 => Ok(4),
+        _ => Err(Error::VncError(format!(
+            "Invalid bits per pixel: {}",
+            format.bits_per_pixel
+        ))),
+    }
 }
 
-fn vnc_capture(
-    target: &Target,
+/// Mask a shifted 32-bit pixel down to a single channel and scale it to
+/// 8 bits. Channels are normally 8 bits wide at 32bpp, but the maximum is
+/// respected in case the server uses something else.
+fn channel_to_u8(shifted: u32, max: u16) -> u8 {
+    let max = u32::from(max);
+    if max == 0 {
+        return 0;
+    }
+    let value = shifted & max;
+    if max == 0xff {
+        value as u8
+    } else {
+        (value * 0xff / max) as u8
+    }
+}
+
+/// The index of the byte missing from a ZRLE CPIXEL in the given format,
+/// or None if CPIXELs are the full pixel. For 32 bit true colour with a
+/// depth of 24 or less, a CPIXEL is the 3 bytes of the pixel value that
+/// hold the colour, in the pixel's byte order (RFC 6143 section 7.7.6)
+fn cpixel_padding(format: &PixelFormat) -> Option<usize> {
+    if format.bits_per_pixel != 32 || format.depth > 24 || !format.true_colour {
+        return None;
+    }
+    let mask = (u32::from(format.red_max) << format.red_shift)
+        | (u32::from(format.green_max) << format.green_shift)
+        | (u32::from(format.blue_max) << format.blue_shift);
+    let low_bytes = mask & 0xff00_0000 == 0;
+    let high_bytes = mask & 0x0000_00ff == 0;
+    // The unused byte is last in memory when it's the most significant
+    // byte of a little endian pixel, or the least significant byte of a
+    // big endian one
+    match (low_bytes, high_bytes, format.big_endian) {
+        (true, _, false) | (false, true, true) => Some(3),
+        (true, _, true) | (false, true, false) => Some(0),
+        (false, false, _) => None,
+    }
+}
+
+/// Expand 3 byte CPIXELs to 4 byte pixels with a zero byte at `pad`
+fn expand_cpixels(cpixels: &[u8], pad: usize) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(cpixels.len() / 3 * 4);
+    for cpixel in cpixels.chunks_exact(3) {
+        if pad == 0 {
+            pixels.push(0);
+        }
+        pixels.extend_from_slice(cpixel);
+        if pad == 3 {
+            pixels.push(0);
+        }
+    }
+    pixels
+}
+
+/// Find the part of the image left after removing rows and columns of a
+/// single colour from each edge. Returns None if there is nothing to
+/// trim, or if trimming would remove more than 90% of the image, as the
+/// image is then most likely just a blank screen.
+fn trim_bounds(image: &RgbImage) -> Option<(u32, u32, u32, u32)> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let row_is = |y: u32, colour: &Rgb<u8>| {
+        (0..width).all(|x| image.get_pixel(x, y) == colour)
+    };
+    let top_colour = *image.get_pixel(0, 0);
+    let mut top = 0;
+    while top < height && row_is(top, &top_colour) {
+        top += 1;
+    }
+    if top == height {
+        return None;
+    }
+    let bottom_colour = *image.get_pixel(0, height - 1);
+    let mut bottom = height;
+    while bottom > top && row_is(bottom - 1, &bottom_colour) {
+        bottom -= 1;
+    }
+    // Two bands of colour, e.g. a white top half over a black bottom half
+    if bottom == top {
+        return None;
+    }
+
+    let column_is = |x: u32, colour: &Rgb<u8>| {
+        (top..bottom).all(|y| image.get_pixel(x, y) == colour)
+    };
+    let left_colour = *image.get_pixel(0, top);
+    let mut left = 0;
+    while left < width && column_is(left, &left_colour) {
+        left += 1;
+    }
+    // What is left is a single band of another colour
+    if left == width {
+        return None;
+    }
+    let right_colour = *image.get_pixel(width - 1, top);
+    let mut right = width;
+    while right > left && column_is(right - 1, &right_colour) {
+        right -= 1;
+    }
+
+    if (left, top, right, bottom) == (0, 0, width, height) {
+        return None;
+    }
+    let kept = u64::from(right - left) * u64::from(bottom - top);
+    if kept * 10 < u64::from(width) * u64::from(height) {
+        debug!("Not trimming as only {} pixels would be left", kept);
+        return None;
+    }
+    Some((left, top, right - left, bottom - top))
+}
+
+/// Select an authentication method from those offered by the server and
+/// allowed by --vnc-auth, preferring no authentication
+fn choose_auth(
+    methods: &[AuthMethod],
+    password: Option<&str>,
+    allowed: &[VncAuthMethod],
+) -> Option<AuthChoice> {
+    debug!("available auth methods: {:?}", methods);
+    if allowed.contains(&VncAuthMethod::None)
+        && methods.iter().any(|m| matches!(m, AuthMethod::None))
+    {
+        return Some(AuthChoice::None);
+    }
+    if let Some(password) = password {
+        if allowed.contains(&VncAuthMethod::Password)
+            && methods.iter().any(|m| matches!(m, AuthMethod::Password))
+        {
+            return Some(AuthChoice::Password(password_key(password)));
+        }
+    }
+    None
+}
+
+/// Authentication methods allowed inside the VeNCrypt tunnel: those of
+/// `allowed` that can be used there, or both no authentication and VNC
+/// authentication if --vnc-auth only gave vencrypt
+fn vencrypt_auth(allowed: &[VncAuthMethod]) -> Vec<VncAuthMethod> {
+    let inner: Vec<_> = allowed
+        .iter()
+        .copied()
+        .filter(|m| matches!(m, VncAuthMethod::None | VncAuthMethod::Password))
+        .collect();
+    if inner.is_empty() {
+        vec![VncAuthMethod::None, VncAuthMethod::Password]
+    } else {
+        inner
+    }
+}
+
+/// Whether the server offers a method that would have been used if
+/// --vnc-auth allowed it. An empty list means that the server may be
+/// offering VeNCrypt
+fn auth_disallowed(methods: &[AuthMethod], allowed: &[VncAuthMethod]) -> bool {
+    if methods.is_empty() {
+        return !allowed.contains(&VncAuthMethod::VeNCrypt);
+    }
+    methods.iter().any(|m| match m {
+        AuthMethod::None => !allowed.contains(&VncAuthMethod::None),
+        AuthMethod::Password => !allowed.contains(&VncAuthMethod::Password),
+        _ => false,
+    })
+}
+
+/// VNC authentication uses a DES key made from the first eight bytes of
+/// the password, padded with nulls
+fn password_key(password: &str) -> [u8; 8] {
+    let mut key = [0; 8];
+    for (k, b) in key.iter_mut().zip(password.bytes()) {
+        *k = b;
+    }
+    key
+}
+
+fn load_password_list(file: &str) -> Result<Vec<String>, Error> {
+    Ok(fs::read_to_string(file)?
+        .lines()
+        .map(|l| l.trim_end_matches('\r'))
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// Open a connection and perform the RFB handshake. The timeout applies
+/// to the TCP connection and to each read and write during the
+/// handshake, and is cleared afterwards so that the client's event thread
+/// can wait for updates indefinitely.
+///
+/// The vnc crate leaves out security types that it doesn't support when
+/// calling `auth`, so if the list is empty then the server may instead
+/// be offering VeNCrypt. In that case the handshake is retried over a new
+/// connection with VeNCrypt.
+///
+/// `auth` is also given the methods that --vnc-auth allows. Inside the
+/// VeNCrypt tunnel it is given the same methods, except that both no
+/// authentication and VNC authentication are allowed if --vnc-auth only
+/// allows VeNCrypt. The details of the TLS session are returned with
+/// the client if VeNCrypt was used.
+fn handshake<F>(
+    endpoint: &Endpoint,
     opts: &Opts,
-    report_tx: &Sender<ReportMessage>,
-) -> Result<(), Error> {
-    info!("Connecting to {:?}", target);
-    let addr = match target {
-        Target::Address(sock_addr) => sock_addr,
-        Target::Url(_) => {
+    auth: F,
+) -> Result<(Client, Option<TlsDetails>), vnc::Error>
+where
+    F: Fn(&[AuthMethod], &[VncAuthMethod]) -> Option<AuthChoice>,
+{
+    let timeout = Duration::from_secs(opts.vnc_timeout);
+    let unsupported = Cell::new(false);
+    let result =
+        client_from_stream(open_stream(endpoint, opts, timeout)?, opts, |m| {
+            unsupported.set(m.is_empty());
+            auth(m, &opts.vnc_auth)
+        });
+    match result {
+        Err(vnc::Error::AuthenticationUnavailable)
+            if unsupported.get()
+                && opts.vnc_auth.contains(&VncAuthMethod::VeNCrypt) =>
+        {
+            debug!("No supported security types offered, trying VeNCrypt");
+            let (stream, details) = vencrypt::connect(
+                open_stream(endpoint, opts, timeout)?,
+                &endpoint.tls_domain(),
+                opts.vnc_insecure_tls,
+            )?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            let inner = vencrypt_auth(&opts.vnc_auth);
+            let client = client_from_stream(stream, opts, |m| auth(m, &inner))?;
+            Ok((client, Some(details)))
+        }
+        result => result.map(|client| (client, None)),
+    }
+}
+
+/// Where the VNC server is listening
+enum Endpoint<'a> {
+    Tcp(&'a SocketAddr),
+    /// A Unix domain socket, from a unix:/path/to/socket target
+    Unix(PathBuf),
+}
+
+impl Endpoint<'_> {
+    /// Name that the server's VeNCrypt certificate should be valid for
+    fn tls_domain(&self) -> String {
+        match self {
+            Endpoint::Tcp(addr) => resolve::server_name(addr),
+            Endpoint::Unix(_) => "localhost".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn open_stream(
+    endpoint: &Endpoint,
+    opts: &Opts,
+    timeout: Duration,
+) -> Result<TcpStream, vnc::Error> {
+    throttle::wait_for_rate();
+    let stream = match endpoint {
+        Endpoint::Tcp(addr) => {
+            util::connect(addr, opts.vnc_proxy.as_deref(), Some(timeout))?
+        }
+        Endpoint::Unix(path) => unix::connect(path)?,
+    };
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+fn client_from_stream<F>(
+    stream: TcpStream,
+    opts: &Opts,
+    auth: F,
+) -> Result<Client, vnc::Error>
+where
+    F: FnOnce(&[AuthMethod]) -> Option<AuthChoice>,
+{
+    // The clone shares the socket, so it can be used to clear the
+    // timeouts after the stream has been handed to the client
+    let handle = stream.try_clone()?;
+    let vnc = Client::from_tcp_stream(stream, opts.vnc_shared, auth)?;
+    handle.set_read_timeout(None)?;
+    handle.set_write_timeout(None)?;
+    Ok(vnc)
+}
+
+/// Connect to the VNC server using `password`, from --vnc-password or
+/// --creds-file, if the server requires authentication, falling back to
+/// the passwords in the password list if one has been supplied. Each
+/// password attempt uses a fresh connection. How the session was
+/// authenticated, and the details of any VeNCrypt TLS session, are
+/// returned alongside the client.
+fn connect(
+    endpoint: &Endpoint,
+    opts: &Opts,
+    password: Option<&str>,
+) -> Result<(Client, VncAuth, Option<TlsDetails>), Error> {
+    let used_password = Cell::new(false);
+    // The methods offered, if --vnc-auth ruled out one that would have
+    // been used
+    let disallowed = RefCell::new(None);
+    let password_list = opts.vnc_password_list.as_ref().filter(|_| {
+        opts.vnc_auth.contains(&VncAuthMethod::Password)
+            || opts.vnc_auth.contains(&VncAuthMethod::VeNCrypt)
+    });
+    let list = match (
+        handshake(endpoint, opts, |methods, allowed| {
+            let choice = choose_auth(methods, password, allowed);
+            used_password.set(matches!(choice, Some(AuthChoice::Password(_))));
+            if choice.is_none() && auth_disallowed(methods, allowed) {
+                let offered = if methods.is_empty() {
+                    "only methods that may include VeNCrypt".to_string()
+                } else {
+                    format!("{:?}", methods)
+                };
+                disallowed.replace(Some(offered));
+            }
+            choice
+        }),
+        password_list,
+    ) {
+        (Ok((vnc, tls)), _) if used_password.get() => {
+            return Ok((vnc, VncAuth::Password, tls))
+        }
+        (Ok((vnc, tls)), _) => return Ok((vnc, VncAuth::NoPassword, tls)),
+        (Err(vnc::Error::AuthenticationUnavailable), None)
+            if disallowed.borrow().is_some() =>
+        {
+            let allowed: Vec<String> =
+                opts.vnc_auth.iter().map(|m| m.to_string()).collect();
             return Err(Error::VncError(format!(
-                "Invalid VNC target: {}",
-                target
+                "None of the authentication methods allowed by --vnc-auth \
+                ({}) are offered by the server, which offers {}",
+                allowed.join(","),
+                disallowed.borrow().as_deref().unwrap_or_default()
             )));
         }
+        (Err(vnc::Error::AuthenticationUnavailable), Some(list))
+        | (Err(vnc::Error::AuthenticationFailure(_)), Some(list)) => list,
+        (Err(vnc::Error::AuthenticationUnavailable), None) => {
+            return Err(Error::VncError(
+                "Server requires a password, supply one with \
+                --vnc-password, --creds-file or --vnc-password-list"
+                    .to_string(),
+            ))
+        }
+        (Err(vnc::Error::AuthenticationFailure(e)), None) => {
+            return Err(Error::VncError(format!(
+                "The VNC password was rejected: {}",
+                e
+            )))
+        }
+        (Err(e), _) => return Err(e.into()),
     };
 
-    let stream = TcpStream::connect(addr)?;
-
-    let mut vnc = Client::from_tcp_stream(stream, false, |methods| {
-        debug!("available auth methods: {:?}", methods);
-        // Turn off Clippy's single_match check because there might be
-        // other auth methods in the future
-        #[allow(clippy::single_match)]
-        for method in methods {
-            match method {
-                AuthMethod::None => return Some(AuthChoice::None),
-                _ => {}
+    warn!(
+        "Trying VNC passwords from {} against {}. Only do this against \
+        systems that you are authorised to test",
+        list, endpoint
+    );
+    let passwords = load_password_list(list)?;
+    let delay = Duration::from_millis(opts.vnc_attempt_delay);
+    for (idx, password) in
+        passwords.iter().enumerate().take(opts.vnc_max_attempts)
+    {
+        if idx > 0 {
+            thread::sleep(delay);
+        }
+        debug!("Trying password list entry {}", idx + 1);
+        match handshake(endpoint, opts, |methods, allowed| {
+            choose_auth(methods, Some(password), allowed)
+        }) {
+            Ok((vnc, tls)) => {
+                info!("Password list entry {} accepted", idx + 1);
+                return Ok((vnc, VncAuth::PasswordList(idx + 1), tls));
+            }
+            Err(vnc::Error::AuthenticationFailure(e)) => {
+                debug!("Password list entry {} rejected: {}", idx + 1, e);
             }
+            Err(e) => return Err(e.into()),
         }
-        warn!("AuthMethod::None may not be supported");
-        None
-    })?;
+    }
+
+    Err(Error::VncError(format!(
+        "None of the first {} passwords were accepted",
+        passwords.len().min(opts.vnc_max_attempts)
+    )))
+}
+
+/// Connect to the server and receive a single frame
+fn capture_frame(
+    endpoint: &Endpoint,
+    opts: &Opts,
+    password: Option<&str>,
+) -> Result<(Image, FrameStatus, VncAuth, String, Option<TlsDetails>), Error> {
+    let (mut vnc, auth, tls) = connect(endpoint, opts, password)?;
+    events::emit(Event::Connected, "vnc", endpoint);
 
     let (width, height) = vnc.size();
+    let desktop_name = vnc.name().to_string();
     info!(
         "connected to \"{}\", {}x{} framebuffer",
-        vnc.name(),
-        width,
-        height
+        desktop_name, width, height
     );
 
-    vnc.set_encodings(&[
-        vnc::Encoding::Zrle,
-        vnc::Encoding::CopyRect,
-        vnc::Encoding::Raw,
-        vnc::Encoding::Cursor,
-        vnc::Encoding::DesktopSize,
-    ])?;
+    vnc.set_encodings(ENCODINGS)?;
 
+    if opts.vnc_native_format {
+        debug!("Server pixel format: {:?}", vnc.format());
+    } else {
+        debug!("Server pixel format: {:?}, requesting 32 bit", vnc.format());
+        vnc.set_format(PREFERRED_FORMAT)?;
+    }
     let vnc_format = vnc.format();
     debug!("VNC pixel format: {:?}", vnc_format);
 
+    let mut vnc_image = Image::new(vnc_format, width, height)?;
+    if let Some(region) = opts.vnc_region {
+        let region = Rect {
+            left: region.left,
+            top: region.top,
+            width: region.width,
+            height: region.height,
+        };
+        check_region(&region, width, height)?;
+        vnc_image = vnc_image.with_region(region);
+    }
     debug!("requesting update");
-    vnc.request_update(
-        vnc::Rect {
-            left: 0,
-            top: 0,
-            width,
-            height,
-        },
-        false,
-    )?;
+    vnc.request_update(vnc_image.update_rect(), false)?;
 
-    let mut vnc_image = Image::new(vnc_format, width, height)?;
+    if opts.save_raw {
+        vnc_image = vnc_image.keep_raw()?;
+    }
 
-    vnc_poll(vnc, &mut vnc_image)?;
+    let frame_timeout = Duration::from_secs(opts.vnc_frame_timeout);
+    let frames = FrameLimit {
+        frames: opts.vnc_frames,
+        wait: Duration::from_millis(opts.vnc_frames_wait),
+    };
+    let status = vnc_poll(vnc, &mut vnc_image, frame_timeout, frames)?;
+    Ok((vnc_image, status, auth, desktop_name, tls))
+}
 
-    // Save the image
-    info!("Successfully received image");
-    let filename = format!("{}.png", target_to_filename(&target));
-    let relative_filepath = Path::new("vnc").join(&filename);
-    let filepath = Path::new(&opts.output_dir).join(&relative_filepath);
-    info!("Saving image as {}", filepath.display());
-    vnc_image.image.save(&filepath)?;
-    let vnc_message = VncOutput {
-        target: target.to_string(),
-        file: relative_filepath.display().to_string(),
+/// Make sure that --vnc-region fits in the server's framebuffer, which
+/// isn't known until after connecting
+fn check_region(region: &Rect, width: u16, height: u16) -> Result<(), Error> {
+    if u32::from(region.left) + u32::from(region.width) > u32::from(width)
+        || u32::from(region.top) + u32::from(region.height) > u32::from(height)
+    {
+        return Err(Error::VncError(format!(
+            "--vnc-region {}x{} at ({}, {}) is outside the {}x{} \
+            framebuffer",
+            region.width, region.height, region.left, region.top, width, height
+        )));
     }
-    .as_report_message();
-    report_tx.send(vnc_message)?;
-
     Ok(())
 }
 
-fn vnc_poll(mut vnc: Client, vnc_image: &mut Image) -> Result<(), Error> {
-    use vnc::client::Event::*;
+/// A frame received from a VNC server, already trimmed and scaled
+pub(crate) struct Frame {
+    pub image: DynamicImage,
+    pub desktop_name: String,
+    auth: VncAuth,
+    /// Percentage of the framebuffer received if the frame is incomplete
+    coverage: Option<f64>,
+    attempts: usize,
+    /// The untrimmed framebuffer without the cursor, for --save-raw
+    raw: Option<RawFrame>,
+    /// Clipboard text sent by the server, for --vnc-clipboard
+    clipboard: Option<String>,
+    tls: Option<TlsDetails>,
+}
+
+/// Connect to the target and receive a frame, reconnecting if the server
+/// disconnects part way through
+pub(crate) fn grab(target: &Target, opts: &Opts) -> Result<Frame, Error> {
+    info!("Connecting to {:?}", target);
+    let endpoint = match (target, target.unix_socket()) {
+        (Target::Address(sock_addr), _) => Endpoint::Tcp(sock_addr),
+        (Target::Url(_), Some(path)) => Endpoint::Unix(path),
+        (Target::Url(_), None) => {
+            return Err(Error::VncError(format!(
+                "Invalid VNC target: {}",
+                target
+            )));
+        }
+    };
+
+    // --vnc-password takes precedence over the credentials file
+    let credential = opts
+        .credentials
+        .as_ref()
+        .map(|c| c.for_target(target))
+        .unwrap_or_default();
+    let password = opts
+        .vnc_password
+        .as_deref()
+        .or(credential.password.as_deref());
+
+    // Reconnect if the server drops the connection part way through the
+    // frame. If it keeps doing so then the last partial image is saved.
+    // Connections that time out are retried separately, with backoff
+    let mut attempts = 0;
+    let mut retries = 0;
+    let (mut vnc_image, status, auth, desktop_name, tls) = loop {
+        attempts += 1;
+        let (vnc_image, status, auth, desktop_name, tls) =
+            match capture_frame(&endpoint, opts, password) {
+                Ok(frame) => frame,
+                Err(Error::ConnectionTimedOut(e)) if retries < opts.retries => {
+                    retries += 1;
+                    let delay = throttle::backoff_delay(retries);
+                    warn!(
+                        "VNC connection to {} timed out ({}), retrying in \
+                        {}s (retry {} of {})",
+                        endpoint,
+                        e,
+                        delay.as_secs(),
+                        retries,
+                        opts.retries
+                    );
+                    thread::sleep(delay);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+        if status != FrameStatus::Disconnected || attempts > opts.vnc_retries {
+            break (vnc_image, status, auth, desktop_name, tls);
+        }
+        let delay = RETRY_DELAY * attempts as u32;
+        warn!(
+            "Disconnected with {:.1}% of the frame received, retrying in \
+            {}s (retry {} of {})",
+            vnc_image.coverage(),
+            delay.as_secs(),
+            attempts,
+            opts.vnc_retries
+        );
+        thread::sleep(delay);
+    };
+
+    let coverage = if status == FrameStatus::Complete {
+        info!("Successfully received image");
+        None
+    } else {
+        let coverage = vnc_image.coverage();
+        warn!(
+            "Incomplete frame ({:?}), {:.1}% of the framebuffer was \
+            received. Saving the partial image",
+            status, coverage
+        );
+        Some(coverage)
+    };
+
+    let raw = vnc_image.take_raw();
+    let clipboard = vnc_image.clipboard.take().filter(|_| opts.vnc_clipboard);
+    if opts.vnc_draw_cursor {
+        vnc_image.draw_cursor();
+    }
+    let bounds = if opts.trim {
+        trim_bounds(&vnc_image.image)
+    } else {
+        None
+    };
+    let mut image = DynamicImage::ImageRgb8(vnc_image.image);
+    if let Some((x, y, width, height)) = bounds {
+        debug!("Trimming borders to {}x{} at ({}, {})", width, height, x, y);
+        image = image.crop_imm(x, y, width, height);
+    }
+    // RFB has no standard way of asking the server for a scaled
+    // framebuffer, so the thumbnail is made from the first full update
+    if let Some(size) = opts.vnc_thumbnail {
+        debug!("Reducing image to a {}px thumbnail", size);
+        image = image.thumbnail(size, size);
+    }
+    Ok(Frame {
+        image,
+        desktop_name,
+        auth,
+        coverage,
+        attempts,
+        raw,
+        clipboard,
+        tls,
+    })
+}
+
+fn vnc_capture(
+    target: &Target,
+    opts: &Opts,
+    report_tx: &Sender<ReportMessage>,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let Frame {
+        image,
+        desktop_name,
+        auth,
+        coverage,
+        attempts,
+        raw,
+        clipboard,
+        tls,
+    } = grab(target, opts)?;
+
+    // Save the image
+    let filename = util::image_filename(opts, "vnc", target);
+    let relative_filepath = Path::new("vnc").join(&filename);
+    if opts.only_successful && util::is_uniform(&image) {
+        return Err(Error::BlankImage);
+    }
+    let mut file = sink::location(opts, &relative_filepath);
+    let duplicate =
+        dedupe::find_duplicate(&image, opts.dedupe, &target.to_string(), &file);
+    if let Some(original) = &duplicate {
+        info!("Image is a duplicate of {}, not saving", original.target);
+        file = original.file.clone();
+    } else {
+        info!("Saving image as {}", file);
+        util::save_image(&image, &relative_filepath, opts)?;
+        if opts.enhance {
+            util::save_enhanced(&image, &relative_filepath, opts)?;
+        }
+        if let Some(raw) = raw {
+            raw.save(&relative_filepath, opts)?;
+        }
+    }
+    let vnc_message = VncOutput {
+        target: target.to_string(),
+        file,
+        duplicate_of: duplicate.map(|d| d.target),
+        desktop_name,
+        auth,
+        coverage,
+        attempts,
+        duration: start.elapsed(),
+        correlation_id: logging::correlation_id().unwrap_or_default(),
+        clipboard,
+        tls,
+    }
+    .as_report_message();
+    report_tx.send(vnc_message)?;
+
+    Ok(())
+}
+
+/// Name of an encoding as used in the RFB specification
+fn encoding_name(encoding: &vnc::Encoding) -> String {
+    use vnc::Encoding::*;
+    match encoding {
+        Raw => "Raw".to_string(),
+        CopyRect => "CopyRect".to_string(),
+        Rre => "RRE".to_string(),
+        Hextile => "Hextile".to_string(),
+        Zrle => "ZRLE".to_string(),
+        Cursor => "Cursor".to_string(),
+        DesktopSize => "DesktopSize".to_string(),
+        Unknown(n) => format!("unknown ({})", n),
+    }
+}
+
+/// The error for a server that sent a rectangle in an encoding that
+/// wasn't offered, which the vnc crate reports by disconnecting. Anything
+/// drawn so far is incomplete, so it isn't worth saving
+fn unsupported_encoding(error: &vnc::Error) -> Option<Error> {
+    match error {
+        vnc::Error::Unexpected("encoding") => {
+            let names: Vec<String> =
+                ENCODINGS.iter().map(encoding_name).collect();
+            Some(Error::VncError(format!(
+                "Server sent a framebuffer update in an unsupported \
+                encoding, only {} can be decoded",
+                names.join(", ")
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Clipboard text for the report, cut down to `CLIPBOARD_LIMIT`
+/// characters
+fn clipboard_text(text: String) -> String {
+    match text.char_indices().nth(CLIPBOARD_LIMIT) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// Score and copy of a frame for --vnc-frames, with its raw framebuffer
+/// if --save-raw was given
+type BestFrame = (usize, RgbImage, Option<Vec<u8>>);
+
+/// Put the best frame from --vnc-frames back into `vnc_image`
+fn keep_best(vnc_image: &mut Image, best: Option<BestFrame>) -> FrameStatus {
+    if let Some((score, image, raw)) = best {
+        debug!("Keeping the frame with {} pixels drawn", score);
+        vnc_image.image = image;
+        vnc_image.raw = raw;
+    }
+    FrameStatus::Complete
+}
+
+/// Receive framebuffer updates until the end of the frame, saving them in
+/// `vnc_image`. Some servers never mark the end of the frame, so after
+/// `timeout` has passed since the first event, or since the start if
+/// there have been no events, whatever has been received is kept.
+///
+/// If `limit` allows more than one frame, incremental updates are
+/// requested after each one until enough have arrived or the limit's
+/// wait has passed since the first, and the frame with the most drawn
+/// over the background is left in `vnc_image`.
+fn vnc_poll(
+    mut vnc: Client,
+    vnc_image: &mut Image,
+    timeout: Duration,
+    limit: FrameLimit,
+) -> Result<FrameStatus, Error> {
+    use vnc::client::Event::*;
+    let mut deadline = Instant::now() + timeout;
+    let mut first_event = true;
+    // The desktop size change arrives as part of a framebuffer update,
+    // so the end of that update isn't the end of the frame
+    let mut resized = false;
+    let mut frames = 0;
+    let mut frames_deadline = None;
+    // Score and copy of the best frame so far
+    let mut best: Option<BestFrame> = None;
     loop {
+        if frames_deadline.is_some_and(|d| Instant::now() >= d) {
+            debug!("No more frames after {}", frames);
+            return Ok(keep_best(vnc_image, best));
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "Timed out after {}s waiting for the end of the frame",
+                timeout.as_secs()
+            );
+            // The frames that did finish are still worth keeping
+            if frames > 0 {
+                return Ok(keep_best(vnc_image, best));
+            }
+            return Ok(FrameStatus::TimedOut);
+        }
+        // Whether to request another update, and if so whether it should
+        // be incremental
+        let mut request_update = None;
         for event in vnc.poll_iter() {
+            if first_event {
+                deadline = Instant::now() + timeout;
+                first_event = false;
+            }
             match event {
+                Disconnected(None) if frames > 0 => {
+                    warn!("VNC Channel disconnected after {} frames", frames);
+                    return Ok(keep_best(vnc_image, best));
+                }
                 Disconnected(None) => {
                     warn!("VNC Channel disconnected");
-                    return Ok(());
+                    return Ok(FrameStatus::Disconnected);
                 }
+                Disconnected(Some(ref e)) => match unsupported_encoding(e) {
+                    // Earlier frames were decoded before the bad update
+                    Some(error) if frames > 0 => {
+                        warn!("{} after {} frames", error, frames);
+                        return Ok(keep_best(vnc_image, best));
+                    }
+                    Some(error) => return Err(error),
+                    None if frames > 0 => {
+                        warn!("VNC error after {} frames: {}", frames, e);
+                        return Ok(keep_best(vnc_image, best));
+                    }
+                    None => {
+                        warn!("VNC Channel disconnected: {}", e);
+                        return Ok(FrameStatus::Disconnected);
+                    }
+                },
                 PutPixels(vnc_rect, ref pixels) => {
                     trace!("PutPixels");
                     vnc_image.put_pixels(vnc_rect, pixels)?;
                 }
-                EndOfFrame => {
+                CopyPixels { src, dst } => {
+                    trace!("CopyPixels from {:?} to {:?}", src, dst);
+                    vnc_image.copy_pixels(src, dst)?;
+                }
+                Resize(width, height) => {
+                    info!("Desktop resized to {}x{}", width, height);
+                    if let Some(region) = vnc_image.region {
+                        check_region(&region, width, height)?;
+                    }
+                    vnc_image.resize(width, height);
+                    resized = true;
+                    // Earlier frames were of the old desktop size
+                    best = None;
+                }
+                EndOfFrame if resized => {
+                    debug!("End of resize update, requesting a full frame");
+                    resized = false;
+                    request_update = Some(false);
+                    // The client is borrowed by the iterator
+                    break;
+                }
+                EndOfFrame if limit.frames <= 1 => {
                     debug!("End of frame");
-                    return Ok(());
+                    return Ok(FrameStatus::Complete);
+                }
+                EndOfFrame => {
+                    frames += 1;
+                    let score = frame_score(&vnc_image.image);
+                    debug!("End of frame {}, {} pixels drawn", frames, score);
+                    // Later frames win ties, as they are more up to date
+                    if best.as_ref().is_none_or(|(b, _, _)| score >= *b) {
+                        best = Some((
+                            score,
+                            vnc_image.image.clone(),
+                            vnc_image.raw.clone(),
+                        ));
+                    }
+                    if frames >= limit.frames {
+                        return Ok(keep_best(vnc_image, best));
+                    }
+                    frames_deadline.get_or_insert(Instant::now() + limit.wait);
+                    request_update = Some(true);
+                    break;
                 }
                 SetColourMap {
                     first_colour,
@@ -481,9 +1761,35 @@ fn vnc_poll(mut vnc: Client, vnc_image: &mut Image) -> Result<(), Error> {
                     trace!("colours: {:?}", colours);
                     vnc_image.set_colour_map(first_colour, colours)?;
                 }
-                other => debug!("Unsupported event: {:?}", other),
+                SetCursor {
+                    size,
+                    hotspot,
+                    ref pixels,
+                    ref mask_bits,
+                } => {
+                    debug!("Cursor shape is {}x{}", size.0, size.1);
+                    // The pointer is only decoration, so a bad one doesn't
+                    // spoil the capture
+                    if let Err(e) =
+                        vnc_image.set_cursor(size, hotspot, pixels, mask_bits)
+                    {
+                        warn!("Ignoring cursor: {}", e);
+                    }
+                }
+                // Some servers ring the bell when a client connects
+                Bell => trace!("Bell"),
+                Clipboard(text) => {
+                    debug!(
+                        "Received {} characters of clipboard text",
+                        text.chars().count()
+                    );
+                    vnc_image.clipboard = Some(clipboard_text(text));
+                }
             }
         }
+        if let Some(incremental) = request_update {
+            vnc.request_update(vnc_image.update_rect(), incremental)?;
+        }
     }
 }
 
@@ -493,9 +1799,920 @@ pub fn capture(
     tx: Sender<ThreadStatus>,
     report_tx: &Sender<ReportMessage>,
 ) {
+    let start = Instant::now();
     if let Err(e) = vnc_capture(&target, opts, report_tx) {
         warn!("VNC error: {}", e);
+        if opts.only_successful {
+            util::remove_image(opts, "vnc", target);
+        }
+        let failure = FailureOutput::new(target, "vnc", &e, start.elapsed());
+        // Sending fails if --target-timeout has already given up on
+        // this capture and nothing is listening any more
+        let _ = report_tx.send(failure.as_report_message());
+    }
+
+    let _ = tx.send(ThreadStatus::Complete);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::argparse::VncRegion;
+
+    fn rgb565_format() -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: false,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        }
+    }
+
+    fn rgb888_format(depth: u8, big_endian: bool) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: 32,
+            depth,
+            big_endian,
+            true_colour: true,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+        }
+    }
+
+    fn rgb(format: &PixelFormat, bytes: &[u8]) -> (u8, u8, u8) {
+        Image::pixel_to_rgb(format, &None, bytes).unwrap()
+    }
+
+    #[test]
+    fn pixel_to_rgb_32bpp_padding() {
+        for depth in &[24, 32] {
+            // BGRA: little endian with the padding/alpha in the high byte
+            let format = rgb888_format(*depth, false);
+            assert_eq!(
+                rgb(&format, &[0x33, 0x22, 0x11, 0xaa]),
+                (0x11, 0x22, 0x33)
+            );
+
+            // ARGB: big endian with the same shifts
+            let format = rgb888_format(*depth, true);
+            assert_eq!(
+                rgb(&format, &[0xaa, 0x11, 0x22, 0x33]),
+                (0x11, 0x22, 0x33)
+            );
+
+            // Padding/alpha in the low byte instead
+            let format = PixelFormat {
+                red_shift: 24,
+                green_shift: 16,
+                blue_shift: 8,
+                ..rgb888_format(*depth, false)
+            };
+            assert_eq!(
+                rgb(&format, &[0xaa, 0x33, 0x22, 0x11]),
+                (0x11, 0x22, 0x33)
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_rect_rejected() {
+        let mut image = Image::new(rgb565_format(), 4, 4).unwrap();
+        let rect = |left, top, width, height| Rect {
+            left,
+            top,
+            width,
+            height,
+        };
+
+        // Fits exactly
+        assert!(image.put_pixels(rect(2, 2, 2, 2), &[0; 8]).is_ok());
+        // Too wide, too tall, and offset past the edge
+        assert!(image.put_pixels(rect(0, 0, 5, 1), &[0; 10]).is_err());
+        assert!(image.put_pixels(rect(0, 0, 1, 5), &[0; 10]).is_err());
+        assert!(image.put_pixels(rect(4, 0, 1, 1), &[0; 2]).is_err());
+        // Would overflow a u16
+        assert!(image
+            .put_pixels(rect(u16::MAX, u16::MAX, 2, 2), &[0; 8])
+            .is_err());
+        // Fits but with the wrong amount of pixel data
+        assert!(image.put_pixels(rect(0, 0, 2, 2), &[0; 7]).is_err());
+        assert!(image.put_pixels(rect(0, 0, 2, 2), &[0; 9]).is_err());
+    }
+
+    #[test]
+    fn resize_framebuffer() {
+        let mut image = Image::new(rgb888_format(24, false), 2, 2).unwrap();
+        let rect = |width, height| Rect {
+            left: 0,
+            top: 0,
+            width,
+            height,
+        };
+        image.put_pixels(rect(2, 2), &[0; 16]).unwrap();
+        assert_eq!(image.coverage(), 100.0);
+
+        image.resize(4, 3);
+        assert_eq!(image.image.dimensions(), (4, 3));
+        assert_eq!(image.coverage(), 0.0);
+        image.put_pixels(rect(4, 3), &[0xff; 48]).unwrap();
+        assert_eq!(image.coverage(), 100.0);
+        assert_eq!(*image.image.get_pixel(3, 2), Rgb([0xff, 0xff, 0xff]));
+    }
+
+    #[test]
+    fn raw_framebuffer() {
+        let mut image = Image::new(rgb565_format(), 3, 2)
+            .unwrap()
+            .keep_raw()
+            .unwrap();
+        let rect = |left, top, width, height| Rect {
+            left,
+            top,
+            width,
+            height,
+        };
+        image
+            .put_pixels(rect(1, 0, 2, 2), &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        assert_eq!(
+            image.raw.as_deref(),
+            Some(&[0, 0, 1, 2, 3, 4, 0, 0, 5, 6, 7, 8][..])
+        );
+        image
+            .copy_pixels(rect(2, 0, 1, 2), rect(0, 0, 1, 2))
+            .unwrap();
+        assert_eq!(
+            image.raw.as_deref(),
+            Some(&[3, 4, 1, 2, 3, 4, 7, 8, 5, 6, 7, 8][..])
+        );
+
+        let raw = image.take_raw().unwrap();
+        assert_eq!(raw.data.len(), 12);
+        assert_eq!(raw.metadata.width, 3);
+        assert_eq!(raw.metadata.pixel_format.bits_per_pixel, 16);
+        assert!(image.take_raw().is_none());
+
+        // Not kept unless asked for
+        let mut image = Image::new(rgb565_format(), 1, 1).unwrap();
+        image.put_pixels(rect(0, 0, 1, 1), &[1, 2]).unwrap();
+        assert!(image.take_raw().is_none());
+    }
+
+    #[test]
+    fn unsupported_encodings() {
+        let e = unsupported_encoding(&vnc::Error::Unexpected("encoding"));
+        match e {
+            Some(Error::VncError(message)) => assert!(message.ends_with(
+                "only ZRLE, CopyRect, Raw, Cursor, DesktopSize can be decoded"
+            )),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        assert!(unsupported_encoding(&vnc::Error::Disconnected).is_none());
+        assert!(
+            unsupported_encoding(&vnc::Error::Unexpected("message type"))
+                .is_none()
+        );
+        assert_eq!(encoding_name(&vnc::Encoding::Unknown(7)), "unknown (7)");
+    }
+
+    #[test]
+    fn trim_uniform_borders() {
+        let black = Rgb([0, 0, 0]);
+        let grey = Rgb([0x80, 0x80, 0x80]);
+        let white = Rgb([0xff, 0xff, 0xff]);
+
+        // 20x10 with black on the top and left, grey on the right, and
+        // content with some white in it in the middle
+        let mut image = RgbImage::from_pixel(20, 10, black);
+        for y in 2..10 {
+            for x in 3..18 {
+                image.put_pixel(x, y, white);
+            }
+            for x in 18..20 {
+                image.put_pixel(x, y, grey);
+            }
+        }
+        image.put_pixel(5, 2, black);
+        image.put_pixel(16, 9, black);
+        assert_eq!(trim_bounds(&image), Some((3, 2, 15, 8)));
+
+        // Nothing to trim
+        let mut image = RgbImage::from_pixel(4, 4, white);
+        image.put_pixel(0, 0, black);
+        image.put_pixel(3, 3, black);
+        assert_eq!(trim_bounds(&image), None);
+
+        // Blank screens and tiny content are left alone
+        let mut image = RgbImage::from_pixel(20, 20, black);
+        assert_eq!(trim_bounds(&image), None);
+        image.put_pixel(10, 10, white);
+        assert_eq!(trim_bounds(&image), None);
+
+        // Two colours split horizontally, with and without a band of a
+        // third colour between them
+        let mut image = RgbImage::from_pixel(20, 10, white);
+        for y in 5..10 {
+            for x in 0..20 {
+                image.put_pixel(x, y, black);
+            }
+        }
+        assert_eq!(trim_bounds(&image), None);
+        for x in 0..20 {
+            image.put_pixel(x, 5, grey);
+        }
+        assert_eq!(trim_bounds(&image), None);
+    }
+
+    /// Pack an RGB colour into the wire representation of a pixel in the
+    /// given format, dropping the low bits of channels narrower than 8
+    /// bits
+    fn encode(format: &PixelFormat, (r, g, b): (u8, u8, u8)) -> Vec<u8> {
+        let channel = |value: u8, max: u16, shift: u8| -> u32 {
+            let bits = max.count_ones();
+            (u32::from(value) >> (8 - bits)) << shift
+        };
+        let px = channel(r, format.red_max, format.red_shift)
+            | channel(g, format.green_max, format.green_shift)
+            | channel(b, format.blue_max, format.blue_shift);
+        match (format.bits_per_pixel, format.big_endian) {
+            (16, false) => (px as u16).to_le_bytes().to_vec(),
+            (16, true) => (px as u16).to_be_bytes().to_vec(),
+            (32, false) => px.to_le_bytes().to_vec(),
+            (32, true) => px.to_be_bytes().to_vec(),
+            (bpp, _) => panic!("Unsupported bits per pixel {}", bpp),
+        }
+    }
+
+    #[test]
+    fn cursor_compositing() {
+        let format = rgb888_format(24, false);
+        let mut image = Image::new(format, 4, 4).unwrap();
+        // Nothing to draw before the server sends a cursor
+        image.draw_cursor();
+        assert!(image.image.pixels().all(|p| p == &Rgb([0, 0, 0])));
+
+        // A 3x2 cursor with the hotspot on its middle pixel and the top
+        // right pixel masked out
+        let white = encode(&format, (0xff, 0xff, 0xff));
+        let pixels: Vec<u8> = (0..6).flat_map(|_| white.clone()).collect();
+        let mask = [0b1100_0000, 0b1110_0000];
+        assert!(image
+            .set_cursor((3, 2), (1, 1), &pixels, &mask[..1])
+            .is_err());
+        assert!(image
+            .set_cursor((3, 2), (1, 1), &pixels[4..], &mask)
+            .is_err());
+        image.set_cursor((3, 2), (1, 1), &pixels, &mask).unwrap();
+        image.draw_cursor();
+
+        // The hotspot is at the centre, (2, 2)
+        let drawn: Vec<_> = (0..4)
+            .flat_map(|y| (0..4).map(move |x| (x, y)))
+            .filter(|&(x, y)| image.image.get_pixel(x, y) == &Rgb([255; 3]))
+            .collect();
+        assert_eq!(drawn, vec![(1, 1), (2, 1), (1, 2), (2, 2), (3, 2)]);
+
+        // Drawing near the edge clips the cursor, leaving only the part
+        // of the bottom row at (2, 0) and (3, 0)
+        let mut image = Image::new(format, 4, 4).unwrap();
+        image.set_cursor((3, 2), (0, 3), &pixels, &mask).unwrap();
+        image.draw_cursor();
+        let drawn: Vec<_> = (0..4)
+            .flat_map(|y| (0..4).map(move |x| (x, y)))
+            .filter(|&(x, y)| image.image.get_pixel(x, y) == &Rgb([255; 3]))
+            .collect();
+        assert_eq!(drawn, vec![(2, 0), (3, 0)]);
+
+        // Hiding the cursor
+        image.set_cursor((0, 0), (0, 0), &[], &[]).unwrap();
+        assert!(image.cursor.is_none());
+    }
+
+    #[test]
+    fn preferred_format() {
+        // Servers are asked for the format that is decoded most simply
+        assert_eq!(PREFERRED_FORMAT, rgb888_format(24, false));
+        assert!(Image::new(PREFERRED_FORMAT, 1, 1).is_ok());
+        assert_eq!(
+            rgb(&PREFERRED_FORMAT, &[0x33, 0x22, 0x11, 0x00]),
+            (0x11, 0x22, 0x33)
+        );
+        // ZRLE then uses 3 byte CPIXELs
+        assert_eq!(cpixel_padding(&PREFERRED_FORMAT), Some(3));
+    }
+
+    #[test]
+    fn cpixel_layouts() {
+        let low = rgb888_format(24, false);
+        assert_eq!(cpixel_padding(&low), Some(3));
+        let low_be = rgb888_format(24, true);
+        assert_eq!(cpixel_padding(&low_be), Some(0));
+        let high = PixelFormat {
+            red_shift: 24,
+            green_shift: 16,
+            blue_shift: 8,
+            ..low
+        };
+        assert_eq!(cpixel_padding(&high), Some(0));
+        let high_be = PixelFormat {
+            big_endian: true,
+            ..high
+        };
+        assert_eq!(cpixel_padding(&high_be), Some(3));
+
+        // Full 4 byte CPIXELs
+        assert_eq!(cpixel_padding(&rgb888_format(32, false)), None);
+        assert_eq!(cpixel_padding(&rgb565_format()), None);
+        let spread = PixelFormat {
+            red_shift: 24,
+            green_shift: 8,
+            blue_shift: 0,
+            ..low
+        };
+        assert_eq!(cpixel_padding(&spread), None);
+
+        // Each CPIXEL is the pixel with the unused byte dropped
+        for format in &[low, low_be, high, high_be] {
+            let pixel = encode(format, (0x11, 0x22, 0x33));
+            let pad = cpixel_padding(format).unwrap();
+            let mut cpixel = pixel.clone();
+            assert_eq!(cpixel.remove(pad), 0, "{:?}", format);
+            assert_eq!(expand_cpixels(&cpixel, pad), pixel, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn zrle_tile_cpixels() {
+        // A 64x16 tile like those decoded from ZRLE for a 24 bit depth
+        // server, which sends 3 bytes per pixel rather than 4
+        let format = rgb888_format(24, false);
+        let mut image = Image::new(format, 64, 32).unwrap();
+        let rect = Rect {
+            left: 0,
+            top: 16,
+            width: 64,
+            height: 16,
+        };
+        let colour = |x: u8, y: u8| (x * 4, y * 16, 0x80);
+        let mut cpixels = Vec::new();
+        for y in 0..16 {
+            for x in 0..64 {
+                let mut pixel = encode(&format, colour(x, y));
+                pixel.truncate(3);
+                cpixels.extend(pixel);
+            }
+        }
+        image.put_pixels(rect, &cpixels).unwrap();
+        for y in 0..16 {
+            for x in 0..64 {
+                let (r, g, b) = colour(x, y);
+                assert_eq!(
+                    image.image.get_pixel(x.into(), u32::from(y) + 16),
+                    &Rgb([r, g, b])
+                );
+            }
+        }
+        assert_eq!(image.covered_count, 64 * 16);
+
+        // Full 4 byte pixels still work for the same format
+        let pixels: Vec<u8> = (0..64 * 16)
+            .flat_map(|_| encode(&format, (1, 2, 3)))
+            .collect();
+        image.put_pixels(rect, &pixels).unwrap();
+        assert_eq!(image.image.get_pixel(0, 16), &Rgb([1, 2, 3]));
+
+        // A CPIXEL format that doesn't drop a byte must still be rejected
+        let mut image = Image::new(rgb888_format(32, false), 64, 32).unwrap();
+        assert!(image.put_pixels(rect, &cpixels).is_err());
+    }
+
+    #[test]
+    fn endianness_matrix() {
+        // The formats from Xvfb documented on pixel_to_rgb
+        let rgb555 = PixelFormat {
+            depth: 15,
+            green_max: 31,
+            red_shift: 10,
+            ..rgb565_format()
+        };
+        let formats = [rgb565_format(), rgb555, rgb888_format(24, false)];
+        let colours = [
+            (0xff, 0, 0),
+            (0, 0xff, 0),
+            (0, 0, 0xff),
+            (0x80, 0x40, 0x20),
+            (0xff, 0xff, 0xff),
+        ];
+
+        for format in &formats {
+            for colour in &colours {
+                let little = PixelFormat {
+                    big_endian: false,
+                    ..*format
+                };
+                let big = PixelFormat {
+                    big_endian: true,
+                    ..*format
+                };
+                let from_little = rgb(&little, &encode(&little, *colour));
+                let from_big = rgb(&big, &encode(&big, *colour));
+                assert_eq!(
+                    from_little, from_big,
+                    "{:?} differs between endiannesses in {:?}",
+                    colour, format
+                );
+
+                // Narrow channels lose their low bits but nothing else
+                let mask = |max: u16| 0xff_u8 << (8 - max.count_ones());
+                let expected = (
+                    colour.0 & mask(format.red_max),
+                    colour.1 & mask(format.green_max),
+                    colour.2 & mask(format.blue_max),
+                );
+                assert_eq!(from_little, expected, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn channel_scaling() {
+        assert_eq!(channel_to_u8(0x1ff, 0xff), 0xff);
+        assert_eq!(channel_to_u8(0x0f, 0x0f), 0xff);
+        assert_eq!(channel_to_u8(0, 0x0f), 0);
+        assert_eq!(channel_to_u8(0x3ff, 0x3ff), 0xff);
+    }
+
+    #[test]
+    fn indexed_colour() {
+        let format = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            big_endian: false,
+            true_colour: false,
+            red_max: 0,
+            green_max: 0,
+            blue_max: 0,
+            red_shift: 0,
+            green_shift: 0,
+            blue_shift: 0,
+        };
+        let mut image = Image::new(format, 2, 1).unwrap();
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 2,
+            height: 1,
+        };
+
+        // Pixels can't be decoded before the colour map arrives
+        assert!(image.put_pixels(rect, &[0, 1]).is_err());
+
+        image
+            .set_colour_map(
+                4,
+                vec![
+                    Colour {
+                        red: 0xffff,
+                        green: 0x8000,
+                        blue: 0,
+                    },
+                    Colour {
+                        red: 0x1234,
+                        green: 0,
+                        blue: 0xff00,
+                    },
+                ],
+            )
+            .unwrap();
+        image.put_pixels(rect, &[5, 4]).unwrap();
+        assert_eq!(image.image.get_pixel(0, 0), &Rgb([0x12, 0, 0xff]));
+        assert_eq!(image.image.get_pixel(1, 0), &Rgb([0xff, 0x80, 0]));
+
+        // Unset entries are black, and the map can't overflow 256 entries
+        image.put_pixels(rect, &[0, 0]).unwrap();
+        assert_eq!(image.image.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        let black = Colour {
+            red: 0,
+            green: 0,
+            blue: 0,
+        };
+        assert!(image.set_colour_map(255, vec![black; 2]).is_err());
     }
 
-    tx.send(ThreadStatus::Complete).unwrap();
+    #[test]
+    fn copy_overlapping_pixels() {
+        let mut image = Image::new(rgb888_format(24, false), 4, 1).unwrap();
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 3,
+            height: 1,
+        };
+        image
+            .put_pixels(rect, &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0])
+            .unwrap();
+
+        // Shift right by one pixel, overlapping the source
+        let dst = Rect {
+            left: 1,
+            top: 0,
+            width: 3,
+            height: 1,
+        };
+        image.copy_pixels(rect, dst).unwrap();
+        let blues: Vec<u8> =
+            (0..4).map(|x| image.image.get_pixel(x, 0)[2]).collect();
+        assert_eq!(blues, vec![1, 1, 2, 3]);
+        assert_eq!(image.coverage(), 100.0);
+
+        // Copying from outside the framebuffer is an error
+        let dst = Rect {
+            left: 2,
+            top: 0,
+            width: 3,
+            height: 1,
+        };
+        assert!(image.copy_pixels(rect, dst).is_err());
+    }
+
+    #[test]
+    fn put_pixels_short_buffer() {
+        let mut image = Image::new(rgb565_format(), 8, 8).unwrap();
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 4,
+            height: 4,
+        };
+
+        // 4x4 pixels at 2 bytes per pixel needs 32 bytes
+        assert!(image.put_pixels(rect, &[0; 31]).is_err());
+        assert!(image.put_pixels(rect, &[0; 33]).is_err());
+        assert!(image.put_pixels(rect, &[0; 32]).is_ok());
+    }
+
+    #[test]
+    fn coverage_tracking() {
+        let mut image = Image::new(rgb565_format(), 8, 8).unwrap();
+        assert_eq!(image.coverage(), 0.0);
+
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 4,
+            height: 4,
+        };
+        image.put_pixels(rect, &[0; 32]).unwrap();
+        assert_eq!(image.coverage(), 25.0);
+
+        // Overlapping updates are only counted once
+        let rect = Rect {
+            left: 2,
+            top: 0,
+            width: 4,
+            height: 4,
+        };
+        image.put_pixels(rect, &[0; 32]).unwrap();
+        assert_eq!(image.coverage(), 37.5);
+    }
+
+    #[test]
+    fn vnc_password_key() {
+        assert_eq!(password_key(""), [0; 8]);
+        assert_eq!(password_key("abc"), [b'a', b'b', b'c', 0, 0, 0, 0, 0]);
+        assert_eq!(password_key("password123"), *b"password");
+    }
+
+    const ALL: &[VncAuthMethod] = &[
+        VncAuthMethod::None,
+        VncAuthMethod::Password,
+        VncAuthMethod::VeNCrypt,
+    ];
+
+    #[test]
+    fn vnc_auth_choice() {
+        use AuthMethod::{None as NoAuth, Password};
+        assert!(matches!(
+            choose_auth(&[Password, NoAuth], Some("x"), ALL),
+            Some(AuthChoice::None)
+        ));
+        assert!(matches!(
+            choose_auth(&[Password], Some("x"), ALL),
+            Some(AuthChoice::Password(_))
+        ));
+        assert!(choose_auth(&[Password], None, ALL).is_none());
+
+        // Methods left out of --vnc-auth are never chosen
+        let no_password = &[VncAuthMethod::None, VncAuthMethod::VeNCrypt];
+        assert!(choose_auth(&[Password], Some("x"), no_password).is_none());
+        assert!(matches!(
+            choose_auth(
+                &[Password, NoAuth],
+                Some("x"),
+                &[VncAuthMethod::Password]
+            ),
+            Some(AuthChoice::Password(_))
+        ));
+    }
+
+    #[test]
+    fn vnc_auth_disallowed() {
+        use AuthMethod::{None as NoAuth, Password};
+        assert!(!auth_disallowed(&[Password, NoAuth], ALL));
+        assert!(auth_disallowed(&[Password], &[VncAuthMethod::None]));
+        assert!(!auth_disallowed(&[Password], &[VncAuthMethod::Password]));
+        assert!(auth_disallowed(&[], &[VncAuthMethod::None]));
+        assert!(!auth_disallowed(&[], &[VncAuthMethod::VeNCrypt]));
+    }
+
+    #[test]
+    fn vencrypt_inner_auth() {
+        use AuthMethod::{None as NoAuth, Password};
+        // No password is sent inside the tunnel if only none is allowed
+        let no_password =
+            vencrypt_auth(&[VncAuthMethod::None, VncAuthMethod::VeNCrypt]);
+        assert_eq!(no_password, vec![VncAuthMethod::None]);
+        assert!(choose_auth(&[Password], Some("x"), &no_password).is_none());
+        assert!(matches!(
+            choose_auth(&[Password, NoAuth], Some("x"), &no_password),
+            Some(AuthChoice::None)
+        ));
+
+        let only_vencrypt = vencrypt_auth(&[VncAuthMethod::VeNCrypt]);
+        assert!(matches!(
+            choose_auth(&[Password], Some("x"), &only_vencrypt),
+            Some(AuthChoice::Password(_))
+        ));
+        assert_eq!(
+            vencrypt_auth(&[VncAuthMethod::Password, VncAuthMethod::VeNCrypt]),
+            vec![VncAuthMethod::Password]
+        );
+    }
+
+    #[test]
+    fn frame_scores() {
+        let mut image = RgbImage::from_pixel(4, 3, Rgb([0, 0, 0]));
+        assert_eq!(frame_score(&image), 0);
+
+        image.put_pixel(0, 0, Rgb([255, 255, 255]));
+        image.put_pixel(1, 0, Rgb([0, 0, 255]));
+        assert_eq!(frame_score(&image), 2);
+
+        // Once most of the screen is drawn, that becomes the background
+        for x in 0..4 {
+            for y in 0..2 {
+                image.put_pixel(x, y, Rgb([0, 0, 255]));
+            }
+        }
+        assert_eq!(frame_score(&image), 4);
+    }
+
+    #[test]
+    fn desktop_name_banner() {
+        let mut out = VncOutput {
+            target: "192.0.2.1:5900".to_string(),
+            file: "vnc/192.0.2.1-5900.png".to_string(),
+            duplicate_of: None,
+            desktop_name: "root's X desktop (db01:1)".to_string(),
+            auth: VncAuth::NoPassword,
+            coverage: None,
+            attempts: 1,
+            duration: Duration::from_secs(1),
+            correlation_id: "T00001".to_string(),
+            clipboard: Some("db01.corp.example".to_string()),
+            tls: Some(TlsDetails {
+                subject: Some("CN=db01.corp.example".to_string()),
+                sha256: Some("ab".repeat(32)),
+                ..Default::default()
+            }),
+        };
+        assert_eq!(out.banner(), Some("root's X desktop (db01:1)"));
+
+        out.clear_banner();
+        assert_eq!(out.banner(), None);
+        assert_eq!(out.clipboard(), None);
+        // The certificate's hash doesn't name the host
+        let tls = out.tls().unwrap();
+        assert_eq!(tls.subject, None);
+        assert_eq!(tls.sha256, Some("ab".repeat(32)));
+    }
+
+    #[test]
+    fn clipboard_limit() {
+        assert_eq!(clipboard_text("hunter2".to_string()), "hunter2");
+        let long = "é".repeat(CLIPBOARD_LIMIT + 5);
+        let text = clipboard_text(long);
+        assert_eq!(text.chars().count(), CLIPBOARD_LIMIT + 3);
+        assert!(text.ends_with("é..."));
+    }
+
+    /// Options for capturing from a test server, with the images saved
+    /// under `output_dir`
+    fn test_server_opts(output_dir: &Path) -> Opts {
+        Opts {
+            output_dir: output_dir.display().to_string(),
+            vnc_auth: vec![VncAuthMethod::None],
+            vnc_timeout: 5,
+            vnc_frame_timeout: 5,
+            ..Default::default()
+        }
+    }
+
+    fn test_pattern() -> RgbImage {
+        RgbImage::from_fn(4, 3, |x, y| Rgb([x as u8 * 60, y as u8 * 120, 0xff]))
+    }
+
+    #[test]
+    fn grab_from_test_server() {
+        let addr = test_server::TestServer::new("test desktop", test_pattern())
+            .serve()
+            .unwrap();
+        let opts = test_server_opts(Path::new("."));
+        let frame = grab(&Target::Address(addr), &opts).unwrap();
+        assert_eq!(frame.desktop_name, "test desktop");
+        assert_eq!(frame.auth, VncAuth::NoPassword);
+        assert_eq!(frame.coverage, None);
+        assert_eq!(frame.attempts, 1);
+        assert_eq!(frame.image.as_rgb8(), Some(&test_pattern()));
+
+        // Decoded from the server's own format rather than the 32 bit
+        // format that is usually requested
+        let addr = test_server::TestServer::new("test desktop", test_pattern())
+            .serve()
+            .unwrap();
+        let opts = Opts {
+            vnc_native_format: true,
+            ..opts
+        };
+        let frame = grab(&Target::Address(addr), &opts).unwrap();
+        assert_eq!(frame.image.as_rgb8(), Some(&test_pattern()));
+    }
+
+    #[test]
+    fn grab_zrle_from_test_server() {
+        // More than one 64x64 tile in each direction, with the tiles on
+        // the right a single colour so that they are sent as solid tiles
+        let image = RgbImage::from_fn(70, 66, |x, y| {
+            if x >= 64 {
+                Rgb([0x20, 0x40, 0x80])
+            } else {
+                Rgb([x as u8 * 4, y as u8 * 3, 0xff])
+            }
+        });
+        // Both the usual requested format and the server's own use 3 byte
+        // CPIXELs
+        for native in [false, true].iter() {
+            let addr =
+                test_server::TestServer::new("test desktop", image.clone())
+                    .zrle()
+                    .serve()
+                    .unwrap();
+            let opts = Opts {
+                vnc_native_format: *native,
+                ..test_server_opts(Path::new("."))
+            };
+            let frame = grab(&Target::Address(addr), &opts).unwrap();
+            assert_eq!(frame.coverage, None);
+            assert_eq!(frame.image.as_rgb8(), Some(&image));
+        }
+    }
+
+    #[test]
+    fn region_crops_rects() {
+        let region = Rect {
+            left: 1,
+            top: 1,
+            width: 2,
+            height: 1,
+        };
+        let mut image = Image::new(rgb565_format(), 4, 3)
+            .unwrap()
+            .keep_raw()
+            .unwrap()
+            .with_region(region);
+        assert_eq!(image.image.dimensions(), (2, 1));
+        let update = image.update_rect();
+        assert_eq!((update.left, update.top), (1, 1));
+        assert_eq!((update.width, update.height), (2, 1));
+
+        // The whole framebuffer, with each pixel's value its position
+        let pixels: Vec<u8> = (0..12u8).flat_map(|i| vec![i, 0]).collect();
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            width: 4,
+            height: 3,
+        };
+        image.put_pixels(rect, &pixels).unwrap();
+        assert_eq!(image.coverage(), 100.0);
+        assert_eq!(image.raw.as_deref(), Some(&[5, 0, 6, 0][..]));
+
+        // Entirely outside the region
+        let rect = |left, top, width, height| Rect {
+            left,
+            top,
+            width,
+            height,
+        };
+        assert!(image.put_pixels(rect(3, 0, 1, 3), &[0; 6]).is_ok());
+        image
+            .copy_pixels(rect(1, 1, 1, 1), rect(3, 0, 1, 1))
+            .unwrap();
+        assert_eq!(image.raw.as_deref(), Some(&[5, 0, 6, 0][..]));
+
+        // Within the region
+        image
+            .copy_pixels(rect(2, 1, 1, 1), rect(1, 1, 1, 1))
+            .unwrap();
+        assert_eq!(image.raw.as_deref(), Some(&[6, 0, 6, 0][..]));
+        assert_eq!(image.coverage(), 100.0);
+
+        // Pixels from outside the region weren't kept, so they are no
+        // longer covered
+        image
+            .copy_pixels(rect(0, 1, 2, 1), rect(1, 1, 2, 1))
+            .unwrap();
+        assert_eq!(image.raw.as_deref(), Some(&[0, 0, 6, 0][..]));
+        assert_eq!(image.coverage(), 50.0);
+        assert_eq!(image.image.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn grab_region_from_test_server() {
+        let opts = Opts {
+            vnc_region: Some(VncRegion {
+                left: 1,
+                top: 1,
+                width: 2,
+                height: 2,
+            }),
+            ..test_server_opts(Path::new("."))
+        };
+        let addr = test_server::TestServer::new("test desktop", test_pattern())
+            .serve()
+            .unwrap();
+        let frame = grab(&Target::Address(addr), &opts).unwrap();
+        assert_eq!(frame.coverage, None);
+        let expected = RgbImage::from_fn(2, 2, |x, y| {
+            *test_pattern().get_pixel(x + 1, y + 1)
+        });
+        assert_eq!(frame.image.as_rgb8(), Some(&expected));
+
+        // The test pattern is only 4x3
+        let opts = Opts {
+            vnc_region: Some(VncRegion {
+                left: 2,
+                top: 0,
+                width: 3,
+                height: 3,
+            }),
+            ..opts
+        };
+        let addr = test_server::TestServer::new("test desktop", test_pattern())
+            .serve()
+            .unwrap();
+        assert!(grab(&Target::Address(addr), &opts).is_err());
+    }
+
+    #[test]
+    fn vnc_capture_from_test_server() {
+        let output_dir = std::env::temp_dir()
+            .join(format!("scrying-vnc-test-{}", std::process::id()));
+        fs::create_dir_all(output_dir.join("vnc")).unwrap();
+        let addr = test_server::TestServer::new("test desktop", test_pattern())
+            .clipboard("db01.corp.example")
+            .serve()
+            .unwrap();
+        let target = Target::Address(addr);
+        let opts = Opts {
+            vnc_clipboard: true,
+            ..test_server_opts(&output_dir)
+        };
+
+        let (report_tx, report_rx) = std::sync::mpsc::channel();
+        vnc_capture(&target, &opts, &report_tx).unwrap();
+        let out = match report_rx.try_recv().unwrap() {
+            ReportMessage::VncOutput(out) => out,
+            _ => panic!("expected a VNC output"),
+        };
+        let file =
+            Path::new("vnc").join(util::image_filename(&opts, "vnc", &target));
+        assert_eq!(out.file, file.display().to_string());
+        assert!(output_dir.join(&file).is_file());
+        assert_eq!(out.banner(), Some("test desktop"));
+        assert_eq!(out.clipboard(), Some("db01.corp.example"));
+        assert_eq!(out.auth, VncAuth::NoPassword);
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
 }
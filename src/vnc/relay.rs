@@ -0,0 +1,58 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Loopback relays. The vnc crate only takes a TcpStream, so servers that
+//! are reached some other way are relayed through a loopback TCP
+//! connection that the client can use as if it were the server
+
+use std::io;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+/// Open a loopback connection. Returns the end to give to the vnc client
+/// and the end for the relay to read from and write to
+pub fn loopback() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let (local, peer) = listener.accept()?;
+    // Anything else on the host could connect to the listener, so make
+    // sure that this is the connection that was just made
+    if peer != client.local_addr()? {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "Unexpected connection to the loopback relay",
+        ));
+    }
+    Ok((client, local))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn loopback_pair() {
+        let (mut client, mut local) = loopback().unwrap();
+        assert_eq!(client.peer_addr().unwrap(), local.local_addr().unwrap());
+        local.write_all(b"RFB 003.008\n").unwrap();
+        let mut banner = [0; 12];
+        client.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"RFB 003.008\n");
+    }
+}
@@ -0,0 +1,369 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! A minimal RFB server for testing captures without a real VNC server.
+//! It offers no authentication and answers each non-incremental update
+//! request with the whole framebuffer as a single raw rectangle, in
+//! whatever pixel format the client has asked for. With `zrle` the
+//! rectangle is ZRLE encoded instead if the client supports it, using
+//! uncompressed deflate blocks so that no compressor is needed
+
+use image::RgbImage;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use vnc::PixelFormat;
+
+/// Pixel format given in the ServerInit message, 32 bit little endian
+/// with the padding in the high byte
+const SERVER_FORMAT: PixelFormat = PixelFormat {
+    bits_per_pixel: 32,
+    depth: 24,
+    big_endian: false,
+    true_colour: true,
+    red_max: 255,
+    green_max: 255,
+    blue_max: 255,
+    red_shift: 16,
+    green_shift: 8,
+    blue_shift: 0,
+};
+
+const ZRLE_ENCODING: i32 = 16;
+
+/// ZRLE rectangles are split into tiles of up to this many pixels square
+const ZRLE_TILE: u32 = 64;
+
+pub struct TestServer {
+    name: String,
+    image: RgbImage,
+    /// Sent as ServerCutText before the first framebuffer update
+    clipboard: Option<String>,
+    /// Whether to send ZRLE rectangles to clients that ask for them
+    zrle: bool,
+}
+
+impl TestServer {
+    pub fn new(name: &str, image: RgbImage) -> Self {
+        Self {
+            name: name.to_string(),
+            image,
+            clipboard: None,
+            zrle: false,
+        }
+    }
+
+    pub fn clipboard(mut self, text: &str) -> Self {
+        self.clipboard = Some(text.to_string());
+        self
+    }
+
+    pub fn zrle(mut self) -> Self {
+        self.zrle = true;
+        self
+    }
+
+    /// Listen on a loopback port and serve a single connection in the
+    /// background, returning the address to connect to
+    pub fn serve(self) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            self.session(stream)
+        });
+        Ok(addr)
+    }
+
+    fn session(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.write_all(b"RFB 003.008\n")?;
+        read_bytes(&mut stream, 12)?;
+
+        // One security type, None, which the client has to choose
+        stream.write_all(&[1, 1])?;
+        if read_u8(&mut stream)? != 1 {
+            return Err(protocol_error("unexpected security type"));
+        }
+        stream.write_all(&0u32.to_be_bytes())?;
+
+        // ClientInit, with the shared flag
+        read_u8(&mut stream)?;
+        let (width, height) = self.image.dimensions();
+        let mut init = Vec::new();
+        init.extend_from_slice(&(width as u16).to_be_bytes());
+        init.extend_from_slice(&(height as u16).to_be_bytes());
+        init.extend_from_slice(&encode_format(&SERVER_FORMAT));
+        init.extend_from_slice(&(self.name.len() as u32).to_be_bytes());
+        init.extend_from_slice(self.name.as_bytes());
+        stream.write_all(&init)?;
+
+        let mut format = SERVER_FORMAT;
+        let mut clipboard = self.clipboard.as_deref();
+        let mut zrle = false;
+        // ZRLE uses one zlib stream for the whole session, so only the
+        // first rectangle starts with the zlib header
+        let mut zlib_started = false;
+        loop {
+            // The client disconnecting ends the session
+            let message = match read_u8(&mut stream) {
+                Ok(message) => message,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e),
+            };
+            match message {
+                // SetPixelFormat
+                0 => {
+                    let data = read_bytes(&mut stream, 19)?;
+                    format = decode_format(&data[3..]);
+                }
+                // SetEncodings. Raw is always allowed, so only whether
+                // ZRLE is in the list matters
+                2 => {
+                    let data = read_bytes(&mut stream, 3)?;
+                    let count = u16::from_be_bytes([data[1], data[2]]);
+                    let encodings =
+                        read_bytes(&mut stream, count as usize * 4)?;
+                    zrle = self.zrle
+                        && encodings.chunks_exact(4).any(|e| {
+                            i32::from_be_bytes([e[0], e[1], e[2], e[3]])
+                                == ZRLE_ENCODING
+                        });
+                }
+                // FramebufferUpdateRequest
+                3 => {
+                    let incremental = read_bytes(&mut stream, 9)?[0] != 0;
+                    if let Some(text) = clipboard.take() {
+                        stream.write_all(&server_cut_text(text))?;
+                    }
+                    // Nothing ever changes, so incremental requests are
+                    // never answered
+                    if !incremental && zrle {
+                        let update = self.zrle_update(&format, zlib_started)?;
+                        stream.write_all(&update)?;
+                        zlib_started = true;
+                    } else if !incremental {
+                        stream.write_all(&self.update(&format)?)?;
+                    }
+                }
+                // KeyEvent
+                4 => {
+                    read_bytes(&mut stream, 7)?;
+                }
+                // PointerEvent
+                5 => {
+                    read_bytes(&mut stream, 5)?;
+                }
+                // ClientCutText
+                6 => {
+                    let data = read_bytes(&mut stream, 7)?;
+                    let length = u32::from_be_bytes([
+                        data[3], data[4], data[5], data[6],
+                    ]);
+                    read_bytes(&mut stream, length as usize)?;
+                }
+                _ => return Err(protocol_error("unexpected client message")),
+            }
+        }
+    }
+
+    /// A FramebufferUpdate with the whole image as one raw rectangle
+    fn update(&self, format: &PixelFormat) -> io::Result<Vec<u8>> {
+        let (width, height) = self.image.dimensions();
+        let mut update = vec![0, 0];
+        update.extend_from_slice(&1u16.to_be_bytes());
+        for value in &[0, 0, width as u16, height as u16] {
+            update.extend_from_slice(&value.to_be_bytes());
+        }
+        // Raw encoding
+        update.extend_from_slice(&0i32.to_be_bytes());
+        for pixel in self.image.pixels() {
+            update.extend_from_slice(&encode_pixel(format, pixel.0)?);
+        }
+        Ok(update)
+    }
+
+    /// A FramebufferUpdate with the whole image as one ZRLE rectangle.
+    /// Tiles of a single colour are sent as solid tiles and the rest as
+    /// raw CPIXELs
+    fn zrle_update(
+        &self,
+        format: &PixelFormat,
+        zlib_started: bool,
+    ) -> io::Result<Vec<u8>> {
+        let (width, height) = self.image.dimensions();
+        let mut tiles = Vec::new();
+        for tile_y in (0..height).step_by(ZRLE_TILE as usize) {
+            for tile_x in (0..width).step_by(ZRLE_TILE as usize) {
+                let mut pixels = Vec::new();
+                for y in tile_y..height.min(tile_y + ZRLE_TILE) {
+                    for x in tile_x..width.min(tile_x + ZRLE_TILE) {
+                        pixels.push(self.image.get_pixel(x, y).0);
+                    }
+                }
+                if pixels.iter().all(|p| *p == pixels[0]) {
+                    tiles.push(1);
+                    tiles.extend(encode_cpixel(format, pixels[0])?);
+                } else {
+                    tiles.push(0);
+                    for pixel in pixels {
+                        tiles.extend(encode_cpixel(format, pixel)?);
+                    }
+                }
+            }
+        }
+
+        let mut zlib = Vec::new();
+        if !zlib_started {
+            // Deflate with a 32K window and no preset dictionary
+            zlib.extend_from_slice(&[0x78, 0x01]);
+        }
+        for block in tiles.chunks(u16::MAX as usize) {
+            // A stored block that isn't the last, so the stream carries
+            // on into the next rectangle
+            let len = block.len() as u16;
+            zlib.push(0);
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+
+        let mut update = vec![0, 0];
+        update.extend_from_slice(&1u16.to_be_bytes());
+        for value in &[0, 0, width as u16, height as u16] {
+            update.extend_from_slice(&value.to_be_bytes());
+        }
+        update.extend_from_slice(&ZRLE_ENCODING.to_be_bytes());
+        update.extend_from_slice(&(zlib.len() as u32).to_be_bytes());
+        update.extend(zlib);
+        Ok(update)
+    }
+}
+
+/// A pixel as a ZRLE CPIXEL, which leaves out the unused byte of 32 bit
+/// pixels when the colour fits in the other three (RFC 6143 section
+/// 7.7.6)
+fn encode_cpixel(format: &PixelFormat, rgb: [u8; 3]) -> io::Result<Vec<u8>> {
+    let mut pixel = encode_pixel(format, rgb)?;
+    if format.bits_per_pixel != 32 || format.depth > 24 {
+        return Ok(pixel);
+    }
+    let mask = (u32::from(format.red_max) << format.red_shift)
+        | (u32::from(format.green_max) << format.green_shift)
+        | (u32::from(format.blue_max) << format.blue_shift);
+    let unused = if mask & 0xff00_0000 == 0 {
+        0xff00_0000u32
+    } else if mask & 0x0000_00ff == 0 {
+        0x0000_00ff
+    } else {
+        return Ok(pixel);
+    };
+    let unused_bytes = if format.big_endian {
+        unused.to_be_bytes()
+    } else {
+        unused.to_le_bytes()
+    };
+    let index = unused_bytes.iter().position(|b| *b != 0).unwrap();
+    pixel.remove(index);
+    Ok(pixel)
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u8(stream: &mut TcpStream) -> io::Result<u8> {
+    Ok(read_bytes(stream, 1)?[0])
+}
+
+fn read_bytes(stream: &mut TcpStream, length: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; length];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn encode_format(format: &PixelFormat) -> Vec<u8> {
+    let mut data = vec![
+        format.bits_per_pixel,
+        format.depth,
+        format.big_endian as u8,
+        format.true_colour as u8,
+    ];
+    for max in &[format.red_max, format.green_max, format.blue_max] {
+        data.extend_from_slice(&max.to_be_bytes());
+    }
+    data.extend_from_slice(&[
+        format.red_shift,
+        format.green_shift,
+        format.blue_shift,
+        0,
+        0,
+        0,
+    ]);
+    data
+}
+
+fn decode_format(data: &[u8]) -> PixelFormat {
+    let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    PixelFormat {
+        bits_per_pixel: data[0],
+        depth: data[1],
+        big_endian: data[2] != 0,
+        true_colour: data[3] != 0,
+        red_max: u16_at(4),
+        green_max: u16_at(6),
+        blue_max: u16_at(8),
+        red_shift: data[10],
+        green_shift: data[11],
+        blue_shift: data[12],
+    }
+}
+
+/// Scale each channel to the format's maximum and pack the pixel into
+/// the format's byte order. Colour maps aren't supported
+fn encode_pixel(format: &PixelFormat, rgb: [u8; 3]) -> io::Result<Vec<u8>> {
+    if !format.true_colour {
+        return Err(protocol_error("colour map formats aren't supported"));
+    }
+    let channel = |value: u8, max: u16, shift: u8| {
+        (value as u32 * max as u32 / 255) << shift
+    };
+    let value = channel(rgb[0], format.red_max, format.red_shift)
+        | channel(rgb[1], format.green_max, format.green_shift)
+        | channel(rgb[2], format.blue_max, format.blue_shift);
+    let bytes = match format.bits_per_pixel {
+        8 => 1,
+        16 => 2,
+        32 => 4,
+        _ => return Err(protocol_error("unsupported bits per pixel")),
+    };
+    Ok(if format.big_endian {
+        value.to_be_bytes()[4 - bytes..].to_vec()
+    } else {
+        value.to_le_bytes()[..bytes].to_vec()
+    })
+}
+
+fn server_cut_text(text: &str) -> Vec<u8> {
+    let mut message = vec![3, 0, 0, 0];
+    message.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    message.extend_from_slice(text.as_bytes());
+    message
+}
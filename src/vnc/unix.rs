@@ -0,0 +1,96 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! VNC servers listening on a Unix domain socket, which are relayed
+//! through a loopback TCP connection
+
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::io;
+use std::net::TcpStream;
+use std::path::Path;
+
+/// Connect to the socket at `path` and return a loopback connection
+/// that carries the same data
+#[cfg(unix)]
+pub fn connect(path: &Path) -> io::Result<TcpStream> {
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    let upstream = UnixStream::connect(path)?;
+    let (client, local) = super::relay::loopback()?;
+
+    let (mut upstream_rx, mut local_tx) =
+        (upstream.try_clone()?, local.try_clone()?);
+    thread::spawn(move || {
+        if let Err(e) = io::copy(&mut upstream_rx, &mut local_tx) {
+            debug!("Unix socket relay stopped: {}", e);
+        }
+        let _ = local_tx.shutdown(Shutdown::Both);
+    });
+    let (mut local_rx, mut upstream_tx) = (local, upstream);
+    thread::spawn(move || {
+        if let Err(e) = io::copy(&mut local_rx, &mut upstream_tx) {
+            debug!("Unix socket relay stopped: {}", e);
+        }
+        let _ = upstream_tx.shutdown(Shutdown::Both);
+    });
+    Ok(client)
+}
+
+#[cfg(not(unix))]
+pub fn connect(_path: &Path) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets aren't supported on this platform",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn relay_unix_socket() {
+        let path = std::env::temp_dir()
+            .join(format!("scrying-unix-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"RFB 003.008\n").unwrap();
+            let mut reply = [0; 12];
+            stream.read_exact(&mut reply).unwrap();
+            reply
+        });
+
+        let mut stream = connect(&path).unwrap();
+        let mut banner = [0; 12];
+        stream.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"RFB 003.008\n");
+        stream.write_all(b"RFB 003.003\n").unwrap();
+        assert_eq!(&server.join().unwrap(), b"RFB 003.003\n");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! VeNCrypt support. The vnc crate only speaks plain RFB over a
+//! TcpStream, so the VeNCrypt negotiation and TLS handshake are done here
+//! and the vnc client is given a loopback connection to a relay that
+//! makes the server look like a plain RFB server offering the security
+//! type that VeNCrypt uses inside the TLS tunnel
+
+use crate::tls::{self, Recorder, TlsDetails};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use native_tls::TlsConnector;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+const SECURITY_NONE: u8 = 1;
+const SECURITY_VNC_AUTH: u8 = 2;
+const SECURITY_VENCRYPT: u8 = 19;
+
+/// VeNCrypt subtypes that use X.509 certificates. The anonymous TLS
+/// subtypes (TLSNone and TLSVnc) need anonymous Diffie-Hellman cipher
+/// suites, which native-tls doesn't offer
+const X509_NONE: u32 = 260;
+const X509_VNC: u32 = 261;
+
+/// How long the relay waits for data from one side before checking the
+/// other
+const RELAY_POLL: Duration = Duration::from_millis(10);
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Negotiate VeNCrypt on a freshly opened connection, wrap it in TLS and
+/// return a connection to a local relay that the vnc client can use as
+/// if it were talking to the server directly, along with the details of
+/// the TLS session. Unless `insecure` is set the server's certificate
+/// must be valid for `domain`
+pub fn connect(
+    mut stream: TcpStream,
+    domain: &str,
+    insecure: bool,
+) -> io::Result<(TcpStream, TlsDetails)> {
+    let inner = negotiate(&mut stream)?;
+
+    let mut builder = TlsConnector::builder();
+    if insecure {
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    let connector = builder
+        .build()
+        .map_err(|e| invalid(format!("TLS error: {}", e)))?;
+    let mut tls =
+        connector
+            .connect(domain, Recorder::new(stream))
+            .map_err(|e| {
+                invalid(format!("VeNCrypt TLS handshake failed: {}", e))
+            })?;
+    debug!("VeNCrypt TLS session established");
+    let details = tls::details(&mut tls);
+
+    tls.get_ref().get_ref().set_read_timeout(Some(RELAY_POLL))?;
+    Ok((spawn_relay(tls, inner)?, details))
+}
+
+/// Perform the RFB version exchange and VeNCrypt negotiation up to the
+/// start of the TLS handshake. Returns the security type that the server
+/// will use inside the tunnel
+fn negotiate<S: Read + Write>(stream: &mut S) -> io::Result<u8> {
+    let mut version = [0; 12];
+    stream.read_exact(&mut version)?;
+    // Versions are zero-padded so compare as bytes
+    if &version[..4] != b"RFB " || version < *b"RFB 003.007\n" {
+        return Err(invalid(format!(
+            "VeNCrypt needs RFB 3.7 or later, server version is {:?}",
+            String::from_utf8_lossy(&version).trim_end()
+        )));
+    }
+    stream.write_all(b"RFB 003.008\n")?;
+
+    let mut count = [0; 1];
+    stream.read_exact(&mut count)?;
+    let mut types = vec![0; usize::from(count[0])];
+    stream.read_exact(&mut types)?;
+    debug!("Security types offered: {:?}", types);
+    if !types.contains(&SECURITY_VENCRYPT) {
+        return Err(invalid(format!(
+            "Server does not offer VeNCrypt, security types: {:?}",
+            types
+        )));
+    }
+    stream.write_all(&[SECURITY_VENCRYPT])?;
+
+    let mut vencrypt_version = [0; 2];
+    stream.read_exact(&mut vencrypt_version)?;
+    if vencrypt_version < [0, 2] {
+        return Err(invalid(format!(
+            "Unsupported VeNCrypt version {}.{}",
+            vencrypt_version[0], vencrypt_version[1]
+        )));
+    }
+    stream.write_all(&[0, 2])?;
+    let mut ack = [0; 1];
+    stream.read_exact(&mut ack)?;
+    if ack[0] != 0 {
+        return Err(invalid("Server rejected VeNCrypt version 0.2".into()));
+    }
+
+    stream.read_exact(&mut count)?;
+    let mut subtypes = Vec::with_capacity(usize::from(count[0]));
+    for _ in 0..count[0] {
+        let mut subtype = [0; 4];
+        stream.read_exact(&mut subtype)?;
+        subtypes.push(u32::from_be_bytes(subtype));
+    }
+    debug!("VeNCrypt subtypes offered: {:?}", subtypes);
+    // Prefer no authentication, as with plain RFB
+    let (subtype, inner) = if subtypes.contains(&X509_NONE) {
+        (X509_NONE, SECURITY_NONE)
+    } else if subtypes.contains(&X509_VNC) {
+        (X509_VNC, SECURITY_VNC_AUTH)
+    } else {
+        return Err(invalid(format!(
+            "No supported VeNCrypt subtypes, server offered {:?}",
+            subtypes
+        )));
+    };
+    stream.write_all(&subtype.to_be_bytes())?;
+    stream.read_exact(&mut ack)?;
+    if ack[0] != 1 {
+        return Err(invalid(format!(
+            "Server rejected VeNCrypt subtype {}",
+            subtype
+        )));
+    }
+
+    Ok(inner)
+}
+
+/// Start a relay between a loopback connection and the upstream
+/// connection, and return a connection to it. The relay stops when
+/// either side disconnects.
+fn spawn_relay<S>(upstream: S, inner: u8) -> io::Result<TcpStream>
+where
+    S: Read + Write + Send + 'static,
+{
+    let (client, local) = super::relay::loopback()?;
+    thread::spawn(move || {
+        if let Err(e) = relay(upstream, local, inner) {
+            debug!("VeNCrypt relay stopped: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+/// Act as a plain RFB 3.8 server offering only the inner security type
+/// to the local client, then pass everything through in both directions.
+/// The upstream stream must have a short read timeout so that the relay
+/// can switch between the two sides.
+fn relay<S: Read + Write>(
+    mut upstream: S,
+    mut local: TcpStream,
+    inner: u8,
+) -> io::Result<()> {
+    local.write_all(b"RFB 003.008\n")?;
+    let mut version = [0; 12];
+    local.read_exact(&mut version)?;
+    local.write_all(&[1, inner])?;
+    let mut choice = [0; 1];
+    local.read_exact(&mut choice)?;
+    if choice[0] != inner {
+        return Err(invalid(format!(
+            "Client chose unexpected security type {}",
+            choice[0]
+        )));
+    }
+
+    local.set_read_timeout(Some(RELAY_POLL))?;
+    let mut buf = vec![0; 64 * 1024];
+    while pass(&mut local, &mut upstream, &mut buf)?
+        && pass(&mut upstream, &mut local, &mut buf)?
+    {}
+    Ok(())
+}
+
+/// Copy whatever data is waiting from one side to the other. Returns
+/// false once the sending side has closed the connection
+fn pass<R: Read, W: Write>(
+    from: &mut R,
+    to: &mut W,
+    buf: &mut [u8],
+) -> io::Result<bool> {
+    match from.read(buf) {
+        Ok(0) => Ok(false),
+        Ok(n) => {
+            to.write_all(&buf[..n])?;
+            Ok(true)
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    /// Run `server` against one end of a loopback connection and return
+    /// the other end
+    fn fake_server<F>(server: F) -> (TcpStream, thread::JoinHandle<()>)
+    where
+        F: FnOnce(TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server(stream);
+        });
+        (TcpStream::connect(addr).unwrap(), handle)
+    }
+
+    fn vencrypt_server(subtypes: &'static [u32]) -> TcpStream {
+        let (client, _) = fake_server(move |mut s| {
+            let mut buf = [0; 12];
+            s.write_all(b"RFB 003.008\n").unwrap();
+            s.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"RFB 003.008\n");
+            s.write_all(&[2, SECURITY_VNC_AUTH, SECURITY_VENCRYPT])
+                .unwrap();
+            s.read_exact(&mut buf[..1]).unwrap();
+            assert_eq!(buf[0], SECURITY_VENCRYPT);
+            s.write_all(&[0, 2]).unwrap();
+            s.read_exact(&mut buf[..2]).unwrap();
+            assert_eq!(buf[..2], [0, 2]);
+            s.write_all(&[0, subtypes.len() as u8]).unwrap();
+            for subtype in subtypes {
+                s.write_all(&subtype.to_be_bytes()).unwrap();
+            }
+            if s.read_exact(&mut buf[..4]).is_ok() {
+                s.write_all(&[1]).unwrap();
+            }
+        });
+        client
+    }
+
+    #[test]
+    fn negotiate_subtype() {
+        let mut stream = vencrypt_server(&[257, 261, 260]);
+        assert_eq!(negotiate(&mut stream).unwrap(), SECURITY_NONE);
+
+        let mut stream = vencrypt_server(&[258, 261]);
+        assert_eq!(negotiate(&mut stream).unwrap(), SECURITY_VNC_AUTH);
+
+        // Only anonymous TLS
+        let mut stream = vencrypt_server(&[257, 258]);
+        assert!(negotiate(&mut stream).is_err());
+    }
+
+    #[test]
+    fn negotiate_without_vencrypt() {
+        let (mut stream, _) = fake_server(|mut s| {
+            s.write_all(b"RFB 003.008\n").unwrap();
+            let mut buf = [0; 12];
+            s.read_exact(&mut buf).unwrap();
+            s.write_all(&[1, SECURITY_VNC_AUTH]).unwrap();
+        });
+        assert!(negotiate(&mut stream).is_err());
+
+        let (mut stream, _) = fake_server(|mut s| {
+            s.write_all(b"RFB 003.003\n").unwrap();
+        });
+        assert!(negotiate(&mut stream).is_err());
+    }
+
+    #[test]
+    fn relay_passes_data() {
+        let (upstream, server) = fake_server(|mut s| {
+            s.write_all(b"challenge").unwrap();
+            let mut buf = [0; 8];
+            s.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"response");
+        });
+        upstream.set_read_timeout(Some(RELAY_POLL)).unwrap();
+        let mut local = spawn_relay(upstream, SECURITY_VNC_AUTH).unwrap();
+
+        let mut buf = [0; 12];
+        local.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"RFB 003.008\n");
+        local.write_all(b"RFB 003.008\n").unwrap();
+        local.read_exact(&mut buf[..2]).unwrap();
+        assert_eq!(buf[..2], [1, SECURITY_VNC_AUTH]);
+        local.write_all(&[SECURITY_VNC_AUTH]).unwrap();
+
+        local.read_exact(&mut buf[..9]).unwrap();
+        assert_eq!(&buf[..9], b"challenge");
+        local.write_all(b"response").unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Deadline for a whole capture, for --target-timeout. Threads can't be
+//! killed, so a capture that runs past its deadline is left running in
+//! the background and its results are dropped. The abandoned capture
+//! keeps its host permit until it really finishes, as it may still be
+//! connected to the host.
+
+use crate::error::Error;
+use crate::logging;
+use crate::parsing::Target;
+use crate::reporting::{AsReportMessage, FailureOutput, ReportMessage};
+use crate::throttle::HostPermit;
+use crate::ThreadStatus;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Run `capture` with the given status and report channels. With a
+/// timeout it runs in its own thread, and its report messages are
+/// passed on until it finishes or the timeout passes. After the timeout
+/// a failure is reported for the target and `tx` is told that the
+/// worker is complete. `permit` is held until the capture finishes,
+/// even if it has been abandoned.
+pub fn run<F>(
+    timeout: Option<Duration>,
+    mode: &'static str,
+    target: &Target,
+    permit: HostPermit,
+    tx: Sender<ThreadStatus>,
+    report_tx: &Sender<ReportMessage>,
+    capture: F,
+) where
+    F: FnOnce(Sender<ThreadStatus>, &Sender<ReportMessage>) + Send + 'static,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            capture(tx, report_tx);
+            drop(permit);
+            return;
+        }
+    };
+
+    let start = Instant::now();
+    let deadline = start + timeout;
+    // The capture's status is only used to tell when it has finished,
+    // which is also when its report sender is dropped
+    let (status_tx, _status_rx) = mpsc::channel();
+    let (capture_tx, capture_rx) = mpsc::channel();
+    let id = logging::correlation_id();
+    let context_target = target.clone();
+    thread::spawn(move || {
+        logging::set_correlation_id(id);
+        logging::set_target(mode, &context_target);
+        capture(status_tx, &capture_tx);
+        drop(permit);
+    });
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match capture_rx.recv_timeout(remaining) {
+            Ok(message) => {
+                if report_tx.send(message).is_err() {
+                    warn!("Report channel closed");
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                warn!(
+                    "Capture still running after {}s, abandoning it",
+                    timeout.as_secs()
+                );
+                let error = Error::CaptureTimeout(timeout.as_secs());
+                let failure =
+                    FailureOutput::new(target, mode, &error, start.elapsed());
+                if report_tx.send(failure.as_report_message()).is_err() {
+                    warn!("Report channel closed");
+                }
+                break;
+            }
+        }
+    }
+    let _ = tx.send(ThreadStatus::Complete);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::throttle::HostLimiter;
+    use std::sync::Arc;
+
+    fn target() -> Target {
+        Target::Address("192.0.2.1:5900".parse().unwrap())
+    }
+
+    #[test]
+    fn abandon_hung_capture() {
+        let limiter = Arc::new(HostLimiter::new(None, None));
+        let (tx, rx) = mpsc::channel();
+        let (report_tx, report_rx) = mpsc::channel();
+        let start = Instant::now();
+        run(
+            Some(Duration::from_millis(100)),
+            "vnc",
+            &target(),
+            limiter.acquire(&target()),
+            tx,
+            &report_tx,
+            |tx, report_tx| {
+                thread::sleep(Duration::from_secs(5));
+                let error = Error::VncError("too late".to_string());
+                let failure = FailureOutput::new(
+                    &target(),
+                    "vnc",
+                    &error,
+                    Duration::from_secs(5),
+                );
+                let _ = report_tx.send(failure.as_report_message());
+                let _ = tx.send(ThreadStatus::Complete);
+            },
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(rx.try_recv(), Ok(ThreadStatus::Complete)));
+        match report_rx.try_recv() {
+            Ok(ReportMessage::Failure(f)) => {
+                assert_eq!(f.mode(), "vnc");
+                assert_eq!(
+                    f.reason(),
+                    "Capture still running after 0s, abandoned"
+                );
+            }
+            _ => panic!("Expected a failure report"),
+        }
+        assert!(report_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn pass_on_finished_capture() {
+        let limiter = Arc::new(HostLimiter::new(None, None));
+        let (tx, rx) = mpsc::channel();
+        let (report_tx, report_rx) = mpsc::channel();
+        run(
+            Some(Duration::from_secs(5)),
+            "rdp",
+            &target(),
+            limiter.acquire(&target()),
+            tx,
+            &report_tx,
+            |tx, report_tx| {
+                let error = Error::RdpError("refused".to_string());
+                let failure = FailureOutput::new(
+                    &target(),
+                    "rdp",
+                    &error,
+                    Duration::from_secs(1),
+                );
+                report_tx.send(failure.as_report_message()).unwrap();
+                tx.send(ThreadStatus::Complete).unwrap();
+            },
+        );
+        assert!(matches!(rx.try_recv(), Ok(ThreadStatus::Complete)));
+        match report_rx.try_recv() {
+            Ok(ReportMessage::Failure(f)) => {
+                assert_eq!(f.reason(), "RDP error: refused")
+            }
+            _ => panic!("Expected the capture's report"),
+        }
+    }
+
+    #[test]
+    fn abandoned_capture_keeps_permit() {
+        let limiter = Arc::new(HostLimiter::new(Some(1), None));
+        let (tx, _rx) = mpsc::channel();
+        let (report_tx, _report_rx) = mpsc::channel();
+        run(
+            Some(Duration::from_millis(100)),
+            "rdp",
+            &target(),
+            limiter.acquire(&target()),
+            tx,
+            &report_tx,
+            |_, _| thread::sleep(Duration::from_millis(500)),
+        );
+
+        // The host is busy until the abandoned capture finishes
+        let (acquired_tx, acquired_rx) = mpsc::channel();
+        let limiter_clone = Arc::clone(&limiter);
+        thread::spawn(move || {
+            let _permit = limiter_clone.acquire(&target());
+            let _ = acquired_tx.send(());
+        });
+        assert!(acquired_rx
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+        assert!(acquired_rx.recv_timeout(Duration::from_secs(2)).is_ok());
+    }
+}
@@ -17,21 +17,62 @@
  *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::argparse::{ImageFormat, Opts};
+use crate::dedupe;
 use crate::error::Error;
-use crate::parsing::Target;
+use crate::events::{self, Event};
+use crate::logging;
+use crate::parsing::{InputLists, Target};
 use crate::reporting::{AsReportMessage, ReportMessage};
-use crate::util::target_to_filename;
-use headless_chrome::{protocol::page::ScreenshotFormat, Tab};
+use crate::resolve;
+use crate::sink;
+use crate::throttle;
+use crate::util::{image_filename, is_uniform};
+use headless_chrome::{
+    protocol::page::ScreenshotFormat, Browser, LaunchOptionsBuilder, Tab,
+};
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::path::Path;
 use std::sync::mpsc;
-use std::{fs::File, io::Write};
+use std::time::{Duration, Instant};
+use url::Url;
+
+mod page_hash;
+mod scheme_probe;
 
 #[derive(Debug)]
 pub struct WebOutput {
     url: String,
     file: String,
+    duplicate_of: Option<String>,
+    title: Option<String>,
+    scroll_size: Option<(u64, u64)>,
+    body_hash: Option<String>,
+    scheme_probed: bool,
+    duration: Duration,
+    correlation_id: String,
+}
+
+impl WebOutput {
+    /// Rendered document size as "WIDTHxHEIGHT", if it could be read.
+    /// This may be larger than the captured viewport
+    pub fn scroll_size(&self) -> Option<String> {
+        self.scroll_size.map(|(w, h)| format!("{}x{}", w, h))
+    }
+
+    /// Hash of the normalised page content, see `page_hash`
+    pub fn body_hash(&self) -> Option<&str> {
+        self.body_hash.as_deref()
+    }
+
+    /// Whether the scheme was chosen by probing the server rather than
+    /// taken from the target
+    pub fn scheme_probed(&self) -> bool {
+        self.scheme_probed
+    }
 }
 
 impl AsReportMessage for WebOutput {
@@ -44,32 +85,246 @@ impl AsReportMessage for WebOutput {
     fn file(&self) -> &str {
         &self.file
     }
+    fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+    fn set_target(&mut self, target: String) {
+        self.url = target;
+    }
+    fn duplicate_of(&self) -> Option<&str> {
+        self.duplicate_of.as_deref()
+    }
+    fn set_duplicate_of(&mut self, target: String) {
+        self.duplicate_of = Some(target);
+    }
+    fn banner(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+    fn clear_banner(&mut self) {
+        self.title = None;
+    }
+}
+
+/// Read the document title, if the page has one
+fn get_title(tab: &Tab) -> Result<Option<String>, Error> {
+    let title = tab
+        .evaluate("document.title", false)?
+        .value
+        .and_then(|v| v.as_str().map(|t| t.trim().to_string()));
+    Ok(title.filter(|t| !t.is_empty()))
+}
+
+/// Read the total scroll width and height of the rendered document
+fn get_scroll_size(tab: &Tab) -> Result<Option<(u64, u64)>, Error> {
+    let width = tab
+        .evaluate(
+            "Math.max(document.body.scrollWidth, \
+            document.documentElement.scrollWidth)",
+            false,
+        )?
+        .value
+        .and_then(|v| v.as_u64());
+    let height = tab
+        .evaluate(
+            "Math.max(document.body.scrollHeight, \
+            document.documentElement.scrollHeight)",
+            false,
+        )?
+        .value
+        .and_then(|v| v.as_u64());
+
+    Ok(width.and_then(|w| height.map(|h| (w, h))))
+}
+
+/// For bare targets on a non-standard port, probe whether the server
+/// speaks HTTP or HTTPS. Returns the target to capture along with whether its scheme
+/// came from the probe. If probing fails the original target is kept
+pub fn choose_scheme(target: &Target) -> (Target, bool) {
+    if let Target::Url(url) = target {
+        match scheme_probe::probe_scheme(url) {
+            Ok(Some(probed)) => {
+                info!("{} speaks {}", target, probed.scheme());
+                return (Target::Url(probed), true);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Unable to probe scheme for {}: {}", target, e),
+        }
+    }
+    (target.clone(), false)
+}
+
+/// HTTP targets to try if the HTTPS target of the same name can't be
+/// captured, keyed by the HTTPS target. Bare hosts are parsed as both, and
+/// usually only one of them works. URLs that were given with a scheme are
+/// always captured
+pub fn scheme_fallbacks(targets: &InputLists) -> HashMap<String, Target> {
+    let urls: Vec<&Url> = targets
+        .web_targets
+        .iter()
+        .filter(|t| targets.is_bare_web_target(t))
+        .filter_map(|t| match t {
+            Target::Url(u) => Some(u),
+            Target::Address(_) => None,
+        })
+        .collect();
+    scheme_probe::http_fallbacks(&urls)
+        .into_iter()
+        .map(|(https, http)| (https, Target::Url(http)))
+        .collect()
+}
+
+/// Start a headless Chrome for taking screenshots, using the web proxy
+/// if there is one
+pub fn launch_browser(opts: &Opts) -> Result<Browser, Error> {
+    let mut chrome_env = HashMap::new();
+    if let Some(p) = &opts.web_proxy {
+        if p.starts_with("socks5://") && p.contains('@') {
+            warn!(
+                "Chrome can't log in to SOCKS5 proxies, so web captures \
+                through this one will fail"
+            );
+        }
+        chrome_env.insert("http_proxy".to_string(), p.to_string());
+        chrome_env.insert("https_proxy".to_string(), p.to_string());
+    }
+    let mut chrome_args = Vec::new();
+    if opts.insecure {
+        chrome_args.push(OsStr::new("--ignore-certificate-errors"));
+    }
+    // Chrome resolves hosts itself, so it's told the addresses that were
+    // found with --hosts-file or --resolver
+    let resolver_rules = resolve::host_resolver_rules()
+        .map(|rules| format!("--host-resolver-rules={}", rules));
+    if let Some(rules) = &resolver_rules {
+        chrome_args.push(OsStr::new(rules));
+    }
+    let user_agent = format!("--user-agent={}", opts.user_agent);
+    if !opts.user_agent.is_empty() {
+        chrome_args.push(OsStr::new(&user_agent));
+    }
+    let launch_options = LaunchOptionsBuilder::default()
+        .headless(true)
+        .window_size(Some((1280, 720)))
+        .process_envs(Some(chrome_env))
+        .args(chrome_args)
+        .build()
+        .map_err(Error::ChromeError)?;
+    Ok(Browser::new(launch_options)?)
+}
+
+/// A screenshot of a web page along with details read from the page
+pub(crate) struct Page {
+    /// The screenshot, encoded in the chosen image format
+    pub image_data: Vec<u8>,
+    pub title: Option<String>,
+    scroll_size: Option<(u64, u64)>,
+    body_hash: Option<String>,
+}
+
+/// Load the URL in the tab and take a screenshot of it
+pub(crate) fn grab(url: &Url, opts: &Opts, tab: &Tab) -> Result<Page, Error> {
+    throttle::wait_for_rate();
+    tab.navigate_to(url.as_str())?;
+    tab.wait_until_navigated()?;
+    events::emit(Event::Connected, "web", url);
+    // Chrome encodes the screenshot in the chosen format, so it can be
+    // written out as it is
+    let format = match opts.image_format {
+        ImageFormat::Png => ScreenshotFormat::PNG,
+        ImageFormat::Jpeg => {
+            ScreenshotFormat::JPEG(Some(opts.jpeg_quality.into()))
+        }
+    };
+    let image_data = tab.capture_screenshot(format, None, true)?;
+
+    // The scroll size is informational so don't fail the capture if it
+    // can't be determined
+    let scroll_size = get_scroll_size(tab).unwrap_or_else(|e| {
+        warn!("Unable to read scroll size: {}", e);
+        None
+    });
+    if let Some((w, h)) = scroll_size {
+        info!("Page scroll size is {}x{}", w, h);
+    }
+
+    let title = get_title(tab).unwrap_or_else(|e| {
+        warn!("Unable to read page title: {}", e);
+        None
+    });
+
+    let body_hash = match tab.get_content() {
+        // Blank pages would all hash the same, so aren't useful
+        Ok(content) if content.trim().is_empty() => None,
+        Ok(content) => Some(page_hash::page_hash(&content)),
+        Err(e) => {
+            warn!("Unable to read page content: {}", e);
+            None
+        }
+    };
+
+    Ok(Page {
+        image_data,
+        title,
+        scroll_size,
+        body_hash,
+    })
 }
 
 pub fn capture(
     target: &Target,
-    output_dir: &str,
+    scheme_probed: bool,
+    opts: &Opts,
     tab: &Tab,
     report_tx: &mpsc::Sender<ReportMessage>,
 ) -> Result<(), Error> {
     info!("Processing {}", target);
+    let start = Instant::now();
 
-    let filename = format!("{}.png", target_to_filename(&target));
+    let filename = image_filename(opts, "web", target);
 
     let relative_filepath = Path::new("web").join(&filename);
-    let output_file = Path::new(output_dir).join(&relative_filepath);
-    info!("Saving image as {}", output_file.display());
     if let Target::Url(target) = target {
-        tab.navigate_to(target.as_str())?;
-        tab.wait_until_navigated()?;
-        let png_data = tab
-            .capture_screenshot(ScreenshotFormat::PNG, None, true)
-            .expect("error making screenshot");
-        let mut file = File::create(&output_file)?;
-        file.write_all(&png_data)?;
+        let Page {
+            image_data,
+            title,
+            scroll_size,
+            body_hash,
+        } = grab(target, opts, tab)?;
+        let mut file = sink::location(opts, &relative_filepath);
+        // The screenshot only needs decoding if it is to be checked for
+        // blankness or hashed
+        let decoded = if opts.only_successful || opts.dedupe.is_some() {
+            Some(image::load_from_memory(&image_data)?)
+        } else {
+            None
+        };
+        if opts.only_successful && decoded.as_ref().is_some_and(is_uniform) {
+            return Err(Error::BlankImage);
+        }
+        let duplicate = decoded.and_then(|image| {
+            dedupe::find_duplicate(&image, opts.dedupe, target.as_str(), &file)
+        });
+        if let Some(original) = &duplicate {
+            info!("Image is a duplicate of {}, not saving", original.target);
+            file = original.file.clone();
+        } else {
+            info!("Saving image as {}", file);
+            sink::write(opts, &relative_filepath, &image_data)?;
+        }
+
         let report_data = WebOutput {
             url: target.as_str().to_string(),
-            file: relative_filepath.display().to_string(),
+            file,
+            duplicate_of: duplicate.map(|d| d.target),
+            title,
+            scroll_size,
+            body_hash,
+            scheme_probed,
+            duration: start.elapsed(),
+            correlation_id: logging::correlation_id().unwrap_or_default(),
         }
         .as_report_message();
         report_tx.send(report_data)?;
@@ -0,0 +1,161 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Hashing of page content so that identical pages, e.g. the same login
+//! page on lots of appliances, can be grouped together. Values that
+//! change on every request are stripped before hashing:
+//!
+//! * `<input>` and `<meta>` tags mentioning CSRF tokens or nonces are
+//!   removed entirely, as are `nonce` attributes on other tags
+//! * Runs of eight or more hex characters containing at least one digit,
+//!   such as timestamps and session IDs, are replaced with a placeholder
+//! * Whitespace is collapsed
+
+/// Words that mark an `<input>` or `<meta>` tag as holding a per-request
+/// value
+const VOLATILE_MARKERS: &[&str] =
+    &["csrf", "xsrf", "token", "nonce", "authenticity"];
+
+/// Runs of at least this many hex characters are assumed to be
+/// timestamps or random IDs
+const VOLATILE_RUN_LENGTH: usize = 8;
+
+/// Hash the normalised page content, returning it as a hex string
+pub fn page_hash(html: &str) -> String {
+    format!("{:016x}", fnv1a(normalise(html).as_bytes()))
+}
+
+/// 64-bit FNV-1a. Not cryptographic, but stable across runs and
+/// platforms, which is what matters here
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn normalise(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(e) => start + e + 1,
+            None => break,
+        };
+        let tag = &rest[start..end];
+        if !is_volatile_tag(tag) {
+            out.push_str(&strip_nonce(tag));
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+
+    mask_runs(&out)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn is_volatile_tag(tag: &str) -> bool {
+    let tag = tag.to_ascii_lowercase();
+    (tag.starts_with("<input") || tag.starts_with("<meta"))
+        && VOLATILE_MARKERS.iter().any(|m| tag.contains(m))
+}
+
+/// Remove nonce="..." attributes, e.g. on script tags
+fn strip_nonce(tag: &str) -> String {
+    let lower = tag.to_ascii_lowercase();
+    let start = match lower.find("nonce=") {
+        Some(s) => s,
+        None => return tag.to_string(),
+    };
+    let value_start = start + "nonce=".len();
+    let end = match tag[value_start..].chars().next() {
+        Some(q) if q == '"' || q == '\'' => tag[value_start + 1..]
+            .find(q)
+            .map(|e| value_start + 1 + e + 1),
+        _ => tag[value_start..]
+            .find(|c: char| c.is_whitespace() || c == '>')
+            .map(|e| value_start + e),
+    }
+    .unwrap_or(tag.len());
+
+    format!("{}{}", &tag[..start], &tag[end..])
+}
+
+/// Replace long runs of hex characters that contain a digit with a
+/// placeholder
+fn mask_runs(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut run = String::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if c.is_ascii_hexdigit() {
+            run.push(c);
+            continue;
+        }
+        if run.len() >= VOLATILE_RUN_LENGTH
+            && run.chars().any(|c| c.is_ascii_digit())
+        {
+            out.push('#');
+        } else {
+            out.push_str(&run);
+        }
+        run.clear();
+        out.push(c);
+    }
+    // Remove the extra space used to flush the final run
+    out.pop();
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn volatile_values_ignored() {
+        let a = r#"<html><head><meta name="csrf-token" content="abc123">
+            <script nonce="r4nd0m">init();</script></head>
+            <body><form><input type="hidden" name="csrf" value="x1y2">
+            <input type="text" name="user"></form>
+            <p>Generated at 1592900000</p></body></html>"#;
+        let b = r#"<html><head><meta name="csrf-token" content="zzz999">
+            <script nonce='0th3r'>init();</script></head>
+            <body><form><input type="hidden" name="csrf" value="q9w8">
+            <input type="text" name="user"></form>
+            <p>Generated at     1592900123</p></body></html>"#;
+        assert_eq!(page_hash(a), page_hash(b));
+    }
+
+    #[test]
+    fn different_pages_differ() {
+        let page = include_str!("../../test/webpage.html");
+        assert_ne!(page_hash(page), page_hash(&page.replace("test", "prod")));
+        assert_eq!(page_hash(page).len(), 16);
+    }
+
+    #[test]
+    fn words_are_not_masked() {
+        assert_eq!(mask_runs("deadbeefcafe"), "deadbeefcafe");
+        assert_eq!(mask_runs("id 0123abcd9 end"), "id # end");
+    }
+}
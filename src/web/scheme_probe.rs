@@ -0,0 +1,281 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Work out whether a web server on a non-standard port speaks HTTP or
+//! HTTPS by sending a TLS ClientHello and seeing whether a TLS record
+//! comes back
+
+use crate::error::Error;
+use crate::resolve;
+use crate::throttle;
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use url::{Host, Url};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TLS record content types that a TLS server may reply with: a
+/// handshake (ServerHello) or an alert if it didn't like the ClientHello
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_ALERT: u8 = 0x15;
+
+/// The scheme is only ambiguous if the URL has a port that isn't the
+/// standard one for either HTTP or HTTPS
+pub fn is_ambiguous(url: &Url) -> bool {
+    match url.port() {
+        Some(80) | Some(443) | None => false,
+        Some(_) => true,
+    }
+}
+
+/// Probe the server behind `url` and return the URL with the scheme that
+/// it actually speaks. Returns None if the scheme isn't ambiguous
+pub fn probe_scheme(url: &Url) -> Result<Option<Url>, Error> {
+    if !is_ambiguous(url) {
+        return Ok(None);
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::IoError(format!("Unable to resolve {}", url)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addr = resolve::resolve(host, port)?;
+    let sni = match url.host() {
+        Some(Host::Domain(d)) => Some(d.to_string()),
+        _ => None,
+    };
+
+    let scheme = if speaks_tls(addr, sni.as_deref())? {
+        "https"
+    } else {
+        "http"
+    };
+    let mut probed = url.clone();
+    probed.set_scheme(scheme).map_err(|_| {
+        Error::IoError(format!("Unable to set scheme on {}", url))
+    })?;
+    Ok(Some(probed))
+}
+
+/// Pair up HTTPS URLs on the default port with the same URL over HTTP,
+/// as a bare host is parsed as both. The map is from each HTTPS URL to
+/// its HTTP counterpart, which is only worth trying if HTTPS fails
+pub fn http_fallbacks(urls: &[&Url]) -> HashMap<String, Url> {
+    let all: HashSet<&str> = urls.iter().map(|u| u.as_str()).collect();
+    urls.iter()
+        .filter(|u| u.scheme() == "https" && u.port().is_none())
+        .filter_map(|u| {
+            let mut http = (*u).clone();
+            http.set_scheme("http").ok()?;
+            // Without an explicit port the HTTP URL is on port 80
+            if all.contains(http.as_str()) && http.port().is_none() {
+                Some((u.to_string(), http))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Send a ClientHello and check whether the reply is a TLS record. A
+/// plain HTTP server will either close the connection, send an HTTP
+/// error, or wait for more data until the timeout
+fn speaks_tls(addr: SocketAddr, sni: Option<&str>) -> io::Result<bool> {
+    throttle::wait_for_rate();
+    let mut stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    stream.write_all(&client_hello(sni))?;
+
+    let mut reply = [0_u8; 2];
+    match stream.read_exact(&mut reply) {
+        Ok(()) => {
+            trace!("Probe reply from {}: {:02x?}", addr, reply);
+            Ok(is_tls_record(&reply))
+        }
+        Err(e)
+            if e.kind() == io::ErrorKind::UnexpectedEof
+                || e.kind() == io::ErrorKind::WouldBlock
+                || e.kind() == io::ErrorKind::TimedOut
+                || e.kind() == io::ErrorKind::ConnectionReset =>
+        {
+            debug!("No TLS reply from {}: {}", addr, e);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn is_tls_record(reply: &[u8]) -> bool {
+    matches!(reply, [TLS_HANDSHAKE, 0x03, ..] | [TLS_ALERT, 0x03, ..])
+}
+
+/// Build a minimal TLS 1.2 ClientHello with a handful of widely
+/// supported cipher suites
+fn client_hello(sni: Option<&str>) -> Vec<u8> {
+    const CIPHER_SUITES: &[u16] = &[
+        0x1301, 0x1302, 0x1303, // TLS 1.3 suites
+        0xc02b, 0xc02f, 0xc02c, 0xc030, // ECDHE GCM
+        0xc013, 0xc014, // ECDHE CBC
+        0x009c, 0x009d, 0x002f, 0x0035, // RSA
+    ];
+
+    let mut extensions = Vec::new();
+    if let Some(name) = sni {
+        let name = name.as_bytes();
+        let mut sni_ext = Vec::new();
+        push_u16(&mut sni_ext, name.len() as u16 + 3);
+        sni_ext.push(0); // host_name
+        push_u16(&mut sni_ext, name.len() as u16);
+        sni_ext.extend_from_slice(name);
+        push_extension(&mut extensions, 0x0000, &sni_ext);
+    }
+    // supported_groups: x25519, secp256r1, secp384r1
+    push_extension(
+        &mut extensions,
+        0x000a,
+        &[0x00, 0x06, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18],
+    );
+    // ec_point_formats: uncompressed
+    push_extension(&mut extensions, 0x000b, &[0x01, 0x00]);
+    // signature_algorithms: rsa_pss_rsae_sha256, ecdsa_secp256r1_sha256,
+    // rsa_pkcs1_sha256, rsa_pkcs1_sha1
+    push_extension(
+        &mut extensions,
+        0x000d,
+        &[0x00, 0x08, 0x08, 0x04, 0x04, 0x03, 0x04, 0x01, 0x02, 0x01],
+    );
+
+    let mut body = Vec::new();
+    push_u16(&mut body, 0x0303); // TLS 1.2
+
+    // The random doesn't need to be random as the handshake is never
+    // completed
+    body.extend_from_slice(&[0x5c; 32]);
+    body.push(0); // empty session ID
+    push_u16(&mut body, (CIPHER_SUITES.len() * 2) as u16);
+    for suite in CIPHER_SUITES {
+        push_u16(&mut body, *suite);
+    }
+    body.extend_from_slice(&[0x01, 0x00]); // null compression only
+    push_u16(&mut body, extensions.len() as u16);
+    body.extend_from_slice(&extensions);
+
+    let mut handshake = vec![0x01]; // ClientHello
+    let len = body.len() as u32;
+    handshake.extend_from_slice(&len.to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+    push_u16(&mut record, handshake.len() as u16);
+    record.extend_from_slice(&handshake);
+    record
+}
+
+fn push_u16(buf: &mut Vec<u8>, val: u16) {
+    buf.extend_from_slice(&val.to_be_bytes());
+}
+
+fn push_extension(buf: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+    push_u16(buf, ext_type);
+    push_u16(buf, data.len() as u16);
+    buf.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn fallback_pairs() {
+        let urls: Vec<Url> = [
+            "https://192.0.2.1/",
+            "http://192.0.2.1/",
+            "https://192.0.2.2/",
+            "https://192.0.2.3:8443/",
+            "http://192.0.2.3:8443/",
+            "http://192.0.2.4/",
+        ]
+        .iter()
+        .map(|u| Url::parse(u).unwrap())
+        .collect();
+        let fallbacks = http_fallbacks(&urls.iter().collect::<Vec<_>>());
+        assert_eq!(fallbacks.len(), 1);
+        assert_eq!(
+            fallbacks.get("https://192.0.2.1/").map(Url::as_str),
+            Some("http://192.0.2.1/")
+        );
+    }
+
+    /// Start a server that reads the ClientHello and replies with the
+    /// given bytes
+    fn fake_server(reply: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0_u8; 512];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(reply);
+        });
+        addr
+    }
+
+    #[test]
+    fn ambiguous_ports() {
+        assert!(!is_ambiguous(&Url::parse("https://example.com").unwrap()));
+        assert!(!is_ambiguous(
+            &Url::parse("https://example.com:80").unwrap()
+        ));
+        assert!(is_ambiguous(
+            &Url::parse("http://example.com:8443").unwrap()
+        ));
+    }
+
+    #[test]
+    fn client_hello_lengths() {
+        let hello = client_hello(Some("example.com"));
+        let record_len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+        assert_eq!(record_len, hello.len() - 5);
+        let handshake_len =
+            u32::from_be_bytes([0, hello[6], hello[7], hello[8]]) as usize;
+        assert_eq!(handshake_len, hello.len() - 9);
+    }
+
+    #[test]
+    fn detect_tls() {
+        // Handshake failure alert
+        let addr = fake_server(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]);
+        assert!(speaks_tls(addr, None).unwrap());
+
+        let addr = fake_server(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        assert!(!speaks_tls(addr, None).unwrap());
+
+        let addr = fake_server(b"");
+        assert!(!speaks_tls(addr, None).unwrap());
+    }
+}
@@ -0,0 +1,289 @@
+/*
+ *   This file is part of NCC Group Scrying https://github.com/nccgroup/scrying
+ *   Copyright 2020 David Young <david(dot)young(at)nccgroup(dot)com>
+ *   Released as open source by NCC Group Plc - https://www.nccgroup.com
+ *
+ *   Scrying is free software: you can redistribute it and/or modify
+ *   it under the terms of the GNU General Public License as published by
+ *   the Free Software Foundation, either version 3 of the License, or
+ *   (at your option) any later version.
+ *
+ *   Scrying is distributed in the hope that it will be useful,
+ *   but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *   GNU General Public License for more details.
+ *
+ *   You should have received a copy of the GNU General Public License
+ *   along with Scrying.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::argparse::Opts;
+use crate::error::Error;
+use crate::logging;
+use crate::parsing::Target;
+use crate::reporting::{AsReportMessage, ReportMessage};
+use crate::resolve;
+use crate::throttle;
+use crate::tls::{self, Recorder, TlsDetails};
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+use native_tls::TlsConnector;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper limit on how much of the response to read, the headers are all
+/// that is needed
+const MAX_RESPONSE_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub struct WinRmOutput {
+    target: String,
+    status: String,
+    /// The certificate and handshake of an HTTPS listener
+    tls: Option<TlsDetails>,
+    duration: Duration,
+    correlation_id: String,
+}
+
+impl WinRmOutput {
+    /// Create an output for the current thread's correlation ID
+    pub fn new(target: String, status: String, duration: Duration) -> Self {
+        Self {
+            target,
+            status,
+            tls: None,
+            duration,
+            correlation_id: logging::correlation_id().unwrap_or_default(),
+        }
+    }
+
+    /// Reachability and service information gathered by the probe
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+impl AsReportMessage for WinRmOutput {
+    fn as_report_message(self) -> ReportMessage {
+        ReportMessage::WinRmOutput(self)
+    }
+    fn target(&self) -> &str {
+        &self.target
+    }
+    /// WinRM outputs are text-only report entries, so there is no file
+    fn file(&self) -> &str {
+        ""
+    }
+    fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+    fn set_target(&mut self, target: String) {
+        self.target = target;
+    }
+    fn banner(&self) -> Option<&str> {
+        self.tls.as_ref()?.subject.as_deref()
+    }
+    fn clear_banner(&mut self) {
+        if let Some(tls) = &mut self.tls {
+            tls.subject = None;
+        }
+    }
+    fn tls(&self) -> Option<&TlsDetails> {
+        self.tls.as_ref()
+    }
+}
+
+/// Summarise the interesting parts of an HTTP response: the status code,
+/// the Server header, and any offered authentication schemes
+fn summarise_response(response: &str) -> Result<String, Error> {
+    let mut lines = response.lines();
+    let status = lines
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .ok_or_else(|| {
+            Error::WinRmError("Invalid HTTP response".to_string())
+        })?;
+
+    let mut summary = format!("HTTP {}", status);
+    let mut auth_schemes: Vec<&str> = Vec::new();
+    for line in lines {
+        if line.is_empty() {
+            // End of the headers
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("server") {
+                summary.push_str(&format!(", Server: {}", value));
+            } else if name.eq_ignore_ascii_case("www-authenticate") {
+                // Only the scheme name is interesting, not the challenge
+                if let Some(scheme) = value.split_whitespace().next() {
+                    auth_schemes.push(scheme);
+                }
+            }
+        }
+    }
+    if !auth_schemes.is_empty() {
+        summary.push_str(&format!(", Auth: {}", auth_schemes.join(", ")));
+    }
+
+    Ok(summary)
+}
+
+/// Probe the target, returning the response summary and, for HTTPS, the
+/// details of the listener's certificate and handshake
+fn probe(target: &Target) -> Result<(String, Option<TlsDetails>), Error> {
+    let url = match target {
+        Target::Url(u) => u,
+        Target::Address(_) => {
+            return Err(Error::WinRmError(format!(
+                "Invalid WinRM target: {}",
+                target
+            )));
+        }
+    };
+
+    let host = url.host_str().ok_or_else(|| {
+        Error::WinRmError(format!("No host in WinRM target: {}", target))
+    })?;
+    let port = url.port_or_known_default().unwrap_or(5985);
+    let addr = resolve::resolve(
+        host.trim_start_matches('[').trim_end_matches(']'),
+        port,
+    )?;
+
+    throttle::wait_for_rate();
+    let stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if url.scheme() == "https" {
+        // WinRM listeners usually have self-signed certificates, and
+        // only the headers are wanted so there's nothing to protect
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .map_err(|e| Error::WinRmError(format!("TLS error: {}", e)))?;
+        let mut stream = connector
+            .connect(host, Recorder::new(stream))
+            .map_err(|e| {
+                Error::WinRmError(format!("TLS handshake failed: {}", e))
+            })?;
+        let details = tls::details(&mut stream);
+        Ok((request(stream, url)?, Some(details)))
+    } else {
+        Ok((request(stream, url)?, None))
+    }
+}
+
+/// Send an empty WS-Management request and summarise the response
+fn request<S: Read + Write>(mut stream: S, url: &Url) -> Result<String, Error> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(5985);
+    write!(
+        stream,
+        "POST /wsman HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: 0\r\n\
+        Connection: close\r\n\r\n",
+        host, port
+    )?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_SIZE as u64)
+        .read_to_end(&mut response)?;
+
+    summarise_response(&String::from_utf8_lossy(&response))
+}
+
+pub fn capture(
+    target: &Target,
+    opts: &Opts,
+    report_tx: &mpsc::Sender<ReportMessage>,
+) -> Result<(), Error> {
+    info!("Probing WinRM endpoint {}", target);
+
+    if opts.web_proxy.is_some() {
+        // Probing directly would bypass the proxy, which is unlikely
+        // to be what was intended
+        return Err(Error::WinRmError(
+            "Proxies are not supported for WinRM probes".to_string(),
+        ));
+    }
+
+    let start = Instant::now();
+    let (status, tls) = probe(target)?;
+    info!("{}: {}", target, status);
+    let mut output =
+        WinRmOutput::new(target.to_string(), status, start.elapsed());
+    output.tls = tls;
+    let report_data = output.as_report_message();
+    report_tx.send(report_data)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summarise_wsman_response() {
+        let response = "HTTP/1.1 401 \r\n\
+            Server: Microsoft-HTTPAPI/2.0\r\n\
+            WWW-Authenticate: Negotiate\r\n\
+            WWW-Authenticate: Kerberos realm=\"EXAMPLE\"\r\n\
+            Date: Tue, 23 Jun 2020 10:00:00 GMT\r\n\
+            Connection: close\r\n\
+            Content-Length: 0\r\n\r\n";
+
+        assert_eq!(
+            summarise_response(response).unwrap(),
+            "HTTP 401, Server: Microsoft-HTTPAPI/2.0, Auth: Negotiate, Kerberos"
+        );
+
+        assert!(summarise_response("").is_err());
+    }
+
+    #[test]
+    fn probe_http() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let len = stream.read(&mut request).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 405 Method Not Allowed\r\n\
+                    Server: Microsoft-HTTPAPI/2.0\r\n\r\n",
+                )
+                .unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        let target = Target::Url(
+            format!("http://127.0.0.1:{}/wsman", port).parse().unwrap(),
+        );
+        assert_eq!(
+            probe(&target).unwrap(),
+            ("HTTP 405, Server: Microsoft-HTTPAPI/2.0".to_string(), None)
+        );
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /wsman HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+    }
+}